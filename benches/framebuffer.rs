@@ -0,0 +1,113 @@
+/*
+ * The cost of drawing into and reading from a framebuffer, which every
+ * application and every update pays: "cargo bench --bench framebuffer".
+ */
+
+use criterion::{black_box, criterion_group, criterion_main};
+use criterion::{BatchSize, Criterion};
+
+use jvnc::source::{Rect, Source};
+use jvnc::Framebuffer;
+
+const WIDTH: usize = 1024;
+const HEIGHT: usize = 768;
+
+fn put(c: &mut Criterion) {
+    let fb = Framebuffer::new(WIDTH, HEIGHT).unwrap();
+    c.bench_function("put full frame", |b| {
+        b.iter(|| {
+            for y in 0..HEIGHT {
+                for x in 0..WIDTH {
+                    fb.put(x, y, x as u8, y as u8, 0x80);
+                }
+            }
+        })
+    });
+}
+
+/*
+ * As above, but every pixel changes every time, so that the cost of tracking
+ * damage is included.
+ */
+fn put_changing(c: &mut Criterion) {
+    let fb = Framebuffer::new(WIDTH, HEIGHT).unwrap();
+    let mut level = 0u8;
+    c.bench_function("put full frame, changing", |b| {
+        b.iter(|| {
+            level = level.wrapping_add(1);
+            for y in 0..HEIGHT {
+                for x in 0..WIDTH {
+                    fb.put(x, y, x as u8, y as u8, level);
+                }
+            }
+        })
+    });
+}
+
+fn get(c: &mut Criterion) {
+    let fb = Framebuffer::new(WIDTH, HEIGHT).unwrap();
+    c.bench_function("get full frame", |b| {
+        b.iter(|| {
+            let mut sum = 0u32;
+            for y in 0..HEIGHT {
+                for x in 0..WIDTH {
+                    let (r, g, b) = fb.get(x, y);
+                    sum = sum.wrapping_add(r as u32 + g as u32 + b as u32);
+                }
+            }
+            black_box(sum)
+        })
+    });
+}
+
+fn read_rect(c: &mut Criterion) {
+    let fb = Framebuffer::new(WIDTH, HEIGHT).unwrap();
+    let full = Rect::new(0, 0, WIDTH, HEIGHT);
+    let tile = Rect::new(100, 100, 64, 64);
+    let mut out = Vec::with_capacity(WIDTH * HEIGHT);
+    c.bench_function("read_rect full frame", |b| {
+        b.iter(|| {
+            out.clear();
+            fb.read_rect(&full, &mut out);
+            black_box(&out);
+        })
+    });
+    c.bench_function("read_rect 64x64", |b| {
+        b.iter(|| {
+            out.clear();
+            fb.read_rect(&tile, &mut out);
+            black_box(&out);
+        })
+    });
+}
+
+fn read_rows(c: &mut Criterion) {
+    let fb = Framebuffer::new(WIDTH, HEIGHT).unwrap();
+    let full = Rect::new(0, 0, WIDTH, HEIGHT);
+    let mut out = vec![0; WIDTH * HEIGHT];
+    c.bench_function("read_rows full frame", |b| {
+        b.iter(|| {
+            fb.read_rows(&full, &mut out);
+            black_box(&out);
+        })
+    });
+}
+
+fn present(c: &mut Criterion) {
+    let fb = Framebuffer::new_buffered(WIDTH, HEIGHT, true).unwrap();
+    let mut level = 0u8;
+    c.bench_function("present full frame", |b| {
+        b.iter_batched(|| {
+            level = level.wrapping_add(1);
+            for y in 0..HEIGHT {
+                for x in 0..WIDTH {
+                    fb.put(x, y, x as u8, y as u8, level);
+                }
+            }
+        }, |_| fb.present(), BatchSize::PerIteration)
+    });
+}
+
+criterion_group!(benches, put, put_changing, get, read_rect, read_rows,
+    present);
+criterion_main!(benches);
@@ -0,0 +1,481 @@
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::diag;
+use crate::encoding::overlay::Layer;
+use crate::framebuffer::MAX_DIMENSION;
+use crate::handoff;
+use crate::listen;
+use crate::reverse;
+use crate::notify::Notification;
+use crate::server::Server;
+use crate::source::Rect;
+use crate::trace;
+
+/*
+ * The administrative interface is a Unix socket on which each connection
+ * carries a single request: one line containing a command, to which the
+ * server replies and then closes the connection.  Anybody who can connect
+ * can control the server, so the socket is for our own user alone.
+ *
+ * If "replace" is set, we have taken over from the process listening on
+ * this path, and the socket is ours to replace even though that process is
+ * still there.
+ */
+pub fn listen(server: &Arc<Server>, path: &Path, replace: bool)
+    -> Result<()>
+{
+    if !replace {
+        listen::clear(path).with_context(|| format!("admin socket {:?}", path))?;
+    }
+    let listener = bind_private(path)
+        .with_context(|| format!("admin socket {:?}", path))?;
+    log!("admin socket @ {:?}", path);
+
+    let server = Arc::clone(server);
+    tokio::spawn(async move {
+        loop {
+            let sock = match listener.accept().await {
+                Ok((sock, _)) => sock,
+                Err(e) => {
+                    log!("admin accept failure: {:?}", e);
+                    continue;
+                }
+            };
+
+            let server = Arc::clone(&server);
+            tokio::spawn(async move {
+                if let Err(e) = handle(&server, sock).await {
+                    log!("admin request failure: {:?}", e);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+/*
+ * Bind the socket in a directory that only we may enter, and move it into
+ * place once its permissions are set, so that there is no moment at which
+ * anybody else could connect to it.
+ */
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let dir = path.parent()
+        .filter(|d| !d.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let private = dir.join(format!(".jvnc-admin.{}", std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+
+    let tmp = private.join("sock");
+    let bound = UnixListener::bind(&tmp).and_then(|l| {
+        let mode = std::fs::Permissions::from_mode(0o600);
+        std::fs::set_permissions(&tmp, mode)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(l)
+    });
+    std::fs::remove_file(&tmp).ok();
+    std::fs::remove_dir(&private).ok();
+    bound
+}
+
+async fn handle(server: &Arc<Server>, mut sock: UnixStream) -> Result<()> {
+    let mut line = String::new();
+    BufReader::new(&mut sock).read_line(&mut line).await?;
+
+    /*
+     * A handoff replies with file descriptors, so needs the socket itself.
+     */
+    let out = if line.trim() == "handoff" {
+        match handoff::give(server, &mut sock).await {
+            Ok(()) => return Ok(()),
+            Err(e) => format!("error: {}\n", e).into_bytes(),
+        }
+    } else {
+        command(server, line.trim()).await?
+    };
+
+    sock.write_all(&out).await?;
+    sock.shutdown().await?;
+    Ok(())
+}
+
+/*
+ * Carry out a command, from the admin socket or the console, and produce the
+ * reply.  Errors are reported in the reply; only an internal failure causes
+ * this to fail.
+ */
+pub async fn command(server: &Arc<Server>, cmd: &str) -> Result<Vec<u8>> {
+    Ok(match cmd {
+        "diag" => {
+            let server = Arc::clone(server);
+            tokio::task::spawn_blocking(move || diag::bundle(&server))
+                .await??
+        }
+        "stats" => {
+            let encoders: serde_json::Map<_, _> = server.encoder_stats()
+                .into_iter()
+                .map(|(enc, e)| (enc.to_string(), serde_json::json!({
+                    "updates": e.updates,
+                    "bytes": e.bytes,
+                    "time_ms": e.time.as_secs_f64() * 1000.0,
+                })))
+                .collect();
+            let v = serde_json::json!({
+                "sessions": server.sessions().len(),
+                "encoders": encoders,
+                "handshakes": server.handshake_stats(),
+                "encode_cache": server.encode_cache.as_ref()
+                    .map(|c| c.describe()),
+                "frames": server.frames.describe(),
+                "idle": server.is_idle(),
+                "last_input_secs": server.last_input().elapsed().as_secs(),
+            });
+            let mut out = serde_json::to_vec_pretty(&v)?;
+            out.push(b'\n');
+            out
+        }
+        "events" => {
+            let mut out =
+                serde_json::to_vec_pretty(&server.events.describe())?;
+            out.push(b'\n');
+            out
+        }
+        "guard" => {
+            let mut out =
+                serde_json::to_vec_pretty(&server.guard.describe())?;
+            out.push(b'\n');
+            out
+        }
+        "leases" => match server.framebuffer() {
+            Some(fb) => {
+                let mut out =
+                    serde_json::to_vec_pretty(&fb.leases().describe())?;
+                out.push(b'\n');
+                out
+            }
+            None => b"error: the source is not a framebuffer\n".to_vec(),
+        },
+        cmd if cmd.starts_with("forgive ") => {
+            match cmd["forgive ".len()..].trim().parse() {
+                Ok(addr) if server.guard.forgive(addr) => b"ok\n".to_vec(),
+                Ok(addr) => format!("error: {} is not restricted\n", addr)
+                    .into_bytes(),
+                Err(e) => format!("error: {}\n", e).into_bytes(),
+            }
+        }
+        "bell" => {
+            server.bell();
+            b"ok\n".to_vec()
+        }
+        cmd if cmd.starts_with("display ") => {
+            match cmd["display ".len()..].trim().parse() {
+                Ok(power) => {
+                    server.set_display_power(power);
+                    b"ok\n".to_vec()
+                }
+                Err(e) => format!("error: {}\n", e).into_bytes(),
+            }
+        }
+        "refresh" => {
+            server.refresh_all();
+            b"ok\n".to_vec()
+        }
+        "sessions" => {
+            let v: Vec<_> = server.sessions().iter()
+                .map(|(_, s)| s.describe())
+                .collect();
+            let mut out = serde_json::to_vec_pretty(&v)?;
+            out.push(b'\n');
+            out
+        }
+        cmd if cmd == "prefs" || cmd.starts_with("prefs ") => {
+            let args: Vec<&str> = cmd.split_whitespace().skip(1).collect();
+            match prefs(server, &args) {
+                Ok(out) => out,
+                Err(e) => format!("error: {}\n", e).into_bytes(),
+            }
+        }
+        cmd if cmd == "sched" || cmd.starts_with("sched ") => {
+            match sched(server, &cmd["sched".len()..]) {
+                Ok(out) => out,
+                Err(e) => format!("error: {}\n", e).into_bytes(),
+            }
+        }
+        cmd if cmd.starts_with("pace ") => {
+            match pace(server, &cmd["pace ".len()..]) {
+                Ok(()) => b"ok\n".to_vec(),
+                Err(e) => format!("error: {}\n", e).into_bytes(),
+            }
+        }
+        cmd if cmd.starts_with("trace ") => {
+            match trace(server, &cmd["trace ".len()..]) {
+                Ok(()) => b"ok\n".to_vec(),
+                Err(e) => format!("error: {}\n", e).into_bytes(),
+            }
+        }
+        cmd if cmd.starts_with("connect ") => {
+            match reverse::spawn(server, cmd["connect ".len()..].trim()) {
+                Ok(()) => b"ok\n".to_vec(),
+                Err(e) => format!("error: {}\n", e).into_bytes(),
+            }
+        }
+        cmd if cmd.starts_with("name ") => {
+            server.set_name(&cmd["name ".len()..]);
+            b"ok\n".to_vec()
+        }
+        cmd if cmd.starts_with("notify ") => {
+            match notify(server, &cmd["notify ".len()..]) {
+                Ok(()) => b"ok\n".to_vec(),
+                Err(e) => format!("error: {}\n", e).into_bytes(),
+            }
+        }
+        cmd if cmd.starts_with("overlay ") => {
+            match overlay(server, &cmd["overlay ".len()..]) {
+                Ok(()) => b"ok\n".to_vec(),
+                Err(e) => format!("error: {}\n", e).into_bytes(),
+            }
+        }
+        cmd if cmd.starts_with("clipboard ") => {
+            server.set_clipboard(&cmd["clipboard ".len()..]);
+            b"ok\n".to_vec()
+        }
+        cmd if cmd.starts_with("source ") => {
+            match server.switch_source(cmd["source ".len()..].trim()) {
+                Ok(()) => b"ok\n".to_vec(),
+                Err(e) => format!("error: {}\n", e).into_bytes(),
+            }
+        }
+        cmd if cmd.starts_with("resize ") => {
+            match resize(server, &cmd["resize ".len()..]) {
+                Ok(()) => b"ok\n".to_vec(),
+                Err(e) => format!("error: {}\n", e).into_bytes(),
+            }
+        }
+        cmd if cmd.starts_with("share ") => {
+            match share(server, &cmd["share ".len()..]) {
+                Ok(()) => b"ok\n".to_vec(),
+                Err(e) => format!("error: {}\n", e).into_bytes(),
+            }
+        }
+        cmd => match server.command(cmd) {
+            Some(Ok(())) => b"ok\n".to_vec(),
+            Some(Err(e)) => format!("error: {}\n", e).into_bytes(),
+            None => format!("unknown command: {:?}\n", cmd).into_bytes(),
+        },
+    })
+}
+
+/*
+ * "prefs" lists the preferences for all users, "prefs ID" for one user, and
+ * "prefs ID KEY VALUE" changes one.  A value of "-" clears the preference.
+ */
+fn prefs(server: &Server, args: &[&str]) -> Result<Vec<u8>> {
+    let Some(store) = &server.prefs else {
+        bail!("preferences are not enabled");
+    };
+    let v = match args {
+        [] => store.describe(None),
+        [id] => store.describe(Some(id)),
+        [id, key, value] => {
+            store.set(id, key, value)?;
+            store.describe(Some(id))
+        }
+        _ => bail!("usage: prefs [ID [KEY VALUE]]"),
+    };
+    let mut out = serde_json::to_vec_pretty(&v)?;
+    out.push(b'\n');
+    Ok(out)
+}
+
+/*
+ * "sched" shows the update scheduler state for all sessions, along with the
+ * encode queue they share, and "sched ID" for one session.
+ */
+fn sched(server: &Server, arg: &str) -> Result<Vec<u8>> {
+    let sessions = match arg.trim() {
+        "" => server.sessions().into_iter().map(|(_, s)| s).collect(),
+        id => vec![server.session(id.parse()?)?],
+    };
+    let (interactive, bulk) = server.pool.queued();
+    let v = serde_json::json!({
+        "encode_queue": {
+            "interactive": interactive,
+            "bulk": bulk,
+        },
+        "sessions": sessions.iter()
+            .map(|s| s.describe_sched())
+            .collect::<Vec<_>>(),
+    });
+    let mut out = serde_json::to_vec_pretty(&v)?;
+    out.push(b'\n');
+    Ok(out)
+}
+
+/*
+ * "pace ID FPS" overrides the frame rate limit for a session, and "pace ID -"
+ * removes the override.
+ */
+fn pace(server: &Server, arg: &str) -> Result<()> {
+    let Some((id, fps)) = arg.split_once(' ') else {
+        bail!("expected a session ID and a frame rate");
+    };
+    let fps = match fps.trim() {
+        "-" => None,
+        fps => match fps.parse() {
+            Ok(n @ 1..=MAX_PACE) => Some(n),
+            _ => bail!("frame rate must be between 1 and {}", MAX_PACE),
+        },
+    };
+    server.session(id.parse()?)?.set_pace(fps);
+    Ok(())
+}
+
+const MAX_PACE: u64 = 120;
+
+/*
+ * "trace ID SECONDS" logs every message to and from a session for that long,
+ * at a limited rate, and "trace ID -" stops.
+ */
+fn trace(server: &Server, arg: &str) -> Result<()> {
+    let Some((id, secs)) = arg.split_once(' ') else {
+        bail!("expected a session ID and a number of seconds");
+    };
+    let time = match secs.trim() {
+        "-" => None,
+        secs => match secs.parse() {
+            Ok(n @ 1..) if Duration::from_secs(n) <= trace::MAX_TIME => {
+                Some(Duration::from_secs(n))
+            }
+            _ => bail!("time must be between 1 and {} seconds",
+                trace::MAX_TIME.as_secs()),
+        },
+    };
+    server.session(id.parse()?)?.set_trace(time);
+    Ok(())
+}
+
+/*
+ * "resize WIDTHxHEIGHT" replaces the framebuffer with one of a new size.
+ */
+fn resize(server: &Server, arg: &str) -> Result<()> {
+    let Some((w, h)) = arg.trim().split_once('x') else {
+        bail!("expected WIDTHxHEIGHT, not {:?}", arg);
+    };
+    server.resize(w.parse()?, h.parse()?)
+}
+
+/*
+ * "share X Y WIDTHxHEIGHT" shares only that part of the screen with clients,
+ * and "share -" all of it again.
+ */
+fn share(server: &Server, arg: &str) -> Result<()> {
+    let r = match arg.split_whitespace().collect::<Vec<_>>()[..] {
+        ["-"] => None,
+        [x, y, size] => {
+            let Some((w, h)) = size.split_once('x') else {
+                bail!("expected WIDTHxHEIGHT, not {:?}", size);
+            };
+            Some(Rect::new(x.parse()?, y.parse()?, w.parse()?, h.parse()?))
+        }
+        _ => bail!("usage: share X Y WIDTHxHEIGHT | -"),
+    };
+    server.share_region(r)
+}
+
+/*
+ * "overlay NAME X Y WIDTHxHEIGHT AARRGGBB" draws a rectangle over the screen,
+ * which may be translucent, and "overlay NAME -" removes it.
+ */
+fn overlay(server: &Server, arg: &str) -> Result<()> {
+    let layer = match arg.split_whitespace().collect::<Vec<_>>()[..] {
+        [name, "-"] => {
+            server.set_overlay(name, None);
+            return Ok(());
+        }
+        [_, x, y, size, colour] => {
+            let Some((w, h)) = size.split_once('x') else {
+                bail!("expected WIDTHxHEIGHT, not {:?}", size);
+            };
+            let (w, h) = (w.parse()?, h.parse()?);
+            if w > MAX_DIMENSION || h > MAX_DIMENSION {
+                bail!("overlay is too large");
+            }
+            let argb = u32::from_str_radix(
+                colour.trim_start_matches("0x"), 16)?;
+            Layer::filled(x.parse()?, y.parse()?, w, h, argb)
+        }
+        _ => bail!("usage: overlay NAME X Y WIDTHxHEIGHT AARRGGBB | NAME -"),
+    };
+    server.set_overlay(arg.split_whitespace().next().unwrap(), Some(layer));
+    Ok(())
+}
+
+/*
+ * "notify ID|all MESSAGE" shows a message to the users of one or all clients.
+ */
+fn notify(server: &Server, arg: &str) -> Result<()> {
+    let Some((who, text)) = arg.split_once(' ') else {
+        bail!("expected a session ID or \"all\", and a message");
+    };
+    let n = Notification::new(text);
+    if who == "all" {
+        server.notify_all(n);
+        Ok(())
+    } else {
+        server.notify(who.parse()?, n)
+    }
+}
+
+/*
+ * Make a request of a running server, returning the complete response.
+ */
+pub async fn request(path: &Path, cmd: &str) -> Result<Vec<u8>> {
+    let mut sock = UnixStream::connect(path).await?;
+    sock.write_all(format!("{}\n", cmd).as_bytes()).await?;
+
+    let mut out = Vec::new();
+    sock.read_to_end(&mut out).await?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    #[tokio::test]
+    async fn socket() {
+        let path = std::env::temp_dir()
+            .join(format!("jvnc-admin-{}.sock", std::process::id()));
+
+        /*
+         * A stale socket is cleared, but a live one is left alone, as is
+         * anything other than a socket.
+         */
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        listen::clear(&path).unwrap();
+        assert!(!path.exists());
+
+        let live = bind_private(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let e = listen::clear(&path).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::AddrInUse);
+        assert!(path.exists());
+        drop(live);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, b"").unwrap();
+        let e = listen::clear(&path).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+use anyhow::{bail, Result};
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::dh::Dh;
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::Private;
+use openssl::symm::{Cipher, Crypter, Mode};
+
+/*
+ * Apple Remote Desktop authentication: the server sends Diffie-Hellman
+ * parameters and its public key, and the client replies with its own public
+ * key and a username and password encrypted with AES, keyed by the MD5 hash
+ * of the shared secret.
+ */
+pub const CREDENTIALS_SIZE: usize = 128;
+
+const GENERATOR: u16 = 2;
+
+pub struct Exchange {
+    dh: Dh<Private>,
+    len: usize,
+}
+
+impl Exchange {
+    pub fn new() -> Result<Exchange> {
+        let p = BigNum::get_rfc3526_prime_2048()?;
+        let len = p.num_bytes() as usize;
+        let dh = Dh::from_pqg(p, None, BigNum::from_u32(GENERATOR as u32)?)?
+            .generate_key()?;
+        Ok(Exchange { dh, len })
+    }
+
+    /*
+     * The size of each public key, in bytes.
+     */
+    pub fn key_len(&self) -> usize {
+        self.len
+    }
+
+    /*
+     * The generator, key length, prime modulus and our public key, as sent
+     * to the client.
+     */
+    pub fn params(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&GENERATOR.to_be_bytes());
+        buf.extend_from_slice(&(self.len as u16).to_be_bytes());
+        buf.extend_from_slice(&self.dh.prime_p().to_vec_padded(
+            self.len as i32)?);
+        buf.extend_from_slice(&self.dh.public_key().to_vec_padded(
+            self.len as i32)?);
+        Ok(buf)
+    }
+
+    /*
+     * Recover the username and password from the client's reply.
+     */
+    pub fn credentials(&self, public: &[u8], creds: &[u8])
+        -> Result<(String, String)>
+    {
+        if creds.len() != CREDENTIALS_SIZE {
+            bail!("credentials are {} bytes, not {}", creds.len(),
+                CREDENTIALS_SIZE);
+        }
+
+        /*
+         * The shared secret is hashed at its full width, leading zero bytes
+         * and all.  We work it out ourselves, as DH_compute_key() leaves
+         * those bytes off and the openssl crate does not tell us how many
+         * there were.
+         */
+        let public = BigNum::from_slice(public)?;
+        let p = self.dh.prime_p();
+        let mut limit = p.to_owned()?;
+        limit.sub_word(1)?;
+        if public <= BigNum::from_u32(1)? || public >= limit {
+            bail!("invalid public key");
+        }
+        let mut ctx = BigNumContext::new()?;
+        let mut secret = BigNum::new()?;
+        secret.mod_exp(&public, self.dh.private_key(), p, &mut ctx)?;
+        let secret = secret.to_vec_padded(self.len as i32)?;
+        let key = hash(MessageDigest::md5(), &secret)?;
+
+        let cipher = Cipher::aes_128_ecb();
+        let mut c = Crypter::new(cipher, Mode::Decrypt, &key, None)?;
+        c.pad(false);
+        let mut plain = vec![0; creds.len() + cipher.block_size()];
+        let mut n = c.update(creds, &mut plain)?;
+        n += c.finalize(&mut plain[n..])?;
+        plain.truncate(n);
+
+        /*
+         * Each field is NUL terminated within its half, and the rest is
+         * random padding.
+         */
+        let field = |b: &[u8]| {
+            let end = b.iter().position(|c| *c == 0).unwrap_or(b.len());
+            String::from_utf8_lossy(&b[..end]).into_owned()
+        };
+        let (user, pass) = plain.split_at(CREDENTIALS_SIZE / 2);
+        Ok((field(user), field(pass)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*
+     * An exchange with a private key of our choosing, rather than a random
+     * one.
+     */
+    fn exchange(private: BigNum) -> Exchange {
+        let p = BigNum::get_rfc3526_prime_2048().unwrap();
+        let len = p.num_bytes() as usize;
+        let dh = Dh::from_pqg(p, None, BigNum::from_u32(2).unwrap()).unwrap()
+            .set_private_key(private).unwrap();
+        Exchange { dh, len }
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /*
+     * The credentials in these vectors were encrypted outside of jvnc, with
+     * Python to work out the shared secret and "openssl enc -aes-128-ecb"
+     * with the MD5 hash of it as the key.  Each field is followed by a NUL
+     * and then padded with 0xa5.
+     */
+    #[test]
+    fn credentials() {
+        let server = exchange(BigNum::from_hex_str("5f3a9c0e7d21b46a8c1f03e9\
+            5b72d6a4c8e1f0b39d2a7c654e18b03f9a6d2c71").unwrap());
+
+        /*
+         * The client's public key is 2 raised to its private key:
+         */
+        let private = BigNum::from_hex_str("2b7e151628aed2a6abf7158809cf4f3c\
+            762e7160f38b4da56a784d9045190cfe").unwrap();
+        let mut public = BigNum::new().unwrap();
+        public.mod_exp(&BigNum::from_u32(2).unwrap(), &private,
+            server.dh.prime_p(), &mut BigNumContext::new().unwrap())
+            .unwrap();
+        let creds = hex("4eb7d332fbe1af814fa8e84d5a8b257b\
+            4f4dc6c8dbbfe9676a7539319602288d4f4dc6c8dbbfe9676a7539319602288d\
+            4f4dc6c8dbbfe9676a7539319602288dd1db40a893f24bc29ef84ce4029dc54f\
+            4f4dc6c8dbbfe9676a7539319602288d4f4dc6c8dbbfe9676a7539319602288d\
+            4f4dc6c8dbbfe9676a7539319602288d");
+        assert_eq!(server.credentials(&public.to_vec(), &creds).unwrap(),
+            ("alice".to_string(), "hunter2".to_string()));
+        assert!(server.credentials(&public.to_vec(), &creds[..64]).is_err());
+        assert!(server.credentials(&[1], &creds).is_err());
+    }
+
+    #[test]
+    fn leading_zero() {
+        /*
+         * With a private key of 1, the shared secret is the client's public
+         * key, which we can then choose to begin with a zero byte.  The
+         * whole 256 bytes of it must still be hashed.
+         */
+        let server = exchange(BigNum::from_u32(1).unwrap());
+        let public = (0..255u32)
+            .map(|i| (i * 37 + 11) as u8)
+            .collect::<Vec<_>>();
+        let creds = hex("8f073f4bd5b60f53478327d6df950006\
+            6bab853e546cba963ff49b068d5b19aa6bab853e546cba963ff49b068d5b19aa\
+            6bab853e546cba963ff49b068d5b19aab30d67e07f5d055482be1f692134cf0d\
+            6bab853e546cba963ff49b068d5b19aa6bab853e546cba963ff49b068d5b19aa\
+            6bab853e546cba963ff49b068d5b19aa");
+        assert_eq!(server.credentials(&public, &creds).unwrap(),
+            ("bob".to_string(), "swordfish".to_string()));
+    }
+}
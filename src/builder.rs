@@ -0,0 +1,194 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::config::Config;
+use crate::framebuffer::Framebuffer;
+use crate::server::{ResizeError, Server};
+use crate::source::Source;
+
+/*
+ * Assemble a Server for an application that embeds jvnc.  Settings not made
+ * here take their defaults from Config, except that the admin socket and the
+ * console are off unless asked for: an application that wants them to read
+ * its standard input or create a socket in /tmp should say so.
+ */
+pub struct ServerBuilder {
+    config: Config,
+    source: Option<Arc<dyn Source>>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder::new()
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> ServerBuilder {
+        let config = Config {
+            admin: None,
+            console: false,
+            ..Config::default()
+        };
+        ServerBuilder { config, source: None }
+    }
+
+    /*
+     * Start from a complete configuration, such as Config::from_env(), and
+     * use it as it is.
+     */
+    pub fn from_config(config: Config) -> ServerBuilder {
+        ServerBuilder { config, source: None }
+    }
+
+    /*
+     * The size of the framebuffer to create, if one is not supplied.
+     */
+    pub fn geometry(mut self, width: usize, height: usize) -> Self {
+        self.config.width = width;
+        self.config.height = height;
+        self
+    }
+
+    /*
+     * Give the framebuffer a back buffer, if one is not supplied, so that the
+     * application can present whole frames.
+     */
+    pub fn double_buffer(mut self) -> Self {
+        self.config.double_buffer = true;
+        self
+    }
+
+    /*
+     * Use a framebuffer the application has already made, and perhaps
+     * drawn in; its size overrides the geometry.
+     */
+    pub fn framebuffer(self, fb: Arc<Framebuffer>) -> Self {
+        self.source(fb)
+    }
+
+    /*
+     * Take pixels from the application's own source, rather than a
+     * framebuffer.
+     */
+    pub fn source(mut self, source: Arc<dyn Source>) -> Self {
+        let (width, height) = source.dimensions();
+        self.config.width = width;
+        self.config.height = height;
+        self.source = Some(source);
+        self
+    }
+
+    /*
+     * The desktop name shown by viewers.
+     */
+    pub fn name(mut self, name: &str) -> Self {
+        self.config.name = name.to_string();
+        self
+    }
+
+    /*
+     * The most updates to send each client per second.
+     */
+    pub fn fps(mut self, fps: u64) -> Self {
+        self.config.fps = fps.max(1);
+        self
+    }
+
+    /*
+     * Replace the default address with these.  Each is a host and port, as
+     * for JVNC_LISTEN.
+     */
+    pub fn listen<I, S>(mut self, addrs: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.config.listen = addrs.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /*
+     * Require VNC Authentication with this password.
+     */
+    pub fn password(mut self, password: &str) -> Self {
+        self.config.password = Some(password.to_string());
+        self
+    }
+
+    pub fn view_password(mut self, password: &str) -> Self {
+        self.config.view_password = Some(password.to_string());
+        self
+    }
+
+    /*
+     * The names of the security types to offer, in order of preference, as
+     * for JVNC_SECURITY.
+     */
+    pub fn security<I, S>(mut self, types: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.config.security = Some(types.into_iter().map(Into::into)
+            .collect());
+        self
+    }
+
+    /*
+     * The certificate and key for the X509 subtypes of VeNCrypt.
+     */
+    pub fn certificate(mut self, cert: PathBuf, key: Option<PathBuf>) -> Self {
+        self.config.cert = Some(cert);
+        self.config.key = key;
+        self
+    }
+
+    /*
+     * Accept administrative requests on a Unix socket at this path.
+     */
+    pub fn admin(mut self, path: PathBuf) -> Self {
+        self.config.admin = Some(path);
+        self
+    }
+
+    /*
+     * Make any other change to the configuration.
+     */
+    pub fn configure<F: FnOnce(&mut Config)>(mut self, f: F) -> Self {
+        f(&mut self.config);
+        self
+    }
+
+    pub fn build(self) -> Result<Arc<Server>> {
+        let config = self.config;
+        let source = match self.source {
+            Some(source) => source,
+            None => {
+                let fb = Framebuffer::new_buffered(config.width,
+                    config.height, config.double_buffer)
+                    .with_context(|| {
+                        format!("framebuffer {}x{}", config.width,
+                            config.height)
+                    })?;
+                if config.debug_framebuffer {
+                    fb.self_test().map_err(|e| {
+                        anyhow!("framebuffer self-test failed: {}", e)
+                    })?;
+                    fb.poison();
+                }
+                Arc::new(fb)
+            }
+        };
+        let server = Server::new(config, source)?;
+        /*
+         * The upstream server decides the size of a proxied desktop.
+         */
+        if !server.config.client_resize || server.config.upstream.is_some() {
+            server.set_resize_policy(|_, _| Err(ResizeError::Prohibited));
+        }
+        Ok(Arc::new(server))
+    }
+}
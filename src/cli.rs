@@ -0,0 +1,277 @@
+/*
+ * Command line arguments for the jvnc demo.  Every setting may also come from
+ * a configuration file, or from the environment as JVNC_* variables; those
+ * given here take precedence over both.
+ */
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+
+use jvnc::tier::{PresetChange, Tier};
+use jvnc::Config;
+
+#[derive(Debug, Parser)]
+#[command(name = "jvnc", version, about = "A VNC server, and the tools for \
+    managing one.")]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub options: Options,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    #[command(about = "Fetch a diagnostics bundle from a running server")]
+    Diag {
+        file: Option<PathBuf>,
+    },
+    #[command(about = "Write a password file in the traditional format")]
+    Passwd {
+        #[arg(help = "Where to write it [default: ~/.vnc/passwd]")]
+        file: Option<PathBuf>,
+    },
+    #[command(about = "Run the demo under synthetic load, and write a \
+        report")]
+    Soak {
+        #[arg(help = "How long to run for")]
+        seconds: Option<u64>,
+        #[arg(help = "How many clients to simulate")]
+        clients: Option<usize>,
+        #[arg(help = "Where to write the report")]
+        file: Option<PathBuf>,
+    },
+}
+
+/*
+ * There is deliberately no option for the password itself, as anybody on the
+ * system could read it from the process table.  Use a password file, or
+ * JVNC_PASSWORD.
+ */
+#[derive(Debug, clap::Args)]
+pub struct Options {
+    #[arg(short, long, value_name = "FILE", global = true,
+        help = "Read settings from this TOML file")]
+    pub config: Option<PathBuf>,
+    #[arg(short, long, value_name = "[SCHEME://]ADDR:PORT|unix:PATH",
+        help = "Accept connections on this address or Unix socket, from \
+        WebSocket clients if prefixed with ws://, serving the viewer to \
+        browsers as well with http://, or in TLS with rfbs://; may be \
+        repeated")]
+    pub listen: Vec<String>,
+    #[arg(short, long, help = "Accept connections on this port, on every \
+        address other than a Unix socket")]
+    pub port: Option<u16>,
+    #[arg(short, long, value_name = "WIDTHxHEIGHT", value_parser = geometry,
+        help = "Size of the framebuffer")]
+    pub geometry: Option<(usize, usize)>,
+    #[arg(long, value_name = "[ID:NNNN@]HOST[:PORT]", help = "Connect \
+        to a viewer listening for us, or to a repeater with our ID, until it \
+        answers; may be repeated")]
+    pub connect: Vec<String>,
+    #[arg(long, value_name = "DIR", help = "A release of noVNC, to serve \
+        on http:// listeners")]
+    pub novnc: Option<PathBuf>,
+    #[arg(long, value_name = "HOST[:PORT]", help = "Show the desktop of \
+        this VNC server instead of our own, whose password, if it needs one, \
+        is in JVNC_UPSTREAM_PASSWORD")]
+    pub upstream: Option<String>,
+    #[arg(short, long, help = "Name of the desktop shown to clients")]
+    pub name: Option<String>,
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..=120),
+        help = "The most updates sent to a client each second")]
+    pub fps: Option<u64>,
+    #[arg(long, value_name = "quality=Q,fps=F,scale=S",
+        value_parser = PresetChange::parse,
+        help = "Encoder settings for clients in the LAN tier")]
+    pub tier_lan: Option<PresetChange>,
+    #[arg(long, value_name = "quality=Q,fps=F,scale=S",
+        value_parser = PresetChange::parse,
+        help = "Encoder settings for clients in the WAN tier")]
+    pub tier_wan: Option<PresetChange>,
+    #[arg(long, value_name = "quality=Q,fps=F,scale=S",
+        value_parser = PresetChange::parse,
+        help = "Encoder settings for clients in the poor tier")]
+    pub tier_poor: Option<PresetChange>,
+    #[arg(long, value_name = "LAN,WAN", value_parser = rtt,
+        help = "Round trip times, in milliseconds, below which clients are \
+        in the LAN and WAN tiers")]
+    pub tier_rtt: Option<(u64, u64)>,
+    #[arg(long, value_name = "TYPES", value_delimiter = ',',
+        value_parser = security,
+        help = "Security types to offer, in order of preference")]
+    pub security: Option<Vec<String>>,
+    #[arg(long, value_name = "FILE", help = "Password file for VNC \
+        Authentication, as written by \"jvnc passwd\"")]
+    pub password_file: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", help = "TLS pre-shared keys, which \
+        every client must then use")]
+    pub psk: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", help = "Certificate for VeNCrypt and \
+        rfbs:// listeners")]
+    pub cert: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", help = "Private key for the \
+        certificate, if not in the same file")]
+    pub key: Option<PathBuf>,
+    #[arg(long, value_name = "PATH", global = true,
+        help = "The admin socket")]
+    pub admin: Option<PathBuf>,
+}
+
+fn geometry(s: &str) -> Result<(usize, usize), String> {
+    let Some((w, h)) = s.split_once('x') else {
+        return Err(format!("expected WIDTHxHEIGHT, not {:?}", s));
+    };
+    match (w.parse(), h.parse()) {
+        (Ok(w), Ok(h)) if w > 0 && h > 0 => Ok((w, h)),
+        _ => Err(format!("invalid geometry {:?}", s)),
+    }
+}
+
+fn rtt(s: &str) -> Result<(u64, u64), String> {
+    let Some((lan, wan)) = s.split_once(',') else {
+        return Err(format!("expected LAN,WAN, not {:?}", s));
+    };
+    match (lan.parse(), wan.parse()) {
+        (Ok(lan), Ok(wan)) if lan <= wan => Ok((lan, wan)),
+        _ => Err(format!("invalid round trip times {:?}", s)),
+    }
+}
+
+fn security(s: &str) -> Result<String, String> {
+    match jvnc::security::from_name(s) {
+        Some(_) => Ok(s.to_string()),
+        None => Err(format!("unknown security type {:?}", s)),
+    }
+}
+
+impl Options {
+    /*
+     * The configuration: the file, if there is one, or the defaults, then
+     * the environment, and then the command line.
+     */
+    pub fn config(&self) -> Result<Config> {
+        let mut c = match &self.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::from_env(),
+        };
+        self.apply(&mut c);
+        Ok(c)
+    }
+
+    /*
+     * Override the settings in "c" with those given on the command line.
+     */
+    pub fn apply(&self, c: &mut Config) {
+        if !self.listen.is_empty() {
+            c.listen = self.listen.clone();
+        }
+        if let Some(port) = self.port {
+            c.listen = c.listen.iter()
+                .map(|addr| match addr.rsplit_once(':') {
+                    _ if addr.starts_with("unix:") => addr.clone(),
+                    Some((host, _)) => format!("{}:{}", host, port),
+                    None => format!("{}:{}", addr, port),
+                })
+                .collect();
+        }
+        if !self.connect.is_empty() {
+            c.connect = self.connect.clone();
+        }
+        if let Some(path) = &self.novnc {
+            c.novnc = Some(path.clone());
+        }
+        if let Some(upstream) = &self.upstream {
+            c.upstream = Some(upstream.clone());
+        }
+        if let Some((width, height)) = self.geometry {
+            c.width = width;
+            c.height = height;
+        }
+        if let Some(name) = &self.name {
+            c.name = name.clone();
+        }
+        if let Some(fps) = self.fps {
+            c.fps = fps;
+        }
+        for (tier, change) in [
+            (Tier::Lan, self.tier_lan),
+            (Tier::Wan, self.tier_wan),
+            (Tier::Poor, self.tier_poor),
+        ] {
+            if let Some(change) = change {
+                change.apply(c.presets.preset_mut(tier));
+            }
+        }
+        if let Some((lan, wan)) = self.tier_rtt {
+            c.presets.lan_rtt = Duration::from_millis(lan);
+            c.presets.wan_rtt = Duration::from_millis(wan);
+        }
+        if let Some(security) = &self.security {
+            c.security = Some(security.clone());
+        }
+        if let Some(path) = &self.password_file {
+            c.password_file = Some(path.clone());
+        }
+        if let Some(path) = &self.psk {
+            c.psk = Some(path.clone());
+        }
+        if let Some(path) = &self.cert {
+            c.cert = Some(path.clone());
+        }
+        if let Some(path) = &self.key {
+            c.key = Some(path.clone());
+        }
+        if let Some(path) = &self.admin {
+            c.admin = Some(path.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn options() {
+        Args::command().debug_assert();
+
+        let args = Args::try_parse_from(["jvnc", "-l", "127.0.0.1:5900",
+            "-l", "[::1]:5900", "-l", "unix:/tmp/x:1.sock", "-p", "5901",
+            "-g", "800x600", "--fps", "30",
+            "--security", "vencrypt,vnc-auth",
+            "--tier-wan", "scale=2", "--tier-rtt", "10,100"]).unwrap();
+        assert!(args.command.is_none());
+        let mut c = Config::default();
+        args.options.apply(&mut c);
+        assert_eq!(c.listen,
+            ["127.0.0.1:5901", "[::1]:5901", "unix:/tmp/x:1.sock"]);
+        assert_eq!((c.width, c.height), (800, 600));
+        assert_eq!(c.fps, 30);
+        assert_eq!(c.security.unwrap(), ["vencrypt", "vnc-auth"]);
+        assert_eq!(c.name, "jvnc");
+        assert_eq!(c.presets.preset(Tier::Wan).scale, 2);
+        assert_eq!(c.presets.preset(Tier::Wan).quality, 6);
+        assert_eq!(c.presets.preset(Tier::Poor).scale, 1);
+        assert_eq!(c.presets.lan_rtt, Duration::from_millis(10));
+
+        assert!(Args::try_parse_from(["jvnc", "-g", "800"]).is_err());
+        assert!(Args::try_parse_from(["jvnc", "--fps", "0"]).is_err());
+        assert!(Args::try_parse_from(["jvnc", "--security", "vnc"])
+            .is_err());
+        assert!(Args::try_parse_from(["jvnc", "--tier-lan", "fps=0"])
+            .is_err());
+        assert!(Args::try_parse_from(["jvnc", "--tier-rtt", "100,10"])
+            .is_err());
+
+        let args = Args::try_parse_from(["jvnc", "soak", "10", "--admin",
+            "/tmp/x.sock"]).unwrap();
+        assert!(matches!(args.command,
+            Some(Command::Soak { seconds: Some(10), clients: None, .. })));
+        assert_eq!(args.options.admin.unwrap(), PathBuf::from("/tmp/x.sock"));
+    }
+}
@@ -0,0 +1,1724 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use futures::StreamExt;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::{Instant, sleep_until, timeout_at};
+
+use crate::clipboard;
+use crate::compress::CompressedStream;
+use crate::config::Placeholder;
+use crate::cursor::Cursor;
+use crate::debounce::Debounce;
+use crate::events::Event;
+use crate::encoding::overlay::{Countdown, Damage, Layer};
+use crate::encoding::palette::Palette;
+use crate::encoding::pseudo::{self, Leds};
+use crate::encoding::{self, Params, Pipeline, Rect, Update};
+use crate::framebuffer;
+use crate::handshake::{Failed, Outcome};
+use crate::input::InputEvent;
+use crate::listen::{Stream, Transport};
+use crate::mousekeys::MouseKeys;
+use crate::notify::{Notification, TITLE_TIME};
+use crate::outbox::Outbox;
+use crate::pacing::Pacer;
+use crate::pool::Priority;
+use crate::rfb::{self, Bell, Frame, Screen, UpdateRequest, Version};
+use crate::rfb::{PixelFormat, ServerCutText, ServerFence, ServerMessage};
+use crate::rfb::{ServerXvp, SetColourMapEntries};
+use crate::security::{self, Conn};
+use crate::server::{Access, DisplayPower, PowerAction, ResizeError, Sched};
+use crate::server::{ClientInfo, Server, Session};
+use crate::source::Source;
+use crate::tier::Classifier;
+use crate::touch::{self, Touch, TouchEvent};
+use crate::wire::Wire;
+
+/*
+ * How long after the last keyboard or pointer input a connection is still
+ * considered interactive:
+ */
+const INTERACTIVE: Duration = Duration::from_secs(2);
+
+/*
+ * Updates covering at least this many pixels are held back until the client
+ * has caught up with everything we sent before, as long as the client answers
+ * our fences within the time limit.
+ */
+const HEAVY_AREA: usize = 128 * 128;
+const FENCE_WAIT: Duration = Duration::from_secs(2);
+
+fn message<M: Into<ServerMessage>>(m: M) -> Vec<u8> {
+    let mut buf = Vec::new();
+    m.into().write(&mut buf);
+    buf
+}
+
+fn fence_message(flags: u32, payload: &[u8]) -> Vec<u8> {
+    assert!(payload.len() <= 64);
+
+    message(ServerFence {
+        flags,
+        payload: payload.to_vec().into(),
+    })
+}
+
+fn xvp_message(code: u8) -> Vec<u8> {
+    message(ServerXvp {
+        version: rfb::XVP_VERSION,
+        code,
+    })
+}
+
+fn cut_text_message(text: &[u8]) -> Vec<u8> {
+    message(ServerCutText { text: text.to_vec().into() })
+}
+
+/*
+ * How to tell a client about new clipboard text, given the extended clipboard
+ * capabilities and size limit it has told us of, if any.  A client that has
+ * neither asked to be notified nor to be sent text gets nothing.
+ */
+fn clipboard_message(caps: Option<(u32, Option<usize>)>, text: &str)
+    -> Result<Option<Vec<u8>>>
+{
+    Ok(match caps {
+        None => Some(cut_text_message(&rfb::cut_text(text))),
+        Some((c, _)) if clipboard::wants_notify(c) => {
+            Some(clipboard::notify_message(true))
+        }
+        Some((c, Some(limit)))
+            if clipboard::accepts_text(c) && clipboard::fits(text, limit) =>
+        {
+            Some(clipboard::provide_message(text)?)
+        }
+        Some(_) => None,
+    })
+}
+
+/*
+ * Pass clipboard text from a client to the application, unless the client
+ * is only allowed to watch.
+ */
+fn client_clipboard(server: &Server, session: &Session, view_only: bool,
+    text: &str)
+{
+    if view_only {
+        log!("  ignoring clipboard from view only client");
+    } else {
+        log!("  clipboard from client: {} characters", text.chars().count());
+        server.client_clipboard(session.id, text);
+    }
+}
+
+/*
+ * What we need to know about a message in the bulk lane once it has been
+ * written:
+ */
+enum Sent {
+    Update {
+        encoding: i32,
+        bytes: usize,
+        /*
+         * The oldest input event reflected in the update, if any:
+         */
+        input: Option<Instant>,
+        /*
+         * When the update was queued:
+         */
+        at: Instant,
+    },
+    Resize,
+    /*
+     * An update with pseudo-rectangles but no pixels, because nothing on the
+     * screen had changed:
+     */
+    Pseudo,
+}
+
+/*
+ * What we drew over the source in an update, so that the next incremental
+ * update can cover whatever has since moved or changed.
+ */
+#[derive(Default)]
+struct Overdraw {
+    layers: Vec<Arc<Layer>>,
+    cursor: Option<(Arc<Cursor>, usize, usize)>,
+    asleep: bool,
+}
+
+impl Overdraw {
+    /*
+     * The areas that differ between this, which was drawn last time, and
+     * "next", or None if that could be anywhere.
+     */
+    fn changes(&self, next: &Overdraw) -> Option<Vec<Rect>> {
+        let same_layers = self.layers.len() == next.layers.len()
+            && self.layers.iter().zip(&next.layers)
+                .all(|(a, b)| Arc::ptr_eq(a, b));
+        if !same_layers || self.asleep != next.asleep {
+            return None;
+        }
+
+        let same_cursor = match (&self.cursor, &next.cursor) {
+            (Some((a, ax, ay)), Some((b, bx, by))) => {
+                Arc::ptr_eq(a, b) && ax == bx && ay == by
+            }
+            (None, None) => true,
+            _ => false,
+        };
+        if same_cursor {
+            return Some(Vec::new());
+        }
+        Some(self.cursor.iter().chain(&next.cursor)
+            .map(|(c, x, y)| {
+                let left = x.saturating_sub(c.hotx);
+                let top = y.saturating_sub(c.hoty);
+                let right = (x + c.width).saturating_sub(c.hotx);
+                let bottom = (y + c.height).saturating_sub(c.hoty);
+                Rect::new(left, top, right - left, bottom - top)
+            })
+            .collect())
+    }
+}
+
+/*
+ * Run "f", a phase of the handshake, for no longer than "limit", nor past
+ * "deadline", by which the whole handshake must be over.
+ */
+async fn phase<F: Future>(deadline: Instant, limit: Duration, what: &str,
+    f: F) -> Result<F::Output>
+{
+    match timeout_at(deadline.min(Instant::now() + limit), f).await {
+        Ok(v) => Ok(v),
+        Err(_) => bail!(Failed(Outcome::Timeout,
+            format!("timed out waiting for {}", what))),
+    }
+}
+
+/*
+ * Serve a client, and account for how its handshake turned out if it failed
+ * before getting as far as ClientInit.
+ */
+pub async fn serve_client(
+    server: &Arc<Server>,
+    sock: Stream,
+    session: &Session,
+    notes: UnboundedReceiver<Notification>,
+) -> Result<()> {
+    let res = wrap_client(server, sock, session, notes).await;
+    if let Err(e) = &res {
+        if session.stats.lock().unwrap().handshake.is_none() {
+            let outcome = Outcome::of(e);
+            log!("  handshake failed ({}): {:#}", outcome.name(), e);
+            server.record_handshake(session, outcome);
+            server.events.record(Event::HandshakeFailed {
+                session: session.id,
+                outcome,
+                error: format!("{:#}", e),
+            });
+        }
+    }
+    res
+}
+
+/*
+ * Wrap the connection in TLS first, if so configured or if it arrived on an
+ * "rfbs://" listener, then in stream compression, and then speak RFB.  The
+ * whole handshake, including that for TLS, must finish within the configured
+ * time.
+ */
+async fn wrap_client(
+    server: &Arc<Server>,
+    sock: Stream,
+    session: &Session,
+    notes: UnboundedReceiver<Notification>,
+) -> Result<()> {
+    let deadline = Instant::now() + server.config.handshake_timeout;
+    let (sock, id): (Box<dyn Conn>, _) = match &server.psk {
+        _ if session.transport == Transport::Tls => {
+            let Some(tls) = &server.tls else {
+                bail!("no certificate for TLS");
+            };
+            let sock = phase(deadline, server.config.security_timeout,
+                "TLS", tls.accept(sock)).await??;
+            (Box::new(sock), None)
+        }
+        Some(psk) => {
+            let (sock, id) = phase(deadline, server.config.security_timeout,
+                "TLS", psk.accept(sock)).await??;
+            log!("  tls-psk identity: {}", id);
+            (Box::new(sock), Some(id))
+        }
+        None => (Box::new(sock), None),
+    };
+
+    match server.config.stream_zstd {
+        Some(level) => {
+            log!("  stream compression: zstd level {}", level);
+            let sock = CompressedStream::new(sock, level)?;
+            process_socket(server, sock, id, session, notes, deadline).await
+        }
+        None => {
+            process_socket(server, sock, id, session, notes, deadline).await
+        }
+    }
+}
+
+/*
+ * Conduct an RFB session over "sock".  If the client has already been
+ * authenticated by the transport, "identity" is the name it proved.  The
+ * handshake must be over by "deadline".
+ */
+async fn process_socket<S>(
+    server: &Arc<Server>,
+    mut sock: S,
+    identity: Option<String>,
+    session: &Session,
+    mut notes: UnboundedReceiver<Notification>,
+    mut deadline: Instant,
+) -> Result<()>
+where
+    S: Conn + 'static,
+{
+    let mut source = server.source();
+    let presets = &server.config.presets;
+    let mut name = server.name();
+
+    /*
+     * Send the RFB ProtocolVersion Handshake.
+     */
+    let ours = &server.config.rfb_version;
+    sock.write_all(format!("{}\n", ours).as_bytes()).await?;
+    sock.flush().await?;
+    let hs_sent = Instant::now();
+
+    /*
+     * Wait for the client to return a handshake:
+     */
+    let config = &server.config;
+    let read = rfb::read_version(&mut sock);
+    let ver = if session.transport == Transport::Repeater {
+        /*
+         * A repeater holds on to us until a viewer asks for us, which may be
+         * a long while, and only then does the viewer answer.  The rest of
+         * the handshake is timed from its answer.
+         */
+        let ver = read.await;
+        deadline = Instant::now() + config.handshake_timeout;
+        ver
+    } else {
+        phase(deadline, config.version_timeout, "version", read).await?
+    };
+    let ver = match ver {
+        Ok(ver) => ver,
+        Err(e) if e.kind() == std::io::ErrorKind::Other => {
+            bail!(Failed(Outcome::BadVersion, e.to_string()));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let Some(version) = Version::parse(&ver) else {
+        /*
+         * Any client should understand a refusal in the oldest form.
+         */
+        security::refuse(&mut sock, Version::V3_3,
+            "unsupported protocol version").await?;
+        bail!(Failed(Outcome::BadVersion,
+            format!("invalid handshake: {:?}", ver)));
+    };
+    let version = version.min(Version::parse(ours).unwrap());
+    log!("  version: {:?} ({:?})", version, ver);
+    {
+        let mut stats = session.stats.lock().unwrap();
+        stats.client_version = Some(ver);
+        stats.version = Some(version);
+    }
+
+    /*
+     * The version exchange is our first opportunity to measure the round trip
+     * time to the client.  We use it to select an initial quality tier, and
+     * in particular the scaling factor, which cannot change once we have sent
+     * ServerInit.
+     */
+    let mut classifier = Classifier::new();
+    classifier.observe(presets, hs_sent.elapsed());
+
+    /*
+     * Security Handshake, which may leave us talking over TLS:
+     */
+    let ip = session.addr.ip();
+    let attempt = match server.guard.check(ip) {
+        Ok(attempt) => attempt,
+        Err(r) => {
+            security::refuse(&mut sock, version, &r.reason()).await?;
+            bail!(Failed(Outcome::Refused,
+                format!("refused: {}", r.reason())));
+        }
+    };
+    let negotiate = security::negotiate(server, sock, version);
+    let auth = match phase(deadline, config.security_timeout, "security",
+        negotiate).await?
+    {
+        Ok(auth) => auth,
+        Err(e) => {
+            if e.is::<security::AuthFailed>() {
+                attempt.failed();
+            }
+            return Err(e);
+        }
+    };
+    attempt.succeeded();
+    let verified = identity.is_some();
+    let identity = identity.or(auth.identity);
+    let sock = auth.conn;
+
+    let (r, mut w) = tokio::io::split(sock);
+    let rfb = rfb::read_stream(r, server.config.max_cut_text,
+        server.config.skip_unknown);
+    tokio::pin!(rfb);
+
+    /*
+     * Apply any preferences stored for this user.  Clients that have not
+     * authenticated are known by their address.
+     */
+    let identity = identity.unwrap_or_else(|| session.addr.ip().to_string());
+    let prefs = server.prefs.as_ref()
+        .map(|s| s.get(&identity))
+        .unwrap_or_default();
+    log!("  identity: {} {:?}", identity, prefs);
+    session.stats.lock().unwrap().identity = Some(identity.clone());
+
+    let tier = prefs.tier.unwrap_or_else(|| classifier.tier());
+    let mut preset = presets.preset(tier);
+    session.stats.lock().unwrap().tier = Some(tier);
+    log!("  initial tier: {:?} ({:?})", tier, preset);
+    let scale = prefs.scale.unwrap_or(preset.scale);
+
+    /*
+     * Input positions are reported to the application in the coordinates of
+     * the source:
+     */
+    let unscale = |v: u16| (v as usize * scale).min(u16::MAX as usize) as u16;
+    let mut debounce = Debounce::new(prefs.debounce
+        .map(Duration::from_millis)
+        .unwrap_or(server.config.key_debounce));
+
+    /*
+     * Wait for client init:
+     */
+    let init = phase(deadline, config.init_timeout, "ClientInit", rfb.next());
+    let asked = match init.await?.transpose()? {
+        Some(Frame::ClientInit(acc)) => acc,
+        Some(f) => {
+            bail!("unexpected frame: {:?}", f);
+        }
+        None => {
+            log!("stream done early?");
+            server.record_handshake(session, Outcome::Closed);
+            return Ok(());
+        }
+    };
+
+    /*
+     * Whether the client shares the desktop is up to the access policy,
+     * which may give it something other than what it asked for.
+     */
+    let client = ClientInfo {
+        identity: &identity,
+        verified,
+        view_only: auth.view_only || prefs.view_only.unwrap_or(false),
+    };
+    let Some(access) = server.client_access(&client, asked) else {
+        bail!(Failed(Outcome::Refused,
+            format!("{} access refused", asked.name())));
+    };
+    log!("  access: asked {}, given {}", asked.name(), access.name());
+    server.record_handshake(session, Outcome::Completed);
+    session.stats.lock().unwrap().access = Some(access);
+    if access == Access::Exclusive {
+        server.close_others(session.id);
+    }
+
+    let view_only = client.view_only || access == Access::ViewOnly;
+    if view_only {
+        log!("  view only");
+    }
+
+    /*
+     * ServerInit:
+     */
+    let (full_width, full_height) = source.dimensions();
+    let mut width = encoding::scaled(full_width, scale);
+    let mut height = encoding::scaled(full_height, scale);
+    w.write_u16(framebuffer::coord(width)?).await?; /* width, pixels */
+    w.write_u16(framebuffer::coord(height)?).await?; /* height, pixels */
+
+    let mut buf = Vec::new();
+    PixelFormat::NATIVE.write(&mut buf);
+    pseudo::name_string(&mut buf, &name);
+    w.write_all(&buf).await?;
+    w.flush().await?;
+
+    /*
+     * From here on, messages are written by the outbox, and we keep count of
+     * those in the bulk lane that have not yet gone out.  We only draw when
+     * the lane is empty, so that a slow client does not accumulate a backlog
+     * of stale updates.
+     */
+    let mut out = Outbox::new(w);
+    let mut queued = 0usize;
+
+    let mut clipboard = server.watch_clipboard();
+    if let Some(text) = clipboard.borrow_and_update().clone() {
+        out.clipboard(cut_text_message(&rfb::cut_text(&text)));
+    }
+
+    let mut draw: Option<UpdateRequest> = None;
+    let mut drawn: Option<Instant> = None;
+
+    /*
+     * The generation of the source as of the last update that covered the
+     * whole screen, and what we drew over it, so that incremental updates
+     * need only cover what has changed since.  Until then, and whenever the
+     * source cannot say what has changed, the client is sent everything it
+     * asks for.
+     */
+    let mut sent_gen: Option<u64> = None;
+    let mut overdrawn = Overdraw::default();
+    let mut pacer = Pacer::new();
+
+    /*
+     * Whether the last update showed the placeholder for a sleeping display.
+     * Updates slow down once it is on the screen, and the name says why.
+     */
+    let mut shown_asleep = false;
+
+    /*
+     * Until the client tells us otherwise, we must use Raw encoding and no
+     * lossy compression:
+     */
+    let mut pipeline = Pipeline::new(width, height);
+    pipeline.cache = server.encode_cache.clone();
+    pipeline.buffers = out.buffers();
+    if server.config.debug_damage {
+        pipeline.damage = Some(Damage::new());
+    }
+
+    /*
+     * Time-limited sessions are closed at the deadline, after a warning:
+     */
+    let deadline = server.config.session_limit.map(|l| Instant::now() + l);
+    let mut warn_at = deadline.map(|d| {
+        d.checked_sub(server.config.session_warning)
+            .unwrap_or_else(Instant::now)
+    });
+    if let Some(deadline) = deadline {
+        pipeline.countdown = Some(Countdown {
+            deadline: deadline.into_std(),
+            warning: server.config.session_warning,
+            width,
+        });
+    }
+    let pipeline = Arc::new(Mutex::new(pipeline));
+
+    /*
+     * When we last heard from the client, for those that fall silent:
+     */
+    let silence = server.config.silence_timeout;
+    let mut heard = Instant::now();
+    let mut quality: Option<u8> = None;
+    let mut compression: Option<u8> = None;
+
+    /*
+     * Some viewers only treat the desktop name as UTF-8 when it arrives via
+     * the DesktopName pseudo-encoding, so we send it again that way if the
+     * client supports it.  The name also carries notifications for a while.
+     */
+    let mut desktop_name = false;
+    let mut send_name = false;
+    let mut notice: Option<(String, Instant)> = None;
+
+    /*
+     * Whether the client can cope with a change in the framebuffer size, and
+     * whether it also understands the extended form, with which it may ask
+     * for a new size itself.  If we owe the client a report of the size, the
+     * reason and status are kept until the next update.
+     */
+    let mut desktop_size = false;
+    let mut extended_size = false;
+    let mut size_reply: Option<(usize, usize)> = None;
+
+    /*
+     * If the client can draw the cursor itself, we send the shape whenever it
+     * differs from the one the client last saw, using whichever of the cursor
+     * pseudo-encodings the client prefers.  Otherwise, we draw the cursor into
+     * the framebuffer updates at the last pointer position the client sent.
+     */
+    let mut cursor_enc: Option<i32> = None;
+    let mut cursor_sent: Option<Arc<Cursor>> = None;
+    let mut pointer = (0, 0);
+
+    /*
+     * Likewise for the keyboard lock lights, if the client can show them:
+     */
+    let mut led_enc: Option<i32> = None;
+    let mut leds_sent: Option<Leds> = None;
+
+    let mut mousekeys = server.config.mousekeys
+        .then(|| MouseKeys::new(width, height));
+    let mut buttons = 0u8;
+
+    /*
+     * Encode jobs for this connection jump the queue if somebody has recently
+     * used the keyboard or pointer:
+     */
+    let mut last_input: Option<Instant> = None;
+
+    /*
+     * To measure how responsive the connection feels, we note when the oldest
+     * input event not yet reflected on the screen arrived.  The first update
+     * captured after that point shows whatever the input changed, at least as
+     * far as the framebuffer had caught up, so the time until it is written
+     * is the delay the user experiences.
+     */
+    let mut input_at: Option<Instant> = None;
+
+    /*
+     * Whether we have offered power control to the client:
+     */
+    let mut xvp = false;
+
+    /*
+     * Whether the client has asked to send QEMU extended key events, and
+     * whether we have yet to confirm that it may:
+     */
+    let mut extended_key = false;
+    let mut send_extended_key = false;
+
+    /*
+     * Whether we have offered the extended clipboard to the client, and
+     * the capabilities and text size limit it replied with:
+     */
+    let mut ext_clipboard = false;
+    let mut clip_caps: Option<(u32, Option<usize>)> = None;
+
+    /*
+     * Whether we have offered touch input, and the touch screens the client
+     * has told us of:
+     */
+    let mut gii = false;
+    let mut touch = Touch::new();
+
+    /*
+     * If the client supports the Fence extension, we follow each update with a
+     * fence that asks the client to reply only once it has processed
+     * everything sent before it.  The reply time is a good measure of both the
+     * network round trip and the client decode cost.  Otherwise, we fall back
+     * to the lag between sending an update and receiving the next request.
+     *
+     * The fence is also a sync point: heavy updates wait until the client has
+     * replied, so that we do not queue up more data than the client can
+     * handle.
+     */
+    let mut fence = false;
+    let mut fence_seq = 0u32;
+    let mut fence_sent: Option<(u32, Instant)> = None;
+    let mut update_sent: Option<Instant> = None;
+
+    loop {
+        /*
+         * The frame rate is capped by the configuration and the current
+         * quality tier, unless an operator has chosen a different limit for
+         * this connection, and lowered further if the connection cannot keep
+         * up.  The next draw cycle is due one interval after the last.
+         */
+        let asleep = server.display_power() != DisplayPower::On;
+        let interval = if asleep && shown_asleep {
+            server.config.sleep_interval
+        } else {
+            pacer.interval(session.pace()
+                .unwrap_or_else(|| server.config.fps.min(preset.fps)))
+        };
+        let drawtime = drawn.map_or_else(Instant::now, |t| t + interval);
+
+        let heavy = draw.as_ref()
+            .is_some_and(|ur| ur.width * ur.height >= HEAVY_AREA);
+        let wake = match fence_sent {
+            Some((_, sent)) if heavy => drawtime.max(sent + FENCE_WAIT),
+            _ => drawtime,
+        };
+        let pri = match last_input {
+            Some(t) if t.elapsed() < INTERACTIVE => Priority::Interactive,
+            _ => Priority::Bulk,
+        };
+
+        session.stats.lock().unwrap().sched = Sched {
+            pending: draw.as_ref().map(|ur| ur.width * ur.height),
+            fence_wait: heavy && fence_sent.is_some(),
+            interval,
+            write_time: pacer.write_time(),
+            priority: Some(pri),
+        };
+
+        tokio::select! {
+            _ = sleep_until(deadline.unwrap_or(drawtime)),
+                if deadline.is_some() =>
+            {
+                log!("  session time limit reached");
+                return Ok(());
+            }
+            _ = sleep_until(heard + silence.unwrap_or_default()),
+                if silence.is_some() =>
+            {
+                bail!("nothing from the client for {:?}", silence.unwrap());
+            }
+            _ = sleep_until(warn_at.unwrap_or(drawtime)),
+                if warn_at.is_some() =>
+            {
+                warn_at = None;
+                let left = server.config.session_limit.unwrap()
+                    .min(server.config.session_warning);
+                server.notify(session.id, Notification::urgent(&format!(
+                    "This session will end in {} seconds",
+                    left.as_secs())))?;
+            }
+            _ = session.close.notified() => {
+                log!("  closed by the server");
+                break;
+            }
+            _ = session.refresh.notified() => {
+                log!("  refresh requested");
+                drawn = None;
+                sent_gen = None;
+                pipeline.lock().unwrap().shadow.invalidate();
+                cursor_sent = None;
+                leds_sent = None;
+                send_name |= desktop_name;
+            }
+            _ = session.retune.notified() => {
+                match session.pace() {
+                    Some(fps) => log!("  pacing set to {} fps", fps),
+                    None => log!("  pacing follows the quality tier"),
+                }
+            }
+            _ = session.bell.notified() => {
+                log!("  bell");
+                out.control(message(Bell {}))?;
+            }
+            Ok(()) = clipboard.changed() => {
+                let text = clipboard.borrow_and_update().clone();
+                if let Some(text) = text {
+                    log!("  clipboard: {} characters", text.chars().count());
+                    if let Some(m) = clipboard_message(clip_caps, &text)? {
+                        out.clipboard(m);
+                    }
+                }
+            }
+            Some(n) = notes.recv() => {
+                log!("  notification: {:?}", n);
+                if n.urgent {
+                    out.control(message(Bell {}))?;
+                }
+                if desktop_name {
+                    notice = Some((n.text, Instant::now() + TITLE_TIME));
+                    send_name = true;
+                } else if let Some(text) = n.latin1() {
+                    out.control(cut_text_message(&text))?;
+                } else {
+                    log!("  client cannot show notification");
+                }
+            }
+            res = out.sent(), if queued > 0 => {
+                queued -= 1;
+                let (encoding, bytes, input, at) = match res? {
+                    Sent::Update { encoding, bytes, input, at } => {
+                        (encoding, bytes, input, at)
+                    }
+                    Sent::Resize => {
+                        session.trace(|| "-> desktop size".to_string());
+                        continue;
+                    }
+                    Sent::Pseudo => {
+                        session.trace(|| "-> pseudo-rectangles".to_string());
+                        continue;
+                    }
+                };
+                pacer.written(at.elapsed());
+                session.trace(|| format!("-> update: encoding {}, {} bytes, \
+                    written in {:?}", encoding, bytes, at.elapsed()));
+
+                {
+                    let mut stats = session.stats.lock().unwrap();
+                    if let Some(t) = input {
+                        stats.input_latency.record(t.elapsed());
+                    }
+                    stats.encoding = encoding;
+                    stats.updates += 1;
+                    stats.bytes += bytes as u64;
+                }
+
+                /*
+                 * The update has been written in full, so a fence sent now
+                 * follows it on the wire.
+                 */
+                if fence {
+                    if fence_sent.is_none() {
+                        fence_seq = fence_seq.wrapping_add(1);
+                        out.control(fence_message(
+                            rfb::FENCE_REQUEST | rfb::FENCE_BLOCK_BEFORE,
+                            &fence_seq.to_be_bytes()))?;
+                        fence_sent = Some((fence_seq, Instant::now()));
+                    }
+                } else {
+                    update_sent = Some(Instant::now());
+                }
+
+                drawn = Some(Instant::now());
+            }
+            _ = sleep_until(wake), if draw.is_some() && queued == 0 => {
+                let ur = draw.take().unwrap();
+
+                if heavy && fence_sent.is_some() {
+                    log!("  no reply to fence after {:?}", FENCE_WAIT);
+                    fence_sent = None;
+                }
+
+                /*
+                 * If the framebuffer has been resized, a client that supports
+                 * it is told the new size and will then ask for a fresh
+                 * update.  Otherwise, the client continues to see the old
+                 * geometry, clipped or padded as needed.
+                 */
+                let (cur, origin) = server.source_origin();
+                if !Arc::ptr_eq(&cur, &source) {
+                    source = cur;
+                    sent_gen = None;
+                    if desktop_size || extended_size {
+                        let (full_width, full_height) = source.dimensions();
+                        width = encoding::scaled(full_width, scale);
+                        height = encoding::scaled(full_height, scale);
+                        pipeline.lock().unwrap().resize(width, height);
+                        if let Some(mk) = &mut mousekeys {
+                            mk.resize(width, height);
+                        }
+
+                        let reason = match origin {
+                            Some(id) if id == session.id => {
+                                pseudo::SIZE_REASON_CLIENT
+                            }
+                            Some(_) => pseudo::SIZE_REASON_OTHER_CLIENT,
+                            None => pseudo::SIZE_REASON_SERVER,
+                        };
+                        size_reply = Some((reason, 0));
+                    } else {
+                        log!("  client cannot resize; keeping {}x{}",
+                            width, height);
+                    }
+                }
+
+                if let Some((reason, status)) = size_reply.take() {
+                    let mut u = Update::new();
+                    if extended_size {
+                        let screen = Screen {
+                            id: 0,
+                            x: 0,
+                            y: 0,
+                            width,
+                            height,
+                            flags: 0,
+                        };
+                        pseudo::extended_desktop_size(&mut u, reason, status,
+                            width, height, &[screen]);
+                    } else {
+                        pseudo::desktop_size(&mut u, width, height);
+                    }
+                    out.bulk(u.finish(), Sent::Resize);
+                    queued += 1;
+                    continue;
+                }
+
+                /*
+                 * Fashion some pixel data for the client...
+                 */
+                let r = Rect::new(ur.xpos, ur.ypos, ur.width, ur.height);
+                let whole = r == Rect::new(0, 0, width, height);
+
+                /*
+                 * The client decides whether lossy compression is acceptable,
+                 * but we may lower the quality further for a poor link.  The
+                 * compression level is entirely up to the client.
+                 */
+                let params = Params {
+                    quality: quality.map(|q| q.min(preset.quality)),
+                    compression,
+                };
+
+                /*
+                 * If the desktop has been renamed, we can only tell clients
+                 * that support the DesktopName pseudo-encoding.
+                 */
+                let current = server.name();
+                if !Arc::ptr_eq(&current, &name) {
+                    name = current;
+                    send_name |= desktop_name;
+                }
+                if notice.as_ref().is_some_and(|(_, t)| *t <= Instant::now()) {
+                    notice = None;
+                    send_name = true;
+                }
+                if asleep != shown_asleep {
+                    shown_asleep = asleep;
+                    send_name |= desktop_name;
+                }
+
+                let mut pending = pseudo::Pending::default();
+                if send_name {
+                    let mut title = name.to_string();
+                    if asleep {
+                        title.push_str(" (display asleep)");
+                    }
+                    if let Some((text, _)) = &notice {
+                        title = format!("{} - {}", title, text);
+                    }
+                    pending.name = Some(title);
+                    send_name = false;
+                }
+                pending.extended_key = std::mem::take(&mut send_extended_key);
+                let mut composite = None;
+                /*
+                 * Cursor shapes are sent in the client's pixel format, which
+                 * we only support for true colour.
+                 */
+                let colour_map = pipeline.lock().unwrap().palette.is_some();
+                match (cursor_enc.filter(|_| !colour_map), server.cursor()) {
+                    (Some(enc), Some(c)) => {
+                        let same = cursor_sent.as_ref()
+                            .is_some_and(|sent| Arc::ptr_eq(&c, sent));
+                        if !same {
+                            pending.cursor = Some((enc, Arc::clone(&c)));
+                            cursor_sent = Some(c);
+                        }
+                    }
+                    (None, Some(c)) => {
+                        composite = Some((c, pointer.0, pointer.1));
+                    }
+                    (_, None) => (),
+                }
+                if let Some(enc) = led_enc {
+                    let leds = server.led_state();
+                    if leds.is_some() && leds != leds_sent {
+                        pending.leds = leds.map(|l| (enc, l));
+                        leds_sent = leds;
+                    }
+                }
+
+                let mut layers = server.overlays();
+                if asleep {
+                    let argb = match server.config.sleep_placeholder {
+                        Placeholder::Black => 0xff000000,
+                        Placeholder::Dim => 0xc0000000,
+                    };
+                    layers.push(Arc::new(Layer::filled(0, 0, width, height,
+                        argb)));
+                }
+                /*
+                 * An incremental update need only cover what has changed
+                 * since the last: the parts of the source that have been
+                 * drawn, as the client sees them, and anything drawn over
+                 * them that has moved.  The damage overlay and the countdown
+                 * change with time, so they need everything.  If nothing has
+                 * changed, the request waits for the next frame.
+                 *
+                 * Whatever the source says, pixels that are the same as those
+                 * the client already has are not sent again, unless something
+                 * drawn over them has moved.
+                 */
+                let next = Overdraw {
+                    layers: layers.clone(),
+                    cursor: composite.clone(),
+                    asleep,
+                };
+                let changed = source.changed_since(sent_gen.unwrap_or(0));
+                let animated = (deadline.is_some() && warn_at.is_none())
+                    || pipeline.lock().unwrap().damage.is_some();
+                let incremental = ur.incremental && !animated;
+                let moved = overdrawn.changes(&next);
+                let dirty = match (&changed, sent_gen, &moved) {
+                    (Some((_, rects)), Some(_), Some(moved)) if incremental => {
+                        let mut dirty = moved.clone();
+                        dirty.extend(rects.iter().map(|d| {
+                            let x = d.x / scale;
+                            let y = d.y / scale;
+                            Rect::new(x, y,
+                                (d.x + d.width).div_ceil(scale) - x,
+                                (d.y + d.height).div_ceil(scale) - y)
+                        }));
+                        Some(dirty)
+                    }
+                    _ => None,
+                };
+                let r = match dirty {
+                    None => r,
+                    Some(dirty) => {
+                        let area = dirty.iter()
+                            .filter_map(|d| d.intersect(&r))
+                            .reduce(|a, b| a.union(&b));
+                        match area {
+                            Some(area) => area,
+                            None if pending.is_empty() => {
+                                draw = Some(ur);
+                                drawn = Some(Instant::now());
+                                continue;
+                            }
+                            None => {
+                                let mut u = Update::new();
+                                pending.write(&mut u);
+                                out.bulk(u.finish(), Sent::Pseudo);
+                                queued += 1;
+                                continue;
+                            }
+                        }
+                    }
+                };
+                if whole {
+                    sent_gen = changed.as_ref().map(|(gen, _)| *gen);
+                    overdrawn = next;
+                }
+
+                /*
+                 * If the source numbers its changes, the pixels are read
+                 * from a frame shared with every other connection sent the
+                 * same generation, already at our scale.
+                 */
+                let (pixels, step): (Arc<dyn Source>, _) = match &changed {
+                    Some((gen, _)) => {
+                        (server.frames.get(&source, scale, *gen), 1)
+                    }
+                    None => (Arc::clone(&source), scale),
+                };
+
+                let moved = moved.filter(|_| incremental);
+                let input = input_at.take();
+                let job = {
+                    let pipeline = Arc::clone(&pipeline);
+                    move || {
+                        let start = std::time::Instant::now();
+                        let mut pl = pipeline.lock().unwrap();
+                        pl.cursor = composite;
+                        pl.layers = layers;
+                        let buf = pl.update(&*pixels, &r, step, &params,
+                            &pending, moved.as_deref());
+                        (pl.encoder.encoding(), buf, start.elapsed())
+                    }
+                };
+                let (enc, buf, time) = server.pool.run(pri, job).await?;
+                let Some(buf) = buf else {
+                    input_at = input;
+                    draw = Some(ur);
+                    drawn = Some(Instant::now());
+                    continue;
+                };
+
+                server.record_encode(enc, buf.len(), time);
+                let bytes = buf.len();
+                out.bulk(buf, Sent::Update {
+                    encoding: enc,
+                    bytes,
+                    input,
+                    at: Instant::now(),
+                });
+                queued += 1;
+            }
+            f = rfb.next() => {
+                let f = match f {
+                    Some(f) => f?,
+                    None => return Ok(()),
+                };
+                heard = Instant::now();
+                session.trace(|| format!("<- {:?}", f));
+
+                let mut rtt = None;
+
+                /*
+                 * Extended key events are handled like the others, but with
+                 * the scancode passed on to the application:
+                 */
+                let (f, scancode) = match f {
+                    Frame::ExtendedKeyEvent(down, key, code) => {
+                        (Frame::KeyEvent(down, key), Some(code))
+                    }
+                    f => (f, None),
+                };
+
+                if let Frame::KeyEvent(down, key) = f {
+                    if !debounce.key(down == 1, key) {
+                        session.stats.lock().unwrap().keys_merged =
+                            debounce.merged();
+                        continue;
+                    }
+                }
+
+                if matches!(f, Frame::KeyEvent(..) | Frame::PointerEvent(..)) {
+                    if view_only {
+                        continue;
+                    }
+                    last_input = Some(Instant::now());
+                    input_at = input_at.or(last_input);
+                    server.input();
+                }
+
+                /*
+                 * Keys consumed by pointer emulation are replaced by the
+                 * pointer events they produce:
+                 */
+                let frames = match (&f, &mut mousekeys) {
+                    (&Frame::KeyEvent(down, key), Some(mk)) => {
+                        match mk.key(down == 1, key) {
+                            Some(ev) => ev.into_iter()
+                                .map(|(m, x, y)| Frame::PointerEvent(m, x, y))
+                                .collect(),
+                            None => vec![f],
+                        }
+                    }
+                    _ => vec![f],
+                };
+
+                for f in frames {
+                    match f {
+                        Frame::FramebufferUpdateRequest(mut ur) => {
+                            if let Some(sent) = update_sent.take() {
+                                rtt = Some(sent.elapsed());
+                            }
+
+                            /*
+                             * Make sure the update request is not out of
+                             * bounds for the geometry we reported to the
+                             * client:
+                             */
+                            if ur.xpos >= width {
+                                ur.xpos = width - 1;
+                            }
+                            if ur.ypos >= height {
+                                ur.ypos = height - 1;
+                            }
+                            if ur.width > width - ur.xpos {
+                                ur.width = width - ur.xpos;
+                            }
+                            if ur.height > height - ur.ypos {
+                                ur.height = height - ur.ypos;
+                            }
+
+                            /*
+                             * Schedule a redraw at the next appropriate
+                             * moment:
+                             */
+                            draw = Some(ur);
+                        }
+                        Frame::KeyEvent(down, keysym) => {
+                            server.input_event(session.id, &InputEvent::Key {
+                                down: down == 1,
+                                keysym,
+                                scancode,
+                            });
+                        }
+                        Frame::SetEncodings(encs) => {
+                            log!("  encodings: {:?}", encs);
+
+                            {
+                                let mut pl = pipeline.lock().unwrap();
+                                let e = encoding::select(&encs);
+                                if e.encoding() != pl.encoder.encoding() {
+                                    pl.encoder = e;
+                                }
+                                pl.copyrect =
+                                    encs.contains(&encoding::COPYRECT);
+                            }
+
+                            desktop_name =
+                                encs.contains(&pseudo::DESKTOP_NAME);
+                            send_name = desktop_name
+                                && (!name.is_ascii() || notice.is_some());
+                            desktop_size =
+                                encs.contains(&pseudo::DESKTOP_SIZE);
+                            let ext = encs
+                                .contains(&pseudo::EXTENDED_DESKTOP_SIZE);
+                            if ext && !extended_size {
+                                /*
+                                 * Describe the current screen layout:
+                                 */
+                                size_reply =
+                                    Some((pseudo::SIZE_REASON_SERVER, 0));
+                            }
+                            extended_size = ext;
+                            cursor_enc = encs.iter()
+                                .find(|e| {
+                                    **e == pseudo::CURSOR
+                                        || **e == pseudo::CURSOR_ALPHA
+                                })
+                                .copied();
+                            cursor_sent = None;
+                            led_enc = [
+                                pseudo::LED_STATE,
+                                pseudo::VMWARE_LED_STATE,
+                            ].iter().find(|e| encs.contains(e)).copied();
+                            leds_sent = None;
+                            quality = encs.iter()
+                                .find(|e| rfb::PSEUDO_QUALITY.contains(e))
+                                .map(|e| {
+                                    (e - rfb::PSEUDO_QUALITY.start()) as u8
+                                });
+                            compression = encs.iter()
+                                .find(|e| rfb::PSEUDO_COMPRESSION.contains(e))
+                                .map(|e| {
+                                    (e - rfb::PSEUDO_COMPRESSION.start()) as u8
+                                });
+                            let ek = encs
+                                .contains(&rfb::PSEUDO_QEMU_EXTENDED_KEY);
+                            if ek && !extended_key {
+                                send_extended_key = true;
+                            }
+                            extended_key = ek;
+
+                            /*
+                             * Tell the client that power control is
+                             * available, so that it can offer it to the user:
+                             */
+                            if !xvp && !view_only
+                                && encs.contains(&rfb::PSEUDO_XVP)
+                                && server.has_power_handler()
+                            {
+                                xvp = true;
+                                out.control(xvp_message(rfb::XVP_INIT))?;
+                            }
+                            if !ext_clipboard && encs
+                                .contains(&rfb::PSEUDO_EXTENDED_CLIPBOARD)
+                            {
+                                ext_clipboard = true;
+                                out.control(clipboard::caps_message(
+                                    server.config.max_cut_text))?;
+                            }
+                            if !gii && !view_only
+                                && encs.contains(&rfb::PSEUDO_GII)
+                                && server.has_input_handler()
+                            {
+                                gii = true;
+                                out.control(touch::version_message())?;
+                            }
+                            if !fence && encs.contains(&rfb::PSEUDO_FENCE) {
+                                fence = true;
+                                update_sent = None;
+                                session.stats.lock().unwrap().fence = true;
+                            }
+                        }
+                        Frame::Fence(flags, payload) => {
+                            if flags & rfb::FENCE_REQUEST != 0 {
+                                /*
+                                 * We process messages strictly in order, so
+                                 * everything before the fence has already been
+                                 * handled by the time we reply.
+                                 */
+                                out.control(fence_message(
+                                    flags & rfb::FENCE_BLOCK_BEFORE,
+                                    &payload))?;
+                            } else if let Some((seq, sent)) = fence_sent {
+                                if payload == seq.to_be_bytes() {
+                                    fence_sent = None;
+                                    rtt = Some(sent.elapsed());
+                                }
+                            }
+                        }
+                        Frame::SetDesktopSize(sw, sh, screens) => {
+                            /*
+                             * We have only the one screen, which must cover
+                             * the framebuffer.
+                             */
+                            let res = match screens.as_slice() {
+                                _ if view_only => {
+                                    Err(ResizeError::Prohibited)
+                                }
+                                [s] if s.x == 0 && s.y == 0
+                                    && s.width == sw && s.height == sh =>
+                                {
+                                    server.client_resize(session.id,
+                                        sw * scale, sh * scale)
+                                }
+                                _ => Err(ResizeError::InvalidLayout),
+                            };
+                            match res {
+                                Ok(()) => log!("  resize to {}x{}", sw, sh),
+                                Err(e) => {
+                                    log!("  resize to {}x{} refused: {:?}",
+                                        sw, sh, e);
+                                    if extended_size {
+                                        size_reply = Some((
+                                            pseudo::SIZE_REASON_CLIENT,
+                                            e as usize,
+                                        ));
+                                    }
+                                }
+                            }
+                        }
+                        Frame::Xvp(version, code) => {
+                            let action = PowerAction::from_xvp(code)
+                                .filter(|_| xvp && version == rfb::XVP_VERSION);
+                            let res = match action {
+                                Some(a) => {
+                                    log!("  power {:?}", a);
+                                    server.power(session.id, a)
+                                }
+                                None => Err(anyhow::anyhow!(
+                                    "unexpected xvp message {}/{}",
+                                    version, code)),
+                            };
+                            if let Err(e) = res {
+                                log!("  power request failed: {}", e);
+                                out.control(xvp_message(rfb::XVP_FAIL))?;
+                            }
+                        }
+                        Frame::SetPixelFormat(pf) => {
+                            let mut pl = pipeline.lock().unwrap();
+                            if !pf.true_colour && pf.bpp == 8 {
+                                log!("  pixel format: 8-bit colour map");
+                                let p = Palette::new();
+                                out.control(message(SetColourMapEntries {
+                                    first: 0,
+                                    colours: p.entries().into(),
+                                }))?;
+                                pl.palette = Some(p);
+                            } else if pf.true_colour {
+                                if pf != PixelFormat::NATIVE {
+                                    log!("  warning: pixel format {:?} is \
+                                        not supported; sending ours", pf);
+                                }
+                                pl.palette = None;
+                            } else {
+                                log!("  warning: colour map with {} bits \
+                                    per pixel is not supported", pf.bpp);
+                            }
+                            cursor_sent = None;
+                            sent_gen = None;
+                            pl.shadow.invalidate();
+                        }
+                        Frame::ClientCutText(text) => {
+                            client_clipboard(server, session, view_only,
+                                &text);
+                        }
+                        Frame::ExtendedClipboard(flags, payload) => {
+                            use clipboard::Message;
+
+                            let current = clipboard.borrow().clone();
+                            match Message::parse(flags, &payload,
+                                server.config.max_cut_text)
+                            {
+                                Ok(Message::Caps(caps, limit)) => {
+                                    log!("  extended clipboard: {:#x}, text \
+                                        limit {:?}", caps, limit);
+                                    clip_caps = Some((caps, limit));
+                                    if let Some(m) = current.as_deref()
+                                        .map(|t| clipboard_message(clip_caps,
+                                            t))
+                                        .transpose()?
+                                        .flatten()
+                                    {
+                                        out.clipboard(m);
+                                    }
+                                }
+                                Ok(Message::Notify(true)) if !view_only => {
+                                    out.control(clipboard::request_message())?;
+                                }
+                                Ok(Message::Notify(_)) => (),
+                                Ok(Message::Request(false)) => (),
+                                Ok(Message::Request(true)) => {
+                                    let limit = clip_caps
+                                        .and_then(|(_, l)| l)
+                                        .unwrap_or(0);
+                                    let fits = |t: &str| {
+                                        clipboard::fits(t, limit)
+                                    };
+                                    out.control(match current {
+                                        Some(t) if fits(&t) => {
+                                            clipboard::provide_message(&t)?
+                                        }
+                                        _ => clipboard::notify_message(false),
+                                    })?;
+                                }
+                                Ok(Message::Peek) => {
+                                    out.control(clipboard::notify_message(
+                                        current.is_some()))?;
+                                }
+                                Ok(Message::Provide(Some(text))) => {
+                                    client_clipboard(server, session,
+                                        view_only, &text);
+                                }
+                                Ok(Message::Provide(None)) => (),
+                                Err(e) => {
+                                    log!("  warning: clipboard message: {}",
+                                        e);
+                                }
+                            }
+                        }
+                        Frame::Gii(_, _) if !gii => {
+                            bail!("GII message without GII");
+                        }
+                        Frame::Gii(sub, payload) => {
+                            let (reply, touches) =
+                                touch.message(sub, &payload)?;
+                            if let Some(m) = reply {
+                                out.control(m)?;
+                            }
+                            if !touches.is_empty() {
+                                last_input = Some(Instant::now());
+                                input_at = input_at.or(last_input);
+                                server.input();
+                            }
+                            for t in touches {
+                                let t = TouchEvent {
+                                    x: unscale(t.x),
+                                    y: unscale(t.y),
+                                    ..t
+                                };
+                                server.input_event(session.id,
+                                    &InputEvent::Touch(t));
+                            }
+                        }
+                        Frame::Skipped(ty, sub) => {
+                            log!("  warning: skipped unsupported message \
+                                {}{}", ty,
+                                sub.map(|s| format!("/{}", s))
+                                    .unwrap_or_default());
+                            session.stats.lock().unwrap().skipped += 1;
+                        }
+                        Frame::ClientCutTextTooLong(len) => {
+                            log!("  warning: discarded {} bytes of clipboard \
+                                text", len);
+                        }
+                        Frame::PointerEvent(mask, x, y) => {
+                            if let Some(mk) = &mut mousekeys {
+                                mk.pointer(x, y);
+                            }
+                            pointer = (x as usize, y as usize);
+                            if mask != buttons {
+                                log!("  buttons {:#04x} at {}, {}",
+                                    mask, x, y);
+                                buttons = mask;
+                            }
+                            server.input_event(session.id,
+                                &InputEvent::Pointer {
+                                    buttons: mask,
+                                    x: unscale(x),
+                                    y: unscale(y),
+                                });
+                        }
+                        f => {
+                            log!("f: {:?}", f);
+                        }
+                    }
+                }
+
+                if let Some(rtt) = rtt {
+                    let t = classifier.observe(presets, rtt)
+                        .filter(|_| prefs.tier.is_none());
+                    session.stats.lock().unwrap().rtt = classifier.rtt();
+                    if let Some(t) = t {
+                        session.stats.lock().unwrap().tier = Some(t);
+                        preset = presets.preset(t);
+                        log!("  tier now {:?}: quality {}, {} fps cap",
+                            t, preset.quality, preset.fps);
+                    }
+                }
+            }
+        }
+    }
+
+    /*
+     * Let the client have whatever we have already queued for it, so that
+     * it is not cut off part way through an update.
+     */
+    out.finish().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rfb::{ClientMessage, FramebufferUpdateRequest, SetEncodings};
+    use crate::ServerBuilder;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use tokio::io::{AsyncReadExt, BufReader};
+    use tokio::net::TcpStream;
+    use tokio::sync::oneshot;
+
+    fn request<M: Into<ClientMessage>>(m: M) -> Vec<u8> {
+        let mut buf = Vec::new();
+        m.into().write(&mut buf);
+        buf
+    }
+
+    /*
+     * Serve "server" on a loopback address until "stop" fires.
+     */
+    fn serve_until(server: &Arc<Server>, stop: oneshot::Receiver<()>)
+        -> (impl std::future::Future<Output = Result<()>> + '_, SocketAddr)
+    {
+        let (listeners, _) =
+            crate::listen::bind(&["127.0.0.1:0".to_string()], false).unwrap();
+        let addr = listeners[0].local_addr().unwrap().tcp().unwrap();
+        let serving = server.serve(listeners,
+            |server, socket, session, notes| async move {
+                serve_client(&server, socket, &session, notes).await
+            },
+            async {
+                stop.await.ok();
+            },
+            Duration::from_secs(1));
+        (serving, addr)
+    }
+
+    /*
+     * Connect as an RFB 3.8 client that understands only Raw encoding, and
+     * asks to share the desktop.
+     */
+    async fn connect(addr: SocketAddr) -> BufReader<TcpStream> {
+        connect_shared(addr, true).await
+    }
+
+    async fn connect_shared(addr: SocketAddr, shared: bool)
+        -> BufReader<TcpStream>
+    {
+        let mut sock = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        rfb::read_version(&mut sock).await.unwrap();
+        sock.write_all(b"RFB 003.008\n").await.unwrap();
+        let n = sock.read_u8().await.unwrap() as usize;
+        let mut types = vec![0; n];
+        sock.read_exact(&mut types).await.unwrap();
+        sock.write_u8(security::NONE).await.unwrap();
+        assert_eq!(sock.read_u32().await.unwrap(), 0);
+        sock.write_u8(shared as u8).await.unwrap();
+        let mut init = [0; 20];
+        sock.read_exact(&mut init).await.unwrap();
+        let namelen = sock.read_u32().await.unwrap() as usize;
+        sock.read_exact(&mut vec![0; namelen]).await.unwrap();
+        sock.write_all(&request(SetEncodings {
+            encodings: vec![encoding::RAW].into(),
+        })).await.unwrap();
+        sock
+    }
+
+    fn ask(incremental: u8, width: u16, height: u16) -> Vec<u8> {
+        request(FramebufferUpdateRequest {
+            incremental,
+            x: 0,
+            y: 0,
+            width,
+            height,
+        })
+    }
+
+    /*
+     * Read an update in Raw encoding, returning its rectangles.
+     */
+    async fn read_update<R>(r: &mut R) -> Vec<Rect>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        assert_eq!(r.read_u8().await.unwrap(), 0);
+        r.read_u8().await.unwrap();
+        let mut rects = Vec::new();
+        for _ in 0..r.read_u16().await.unwrap() {
+            let mut v = [0; 4];
+            for v in &mut v {
+                *v = r.read_u16().await.unwrap() as usize;
+            }
+            assert_eq!(r.read_i32().await.unwrap(), encoding::RAW);
+            let mut px = vec![0; v[2] * v[3] * 4];
+            r.read_exact(&mut px).await.unwrap();
+            rects.push(Rect::new(v[0], v[1], v[2], v[3]));
+        }
+        rects
+    }
+
+    /*
+     * Whether the server sends nothing for a while:
+     */
+    async fn quiet<R>(r: &mut R) -> bool
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        tokio::time::timeout(Duration::from_millis(300), r.read_u8())
+            .await
+            .is_err()
+    }
+
+    #[tokio::test]
+    async fn incremental() {
+        let server = ServerBuilder::new().geometry(128, 48).build().unwrap();
+        let fb = server.framebuffer().unwrap();
+        let (stop, stopped) = oneshot::channel();
+        let (serving, addr) = serve_until(&server, stopped);
+
+        let client = async {
+            let mut sock = connect(addr).await;
+            sock.write_all(&ask(0, 128, 48)).await.unwrap();
+            assert_eq!(read_update(&mut sock).await,
+                vec![Rect::new(0, 0, 128, 48)]);
+
+            /*
+             * Nothing has changed, so nothing is sent...
+             */
+            sock.write_all(&ask(1, 128, 48)).await.unwrap();
+            assert!(quiet(&mut sock).await);
+
+            /*
+             * ... until something does, and then only the tile it is in.
+             */
+            fb.put(70, 5, 0xff, 0, 0);
+            assert_eq!(read_update(&mut sock).await,
+                vec![Rect::new(64, 0, 64, 48)]);
+
+            sock.write_all(&ask(0, 128, 48)).await.unwrap();
+            assert_eq!(read_update(&mut sock).await,
+                vec![Rect::new(0, 0, 128, 48)]);
+            stop.send(()).ok();
+        };
+
+        let (served, ()) = tokio::join!(serving, client);
+        served.unwrap();
+    }
+
+    /*
+     * A client that cannot draw the cursor has it drawn into updates, and
+     * when it moves, is sent the areas it left and now covers.
+     */
+    #[tokio::test]
+    async fn composite_cursor() {
+        let server = ServerBuilder::new().geometry(256, 48).build().unwrap();
+        server.set_cursor(crate::cursor::Cursor::arrow());
+        let (stop, stopped) = oneshot::channel();
+        let (serving, addr) = serve_until(&server, stopped);
+
+        let client = async {
+            let mut sock = connect(addr).await;
+            sock.write_all(&ask(0, 256, 48)).await.unwrap();
+            assert_eq!(read_update(&mut sock).await,
+                vec![Rect::new(0, 0, 256, 48)]);
+
+            sock.write_all(&request(rfb::PointerEvent {
+                buttons: 0,
+                x: 200,
+                y: 10,
+            })).await.unwrap();
+            sock.write_all(&ask(1, 256, 48)).await.unwrap();
+            let rects = read_update(&mut sock).await;
+            let covers = |x, y| rects.iter().any(|r| r.x <= x
+                && x < r.x + r.width && r.y <= y && y < r.y + r.height);
+            assert!(covers(0, 0) && covers(200, 10));
+            assert!(!covers(100, 10));
+            stop.send(()).ok();
+        };
+
+        let (served, ()) = tokio::join!(serving, client);
+        served.unwrap();
+    }
+
+    #[tokio::test]
+    async fn exclusive() {
+        let server = ServerBuilder::new().geometry(32, 16).build().unwrap();
+        server.set_access_policy(|_, asked| match asked {
+            Access::Exclusive => Some(Access::Exclusive),
+            _ => Some(Access::ViewOnly),
+        });
+        let (stop, stopped) = oneshot::channel();
+        let (serving, addr) = serve_until(&server, stopped);
+
+        let client = async {
+            let mut first = connect(addr).await;
+            first.write_all(&ask(0, 32, 16)).await.unwrap();
+            read_update(&mut first).await;
+            /*
+             * The access of the newest client:
+             */
+            let access = || {
+                let (_, s) = server.sessions().pop().unwrap();
+                let access = s.stats.lock().unwrap().access;
+                access
+            };
+            assert_eq!(access(), Some(Access::ViewOnly));
+
+            /*
+             * A client that asks to have the desktop to itself gets it, and
+             * the other is disconnected.
+             */
+            let mut second = connect_shared(addr, false).await;
+            assert!(first.read_u8().await.is_err());
+            second.write_all(&ask(0, 32, 16)).await.unwrap();
+            read_update(&mut second).await;
+            assert_eq!(access(), Some(Access::Exclusive));
+            stop.send(()).ok();
+        };
+
+        let (served, ()) = tokio::join!(serving, client);
+        served.unwrap();
+
+        /*
+         * A client that may only watch cannot push the others off, whatever
+         * the policy says.
+         */
+        let watcher = ClientInfo {
+            identity: "127.0.0.1",
+            verified: false,
+            view_only: true,
+        };
+        assert_eq!(server.client_access(&watcher, Access::Exclusive),
+            Some(Access::ViewOnly));
+    }
+
+    /*
+     * A source of one colour, which cannot say when it changes.
+     */
+    struct Plain(AtomicU32);
+
+    impl Source for Plain {
+        fn dimensions(&self) -> (usize, usize) {
+            (32, 16)
+        }
+
+        fn read_rect(&self, r: &Rect, out: &mut Vec<u32>) {
+            let c = self.0.load(Ordering::Relaxed);
+            out.resize(out.len() + r.area(), c);
+        }
+    }
+
+    #[tokio::test]
+    async fn unchanged() {
+        let plain = Arc::new(Plain(AtomicU32::new(0x123456)));
+        let server = ServerBuilder::new()
+            .source(Arc::clone(&plain) as Arc<dyn Source>)
+            .build()
+            .unwrap();
+        let (stop, stopped) = oneshot::channel();
+        let (serving, addr) = serve_until(&server, stopped);
+
+        let client = async {
+            let mut sock = connect(addr).await;
+            sock.write_all(&ask(0, 32, 16)).await.unwrap();
+            assert_eq!(read_update(&mut sock).await,
+                vec![Rect::new(0, 0, 32, 16)]);
+
+            sock.write_all(&ask(1, 32, 16)).await.unwrap();
+            assert!(quiet(&mut sock).await);
+
+            plain.0.store(0x654321, Ordering::Relaxed);
+            assert_eq!(read_update(&mut sock).await,
+                vec![Rect::new(0, 0, 32, 16)]);
+            stop.send(()).ok();
+        };
+
+        let (served, ()) = tokio::join!(serving, client);
+        served.unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+use std::convert::{TryFrom, TryInto};
+use std::io::{Read, Write};
+
+use anyhow::{bail, Result};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+/*
+ * The extended clipboard, as implemented by TigerVNC and noVNC, replaces the
+ * Latin-1 text of the original cut text messages with UTF-8, and allows other
+ * formats besides.  A client that supports it includes the pseudo-encoding in
+ * SetEncodings; we then announce our capabilities in a ServerCutText with a
+ * negative length, and the client replies in kind.  From then on, cut text
+ * messages in either direction with a negative length carry a set of flags
+ * and a payload:
+ *
+ *  - caps: the actions and formats the sender supports, with the largest
+ *    size it will accept in each format;
+ *  - notify: the sender has new clipboard contents, in the listed formats;
+ *  - request: the sender would like the contents in the listed formats;
+ *  - peek: the sender would like a notify describing the current contents;
+ *  - provide: the contents, as a zlib stream.
+ *
+ * We only deal in plain text.
+ */
+pub const FORMAT_TEXT: u32 = 1 << 0;
+
+pub const ACTION_CAPS: u32 = 1 << 24;
+pub const ACTION_REQUEST: u32 = 1 << 25;
+pub const ACTION_PEEK: u32 = 1 << 26;
+pub const ACTION_NOTIFY: u32 = 1 << 27;
+pub const ACTION_PROVIDE: u32 = 1 << 28;
+
+const FORMATS: u32 = 0xffff;
+const ACTIONS: u32 = 0x1f00_0000;
+
+/*
+ * A ServerCutText carrying extended clipboard flags and payload.
+ */
+fn message(flags: u32, payload: &[u8]) -> Vec<u8> {
+    let len = 4 + payload.len() as i32;
+    let mut buf = vec![3, 0, 0, 0];
+    buf.extend_from_slice(&(-len).to_be_bytes());
+    buf.extend_from_slice(&flags.to_be_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/*
+ * Announce that we can exchange text of up to "max" bytes.
+ */
+pub fn caps_message(max: usize) -> Vec<u8> {
+    let max = u32::try_from(max).unwrap_or(u32::MAX);
+    message(ACTION_CAPS | ACTION_REQUEST | ACTION_PEEK | ACTION_NOTIFY
+        | ACTION_PROVIDE | FORMAT_TEXT, &max.to_be_bytes())
+}
+
+pub fn notify_message(text: bool) -> Vec<u8> {
+    message(ACTION_NOTIFY | if text { FORMAT_TEXT } else { 0 }, &[])
+}
+
+pub fn request_message() -> Vec<u8> {
+    message(ACTION_REQUEST | FORMAT_TEXT, &[])
+}
+
+/*
+ * Text on the wire has CRLF line endings and a terminating NUL.
+ */
+fn encode_text(text: &str) -> Vec<u8> {
+    let mut data = text.replace("\r\n", "\n").replace('\n', "\r\n")
+        .into_bytes();
+    data.push(0);
+    data
+}
+
+/*
+ * Whether "text" is within a client's size limit once it has been encoded
+ * for the wire, which is what the limit applies to.
+ */
+pub fn fits(text: &str, limit: usize) -> bool {
+    let crlf = text.matches("\r\n").count();
+    let lf = text.matches('\n').count();
+    let encoded = text.len() + (lf - crlf) + 1;
+    encoded <= limit
+}
+
+/*
+ * Send "text", encoded for the wire.
+ */
+pub fn provide_message(text: &str) -> Result<Vec<u8>> {
+    let data = encode_text(text);
+
+    let mut z = ZlibEncoder::new(Vec::new(), Compression::default());
+    z.write_all(&(data.len() as u32).to_be_bytes())?;
+    z.write_all(&data)?;
+    Ok(message(ACTION_PROVIDE | FORMAT_TEXT, &z.finish()?))
+}
+
+/*
+ * A message from a client, sent as ClientCutText with a negative length.
+ */
+#[derive(Debug)]
+pub enum Message {
+    /*
+     * The actions and formats the client supports, and the size limit for
+     * text, if the client accepts text at all.
+     */
+    Caps(u32, Option<usize>),
+    Notify(bool),
+    Request(bool),
+    Peek,
+    /*
+     * Text from the client, if it sent any:
+     */
+    Provide(Option<String>),
+}
+
+impl Message {
+    /*
+     * Decode "flags" and "payload".  Text longer than "max" bytes is
+     * refused, however well it compresses.
+     */
+    pub fn parse(flags: u32, payload: &[u8], max: usize) -> Result<Message> {
+        let text = flags & FORMAT_TEXT != 0;
+        if flags & ACTION_CAPS != 0 {
+            /*
+             * The other action bits are those the client supports.  A size
+             * follows for each format, lowest bit first, so that the text
+             * size comes first if there is one.
+             */
+            let size = match payload.get(0..4) {
+                Some(b) if text => {
+                    Some(u32::from_be_bytes(b.try_into().unwrap()) as usize)
+                }
+                None if text => bail!("caps without sizes"),
+                _ => None,
+            };
+            return Ok(Message::Caps(flags, size));
+        }
+
+        Ok(match flags & ACTIONS {
+            ACTION_NOTIFY => Message::Notify(text),
+            ACTION_REQUEST => Message::Request(text),
+            ACTION_PEEK => Message::Peek,
+            ACTION_PROVIDE => Message::Provide(if text {
+                Some(provided_text(payload, max)?)
+            } else {
+                None
+            }),
+            a => bail!("unexpected clipboard actions {:#x}", a),
+        })
+    }
+}
+
+/*
+ * The text from a provide payload, which is the first of the formats it
+ * contains; we ignore the rest.
+ */
+fn provided_text(payload: &[u8], max: usize) -> Result<String> {
+    let mut z = ZlibDecoder::new(payload);
+    let mut len = [0u8; 4];
+    z.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max {
+        bail!("clipboard text of {} bytes is too long", len);
+    }
+
+    let mut data = Vec::with_capacity(len);
+    z.take(len as u64).read_to_end(&mut data)?;
+    if data.len() != len {
+        bail!("clipboard text is truncated");
+    }
+    if let Some(nul) = data.iter().position(|&b| b == 0) {
+        data.truncate(nul);
+    }
+    Ok(String::from_utf8_lossy(&data).replace("\r\n", "\n"))
+}
+
+/*
+ * Whether a client with the given capabilities wants a notify when the
+ * clipboard changes, rather than the text itself.
+ */
+pub fn wants_notify(caps: u32) -> bool {
+    caps & ACTION_NOTIFY != 0
+}
+
+pub fn accepts_text(caps: u32) -> bool {
+    caps & ACTION_PROVIDE != 0 && caps & FORMATS & FORMAT_TEXT != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size() {
+        for text in ["", "abc", "a\nb\n", "a\r\nb\n\r", "\n\n\r\n"] {
+            let len = encode_text(text).len();
+            assert!(fits(text, len), "{:?} in {}", text, len);
+            assert!(!fits(text, len - 1), "{:?} in {}", text, len - 1);
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use std::io::{ErrorKind, Result};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use zstd::bulk::Compressor;
+use zstd::stream::raw::{Decoder, InBuffer, Operation, OutBuffer};
+
+/*
+ * Data written between flushes is accumulated and sent as a single zstd
+ * frame, unless it grows beyond this size first.
+ */
+const MAX_FRAME: usize = 1024 * 1024;
+
+const READ_SIZE: usize = 64 * 1024;
+
+/*
+ * Compression of the whole connection, beneath the RFB protocol, for links
+ * between two jvnc processes that have both been configured to use it.
+ * Each flush produces one zstd frame, and as a sequence of frames is itself
+ * a valid zstd stream, a capture of either direction can be decompressed
+ * with the standard tools.
+ */
+pub struct CompressedStream<S> {
+    inner: S,
+    compressor: Compressor<'static>,
+    /*
+     * Data written but not yet compressed, and the compressed frame that is
+     * being written to the inner stream, with how much of it has been sent.
+     */
+    pending: Vec<u8>,
+    frame: Vec<u8>,
+    sent: usize,
+    decoder: Decoder<'static>,
+    /*
+     * Compressed data read from the inner stream but not yet decoded:
+     */
+    input: Vec<u8>,
+    consumed: usize,
+    /*
+     * Whether the decoder may hold output that did not fit in the last read.
+     */
+    flushing: bool,
+}
+
+impl<S> CompressedStream<S> {
+    pub fn new(inner: S, level: i32) -> Result<CompressedStream<S>> {
+        Ok(CompressedStream {
+            inner,
+            compressor: Compressor::new(level)?,
+            pending: Vec::new(),
+            frame: Vec::new(),
+            sent: 0,
+            decoder: Decoder::new()?,
+            input: Vec::new(),
+            consumed: 0,
+            flushing: false,
+        })
+    }
+}
+
+impl<S: AsyncWrite + Unpin> CompressedStream<S> {
+    /*
+     * Compress whatever is pending, and write it out.
+     */
+    fn poll_frame(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        loop {
+            if self.sent == self.frame.len() {
+                if self.pending.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                self.frame = self.compressor.compress(&self.pending)?;
+                self.sent = 0;
+                self.pending.clear();
+            }
+
+            let n = ready!(Pin::new(&mut self.inner)
+                .poll_write(cx, &self.frame[self.sent..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.sent += n;
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CompressedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let s = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            if s.consumed < s.input.len() || s.flushing {
+                let mut input = InBuffer::around(&s.input[s.consumed..]);
+                let dst = buf.initialize_unfilled();
+                let mut output = OutBuffer::around(dst);
+                s.decoder.run(&mut input, &mut output)?;
+
+                let n = output.pos();
+                s.consumed += input.pos();
+                s.flushing = n == dst.len();
+                buf.advance(n);
+                if n > 0 {
+                    return Poll::Ready(Ok(()));
+                }
+                if s.consumed < s.input.len() {
+                    continue;
+                }
+            }
+
+            /*
+             * The decoder needs more input.
+             */
+            s.input.resize(READ_SIZE, 0);
+            s.consumed = 0;
+            let mut rb = ReadBuf::new(&mut s.input);
+            let res = Pin::new(&mut s.inner).poll_read(cx, &mut rb);
+            let n = rb.filled().len();
+            s.input.truncate(n);
+            ready!(res)?;
+            if n == 0 {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CompressedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        let s = self.get_mut();
+        if s.pending.len() >= MAX_FRAME {
+            ready!(s.poll_frame(cx))?;
+        }
+        s.pending.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>)
+        -> Poll<Result<()>>
+    {
+        let s = self.get_mut();
+        ready!(s.poll_frame(cx))?;
+        Pin::new(&mut s.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>)
+        -> Poll<Result<()>>
+    {
+        let s = self.get_mut();
+        ready!(s.poll_frame(cx))?;
+        Pin::new(&mut s.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /*
+     * Something between noise and a picture, so that frames compress, but
+     * not to nothing:
+     */
+    fn data(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed | 1;
+        (0..len).map(|i| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            if i % 64 < 8 { x as u8 } else { (i / 256) as u8 }
+        }).collect()
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let writes = [data(3 * MAX_FRAME / 2, 1), data(100, 2),
+            data(MAX_FRAME, 3), data(1, 4), data(2 * MAX_FRAME + 7, 5)];
+        let expected = writes.concat();
+
+        /*
+         * A pipe that holds very little, and reads smaller than a frame, make
+         * both ends stop part way through, again and again.
+         */
+        let (a, b) = tokio::io::duplex(509);
+        let mut w = CompressedStream::new(a, 3).unwrap();
+        let mut r = CompressedStream::new(b, 3).unwrap();
+
+        let write = async move {
+            for (i, buf) in writes.iter().enumerate() {
+                w.write_all(buf).await.unwrap();
+                if i % 2 == 0 {
+                    w.flush().await.unwrap();
+                }
+            }
+            w.shutdown().await.unwrap();
+        };
+        let read = async move {
+            let mut out = Vec::new();
+            let mut buf = [0u8; 777];
+            loop {
+                let n = r.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break out;
+                }
+                out.extend_from_slice(&buf[..n]);
+            }
+        };
+        let ((), out) = tokio::join!(write, read);
+        assert_eq!(out.len(), expected.len());
+        assert!(out == expected);
+    }
+
+    #[tokio::test]
+    async fn standard() {
+        let writes = [data(10, 1), data(MAX_FRAME + 1, 2), data(4096, 3)];
+
+        let mut w = CompressedStream::new(Vec::new(), 3).unwrap();
+        for buf in &writes {
+            w.write_all(buf).await.unwrap();
+            w.flush().await.unwrap();
+        }
+
+        /*
+         * The frames written to the connection are a zstd stream, which the
+         * library decodes on its own.
+         */
+        let out = zstd::decode_all(&w.inner[..]).unwrap();
+        assert!(out == writes.concat());
+    }
+}
@@ -0,0 +1,952 @@
+use std::fmt::Write;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+
+use crate::guard::Policy;
+use crate::rfb::Version;
+use crate::tier::{PresetChange, Presets, Tier};
+
+/*
+ * What clients see while the display is asleep:
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placeholder {
+    Black,
+    /*
+     * The last picture, darkened.
+     */
+    Dim,
+}
+
+pub struct Config {
+    /*
+     * Addresses on which to accept connections, each a host and port or
+     * "unix:" and the path of a socket, after "ws://" for a listener that
+     * expects WebSocket clients, "http://" for one that serves the viewer to
+     * browsers as well, or "rfbs://" for one that expects TLS; and whether
+     * to carry on with those that work if any cannot be used.
+     */
+    pub listen: Vec<String>,
+    pub listen_partial: bool,
+    /*
+     * Viewers listening for us to connect to them, as "host" or "host:port",
+     * or repeaters to wait at for a viewer, as "ID:NNNN@host[:port]".  These
+     * are in addition to any listeners, and if there are any, there need be
+     * no listeners.
+     */
+    pub connect: Vec<String>,
+    /*
+     * A directory holding a release of noVNC, to serve to browsers that
+     * visit an "http://" listener.
+     */
+    pub novnc: Option<PathBuf>,
+    /*
+     * Origins, as "scheme://host[:port]", of web pages other than our own
+     * that may open a WebSocket to us.  A browser will connect to us from
+     * any page it has loaded, so without this check any site the user
+     * visits could drive the desktop.
+     */
+    pub origins: Vec<String>,
+    /*
+     * A VNC server whose desktop we show, as "host" or "host:port", rather
+     * than a framebuffer of our own, and the password it asks for, if any.
+     */
+    pub upstream: Option<String>,
+    pub upstream_password: Option<String>,
+    /*
+     * The ProtocolVersion we send, which may be a vendor variant of one we
+     * speak.  Clients that answer with a later version are held to this
+     * one.
+     */
+    pub rfb_version: String,
+    /*
+     * The admin socket of a running jvnc whose listening sockets we should
+     * take over, instead of binding our own.
+     */
+    pub takeover: Option<PathBuf>,
+    pub width: usize,
+    pub height: usize,
+    /*
+     * Give framebuffers we create a back buffer, so that clients only see
+     * frames the application has presented.
+     */
+    pub double_buffer: bool,
+    pub name: String,
+    /*
+     * Encoder settings for each quality tier, and the round trip times that
+     * divide the tiers.
+     */
+    pub presets: Presets,
+    /*
+     * The most updates sent to a client each second.  The client's quality
+     * tier, or a slow connection, may lower this further.
+     */
+    pub fps: u64,
+    /*
+     * Path of the Unix socket for local administrative requests, if enabled.
+     * By default it is "jvnc.sock" in $XDG_RUNTIME_DIR, a directory private
+     * to the user, and there is none if that is not set.
+     */
+    pub admin: Option<PathBuf>,
+    /*
+     * Whether to accept the same commands on standard input.
+     */
+    pub console: bool,
+    /*
+     * Number of threads dedicated to encoding updates, and the number of
+     * encode jobs that may wait for a free thread.
+     */
+    pub encode_threads: usize,
+    pub encode_queue: usize,
+    /*
+     * Memory for encoded rectangles shared between connections, in bytes;
+     * zero disables sharing.
+     */
+    pub encode_cache: usize,
+    /*
+     * Let clients move the pointer from the keypad, in the manner of X11
+     * MouseKeys, after pressing Shift and Num_Lock.  This takes the keypad
+     * from the application for as long as it is on.
+     */
+    pub mousekeys: bool,
+    /*
+     * Highlight the rectangles sent to each client, for debugging.
+     */
+    pub debug_damage: bool,
+    /*
+     * Check that the framebuffer works at startup, and fill framebuffers
+     * with a pattern when they are allocated, so that anything an
+     * application fails to draw stands out.
+     */
+    pub debug_framebuffer: bool,
+    /*
+     * If set, connections are closed once they have been open this long.
+     * Clients see a warning for the final part of the session.
+     */
+    pub session_limit: Option<Duration>,
+    pub session_warning: Duration,
+    /*
+     * Clients must get from connecting through to ClientInit within this
+     * long, or they are disconnected.
+     */
+    pub handshake_timeout: Duration,
+    /*
+     * Within that, each phase of the handshake has a limit of its own: the
+     * exchange of versions, the security handshake, including any TLS, in
+     * which a user may have to type a password, and ClientInit.
+     */
+    pub version_timeout: Duration,
+    pub security_timeout: Duration,
+    pub init_timeout: Duration,
+    /*
+     * If set, connections that have finished the handshake are closed once
+     * the client has sent nothing for this long.  Viewers ask for updates
+     * as long as they are showing the desktop, so this catches only those
+     * that have stopped or lost their connection without closing it.
+     */
+    pub silence_timeout: Option<Duration>,
+    /*
+     * When the server shuts down, connections have this long to finish
+     * writing what they have queued before they are closed regardless.
+     */
+    pub shutdown_grace: Duration,
+    /*
+     * If set, the desktop is considered idle once no client has sent input
+     * for this long.
+     */
+    pub idle_timeout: Option<Duration>,
+    /*
+     * While the embedder says the display is asleep, clients are shown a
+     * placeholder, refreshed only this often.
+     */
+    pub sleep_placeholder: Placeholder,
+    pub sleep_interval: Duration,
+    /*
+     * Whether clients may ask for the framebuffer to be resized.
+     */
+    pub client_resize: bool,
+    /*
+     * Merge repeated keystrokes that arrive within this long of each other,
+     * unless a user's preferences say otherwise.  Zero disables this.
+     */
+    pub key_debounce: Duration,
+    /*
+     * The longest clipboard text we accept from a client, in bytes.  Longer
+     * text is discarded as it arrives.
+     */
+    pub max_cut_text: usize,
+    /*
+     * Skip messages from protocol extensions we do not implement, where we
+     * know their length, rather than closing the connection.
+     */
+    pub skip_unknown: bool,
+    /*
+     * File in which per-user preferences are kept, if enabled.
+     */
+    pub prefs: Option<PathBuf>,
+    /*
+     * If set, a file of TLS pre-shared keys; every connection must then use
+     * TLS and present one of them.
+     */
+    pub psk: Option<PathBuf>,
+    /*
+     * If set, clients must use VNC Authentication with this password, of
+     * which only the first eight bytes are significant.
+     */
+    pub password: Option<String>,
+    /*
+     * If set, clients that give this password instead are admitted, but may
+     * only watch.
+     */
+    pub view_password: Option<String>,
+    /*
+     * A password file in the traditional VNC format, read at startup if no
+     * password is set directly.  A second password in the file is used for
+     * view-only access.
+     */
+    pub password_file: Option<PathBuf>,
+    /*
+     * The names of the security types to offer, in order of preference.  If
+     * not set, we offer VNC Authentication when there is a password and
+     * None otherwise.
+     */
+    pub security: Option<Vec<String>>,
+    /*
+     * A certificate for the X509 subtypes of VeNCrypt, which encrypts the
+     * session with TLS, and for "rfbs://" listeners, which encrypt the whole
+     * connection.  The key may be in the same file as the certificate.
+     */
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /*
+     * If set, the whole connection is compressed with zstd at this level,
+     * beneath RFB.  Ordinary viewers cannot speak this, so it is only for
+     * links to another jvnc, or to tools that expect it.
+     */
+    pub stream_zstd: Option<i32>,
+    /*
+     * How addresses that fail to authenticate are made to wait, and when
+     * they are banned.
+     */
+    pub guard: Policy,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let threads = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+
+        Config {
+            listen: vec!["0.0.0.0:5915".to_string()],
+            listen_partial: false,
+            connect: Vec::new(),
+            novnc: None,
+            origins: Vec::new(),
+            upstream: None,
+            upstream_password: None,
+            rfb_version: Version::V3_8.wire().to_string(),
+            takeover: None,
+            width: 512,
+            height: 384,
+            double_buffer: false,
+            name: "jvnc".to_string(),
+            presets: Presets::default(),
+            fps: 12,
+            admin: std::env::var_os("XDG_RUNTIME_DIR")
+                .map(PathBuf::from)
+                .filter(|p| p.is_absolute())
+                .map(|p| p.join("jvnc.sock")),
+            console: std::io::stdin().is_terminal(),
+            encode_threads: threads,
+            encode_queue: threads * 4,
+            encode_cache: 32 * 1024 * 1024,
+            mousekeys: false,
+            debug_damage: false,
+            debug_framebuffer: false,
+            session_limit: None,
+            session_warning: Duration::from_secs(60),
+            handshake_timeout: Duration::from_secs(30),
+            version_timeout: Duration::from_secs(10),
+            security_timeout: Duration::from_secs(30),
+            init_timeout: Duration::from_secs(10),
+            silence_timeout: None,
+            shutdown_grace: Duration::from_secs(5),
+            idle_timeout: None,
+            sleep_placeholder: Placeholder::Black,
+            sleep_interval: Duration::from_secs(10),
+            client_resize: true,
+            key_debounce: Duration::ZERO,
+            max_cut_text: 1024 * 1024,
+            skip_unknown: false,
+            prefs: None,
+            psk: None,
+            password: None,
+            view_password: None,
+            password_file: None,
+            security: None,
+            cert: None,
+            key: None,
+            stream_zstd: None,
+            guard: Policy::default(),
+        }
+    }
+}
+
+impl Config {
+    /*
+     * Start with the defaults, overridden by any settings in the environment.
+     */
+    pub fn from_env() -> Config {
+        let mut c = Config::default();
+        c.apply_env();
+        c
+    }
+
+    /*
+     * Start with the defaults, overridden by the settings in a TOML file,
+     * and then by any in the environment.  The keys in the file are the
+     * names of the environment variables, in lower case and without the
+     * "JVNC_" prefix, along with "geometry", as "WIDTHxHEIGHT".  Lists such
+     * as "listen" and "security" are arrays.
+     */
+    pub fn from_file(path: &Path) -> Result<Config> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading {}", path.display()))?;
+        let file: File = toml::from_str(&text)
+            .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+
+        let mut c = Config::default();
+        file.apply(&mut c)
+            .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        c.apply_env();
+        Ok(c)
+    }
+
+    fn apply_env(&mut self) {
+        if let Ok(v) = std::env::var("JVNC_LISTEN") {
+            self.listen = v.split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect();
+        }
+        if let Ok(v) = std::env::var("JVNC_LISTEN_PARTIAL") {
+            self.listen_partial = v == "1";
+        }
+        if let Ok(v) = std::env::var("JVNC_CONNECT") {
+            self.connect = v.split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect();
+        }
+        if let Some(path) = std::env::var_os("JVNC_NOVNC") {
+            self.novnc = Some(PathBuf::from(path))
+                .filter(|p| !p.as_os_str().is_empty());
+        }
+        if let Ok(v) = std::env::var("JVNC_ORIGINS") {
+            self.origins = v.split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect();
+        }
+        if let Ok(v) = std::env::var("JVNC_UPSTREAM") {
+            self.upstream = Some(v).filter(|v| !v.is_empty());
+        }
+        if let Ok(v) = std::env::var("JVNC_UPSTREAM_PASSWORD") {
+            self.upstream_password = Some(v).filter(|v| !v.is_empty());
+        }
+        if let Some(path) = std::env::var_os("JVNC_TAKEOVER") {
+            self.takeover = Some(PathBuf::from(path))
+                .filter(|p| !p.as_os_str().is_empty());
+        }
+        if let Ok(v) = std::env::var("JVNC_RFB_VERSION") {
+            /*
+             * Either a version we speak, like "3.7", or a full version
+             * string, like "RFB 003.889".
+             */
+            match rfb_version(&v) {
+                Some(s) => self.rfb_version = s,
+                None => {
+                    log!("warning: ignoring invalid JVNC_RFB_VERSION {:?}", v);
+                }
+            }
+        }
+        if let Ok(v) = std::env::var("JVNC_DOUBLE_BUFFER") {
+            self.double_buffer = v == "1";
+        }
+        if let Ok(name) = std::env::var("JVNC_NAME") {
+            self.name = name;
+        }
+        if let Some(admin) = std::env::var_os("JVNC_ADMIN") {
+            self.admin = if admin.is_empty() {
+                None
+            } else {
+                Some(PathBuf::from(admin))
+            };
+        }
+        for tier in [Tier::Lan, Tier::Wan, Tier::Poor] {
+            let name = format!("JVNC_TIER_{}", tier.name().to_uppercase());
+            let Ok(v) = std::env::var(&name) else {
+                continue;
+            };
+            match PresetChange::parse(&v) {
+                Ok(c) => c.apply(self.presets.preset_mut(tier)),
+                Err(e) => log!("warning: ignoring invalid {}: {}", name, e),
+            }
+        }
+        if let Ok(v) = std::env::var("JVNC_TIER_RTT") {
+            let rtt = v.split_once(',')
+                .and_then(|(l, w)| Some((l.trim().parse().ok()?,
+                    w.trim().parse().ok()?)))
+                .ok_or_else(|| "expected LAN,WAN".to_string())
+                .and_then(|(l, w)| self.presets.set_rtt(l, w));
+            if let Err(e) = rtt {
+                log!("warning: ignoring invalid JVNC_TIER_RTT: {}", e);
+            }
+        }
+        if let Some(n) = env_usize("JVNC_FPS").filter(|&n| n > 0) {
+            self.fps = n as u64;
+        }
+        if let Ok(v) = std::env::var("JVNC_CONSOLE") {
+            self.console = v == "1";
+        }
+        if let Some(n) = env_usize("JVNC_ENCODE_THREADS") {
+            self.encode_threads = n;
+        }
+        if let Some(n) = env_usize("JVNC_ENCODE_QUEUE") {
+            self.encode_queue = n;
+        }
+        if let Some(n) = env_usize("JVNC_ENCODE_CACHE") {
+            self.encode_cache = n;
+        }
+        if let Ok(v) = std::env::var("JVNC_MOUSEKEYS") {
+            self.mousekeys = v == "1";
+        }
+        if let Ok(v) = std::env::var("JVNC_DEBUG_DAMAGE") {
+            self.debug_damage = v == "1";
+        }
+        if let Ok(v) = std::env::var("JVNC_DEBUG_FRAMEBUFFER") {
+            self.debug_framebuffer = v == "1";
+        }
+        if let Some(n) = env_usize("JVNC_SESSION_LIMIT") {
+            self.session_limit = Some(Duration::from_secs(n as u64))
+                .filter(|d| !d.is_zero());
+        }
+        if let Some(n) = env_usize("JVNC_SESSION_WARNING") {
+            self.session_warning = Duration::from_secs(n as u64);
+        }
+        let n = env_usize("JVNC_HANDSHAKE_TIMEOUT");
+        if let Some(n) = n.filter(|&n| n > 0) {
+            self.handshake_timeout = Duration::from_secs(n as u64);
+        }
+        for (name, t) in [
+            ("JVNC_VERSION_TIMEOUT", &mut self.version_timeout),
+            ("JVNC_SECURITY_TIMEOUT", &mut self.security_timeout),
+            ("JVNC_INIT_TIMEOUT", &mut self.init_timeout),
+        ] {
+            if let Some(n) = env_usize(name).filter(|&n| n > 0) {
+                *t = Duration::from_secs(n as u64);
+            }
+        }
+        if let Some(n) = env_usize("JVNC_SILENCE_TIMEOUT") {
+            self.silence_timeout = Some(Duration::from_secs(n as u64))
+                .filter(|d| !d.is_zero());
+        }
+        if let Some(n) = env_usize("JVNC_SHUTDOWN_GRACE") {
+            self.shutdown_grace = Duration::from_secs(n as u64);
+        }
+        if let Some(n) = env_usize("JVNC_IDLE_TIMEOUT") {
+            self.idle_timeout = Some(Duration::from_secs(n as u64))
+                .filter(|d| !d.is_zero());
+        }
+        match std::env::var("JVNC_SLEEP_PLACEHOLDER").as_deref() {
+            Ok("black") => self.sleep_placeholder = Placeholder::Black,
+            Ok("dim") => self.sleep_placeholder = Placeholder::Dim,
+            _ => (),
+        }
+        if let Some(n) = env_usize("JVNC_SLEEP_INTERVAL").filter(|&n| n > 0) {
+            self.sleep_interval = Duration::from_secs(n as u64);
+        }
+        if let Ok(v) = std::env::var("JVNC_CLIENT_RESIZE") {
+            self.client_resize = v != "0";
+        }
+        if let Some(n) = env_usize("JVNC_KEY_DEBOUNCE") {
+            self.key_debounce = Duration::from_millis(n as u64);
+        }
+        if let Some(n) = env_usize("JVNC_MAX_CUT_TEXT") {
+            self.max_cut_text = n;
+        }
+        if let Ok(v) = std::env::var("JVNC_SKIP_UNKNOWN") {
+            self.skip_unknown = v == "1";
+        }
+        if let Some(psk) = std::env::var_os("JVNC_PSK") {
+            self.psk = Some(PathBuf::from(psk)).filter(|p| {
+                !p.as_os_str().is_empty()
+            });
+        }
+        if let Ok(v) = std::env::var("JVNC_PASSWORD") {
+            self.password = Some(v).filter(|v| !v.is_empty());
+        }
+        if let Ok(v) = std::env::var("JVNC_VIEW_PASSWORD") {
+            self.view_password = Some(v).filter(|v| !v.is_empty());
+        }
+        if let Some(path) = std::env::var_os("JVNC_PASSWORD_FILE") {
+            self.password_file = Some(PathBuf::from(path)).filter(|p| {
+                !p.as_os_str().is_empty()
+            });
+        }
+        if let Ok(v) = std::env::var("JVNC_SECURITY") {
+            self.security = Some(v.split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect());
+        }
+        if let Some(cert) = std::env::var_os("JVNC_CERT") {
+            self.cert = Some(PathBuf::from(cert)).filter(|p| {
+                !p.as_os_str().is_empty()
+            });
+        }
+        if let Some(key) = std::env::var_os("JVNC_KEY") {
+            self.key = Some(PathBuf::from(key)).filter(|p| {
+                !p.as_os_str().is_empty()
+            });
+        }
+        if let Ok(v) = std::env::var("JVNC_STREAM_ZSTD") {
+            self.stream_zstd = v.parse().ok().filter(|l| *l != 0);
+        }
+        if let Some(n) = env_usize("JVNC_AUTH_BACKOFF") {
+            self.guard.backoff = Duration::from_secs(n as u64);
+        }
+        if let Some(n) = env_usize("JVNC_AUTH_MAX_BACKOFF") {
+            self.guard.max_backoff = Duration::from_secs(n as u64);
+        }
+        if let Some(n) = env_usize("JVNC_AUTH_BAN_AFTER") {
+            self.guard.ban_after = Some(n as u32).filter(|n| *n > 0);
+        }
+        if let Some(n) = env_usize("JVNC_AUTH_BAN_TIME") {
+            self.guard.ban_time = Duration::from_secs(n as u64);
+        }
+        if let Some(prefs) = std::env::var_os("JVNC_PREFS") {
+            self.prefs = Some(PathBuf::from(prefs)).filter(|p| {
+                !p.as_os_str().is_empty()
+            });
+        }
+    }
+
+    /*
+     * A human-readable summary of the configuration, for diagnostic output.
+     * Anything secret must be redacted here.
+     */
+    pub fn describe(&self) -> String {
+        let mut s = String::new();
+        writeln!(s, "listen = {:?}", self.listen).unwrap();
+        writeln!(s, "listen_partial = {}", self.listen_partial).unwrap();
+        writeln!(s, "connect = {:?}", self.connect).unwrap();
+        writeln!(s, "novnc = {:?}", self.novnc).unwrap();
+        writeln!(s, "origins = {:?}", self.origins).unwrap();
+        writeln!(s, "upstream = {:?}", self.upstream).unwrap();
+        writeln!(s, "upstream_password = {}",
+            redact(&self.upstream_password)).unwrap();
+        writeln!(s, "rfb_version = {:?}", self.rfb_version).unwrap();
+        writeln!(s, "takeover = {:?}", self.takeover).unwrap();
+        writeln!(s, "geometry = {}x{}", self.width, self.height).unwrap();
+        writeln!(s, "double_buffer = {}", self.double_buffer).unwrap();
+        writeln!(s, "name = {:?}", self.name).unwrap();
+        writeln!(s, "admin = {:?}", self.admin).unwrap();
+        writeln!(s, "console = {}", self.console).unwrap();
+        writeln!(s, "presets = {:?}", self.presets).unwrap();
+        writeln!(s, "fps = {}", self.fps).unwrap();
+        writeln!(s, "encode_threads = {}", self.encode_threads).unwrap();
+        writeln!(s, "encode_queue = {}", self.encode_queue).unwrap();
+        writeln!(s, "encode_cache = {}", self.encode_cache).unwrap();
+        writeln!(s, "mousekeys = {}", self.mousekeys).unwrap();
+        writeln!(s, "debug_damage = {}", self.debug_damage).unwrap();
+        writeln!(s, "debug_framebuffer = {}", self.debug_framebuffer)
+            .unwrap();
+        writeln!(s, "session_limit = {:?}", self.session_limit).unwrap();
+        writeln!(s, "session_warning = {:?}", self.session_warning).unwrap();
+        writeln!(s, "handshake_timeout = {:?}", self.handshake_timeout)
+            .unwrap();
+        writeln!(s, "version_timeout = {:?}", self.version_timeout).unwrap();
+        writeln!(s, "security_timeout = {:?}", self.security_timeout)
+            .unwrap();
+        writeln!(s, "init_timeout = {:?}", self.init_timeout).unwrap();
+        writeln!(s, "silence_timeout = {:?}", self.silence_timeout).unwrap();
+        writeln!(s, "shutdown_grace = {:?}", self.shutdown_grace).unwrap();
+        writeln!(s, "idle_timeout = {:?}", self.idle_timeout).unwrap();
+        writeln!(s, "sleep_placeholder = {:?}", self.sleep_placeholder)
+            .unwrap();
+        writeln!(s, "sleep_interval = {:?}", self.sleep_interval).unwrap();
+        writeln!(s, "client_resize = {}", self.client_resize).unwrap();
+        writeln!(s, "key_debounce = {:?}", self.key_debounce).unwrap();
+        writeln!(s, "max_cut_text = {}", self.max_cut_text).unwrap();
+        writeln!(s, "skip_unknown = {}", self.skip_unknown).unwrap();
+        writeln!(s, "prefs = {:?}", self.prefs).unwrap();
+        writeln!(s, "psk = {:?}", self.psk).unwrap();
+        writeln!(s, "password = {}", redact(&self.password)).unwrap();
+        writeln!(s, "view_password = {}", redact(&self.view_password))
+            .unwrap();
+        writeln!(s, "password_file = {:?}", self.password_file).unwrap();
+        writeln!(s, "security = {:?}", self.security).unwrap();
+        writeln!(s, "cert = {:?}", self.cert).unwrap();
+        writeln!(s, "key = {:?}", self.key).unwrap();
+        writeln!(s, "stream_zstd = {:?}", self.stream_zstd).unwrap();
+        writeln!(s, "guard = {:?}", self.guard).unwrap();
+        s
+    }
+}
+
+/*
+ * Either a version we speak, like "3.7", or a full version string, like
+ * "RFB 003.889".
+ */
+fn rfb_version(v: &str) -> Option<String> {
+    let s = match v {
+        "3.3" => Version::V3_3.wire().to_string(),
+        "3.7" => Version::V3_7.wire().to_string(),
+        "3.8" => Version::V3_8.wire().to_string(),
+        s => s.to_string(),
+    };
+    Some(s).filter(|s| s.len() == 11 && Version::parse(s).is_some())
+}
+
+/*
+ * The settings in a configuration file, each of which is optional.  Keys we
+ * do not know are an error rather than ignored, as they are most likely a
+ * misspelling of one we do.  Durations are in seconds, except for
+ * "key_debounce" and the pair in "tier_rtt", which are in milliseconds.
+ */
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    listen: Option<Vec<String>>,
+    listen_partial: Option<bool>,
+    connect: Option<Vec<String>>,
+    novnc: Option<PathBuf>,
+    origins: Option<Vec<String>>,
+    upstream: Option<String>,
+    upstream_password: Option<String>,
+    rfb_version: Option<String>,
+    takeover: Option<PathBuf>,
+    geometry: Option<String>,
+    double_buffer: Option<bool>,
+    name: Option<String>,
+    tier_lan: Option<String>,
+    tier_wan: Option<String>,
+    tier_poor: Option<String>,
+    tier_rtt: Option<(u64, u64)>,
+    fps: Option<u64>,
+    /*
+     * An empty path disables the admin socket:
+     */
+    admin: Option<PathBuf>,
+    console: Option<bool>,
+    encode_threads: Option<usize>,
+    encode_queue: Option<usize>,
+    encode_cache: Option<usize>,
+    mousekeys: Option<bool>,
+    debug_damage: Option<bool>,
+    debug_framebuffer: Option<bool>,
+    session_limit: Option<u64>,
+    session_warning: Option<u64>,
+    handshake_timeout: Option<u64>,
+    version_timeout: Option<u64>,
+    security_timeout: Option<u64>,
+    init_timeout: Option<u64>,
+    silence_timeout: Option<u64>,
+    shutdown_grace: Option<u64>,
+    idle_timeout: Option<u64>,
+    sleep_placeholder: Option<FilePlaceholder>,
+    sleep_interval: Option<u64>,
+    client_resize: Option<bool>,
+    key_debounce: Option<u64>,
+    max_cut_text: Option<usize>,
+    skip_unknown: Option<bool>,
+    prefs: Option<PathBuf>,
+    psk: Option<PathBuf>,
+    password: Option<String>,
+    view_password: Option<String>,
+    password_file: Option<PathBuf>,
+    security: Option<Vec<String>>,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    stream_zstd: Option<i32>,
+    auth_backoff: Option<u64>,
+    auth_max_backoff: Option<u64>,
+    auth_ban_after: Option<u32>,
+    auth_ban_time: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum FilePlaceholder {
+    Black,
+    Dim,
+}
+
+impl File {
+    /*
+     * Most settings have been checked by their types as the file was parsed;
+     * those that could not be are checked here.  Zero means the same as it
+     * does in the environment.
+     */
+    fn apply(self, c: &mut Config) -> Result<()> {
+        let secs = Duration::from_secs;
+        let path = |p: PathBuf| Some(p).filter(|p| !p.as_os_str().is_empty());
+
+        if let Some(v) = self.listen {
+            c.listen = v;
+        }
+        if let Some(v) = self.listen_partial {
+            c.listen_partial = v;
+        }
+        if let Some(v) = self.connect {
+            c.connect = v;
+        }
+        if let Some(v) = self.novnc {
+            c.novnc = path(v);
+        }
+        if let Some(v) = self.origins {
+            c.origins = v;
+        }
+        if let Some(v) = self.upstream {
+            c.upstream = Some(v).filter(|v| !v.is_empty());
+        }
+        if let Some(v) = self.upstream_password {
+            c.upstream_password = Some(v).filter(|v| !v.is_empty());
+        }
+        if let Some(v) = self.rfb_version {
+            match rfb_version(&v) {
+                Some(s) => c.rfb_version = s,
+                None => bail!("rfb_version: invalid version {:?}", v),
+            }
+        }
+        if let Some(v) = self.takeover {
+            c.takeover = path(v);
+        }
+        if let Some(v) = self.geometry {
+            let size = v.split_once('x')
+                .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+                .filter(|&(w, h)| w > 0 && h > 0);
+            match size {
+                Some((w, h)) => (c.width, c.height) = (w, h),
+                None => bail!("geometry: expected WIDTHxHEIGHT, not {:?}", v),
+            }
+        }
+        if let Some(v) = self.double_buffer {
+            c.double_buffer = v;
+        }
+        if let Some(v) = self.name {
+            c.name = v;
+        }
+        for (tier, v) in [
+            (Tier::Lan, self.tier_lan),
+            (Tier::Wan, self.tier_wan),
+            (Tier::Poor, self.tier_poor),
+        ] {
+            if let Some(v) = v {
+                PresetChange::parse(&v)
+                    .map_err(|e| anyhow!("tier_{}: {}", tier.name(), e))?
+                    .apply(c.presets.preset_mut(tier));
+            }
+        }
+        if let Some((lan, wan)) = self.tier_rtt {
+            c.presets.set_rtt(lan, wan)
+                .map_err(|e| anyhow!("tier_rtt: {}", e))?;
+        }
+        if let Some(v) = self.fps {
+            if v == 0 {
+                bail!("fps: must be at least 1");
+            }
+            c.fps = v;
+        }
+        if let Some(v) = self.admin {
+            c.admin = path(v);
+        }
+        if let Some(v) = self.console {
+            c.console = v;
+        }
+        if let Some(v) = self.encode_threads {
+            c.encode_threads = v;
+        }
+        if let Some(v) = self.encode_queue {
+            c.encode_queue = v;
+        }
+        if let Some(v) = self.encode_cache {
+            c.encode_cache = v;
+        }
+        if let Some(v) = self.mousekeys {
+            c.mousekeys = v;
+        }
+        if let Some(v) = self.debug_damage {
+            c.debug_damage = v;
+        }
+        if let Some(v) = self.debug_framebuffer {
+            c.debug_framebuffer = v;
+        }
+        if let Some(v) = self.session_limit {
+            c.session_limit = Some(secs(v)).filter(|d| !d.is_zero());
+        }
+        if let Some(v) = self.session_warning {
+            c.session_warning = secs(v);
+        }
+        if let Some(v) = self.handshake_timeout {
+            if v == 0 {
+                bail!("handshake_timeout: must be at least 1");
+            }
+            c.handshake_timeout = secs(v);
+        }
+        for (name, v, t) in [
+            ("version_timeout", self.version_timeout, &mut c.version_timeout),
+            ("security_timeout", self.security_timeout,
+                &mut c.security_timeout),
+            ("init_timeout", self.init_timeout, &mut c.init_timeout),
+        ] {
+            match v {
+                Some(0) => bail!("{}: must be at least 1", name),
+                Some(v) => *t = secs(v),
+                None => (),
+            }
+        }
+        if let Some(v) = self.silence_timeout {
+            c.silence_timeout = Some(secs(v)).filter(|d| !d.is_zero());
+        }
+        if let Some(v) = self.shutdown_grace {
+            c.shutdown_grace = secs(v);
+        }
+        if let Some(v) = self.idle_timeout {
+            c.idle_timeout = Some(secs(v)).filter(|d| !d.is_zero());
+        }
+        if let Some(v) = self.sleep_placeholder {
+            c.sleep_placeholder = match v {
+                FilePlaceholder::Black => Placeholder::Black,
+                FilePlaceholder::Dim => Placeholder::Dim,
+            };
+        }
+        if let Some(v) = self.sleep_interval {
+            if v == 0 {
+                bail!("sleep_interval: must be at least 1");
+            }
+            c.sleep_interval = secs(v);
+        }
+        if let Some(v) = self.client_resize {
+            c.client_resize = v;
+        }
+        if let Some(v) = self.key_debounce {
+            c.key_debounce = Duration::from_millis(v);
+        }
+        if let Some(v) = self.max_cut_text {
+            c.max_cut_text = v;
+        }
+        if let Some(v) = self.skip_unknown {
+            c.skip_unknown = v;
+        }
+        if let Some(v) = self.prefs {
+            c.prefs = path(v);
+        }
+        if let Some(v) = self.psk {
+            c.psk = path(v);
+        }
+        if let Some(v) = self.password {
+            c.password = Some(v).filter(|v| !v.is_empty());
+        }
+        if let Some(v) = self.view_password {
+            c.view_password = Some(v).filter(|v| !v.is_empty());
+        }
+        if let Some(v) = self.password_file {
+            c.password_file = path(v);
+        }
+        if let Some(v) = self.security {
+            c.security = Some(v);
+        }
+        if let Some(v) = self.cert {
+            c.cert = path(v);
+        }
+        if let Some(v) = self.key {
+            c.key = path(v);
+        }
+        if let Some(v) = self.stream_zstd {
+            c.stream_zstd = Some(v).filter(|l| *l != 0);
+        }
+        if let Some(v) = self.auth_backoff {
+            c.guard.backoff = secs(v);
+        }
+        if let Some(v) = self.auth_max_backoff {
+            c.guard.max_backoff = secs(v);
+        }
+        if let Some(v) = self.auth_ban_after {
+            c.guard.ban_after = Some(v).filter(|n| *n > 0);
+        }
+        if let Some(v) = self.auth_ban_time {
+            c.guard.ban_time = secs(v);
+        }
+        Ok(())
+    }
+}
+
+fn redact(secret: &Option<String>) -> &'static str {
+    if secret.is_some() { "<redacted>" } else { "none" }
+}
+
+fn env_usize(name: &str) -> Option<usize> {
+    std::env::var(name).ok().and_then(|v| v.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config> {
+        let file: File = toml::from_str(text)?;
+        let mut c = Config::default();
+        file.apply(&mut c)?;
+        Ok(c)
+    }
+
+    #[test]
+    fn file() {
+        let c = parse(r#"
+            listen = ["127.0.0.1:5900", "[::1]:5900"]
+            geometry = "1024x768"
+            security = ["vencrypt"]
+            admin = ""
+            session_limit = 3600
+            sleep_placeholder = "dim"
+        "#).unwrap();
+        assert_eq!(c.listen, ["127.0.0.1:5900", "[::1]:5900"]);
+        assert_eq!((c.width, c.height), (1024, 768));
+        assert_eq!(c.security.unwrap(), ["vencrypt"]);
+        assert_eq!(c.admin, None);
+        assert_eq!(c.session_limit, Some(Duration::from_secs(3600)));
+        assert_eq!(c.sleep_placeholder, Placeholder::Dim);
+
+        let c = parse(r#"
+            tier_poor = "quality=2, scale=2"
+            tier_rtt = [10, 200]
+        "#).unwrap();
+        let poor = c.presets.preset(Tier::Poor);
+        assert_eq!((poor.quality, poor.fps, poor.scale), (2, 5, 2));
+        assert_eq!(c.presets.preset(Tier::Wan).scale, 1);
+        assert_eq!(c.presets.wan_rtt, Duration::from_millis(200));
+
+        /*
+         * Mistakes are reported with the key they were made in:
+         */
+        for (text, key) in [
+            ("fsp = 10", "fsp"),
+            ("fps = \"ten\"", "fps"),
+            ("geometry = \"1024\"", "geometry"),
+            ("sleep_placeholder = \"grey\"", "sleep_placeholder"),
+            ("tier_lan = \"scale=0\"", "tier_lan"),
+            ("tier_wan = \"speed=1\"", "tier_wan"),
+            ("tier_rtt = [200, 10]", "tier_rtt"),
+        ] {
+            let e = parse(text).err().unwrap().to_string();
+            assert!(e.contains(key), "{:?} does not mention {}", e, key);
+        }
+    }
+}
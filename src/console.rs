@@ -0,0 +1,67 @@
+use std::sync::Arc;
+
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::admin;
+use crate::server::Server;
+
+const HELP: &str = "\
+commands:
+    refresh                 resend everything to every client
+    stats                   encoder and handshake statistics
+    sessions                connected clients
+    events                  recent connections, failures and resizes
+    sched [ID]              update scheduling
+    leases                  framebuffer areas leased to producers
+    pace ID FPS|-           override the frame rate for a client
+    trace ID SECS|-         log messages to and from a client for a while
+    notify ID|all TEXT      show a message to one or all clients
+    connect [ID:NNNN@]HOST[:PORT]
+                            connect to a listening viewer, or a repeater
+    name TEXT               rename the desktop
+    resize WxH              resize the framebuffer
+    share X Y WxH|-         share only part of the screen, or all of it
+    display on|standby|suspend|off
+                            declare the display awake or asleep
+    prefs [ID [KEY VALUE]]  per-user preferences
+    quit                    shut down the server
+";
+
+/*
+ * Accept the same commands as the admin socket on standard input, so that the
+ * server can be driven from the terminal in which it runs.
+ */
+pub fn spawn(server: &Arc<Server>) {
+    let server = Arc::clone(server);
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        loop {
+            let line = match lines.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => {
+                    log!("console closed");
+                    return;
+                }
+                Err(e) => {
+                    log!("console read failure: {:?}", e);
+                    return;
+                }
+            };
+
+            match line.trim() {
+                "" => (),
+                "help" | "?" => print!("{}", HELP),
+                "quit" | "exit" => {
+                    log!("shutting down at console request");
+                    server.shut_down();
+                    return;
+                }
+                "diag" => println!("use \"jvnc diag FILE\" to fetch a bundle"),
+                cmd => match admin::command(&server, cmd).await {
+                    Ok(out) => print!("{}", String::from_utf8_lossy(&out)),
+                    Err(e) => println!("error: {}", e),
+                },
+            }
+        }
+    });
+}
@@ -0,0 +1,134 @@
+use anyhow::{bail, Result};
+
+/*
+ * The shape of the pointer, which clients that support it draw locally so
+ * that it moves without waiting for a round trip to the server.
+ */
+#[derive(Debug, PartialEq, Eq)]
+pub struct Cursor {
+    pub width: usize,
+    pub height: usize,
+    /*
+     * The position within the image that corresponds to the pointer location:
+     */
+    pub hotx: usize,
+    pub hoty: usize,
+    /*
+     * Pixels in row-major order as 0xAARRGGBB words, where an alpha of zero is
+     * transparent and 0xff is opaque.  The colour is not premultiplied.
+     */
+    pub pixels: Vec<u32>,
+}
+
+/*
+ * The classic arrow pointer.  An "X" is a black pixel, an "o" is a white
+ * pixel, and anything else is transparent.
+ */
+const ARROW: &[&str] = &[
+    "X           ",
+    "XX          ",
+    "XoX         ",
+    "XooX        ",
+    "XoooX       ",
+    "XooooX      ",
+    "XoooooX     ",
+    "XooooooX    ",
+    "XoooooooX   ",
+    "XooooooooX  ",
+    "XoooooooooX ",
+    "XooooooXXXXX",
+    "XoooXooX    ",
+    "XooXXooX    ",
+    "XoX  XooX   ",
+    "XX   XooX   ",
+    "X     XooX  ",
+    "      XooX  ",
+    "       XX   ",
+];
+
+impl Cursor {
+    /*
+     * Create a cursor from 0x00RRGGBB pixels and a transparency mask, with
+     * one bit per pixel, most significant bit first, and each row padded to a
+     * whole number of bytes.  Pixels with a clear bit are transparent.
+     */
+    pub fn new(width: usize, height: usize, hotx: usize, hoty: usize,
+        pixels: Vec<u32>, mask: Vec<u8>) -> Result<Cursor>
+    {
+        if mask.len() != Cursor::mask_len(width, height) {
+            bail!("cursor is {}x{} but has {} mask bytes", width, height,
+                mask.len());
+        }
+
+        let stride = width.div_ceil(8);
+        let pixels = pixels.iter().enumerate()
+            .map(|(i, p)| {
+                let (x, y) = (i % width.max(1), i / width.max(1));
+                match mask.get(y * stride + x / 8) {
+                    Some(m) if m & 0x80 >> (x % 8) != 0 => {
+                        0xff000000 | p & 0xffffff
+                    }
+                    _ => 0,
+                }
+            })
+            .collect();
+
+        Cursor::with_alpha(width, height, hotx, hoty, pixels)
+    }
+
+    /*
+     * Create a cursor from 0xAARRGGBB pixels.
+     */
+    pub fn with_alpha(width: usize, height: usize, hotx: usize, hoty: usize,
+        pixels: Vec<u32>) -> Result<Cursor>
+    {
+        if pixels.len() != width * height {
+            bail!("cursor is {}x{} but has {} pixels", width, height,
+                pixels.len());
+        }
+        if width > 0 && hotx >= width || height > 0 && hoty >= height {
+            bail!("cursor hotspot {},{} is outside the {}x{} image", hotx,
+                hoty, width, height);
+        }
+
+        Ok(Cursor { width, height, hotx, hoty, pixels })
+    }
+
+    pub fn arrow() -> Cursor {
+        let width = ARROW[0].len();
+        let height = ARROW.len();
+        let mut pixels = Vec::with_capacity(width * height);
+        let mut mask = vec![0u8; Cursor::mask_len(width, height)];
+
+        for (y, row) in ARROW.iter().enumerate() {
+            for (x, c) in row.bytes().enumerate() {
+                pixels.push(if c == b'o' { 0xffffff } else { 0 });
+                if c == b'X' || c == b'o' {
+                    mask[y * width.div_ceil(8) + x / 8] |= 0x80 >> (x % 8);
+                }
+            }
+        }
+
+        Cursor::new(width, height, 0, 0, pixels, mask).unwrap()
+    }
+
+    pub fn mask_len(width: usize, height: usize) -> usize {
+        width.div_ceil(8) * height
+    }
+
+    /*
+     * The transparency mask for clients that cannot blend, in which any pixel
+     * that is at least half opaque is drawn.
+     */
+    pub fn mask(&self) -> Vec<u8> {
+        let stride = self.width.div_ceil(8);
+        let mut mask = vec![0u8; Cursor::mask_len(self.width, self.height)];
+        for (i, p) in self.pixels.iter().enumerate() {
+            let (x, y) = (i % self.width, i / self.width);
+            if p >> 24 >= 0x80 {
+                mask[y * stride + x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+        mask
+    }
+}
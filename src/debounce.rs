@@ -0,0 +1,76 @@
+use std::time::{Duration, Instant};
+
+/*
+ * Some viewers, on a high latency link, send a key press and release twice
+ * in quick succession for what the user typed once.  When enabled for a
+ * connection, a press of the key that was released within the last "window"
+ * is dropped, along with its release, so that the application sees a single
+ * keystroke.
+ *
+ * Modifiers, lock keys and dead keys are left alone: input methods for many
+ * keyboard layouts rely on seeing each of their presses, and a repeat of one
+ * of these is rarely an accident.
+ */
+pub struct Debounce {
+    window: Duration,
+    /*
+     * The last key released, and when:
+     */
+    released: Option<(u32, Instant)>,
+    /*
+     * A key whose press we dropped, and whose release we will drop too:
+     */
+    dropped: Option<u32>,
+    /*
+     * The number of keystrokes merged so far:
+     */
+    merged: u64,
+}
+
+impl Debounce {
+    pub fn new(window: Duration) -> Debounce {
+        Debounce { window, released: None, dropped: None, merged: 0 }
+    }
+
+    pub fn merged(&self) -> u64 {
+        self.merged
+    }
+
+    /*
+     * Decide whether to deliver a key event.
+     */
+    pub fn key(&mut self, down: bool, key: u32) -> bool {
+        if self.window.is_zero() || exempt(key) {
+            return true;
+        }
+
+        let now = Instant::now();
+        if !down {
+            if self.dropped == Some(key) {
+                self.dropped = None;
+                return false;
+            }
+            self.released = Some((key, now));
+            return true;
+        }
+
+        match self.released.take() {
+            Some((k, t)) if k == key && now - t < self.window => {
+                self.dropped = Some(key);
+                self.merged += 1;
+                false
+            }
+            _ => true,
+        }
+    }
+}
+
+fn exempt(key: u32) -> bool {
+    matches!(key,
+        0xfe01..=0xfe8f /* ISO group shifts, lock keys and dead keys */
+        | 0xff7e /* Mode_switch */
+        | 0xff7f /* Num_Lock */
+        | 0xffe1..=0xffee /* Shift, Control, Caps_Lock, Meta, Alt, ... */
+        | 0xff14 /* Scroll_Lock */
+    )
+}
@@ -0,0 +1,78 @@
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+
+use crate::log;
+use crate::server::Server;
+use crate::source::Rect;
+
+/*
+ * Gather everything that might help with a bug report into a tar archive:
+ * recent log output and events, session, encoder and handshake statistics,
+ * the configuration, and a snapshot of the framebuffer as a portable pixmap.
+ */
+pub fn bundle(server: &Server) -> Result<Vec<u8>> {
+    let mut tar = tar::Builder::new(Vec::new());
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    let mut add = |name: &str, data: &[u8]| -> Result<()> {
+        let mut h = tar::Header::new_gnu();
+        h.set_size(data.len() as u64);
+        h.set_mode(0o644);
+        h.set_mtime(now);
+        h.set_cksum();
+        tar.append_data(&mut h, format!("jvnc-diag/{}", name), data)?;
+        Ok(())
+    };
+
+    let mut version = String::new();
+    writeln!(version, "{} {}", env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"))?;
+    add("version.txt", version.as_bytes())?;
+
+    add("config.txt", server.config.describe().as_bytes())?;
+
+    let mut logs = log::recent().join("\n");
+    logs.push('\n');
+    add("log.txt", logs.as_bytes())?;
+
+    let mut sessions = String::new();
+    for (id, s) in server.sessions() {
+        let age = s.started.elapsed().unwrap_or_default();
+        writeln!(sessions, "[{}] {} connected {}s ago: {:?}", id, s.addr,
+            age.as_secs(), s.stats.lock().unwrap())?;
+    }
+    add("sessions.txt", sessions.as_bytes())?;
+
+    let mut encoders = String::new();
+    for (enc, e) in server.encoder_stats() {
+        writeln!(encoders, "encoding {}: {:?}", enc, e)?;
+    }
+    add("encoders.txt", encoders.as_bytes())?;
+
+    let mut handshakes = String::new();
+    for (outcome, n) in server.handshake_stats() {
+        writeln!(handshakes, "{}: {}", outcome, n)?;
+    }
+    add("handshakes.txt", handshakes.as_bytes())?;
+
+    let mut events = String::new();
+    for (t, e) in server.events.recent() {
+        let t = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+        writeln!(events, "{}.{:03} {:?}", t.as_secs(), t.subsec_millis(), e)?;
+    }
+    add("events.txt", events.as_bytes())?;
+
+    let source = server.source();
+    let (width, height) = source.dimensions();
+    let mut pixels = Vec::new();
+    source.read_rect(&Rect::new(0, 0, width, height), &mut pixels);
+    let mut ppm = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    for p in pixels {
+        ppm.extend_from_slice(&[(p >> 16) as u8, (p >> 8) as u8, p as u8]);
+    }
+    add("framebuffer.ppm", &ppm)?;
+
+    Ok(tar.into_inner()?)
+}
@@ -0,0 +1,147 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use super::{Params, Rect, Update};
+
+/*
+ * Encoded rectangles, shared between connections, so that when many clients
+ * are watching the same screen, each change is encoded once rather than once
+ * per client.
+ *
+ * Only encoders whose output depends on nothing but the pixels and the
+ * parameters can use the cache.  Those that keep a zlib stream for the life
+ * of the connection produce output that only one client can decode.
+ *
+ * Rather than trying to track which version of the framebuffer a rectangle
+ * was captured from, entries are found by the pixels themselves, after the
+ * cursor and any other overlays have been drawn in.  Two clients share an
+ * entry exactly when they would be sent the same thing, whatever their scale
+ * or the moment at which they captured the screen.
+ */
+pub struct Cache {
+    /*
+     * The most memory to use for pixels and encoded data, in bytes:
+     */
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Key {
+    rect: Rect,
+    encoding: i32,
+    params: Params,
+}
+
+struct Entry {
+    key: Key,
+    pixels: Vec<u32>,
+    update: Arc<Update>,
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        self.pixels.len() * 4 + self.update.len()
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<u64, Entry>,
+    /*
+     * Entries in the order they were added, which is the order in which they
+     * are evicted:
+     */
+    order: VecDeque<u64>,
+    size: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Cache {
+        Cache { capacity, inner: Mutex::new(Inner::default()) }
+    }
+
+    fn hash(key: &Key, pixels: &[u32]) -> u64 {
+        let mut h = DefaultHasher::new();
+        key.hash(&mut h);
+        pixels.hash(&mut h);
+        h.finish()
+    }
+
+    /*
+     * Return the rectangles that encode "pixels" for "r", producing them
+     * with "encode" if nobody has yet.
+     */
+    pub fn get<F>(&self, r: &Rect, encoding: i32, params: &Params,
+        pixels: &[u32], encode: F) -> Arc<Update>
+    where
+        F: FnOnce() -> Update,
+    {
+        let key = Key { rect: *r, encoding, params: *params };
+        let hash = Cache::hash(&key, pixels);
+
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let hit = inner.entries.get(&hash)
+                .filter(|e| e.key == key && e.pixels == pixels)
+                .map(|e| Arc::clone(&e.update));
+            if let Some(u) = hit {
+                inner.hits += 1;
+                return u;
+            }
+            inner.misses += 1;
+        }
+
+        /*
+         * Encode without holding the lock, so that other connections may
+         * carry on in the meantime.
+         */
+        let update = Arc::new(encode());
+        let entry = Entry {
+            key,
+            pixels: pixels.to_vec(),
+            update: Arc::clone(&update),
+        };
+        if entry.size() > self.capacity {
+            return update;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.size += entry.size();
+        if let Some(old) = inner.entries.insert(hash, entry) {
+            /*
+             * Either another connection encoded the same thing while we
+             * did, or two different rectangles share a hash.  Either way,
+             * the newer entry is as good as the old one.
+             */
+            inner.size -= old.size();
+        } else {
+            inner.order.push_back(hash);
+        }
+        while inner.size > self.capacity {
+            let Some(h) = inner.order.pop_front() else {
+                break;
+            };
+            if let Some(old) = inner.entries.remove(&h) {
+                inner.size -= old.size();
+            }
+        }
+
+        update
+    }
+
+    pub fn describe(&self) -> serde_json::Value {
+        let inner = self.inner.lock().unwrap();
+        serde_json::json!({
+            "entries": inner.entries.len(),
+            "bytes": inner.size,
+            "capacity": self.capacity,
+            "hits": inner.hits,
+            "misses": inner.misses,
+        })
+    }
+}
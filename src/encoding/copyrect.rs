@@ -0,0 +1,194 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use super::{wire_coord, Rect, Update, COPYRECT};
+
+/*
+ * Only bother with a CopyRect when at least this many rows have moved.
+ */
+const MIN_SCROLL_ROWS: usize = 16;
+
+/*
+ * Rows that appear more often than this in the previous frame (e.g., blank
+ * lines) say nothing useful about the distance something has moved.
+ */
+const MAX_ROW_REPEATS: usize = 8;
+
+/*
+ * A copy of the framebuffer contents as last sent to the client, in client
+ * coordinates.
+ */
+pub struct Shadow {
+    width: usize,
+    height: usize,
+    pixels: Vec<u32>,
+    /*
+     * We cannot ask the client to copy pixels it has never received, so we
+     * wait for one complete update before detecting anything.
+     */
+    valid: bool,
+}
+
+/*
+ * A band of rows within an update rectangle that the client can copy from
+ * elsewhere in its own framebuffer.
+ */
+pub struct Scroll {
+    pub dest: Rect,
+    pub src_y: usize,
+}
+
+fn row_hash(row: &[u32]) -> u64 {
+    let mut h = DefaultHasher::new();
+    row.hash(&mut h);
+    h.finish()
+}
+
+impl Shadow {
+    pub fn new(width: usize, height: usize) -> Shadow {
+        Shadow {
+            width,
+            height,
+            pixels: vec![0; width * height],
+            valid: false,
+        }
+    }
+
+    fn row(&self, r: &Rect, y: usize) -> &[u32] {
+        let start = y * self.width + r.x;
+        &self.pixels[start..(start + r.width)]
+    }
+
+    /*
+     * Record the pixels we have just sent for "r".
+     */
+    pub fn update(&mut self, r: &Rect, pixels: &[u32]) {
+        for (i, row) in pixels.chunks(r.width.max(1)).enumerate() {
+            let start = (r.y + i) * self.width + r.x;
+            self.pixels[start..(start + r.width)].copy_from_slice(row);
+        }
+
+        if r.area() == self.width * self.height {
+            self.valid = true;
+        }
+    }
+
+    /*
+     * The client may no longer have what we last sent it, so the next update
+     * must not depend on it.
+     */
+    pub fn invalidate(&mut self) {
+        self.valid = false;
+    }
+
+    /*
+     * Whether the client already has "pixels" for "r".  Until it has been
+     * sent a complete update, we cannot be sure.
+     */
+    pub fn matches(&self, r: &Rect, pixels: &[u32]) -> bool {
+        self.valid && pixels.chunks(r.width.max(1)).enumerate()
+            .all(|(i, row)| row == self.row(r, r.y + i))
+    }
+
+    /*
+     * Look for a band of rows in "r" whose new contents are the old contents
+     * of other rows in "r", shifted vertically.
+     */
+    pub fn detect_scroll(&self, r: &Rect, pixels: &[u32]) -> Option<Scroll> {
+        if !self.valid || r.width == 0 || r.height < MIN_SCROLL_ROWS {
+            return None;
+        }
+        let cur = |y: usize| {
+            let start = (y - r.y) * r.width;
+            &pixels[start..(start + r.width)]
+        };
+
+        let mut old: HashMap<u64, Vec<usize>> = HashMap::new();
+        for y in r.y..(r.y + r.height) {
+            old.entry(row_hash(self.row(r, y))).or_default().push(y);
+        }
+
+        /*
+         * Each changed row that matches a row from the previous frame casts a
+         * vote for the distance between them.
+         */
+        let mut votes: HashMap<isize, usize> = HashMap::new();
+        for y in r.y..(r.y + r.height) {
+            if cur(y) == self.row(r, y) {
+                continue;
+            }
+            if let Some(ys) = old.get(&row_hash(cur(y))) {
+                if ys.len() > MAX_ROW_REPEATS {
+                    continue;
+                }
+                for ys in ys {
+                    *votes.entry(y as isize - *ys as isize).or_default() += 1;
+                }
+            }
+        }
+
+        let (dy, n) = votes.into_iter().max_by_key(|(_, n)| *n)?;
+        if n < MIN_SCROLL_ROWS {
+            return None;
+        }
+
+        /*
+         * Find the longest run of rows that match exactly at this distance:
+         */
+        let mut best: Option<(usize, usize)> = None;
+        let mut run: Option<(usize, usize)> = None;
+        for y in r.y..(r.y + r.height) {
+            let ys = y as isize - dy;
+            let matched = ys >= r.y as isize
+                && ys < (r.y + r.height) as isize
+                && cur(y) == self.row(r, ys as usize);
+
+            run = match (matched, run) {
+                (true, Some((start, len))) => Some((start, len + 1)),
+                (true, None) => Some((y, 1)),
+                (false, _) => None,
+            };
+            if let Some((_, len)) = run {
+                if best.map(|(_, l)| len > l).unwrap_or(true) {
+                    best = run;
+                }
+            }
+        }
+
+        let (start, len) = best?;
+        if len < MIN_SCROLL_ROWS {
+            return None;
+        }
+
+        Some(Scroll {
+            dest: Rect::new(r.x, start, r.width, len),
+            src_y: (start as isize - dy) as usize,
+        })
+    }
+}
+
+impl Scroll {
+    pub fn encode(&self, u: &mut Update) {
+        u.rect(&self.dest, COPYRECT);
+        let d = u.data();
+        d.extend_from_slice(&wire_coord(self.dest.x).to_be_bytes());
+        d.extend_from_slice(&wire_coord(self.src_y).to_be_bytes());
+    }
+
+    /*
+     * The parts of "r" not covered by the copied band, which must still be
+     * sent in the usual way.
+     */
+    pub fn remainder(&self, r: &Rect) -> Vec<Rect> {
+        let mut v = Vec::new();
+        if self.dest.y > r.y {
+            v.push(Rect::new(r.x, r.y, r.width, self.dest.y - r.y));
+        }
+        let end = self.dest.y + self.dest.height;
+        if end < r.y + r.height {
+            v.push(Rect::new(r.x, end, r.width, r.y + r.height - end));
+        }
+        v
+    }
+}
@@ -0,0 +1,227 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use super::{capture, scaled, Rect};
+use crate::framebuffer::TILE;
+use crate::source::Source;
+
+/*
+ * Pixels captured from a source, shared between connections, so that when
+ * many clients are watching the same screen, each change is read from the
+ * source once rather than once per client.  Together with the encode cache,
+ * this means the work done for each frame no longer grows with the number of
+ * clients watching it.
+ *
+ * Only sources that number their changes can be shared this way: a frame is
+ * the state of the source as of one generation, at one scale.  Each frame is
+ * captured a tile at a time, as connections first ask for each tile, so that
+ * a small change seen by a single client costs no more than it would without
+ * sharing.
+ */
+pub struct Frames {
+    frames: Mutex<Vec<Arc<Frame>>>,
+    counts: Arc<Counts>,
+}
+
+/*
+ * Tiles captured from a source, and those read from a frame by a connection
+ * other than the one that captured them:
+ */
+#[derive(Default)]
+struct Counts {
+    captured: AtomicU64,
+    shared: AtomicU64,
+}
+
+/*
+ * Connections that ask for the latest generation at slightly different times
+ * may straddle a change, so we keep a few recent frames rather than one.
+ */
+const KEEP: usize = 4;
+
+pub struct Frame {
+    source: Arc<dyn Source>,
+    generation: u64,
+    scale: usize,
+    /*
+     * The dimensions in client coordinates, and the tiles that have been
+     * captured so far, with "across" tiles in each row:
+     */
+    width: usize,
+    height: usize,
+    across: usize,
+    tiles: Vec<OnceLock<Vec<u32>>>,
+    counts: Arc<Counts>,
+}
+
+impl Frames {
+    pub fn new() -> Frames {
+        Frames {
+            frames: Mutex::new(Vec::new()),
+            counts: Arc::new(Counts::default()),
+        }
+    }
+
+    /*
+     * The frame for "source" as of "generation", seen at "scale".
+     */
+    pub fn get(&self, source: &Arc<dyn Source>, scale: usize,
+        generation: u64) -> Arc<Frame>
+    {
+        let mut frames = self.frames.lock().unwrap();
+        let found = frames.iter().find(|f| {
+            Arc::ptr_eq(&f.source, source)
+                && f.scale == scale
+                && f.generation == generation
+        });
+        if let Some(f) = found {
+            return Arc::clone(f);
+        }
+
+        /*
+         * Frames of other sources are of no further use, and the oldest of
+         * this one's go first.
+         */
+        frames.retain(|f| Arc::ptr_eq(&f.source, source));
+        if frames.len() >= KEEP {
+            let oldest = frames.iter()
+                .enumerate()
+                .min_by_key(|(_, f)| f.generation)
+                .map(|(i, _)| i)
+                .unwrap();
+            frames.swap_remove(oldest);
+        }
+
+        let (width, height) = source.dimensions();
+        let (width, height) = (scaled(width, scale), scaled(height, scale));
+        let across = width.div_ceil(TILE);
+        let mut tiles = Vec::new();
+        tiles.resize_with(across * height.div_ceil(TILE), OnceLock::new);
+        let f = Arc::new(Frame {
+            source: Arc::clone(source),
+            generation,
+            scale,
+            width,
+            height,
+            across,
+            tiles,
+            counts: Arc::clone(&self.counts),
+        });
+        frames.push(Arc::clone(&f));
+        f
+    }
+
+    pub fn describe(&self) -> serde_json::Value {
+        serde_json::json!({
+            "frames": self.frames.lock().unwrap().len(),
+            "tiles_captured": self.counts.captured.load(Ordering::Relaxed),
+            "tiles_shared": self.counts.shared.load(Ordering::Relaxed),
+        })
+    }
+}
+
+impl Frame {
+    /*
+     * The pixels of tile "t", capturing them if nobody has yet.
+     */
+    fn tile(&self, t: usize) -> &[u32] {
+        let mut fresh = false;
+        let pixels = self.tiles[t].get_or_init(|| {
+            fresh = true;
+            let x = (t % self.across) * TILE;
+            let y = (t / self.across) * TILE;
+            let r = Rect::new(x, y, TILE.min(self.width - x),
+                TILE.min(self.height - y));
+            capture(&*self.source, &r, self.scale)
+        });
+
+        let count = if fresh {
+            &self.counts.captured
+        } else {
+            &self.counts.shared
+        };
+        count.fetch_add(1, Ordering::Relaxed);
+        pixels
+    }
+}
+
+/*
+ * A frame is itself a source, in client coordinates, from which connections
+ * read at a scale of one.
+ */
+impl Source for Frame {
+    fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    fn read_rect(&self, r: &Rect, out: &mut Vec<u32>) {
+        assert!(r.x + r.width <= self.width
+            && r.y + r.height <= self.height, "rect outside frame");
+        if r.width == 0 {
+            return;
+        }
+        out.reserve(r.area());
+
+        let (tx0, tx1) = (r.x / TILE, (r.x + r.width - 1) / TILE);
+        let mut y = r.y;
+        while y < r.y + r.height {
+            let ty = y / TILE;
+            let rows = (TILE - y % TILE).min(r.y + r.height - y);
+            let tiles: Vec<(usize, &[u32])> = (tx0..=tx1)
+                .map(|tx| (tx * TILE, self.tile(ty * self.across + tx)))
+                .collect();
+            for y in y..(y + rows) {
+                for (x, pixels) in &tiles {
+                    let w = TILE.min(self.width - x);
+                    let row = &pixels[(y % TILE) * w..][..w];
+                    let from = r.x.max(*x) - x;
+                    let to = (r.x + r.width).min(x + w) - x;
+                    out.extend_from_slice(&row[from..to]);
+                }
+            }
+            y += rows;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framebuffer::Framebuffer;
+
+    #[test]
+    fn shared() {
+        let fb = Arc::new(Framebuffer::new(150, 70).unwrap());
+        for y in 0..70 {
+            for x in 0..150 {
+                fb.put(x, y, x as u8, y as u8, 0);
+            }
+        }
+        let source: Arc<dyn Source> = fb.clone();
+        let frames = Frames::new();
+        let (generation, _) = fb.damage_since(0);
+
+        /*
+         * A rectangle straddling tiles reads the same as from the source,
+         * at either scale.
+         */
+        for scale in [1, 2] {
+            let frame = frames.get(&source, scale, generation);
+            let r = Rect::new(60, 30, 10, 5);
+            let mut out = Vec::new();
+            frame.read_rect(&r, &mut out);
+            assert_eq!(out, capture(&*source, &r, scale));
+        }
+
+        /*
+         * A second connection at the same generation shares the tiles the
+         * first captured.
+         */
+        let frame = frames.get(&source, 1, generation);
+        frame.read_rect(&Rect::new(0, 0, 150, 70), &mut Vec::new());
+        let d = frames.describe();
+        assert_eq!(d["tiles_captured"], 2 + 2 + 4);
+        assert_eq!(d["tiles_shared"], 2);
+        assert_eq!(d["frames"], 2);
+    }
+}
@@ -0,0 +1,642 @@
+use std::sync::Arc;
+
+use crate::cursor::Cursor;
+use crate::framebuffer;
+use crate::outbox::Buffers;
+use crate::source::Source;
+use flate2::{Compress, Compression, FlushCompress};
+
+pub mod cache;
+mod copyrect;
+pub mod frame;
+pub mod overlay;
+pub mod palette;
+pub mod pseudo;
+mod png;
+mod raw;
+mod tight;
+mod tile;
+mod trle;
+mod tune;
+mod zlib;
+mod zrle;
+
+use copyrect::Shadow;
+
+pub const RAW: i32 = 0;
+pub const COPYRECT: i32 = 1;
+pub const ZLIB: i32 = 6;
+pub const TIGHT: i32 = 7;
+pub const TRLE: i32 = 15;
+pub const ZRLE: i32 = 16;
+pub const TIGHT_PNG: i32 = -260;
+
+/*
+ * Names for the encodings we support, for reporting purposes:
+ */
+pub const SUPPORTED: &[(&str, i32)] = &[
+    ("raw", RAW),
+    ("copyrect", COPYRECT),
+    ("zlib", ZLIB),
+    ("tight", TIGHT),
+    ("trle", TRLE),
+    ("zrle", ZRLE),
+    ("tightpng", TIGHT_PNG),
+];
+
+/*
+ * A rectangle in client coordinates.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub fn new(x: usize, y: usize, width: usize, height: usize) -> Rect {
+        Rect { x, y, width, height }
+    }
+
+    pub fn area(&self) -> usize {
+        self.width * self.height
+    }
+
+    /*
+     * The part of this rectangle that also lies within "other", if any.
+     */
+    pub fn intersect(&self, other: &Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = (self.x + self.width).min(other.x + other.width);
+        let bottom = (self.y + self.height).min(other.y + other.height);
+        if right <= x || bottom <= y {
+            return None;
+        }
+        Some(Rect::new(x, y, right - x, bottom - y))
+    }
+
+    /*
+     * The smallest rectangle that covers both this one and "other".
+     */
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        Rect::new(x, y, right - x, bottom - y)
+    }
+}
+
+/*
+ * Convert a position or size within the framebuffer for the wire.  The
+ * framebuffer itself is never larger than the protocol allows, so anything
+ * that does not fit is a bug.
+ */
+pub fn wire_coord(v: usize) -> u16 {
+    match framebuffer::coord(v) {
+        Ok(v) => v,
+        Err(e) => panic!("rectangle outside the framebuffer: {}", e),
+    }
+}
+
+/*
+ * Encoder tuning that may change over the life of a connection, either because
+ * the client has asked for it or because the link quality has changed.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Params {
+    /*
+     * JPEG quality level, 0 through 9, if the client has told us it is willing
+     * to accept lossy compression.
+     */
+    pub quality: Option<u8>,
+    /*
+     * The zlib compression level, 0 through 9, if the client has asked for a
+     * particular trade between bandwidth and CPU time.
+     */
+    pub compression: Option<u8>,
+}
+
+/*
+ * Accumulates the rectangles of a single FramebufferUpdate message.
+ */
+pub struct Update {
+    nrects: u16,
+    buf: Vec<u8>,
+    rects: Vec<(Rect, i32)>,
+}
+
+/*
+ * The FramebufferUpdate message header, which comes before the rectangles in
+ * the buffer, with the count filled in once the update is finished:
+ */
+const HEADER: usize = 4;
+
+impl Update {
+    pub fn new() -> Update {
+        Update::with_buffer(Vec::new())
+    }
+
+    /*
+     * Build the update in "buf", which has room left over from a message that
+     * has already been sent, so that large updates are not each allocated
+     * and copied afresh.
+     */
+    pub fn with_buffer(mut buf: Vec<u8>) -> Update {
+        buf.clear();
+        buf.extend_from_slice(&[0; HEADER]);
+        Update {
+            nrects: 0,
+            buf,
+            rects: Vec::new(),
+        }
+    }
+
+    /*
+     * Begin a new rectangle.  The encoding-specific payload is then appended
+     * to the buffer returned by data().
+     */
+    pub fn rect(&mut self, r: &Rect, encoding: i32) {
+        self.nrects = self.nrects.checked_add(1).unwrap();
+        for v in [r.x, r.y, r.width, r.height] {
+            self.buf.extend_from_slice(&wire_coord(v).to_be_bytes());
+        }
+        self.buf.extend_from_slice(&encoding.to_be_bytes());
+        self.rects.push((*r, encoding));
+    }
+
+    /*
+     * The rectangles in the update so far, and their encodings:
+     */
+    pub fn rects(&self) -> &[(Rect, i32)] {
+        &self.rects
+    }
+
+    pub fn data(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+
+    /*
+     * The size of the rectangles so far, in bytes.
+     */
+    pub fn len(&self) -> usize {
+        self.buf.len() - HEADER
+    }
+
+    /*
+     * Add the rectangles of another update to this one.
+     */
+    pub fn append(&mut self, other: &Update) {
+        self.nrects = self.nrects.checked_add(other.nrects).unwrap();
+        self.buf.extend_from_slice(&other.buf[HEADER..]);
+        self.rects.extend_from_slice(&other.rects);
+    }
+
+    /*
+     * Produce the complete message, ready to write to the client.
+     */
+    pub fn finish(self) -> Vec<u8> {
+        let mut out = self.buf;
+        out[0] = 0; /* type: FramebufferUpdate */
+        out[1] = 0; /* padding */
+        out[2..HEADER].copy_from_slice(&self.nrects.to_be_bytes());
+        out
+    }
+}
+
+/*
+ * The per-connection state needed to produce framebuffer updates.
+ */
+pub struct Pipeline {
+    pub encoder: Box<dyn Encoder>,
+    pub shadow: Shadow,
+    pub copyrect: bool,
+    /*
+     * If set, recently sent rectangles are highlighted on the client screen.
+     */
+    pub damage: Option<overlay::Damage>,
+    /*
+     * Layers drawn over the screen for every client, bottom first, in the
+     * coordinates of this client's view of it:
+     */
+    pub layers: Vec<Arc<overlay::Layer>>,
+    /*
+     * If the client cannot draw the cursor itself, we draw it into the
+     * pixels we send at the pointer location.
+     */
+    pub cursor: Option<(Arc<Cursor>, usize, usize)>,
+    /*
+     * If the session is time-limited, a warning as the end approaches:
+     */
+    pub countdown: Option<overlay::Countdown>,
+    /*
+     * Rectangles encoded for other connections, which we may reuse:
+     */
+    pub cache: Option<Arc<cache::Cache>>,
+    /*
+     * If the client has asked for colour-mapped pixels, the palette it has
+     * been sent.  Such clients are sent Raw rectangles of palette indices,
+     * whatever encodings they support.
+     */
+    pub palette: Option<palette::Palette>,
+    /*
+     * Buffers from updates that have been sent, in which to build the next:
+     */
+    pub buffers: Buffers,
+}
+
+impl Pipeline {
+    pub fn new(width: usize, height: usize) -> Pipeline {
+        Pipeline {
+            /*
+             * Until the client tells us otherwise, we must use Raw encoding:
+             */
+            encoder: select(&[]),
+            shadow: Shadow::new(width, height),
+            copyrect: false,
+            damage: None,
+            layers: Vec::new(),
+            cursor: None,
+            countdown: None,
+            cache: None,
+            palette: None,
+            buffers: Buffers::default(),
+        }
+    }
+
+    /*
+     * The client has accepted a new framebuffer geometry, and has discarded
+     * its copy of the screen.
+     */
+    pub fn resize(&mut self, width: usize, height: usize) {
+        self.shadow = Shadow::new(width, height);
+        if let Some(c) = &mut self.countdown {
+            c.width = width;
+        }
+    }
+
+    /*
+     * Produce a complete FramebufferUpdate message for "r", preceded by any
+     * pending pseudo-rectangles.  The pixels are sent in bands of tiles, and
+     * an incremental update leaves out the tiles that are the same as what
+     * the client was last sent.  If that is all of them, and nothing is
+     * pending, there is no update at all.
+     *
+     * An incremental update names the areas where something drawn over the
+     * screen, such as the cursor, has since moved.  The tiles those touch are
+     * sent even if the pixels beneath them are unchanged.
+     */
+    pub fn update(&mut self, source: &dyn Source, r: &Rect, scale: usize,
+        params: &Params, pending: &pseudo::Pending,
+        incremental: Option<&[Rect]>) -> Option<Vec<u8>>
+    {
+        let mut pixels = capture(source, r, scale);
+
+        let mut u = Update::with_buffer(self.buffers.take());
+        pending.write(&mut u);
+
+        /*
+         * If part of the screen has scrolled, the client can copy those pixels
+         * from where it already has them.  The copy must come first, before
+         * any other rectangle in this update overwrites the source area.
+         */
+        let scroll = if self.copyrect {
+            self.shadow.detect_scroll(r, &pixels)
+        } else {
+            None
+        };
+        let rest = match &scroll {
+            Some(scroll) => scroll.remainder(r),
+            None => vec![*r],
+        };
+        let shadow = &self.shadow;
+        let mut parts: Vec<Rect> = rest.iter()
+            .flat_map(|sub| split(sub, |t| {
+                let Some(moved) = incremental else {
+                    return true;
+                };
+                moved.iter().any(|m| m.intersect(t).is_some())
+                    || !shadow.matches(t, &sub_pixels(r, &pixels, t))
+            }))
+            .collect();
+        if parts.len() > MAX_PARTS {
+            parts = rest;
+        }
+        if parts.is_empty() && scroll.is_none() {
+            if incremental.is_some() && pending.is_empty() {
+                self.buffers.put(u.finish());
+                return None;
+            }
+            return Some(u.finish());
+        }
+        self.shadow.update(r, &pixels);
+
+        for l in &self.layers {
+            l.draw(r, &mut pixels);
+        }
+        if let Some(c) = &self.countdown {
+            c.draw(r, &mut pixels);
+        }
+        if let Some((c, x, y)) = &self.cursor {
+            overlay::cursor(c, *x, *y, r, &mut pixels);
+        }
+        if let Some(damage) = &self.damage {
+            damage.draw(r, &mut pixels);
+        }
+
+        if let Some(scroll) = scroll {
+            scroll.encode(&mut u);
+        }
+        for sub in parts {
+            let px = sub_pixels(r, &pixels, &sub);
+            self.encode(&sub, &px, params, &mut u);
+        }
+
+        if let Some(damage) = &mut self.damage {
+            damage.sent(u.rects());
+        }
+
+        Some(u.finish())
+    }
+
+    /*
+     * Encode "r", or reuse what another connection sent for the same pixels.
+     */
+    fn encode(&mut self, r: &Rect, pixels: &[u32], params: &Params,
+        u: &mut Update)
+    {
+        if let Some(p) = &self.palette {
+            u.rect(r, RAW);
+            u.data().extend(pixels.iter().map(|&px| p.index(px)));
+            return;
+        }
+
+        match &self.cache {
+            Some(cache) if self.encoder.cacheable() => {
+                let enc = &mut self.encoder;
+                let cached = cache.get(r, enc.encoding(), params, pixels, || {
+                    let mut u = Update::new();
+                    enc.encode(r, pixels, params, &mut u);
+                    u
+                });
+                u.append(&cached);
+            }
+            _ => self.encoder.encode(r, pixels, params, u),
+        }
+    }
+}
+
+pub trait Encoder: Send {
+    fn encoding(&self) -> i32;
+
+    /*
+     * Whether the output depends only on the pixels and parameters, so that
+     * it may be shared with other connections.
+     */
+    fn cacheable(&self) -> bool {
+        false
+    }
+
+    /*
+     * Append one or more rectangles to the update that together cover "r".
+     * The pixels are provided in row-major order as 0x00RRGGBB words.
+     */
+    fn encode(&mut self, r: &Rect, pixels: &[u32], params: &Params,
+        u: &mut Update);
+}
+
+/*
+ * Choose the first encoding in the client's list of preferences that we
+ * support.  Every client must support Raw, so that is the fallback.
+ */
+pub fn select(encs: &[i32]) -> Box<dyn Encoder> {
+    for enc in encs {
+        match *enc {
+            RAW => return Box::new(raw::Raw),
+            ZLIB => return Box::new(zlib::Zlib::new()),
+            TIGHT => return Box::new(tight::Tight::new()),
+            TRLE => return Box::new(trle::Trle),
+            ZRLE => return Box::new(zrle::Zrle::new()),
+            TIGHT_PNG => return Box::new(tight::Tight::png()),
+            _ => (),
+        }
+    }
+    Box::new(raw::Raw)
+}
+
+/*
+ * A dimension of the source, as a client viewing it at "scale" sees it.  A
+ * source smaller than the scale is still shown as a single pixel, rather than
+ * as nothing at all.
+ */
+pub fn scaled(n: usize, scale: usize) -> usize {
+    (n / scale).max(1)
+}
+
+/*
+ * Read the pixels for a rectangle in client coordinates from the source.  The
+ * client may be viewing a source reduced by an integer scaling factor.  If the
+ * source has shrunk and the client does not know, anything outside it is
+ * black.
+ */
+pub fn capture(source: &dyn Source, r: &Rect, scale: usize) -> Vec<u32> {
+    let (width, height) = source.dimensions();
+    let mut v = Vec::with_capacity(r.area());
+    if r.width == 0 {
+        return v;
+    }
+    if scale == 1 && r.x + r.width <= width && r.y + r.height <= height {
+        source.read_rect(r, &mut v);
+        return v;
+    }
+
+    /*
+     * The columns of the source that the rectangle covers, at full scale:
+     */
+    let x0 = r.x * scale;
+    let x1 = ((r.x + r.width - 1) * scale + 1).min(width);
+
+    let mut row = Vec::new();
+    for y in r.y..(r.y + r.height) {
+        let start = v.len();
+        let y = y * scale;
+        if y < height && x0 < x1 {
+            row.clear();
+            source.read_rect(&Rect::new(x0, y, x1 - x0, 1), &mut row);
+            v.extend(row.iter().step_by(scale));
+        }
+        v.resize(start + r.width, 0);
+    }
+    v
+}
+
+/*
+ * Updates are sent in parts no taller than a tile, so that a client can
+ * decode the first while the rest arrive, and so that each can be encoded in
+ * whichever way suits it best.  An update split into more parts than this is
+ * sent whole instead, as the overhead of each part outweighs the benefit.
+ */
+const MAX_PARTS: usize = 1024;
+
+/*
+ * Divide "r" along a grid of tiles, aligned to the framebuffer, and join
+ * those tiles for which "keep" is true into bands along each row of tiles.
+ */
+fn split<F>(r: &Rect, mut keep: F) -> Vec<Rect>
+where
+    F: FnMut(&Rect) -> bool,
+{
+    use framebuffer::TILE;
+
+    let mut v = Vec::new();
+    let mut y = r.y;
+    while y < r.y + r.height {
+        let height = (TILE - y % TILE).min(r.y + r.height - y);
+        let mut band: Option<Rect> = None;
+        let mut x = r.x;
+        while x < r.x + r.width {
+            let width = (TILE - x % TILE).min(r.x + r.width - x);
+            let t = Rect::new(x, y, width, height);
+            if keep(&t) {
+                band = Some(band.map_or(t, |b| b.union(&t)));
+            } else if let Some(b) = band.take() {
+                v.push(b);
+            }
+            x += width;
+        }
+        v.extend(band);
+        y += height;
+    }
+    v
+}
+
+/*
+ * Extract the pixels for "sub", which must lie within "r", from the pixels for
+ * "r".
+ */
+pub fn sub_pixels(r: &Rect, pixels: &[u32], sub: &Rect) -> Vec<u32> {
+    assert!(sub.x >= r.x && sub.x + sub.width <= r.x + r.width);
+    assert!(sub.y >= r.y && sub.y + sub.height <= r.y + r.height);
+
+    let mut v = Vec::with_capacity(sub.area());
+    for y in (sub.y - r.y)..(sub.y - r.y + sub.height) {
+        let start = y * r.width + sub.x - r.x;
+        v.extend_from_slice(&pixels[start..(start + sub.width)]);
+    }
+    v
+}
+
+/*
+ * A zlib stream that persists for the life of the connection, as the client
+ * keeps a matching inflate stream.  Each update is flushed to a byte boundary,
+ * so if the compression level changes we can carry on with a fresh compressor
+ * at the new level, whose raw deflate blocks simply continue the stream.
+ */
+pub struct Deflater {
+    z: Compress,
+    level: u32,
+}
+
+const DEFAULT_LEVEL: u32 = 6;
+
+impl Deflater {
+    pub fn new() -> Deflater {
+        Deflater {
+            z: Compress::new(Compression::new(DEFAULT_LEVEL), true),
+            level: DEFAULT_LEVEL,
+        }
+    }
+
+    /*
+     * Append the deflated form of "data" to "out", flushed so that the client
+     * can decode everything we have sent so far.
+     */
+    pub fn deflate(&mut self, data: &[u8], out: &mut Vec<u8>,
+        level: Option<u8>)
+    {
+        let level = level.map_or(DEFAULT_LEVEL, |l| u32::from(l.min(9)));
+        if level != self.level {
+            /*
+             * The zlib header goes out with the first data, so only a stream
+             * that has not produced anything yet still needs one.
+             */
+            let header = self.z.total_out() == 0;
+            self.z = Compress::new(Compression::new(level), header);
+            self.level = level;
+        }
+
+        deflate(&mut self.z, data, out);
+    }
+}
+
+fn deflate(z: &mut Compress, data: &[u8], out: &mut Vec<u8>) {
+    let start = z.total_in();
+    loop {
+        if out.capacity() - out.len() < 64 {
+            out.reserve(data.len() / 2 + 64);
+        }
+
+        let consumed = (z.total_in() - start) as usize;
+        z.compress_vec(&data[consumed..], out, FlushCompress::Sync)
+            .expect("deflate failure");
+
+        /*
+         * If the stream did not fill the output buffer and has consumed all
+         * of the input, the flush is complete.
+         */
+        if z.total_in() - start == data.len() as u64
+            && out.len() < out.capacity()
+        {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reused_buffer() {
+        let r = Rect::new(1, 2, 2, 1);
+        let build = |mut u: Update| {
+            u.rect(&r, RAW);
+            raw::serialise(u.data(), &[0x123456, 0xabcdef]);
+            u.finish()
+        };
+        let fresh = build(Update::new());
+        assert_eq!(&fresh[..4], &[0, 0, 0, 1]);
+
+        /*
+         * An update built where an earlier one was sent is the same, and
+         * needs no more room.
+         */
+        let old = vec![0xff; 1000];
+        let at = old.as_ptr();
+        let reused = build(Update::with_buffer(old));
+        assert_eq!(reused, fresh);
+        assert_eq!(reused.as_ptr(), at);
+    }
+
+    #[test]
+    fn split_bands() {
+        let r = Rect::new(10, 60, 200, 10);
+        assert_eq!(split(&r, |_| true), vec![
+            Rect::new(10, 60, 200, 4),
+            Rect::new(10, 64, 200, 6),
+        ]);
+
+        /*
+         * Tiles left out break the bands:
+         */
+        assert_eq!(split(&r, |t| t.x != 64 || t.y != 64), vec![
+            Rect::new(10, 60, 200, 4),
+            Rect::new(10, 64, 54, 6),
+            Rect::new(128, 64, 82, 6),
+        ]);
+        assert_eq!(split(&r, |_| false), vec![]);
+    }
+}
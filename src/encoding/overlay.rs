@@ -0,0 +1,215 @@
+use std::time::{Duration, Instant};
+
+use super::{Rect, COPYRECT, RAW, TIGHT, TIGHT_PNG, TRLE, ZLIB, ZRLE};
+use crate::cursor::Cursor;
+
+/*
+ * Layers drawn over the captured pixels of each update before they are
+ * encoded.  The shadow copy of the client screen, and thus scroll detection,
+ * sees only the pixels underneath.
+ */
+
+/*
+ * How long a rectangle stays visible after it was sent:
+ */
+const FADE: Duration = Duration::from_secs(1);
+
+/*
+ * Mix "c" into "p" with an opacity between 0 (none) and 256 (opaque).
+ */
+pub fn blend(p: u32, c: u32, alpha: u32) -> u32 {
+    let mut out = 0;
+    for shift in [0, 8, 16] {
+        let a = p >> shift & 0xff;
+        let b = c >> shift & 0xff;
+        out |= (a * (256 - alpha) + b * alpha) >> 8 << shift;
+    }
+    out
+}
+
+/*
+ * Composite a 0xAARRGGBB pixel over "p", whose own top byte is unused.  The
+ * colour is not premultiplied.  Each channel is rounded to nearest, so that
+ * an opaque pixel replaces "p" exactly and a transparent one leaves it alone.
+ */
+pub fn over(p: u32, argb: u32) -> u32 {
+    let alpha = argb >> 24;
+    match alpha {
+        0 => return p,
+        0xff => return argb & 0xffffff,
+        _ => (),
+    }
+    let mut out = 0;
+    for shift in [0, 8, 16] {
+        let a = p >> shift & 0xff;
+        let b = argb >> shift & 0xff;
+        out |= ((a * (255 - alpha) + b * alpha + 127) / 255) << shift;
+    }
+    out
+}
+
+/*
+ * Composite an image of 0xAARRGGBB pixels, whose top left corner is at "x",
+ * "y" and may be off the screen, onto "pixels", which cover "r".
+ */
+fn image(src: &[u32], width: usize, x: isize, y: isize, r: &Rect,
+    pixels: &mut [u32])
+{
+    for (sy, row) in src.chunks(width.max(1)).enumerate() {
+        let py = y + sy as isize;
+        if py < r.y as isize || py >= (r.y + r.height) as isize {
+            continue;
+        }
+        for (sx, argb) in row.iter().enumerate() {
+            let px = x + sx as isize;
+            if px < r.x as isize || px >= (r.x + r.width) as isize {
+                continue;
+            }
+            let p = &mut pixels[(py as usize - r.y) * r.width
+                + px as usize - r.x];
+            *p = over(*p, *argb);
+        }
+    }
+}
+
+/*
+ * Draw the cursor with its hotspot at "x", "y", for clients that cannot draw
+ * it themselves.
+ */
+pub fn cursor(c: &Cursor, x: usize, y: usize, r: &Rect, pixels: &mut [u32]) {
+    image(&c.pixels, c.width, x as isize - c.hotx as isize,
+        y as isize - c.hoty as isize, r, pixels);
+}
+
+/*
+ * An image drawn over the screen for every client, such as an on-screen
+ * display or an annotation.  Like those of a cursor, its pixels carry an
+ * alpha channel in the top byte, so that it may be partly transparent.
+ */
+#[derive(Debug)]
+pub struct Layer {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u32>,
+}
+
+impl Layer {
+    /*
+     * A rectangle of a single colour.
+     */
+    pub fn filled(x: usize, y: usize, width: usize, height: usize, argb: u32)
+        -> Layer
+    {
+        Layer { x, y, width, height, pixels: vec![argb; width * height] }
+    }
+
+    pub fn draw(&self, r: &Rect, pixels: &mut [u32]) {
+        let n = (self.width * self.height).min(self.pixels.len());
+        image(&self.pixels[..n], self.width, self.x as isize,
+            self.y as isize, r, pixels);
+    }
+}
+
+fn colour(encoding: i32) -> u32 {
+    match encoding {
+        RAW => 0xff0000,
+        COPYRECT => 0x00ff00,
+        ZLIB => 0xffff00,
+        TIGHT | TIGHT_PNG => 0x0080ff,
+        TRLE => 0xff00ff,
+        ZRLE => 0x00ffff,
+        _ => 0xffffff,
+    }
+}
+
+/*
+ * A bar across the top of the screen that counts down the time remaining
+ * before a session expires.  It appears once the expiry is within the warning
+ * period, and shrinks from the full width of the screen.
+ */
+pub struct Countdown {
+    pub deadline: Instant,
+    pub warning: Duration,
+    pub width: usize,
+}
+
+const COUNTDOWN_HEIGHT: usize = 8;
+const COUNTDOWN_COLOUR: u32 = 0xffa000;
+
+impl Countdown {
+    pub fn draw(&self, r: &Rect, pixels: &mut [u32]) {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left >= self.warning {
+            return;
+        }
+
+        let bar = (self.width as u128 * left.as_millis()
+            / self.warning.as_millis().max(1)) as usize;
+        for y in r.y..(r.y + r.height).min(COUNTDOWN_HEIGHT) {
+            for x in r.x..(r.x + r.width).min(bar) {
+                pixels[(y - r.y) * r.width + x - r.x] = COUNTDOWN_COLOUR;
+            }
+        }
+    }
+}
+
+/*
+ * A debugging aid that outlines the rectangles recently sent to the client in
+ * a colour that identifies the encoding used, fading out over time.  Each
+ * rectangle is drawn into the update that follows it.
+ */
+pub struct Damage {
+    sent: Vec<(Rect, i32, Instant)>,
+}
+
+impl Damage {
+    pub fn new() -> Damage {
+        Damage { sent: Vec::new() }
+    }
+
+    /*
+     * Record the rectangles of an update that was just encoded.
+     */
+    pub fn sent(&mut self, rects: &[(Rect, i32)]) {
+        let now = Instant::now();
+        self.sent.retain(|(_, _, t)| now - *t < FADE);
+        self.sent.extend(rects.iter()
+            .filter(|(r, enc)| *enc >= 0 && r.area() > 0)
+            .map(|(r, enc)| (*r, *enc, now)));
+    }
+
+    /*
+     * Draw onto "pixels", which cover "r".
+     */
+    pub fn draw(&self, r: &Rect, pixels: &mut [u32]) {
+        let now = Instant::now();
+        for (d, enc, t) in &self.sent {
+            let age = (now - *t).as_millis() as u32;
+            let fade = FADE.as_millis() as u32;
+            if age >= fade {
+                continue;
+            }
+            let alpha = 256 * (fade - age) / fade;
+            let c = colour(*enc);
+
+            /*
+             * Tint the interior lightly, and the border strongly:
+             */
+            let x0 = d.x.max(r.x);
+            let y0 = d.y.max(r.y);
+            let x1 = (d.x + d.width).min(r.x + r.width);
+            let y1 = (d.y + d.height).min(r.y + r.height);
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    let edge = x == d.x || y == d.y
+                        || x + 1 == d.x + d.width || y + 1 == d.y + d.height;
+                    let a = if edge { alpha } else { alpha / 8 };
+                    let p = &mut pixels[(y - r.y) * r.width + x - r.x];
+                    *p = blend(*p, c, a);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use crate::rfb::Colour;
+
+/*
+ * A fixed palette for clients that ask for 8-bit colour-mapped pixels: a
+ * cube of six levels in each channel, which covers the whole gamut coarsely,
+ * and a ramp of the greys in between the cube's own, as text and window
+ * chrome are so often grey.
+ */
+const LEVELS: u32 = 6;
+const GREYS: u32 = 40;
+
+pub struct Palette {
+    colours: Vec<(u8, u8, u8)>,
+}
+
+fn cube_level(i: u32) -> u8 {
+    (i * 255 / (LEVELS - 1)) as u8
+}
+
+fn grey_level(i: u32) -> u8 {
+    ((i + 1) * 255 / (GREYS + 1)) as u8
+}
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let d = |x: u8, y: u8| (x as i32 - y as i32).unsigned_abs().pow(2);
+    d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)
+}
+
+impl Palette {
+    pub fn new() -> Palette {
+        let mut colours = Vec::with_capacity((LEVELS.pow(3) + GREYS) as usize);
+        for r in 0..LEVELS {
+            for g in 0..LEVELS {
+                for b in 0..LEVELS {
+                    colours.push((cube_level(r), cube_level(g),
+                        cube_level(b)));
+                }
+            }
+        }
+        for i in 0..GREYS {
+            let v = grey_level(i);
+            colours.push((v, v, v));
+        }
+        Palette { colours }
+    }
+
+    /*
+     * The entries for SetColourMapEntries, starting from zero.
+     */
+    pub fn entries(&self) -> Vec<Colour> {
+        self.colours.iter()
+            .map(|&(r, g, b)| Colour {
+                red: r as u16 * 257,
+                green: g as u16 * 257,
+                blue: b as u16 * 257,
+            })
+            .collect()
+    }
+
+    /*
+     * The index of the entry closest to a 0x00RRGGBB pixel: the nearest
+     * point in the cube, or the nearest grey if that is closer.
+     */
+    pub fn index(&self, pixel: u32) -> u8 {
+        let c = ((pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8);
+        let q = |v: u8| (v as u32 * (LEVELS - 1) + 127) / 255;
+        let cube = (q(c.0) * LEVELS + q(c.1)) * LEVELS + q(c.2);
+
+        let mean = (c.0 as u32 + c.1 as u32 + c.2 as u32) / 3;
+        let grey = ((mean * (GREYS + 1) + 127) / 255).clamp(1, GREYS) - 1;
+        let grey = LEVELS.pow(3) + grey;
+
+        let d = |i: u32| distance(c, self.colours[i as usize]);
+        if d(grey) < d(cube) {
+            grey as u8
+        } else {
+            cube as u8
+        }
+    }
+}
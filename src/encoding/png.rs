@@ -0,0 +1,139 @@
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use std::io::Write;
+
+/*
+ * Just enough PNG for Tight PNG: truecolour or paletted images, without
+ * filtering or interlacing, in a single IDAT chunk.
+ */
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+const COLOUR_RGB: u8 = 2;
+const COLOUR_PALETTE: u8 = 3;
+
+fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    out.extend_from_slice(&crc.sum().to_be_bytes());
+}
+
+/*
+ * Assemble an image from rows that have already been packed at the given bit
+ * depth, which is per index if there is a palette and otherwise per channel.
+ */
+fn image(width: usize, height: usize, depth: u8, palette: Option<&[u32]>,
+    rows: &[u8], level: Option<u8>) -> Vec<u8>
+{
+    let (colour, channels) = match palette {
+        Some(_) => (COLOUR_PALETTE, 1),
+        None => (COLOUR_RGB, 3),
+    };
+    let stride = (width * channels * depth as usize).div_ceil(8);
+    assert_eq!(rows.len(), stride * height);
+
+    let mut out = SIGNATURE.to_vec();
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    ihdr.extend_from_slice(&[depth, colour, 0, 0, 0]);
+    chunk(&mut out, b"IHDR", &ihdr);
+
+    if let Some(palette) = palette {
+        let mut plte = Vec::with_capacity(palette.len() * 3);
+        for p in palette {
+            plte.extend_from_slice(&[(p >> 16) as u8, (p >> 8) as u8,
+                *p as u8]);
+        }
+        chunk(&mut out, b"PLTE", &plte);
+    }
+
+    /*
+     * Every row begins with the filter type, which for us is always None.
+     */
+    let level = level.map_or(Compression::default(), |l| {
+        Compression::new(u32::from(l.min(9)))
+    });
+    let mut z = ZlibEncoder::new(Vec::new(), level);
+    for row in rows.chunks(stride.max(1)) {
+        z.write_all(&[0]).unwrap();
+        z.write_all(row).unwrap();
+    }
+    chunk(&mut out, b"IDAT", &z.finish().unwrap());
+    chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+/*
+ * A truecolour image of 0x00RRGGBB pixels.
+ */
+pub fn rgb(width: usize, height: usize, pixels: &[u32], level: Option<u8>)
+    -> Vec<u8>
+{
+    let mut rows = Vec::with_capacity(pixels.len() * 3);
+    for p in pixels {
+        rows.extend_from_slice(&[(p >> 16) as u8, (p >> 8) as u8, *p as u8]);
+    }
+    image(width, height, 8, None, &rows, level)
+}
+
+/*
+ * A paletted image, given the indices of each pixel packed at one or eight
+ * bits each, with rows padded to a whole byte, as Tight packs them.
+ */
+pub fn indexed(width: usize, height: usize, palette: &[u32], depth: u8,
+    rows: &[u8], level: Option<u8>) -> Vec<u8>
+{
+    image(width, height, depth, Some(palette), rows, level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+    use std::convert::TryInto;
+    use std::io::Read;
+
+    /*
+     * Walk the chunks of a PNG, checking each CRC.
+     */
+    fn chunks(png: &[u8]) -> Vec<(String, Vec<u8>)> {
+        assert_eq!(&png[..8], &SIGNATURE);
+        let mut out = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap())
+                as usize;
+            let kind = &rest[4..8];
+            let data = &rest[8..8 + len];
+            let mut crc = Crc::new();
+            crc.update(kind);
+            crc.update(data);
+            assert_eq!(crc.sum().to_be_bytes(), rest[8 + len..12 + len]);
+            out.push((String::from_utf8(kind.to_vec()).unwrap(),
+                data.to_vec()));
+            rest = &rest[12 + len..];
+        }
+        out
+    }
+
+    #[test]
+    fn mono() {
+        let png = indexed(3, 2, &[0x000000, 0xff0000], 1,
+            &[0b1010_0000, 0b0100_0000], None);
+        let c = chunks(&png);
+        let names: Vec<_> = c.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["IHDR", "PLTE", "IDAT", "IEND"]);
+        assert_eq!(c[0].1, [0, 0, 0, 3, 0, 0, 0, 2, 1, 3, 0, 0, 0]);
+        assert_eq!(c[1].1, [0, 0, 0, 0xff, 0, 0]);
+
+        let mut raw = Vec::new();
+        ZlibDecoder::new(&c[2].1[..]).read_to_end(&mut raw).unwrap();
+        assert_eq!(raw, [0, 0b1010_0000, 0, 0b0100_0000]);
+    }
+}
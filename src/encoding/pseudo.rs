@@ -0,0 +1,177 @@
+use std::sync::Arc;
+
+use super::{raw, Rect, Update, RAW};
+use crate::cursor::Cursor;
+use crate::rfb::{self, Screen};
+use crate::wire::Wire;
+
+pub const DESKTOP_SIZE: i32 = -223;
+pub const CURSOR: i32 = -239;
+pub const DESKTOP_NAME: i32 = -307;
+pub const EXTENDED_DESKTOP_SIZE: i32 = -308;
+pub const CURSOR_ALPHA: i32 = -314;
+pub const LED_STATE: i32 = -261;
+pub const VMWARE_LED_STATE: i32 = 0x574d5668;
+
+/*
+ * The state of the keyboard lock lights:
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Leds {
+    pub caps: bool,
+    pub num: bool,
+    pub scroll: bool,
+}
+
+/*
+ * Pseudo-rectangles that are due to be sent to the client ahead of the pixel
+ * data in the next update:
+ */
+#[derive(Default)]
+pub struct Pending {
+    pub name: Option<String>,
+    /*
+     * The cursor shape, and which of the cursor pseudo-encodings to send it
+     * with:
+     */
+    pub cursor: Option<(i32, Arc<Cursor>)>,
+    /*
+     * Whether to tell the client that it may send QEMU extended key events:
+     */
+    pub extended_key: bool,
+    /*
+     * The lock lights, and which of the two LED state pseudo-encodings to
+     * send them with:
+     */
+    pub leds: Option<(i32, Leds)>,
+}
+
+impl Pending {
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none() && self.cursor.is_none() && !self.extended_key
+            && self.leds.is_none()
+    }
+
+    pub fn write(&self, u: &mut Update) {
+        if let Some(name) = &self.name {
+            desktop_name(u, name);
+        }
+        match &self.cursor {
+            Some((CURSOR, c)) => cursor(u, c),
+            Some((CURSOR_ALPHA, c)) => cursor_alpha(u, c),
+            _ => (),
+        }
+        if self.extended_key {
+            u.rect(&Rect::new(0, 0, 0, 0), rfb::PSEUDO_QEMU_EXTENDED_KEY);
+        }
+        if let Some((enc, leds)) = self.leds {
+            led_state(u, enc, leds);
+        }
+    }
+}
+
+/*
+ * Desktop names longer than this, in bytes, are truncated.
+ */
+const MAX_NAME: usize = 255;
+
+/*
+ * Clip the name to a length that any client should be willing to accept,
+ * without splitting a multi-byte UTF-8 sequence.
+ */
+pub fn truncate_name(name: &str) -> &str {
+    let mut end = name.len().min(MAX_NAME);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+/*
+ * Write a length-prefixed desktop name, as used in both ServerInit and the
+ * DesktopName pseudo-encoding.
+ */
+pub fn name_string(d: &mut Vec<u8>, name: &str) {
+    let name = truncate_name(name);
+    d.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    d.extend_from_slice(name.as_bytes());
+}
+
+pub fn desktop_name(u: &mut Update, name: &str) {
+    u.rect(&Rect::new(0, 0, 0, 0), DESKTOP_NAME);
+    name_string(u.data(), name);
+}
+
+/*
+ * The cursor rectangle is positioned at the hotspot, and carries the image in
+ * our pixel format followed by the transparency mask.
+ */
+pub fn cursor(u: &mut Update, c: &Cursor) {
+    u.rect(&Rect::new(c.hotx, c.hoty, c.width, c.height), CURSOR);
+    let pixels: Vec<u32> = c.pixels.iter().map(|p| p & 0xffffff).collect();
+    raw::serialise(u.data(), &pixels);
+    u.data().extend_from_slice(&c.mask());
+}
+
+/*
+ * The alpha cursor carries an encoding for the image, for which we always use
+ * Raw, followed by R, G, B and premultiplied alpha bytes for each pixel.
+ */
+pub fn cursor_alpha(u: &mut Update, c: &Cursor) {
+    u.rect(&Rect::new(c.hotx, c.hoty, c.width, c.height), CURSOR_ALPHA);
+    let d = u.data();
+    d.extend_from_slice(&RAW.to_be_bytes());
+    for p in &c.pixels {
+        let [b, g, r, a] = p.to_le_bytes();
+        let pm = |v: u8| (v as u32 * a as u32 / 255) as u8;
+        d.extend_from_slice(&[pm(r), pm(g), pm(b), a]);
+    }
+}
+
+/*
+ * The lock lights are packed with Scroll Lock in the lowest bit, then Num
+ * Lock, then Caps Lock.  QEMU sends a single byte; VMware, whose variant is
+ * all that some viewers understand, sends the same bits in a 32-bit word.
+ */
+pub fn led_state(u: &mut Update, enc: i32, leds: Leds) {
+    u.rect(&Rect::new(0, 0, 0, 0), enc);
+    let bits =
+        (leds.scroll as u8) | (leds.num as u8) << 1 | (leds.caps as u8) << 2;
+    if enc == VMWARE_LED_STATE {
+        u.data().extend_from_slice(&(bits as u32).to_be_bytes());
+    } else {
+        u.data().push(bits);
+    }
+}
+
+/*
+ * Tell the client the framebuffer is now "width" by "height".  This must be
+ * the last rectangle in an update, after which the client will discard its
+ * copy of the screen and request a fresh one.
+ */
+pub fn desktop_size(u: &mut Update, width: usize, height: usize) {
+    u.rect(&Rect::new(0, 0, width, height), DESKTOP_SIZE);
+}
+
+/*
+ * Why an ExtendedDesktopSize rectangle is being sent:
+ */
+pub const SIZE_REASON_SERVER: usize = 0;
+pub const SIZE_REASON_CLIENT: usize = 1;
+pub const SIZE_REASON_OTHER_CLIENT: usize = 2;
+
+/*
+ * Like desktop_size(), but also carrying the reason for the change, the
+ * result of a resize request from this client, and the screen layout.
+ */
+pub fn extended_desktop_size(u: &mut Update, reason: usize, status: usize,
+    width: usize, height: usize, screens: &[Screen])
+{
+    u.rect(&Rect::new(reason, status, width, height), EXTENDED_DESKTOP_SIZE);
+    let d = u.data();
+    d.push(screens.len() as u8);
+    d.extend_from_slice(&[0; 3]); /* padding */
+    for s in screens {
+        s.write(d);
+    }
+}
@@ -0,0 +1,27 @@
+use super::{Encoder, Params, Rect, Update, RAW};
+
+pub struct Raw;
+
+/*
+ * We advertise a little endian 32 bits per pixel format in ServerInit, which
+ * is the same as the in-memory layout of the pixel word:
+ */
+pub fn serialise(d: &mut Vec<u8>, pixels: &[u32]) {
+    d.reserve(pixels.len() * 4);
+    for p in pixels {
+        d.extend_from_slice(&p.to_le_bytes());
+    }
+}
+
+impl Encoder for Raw {
+    fn encoding(&self) -> i32 {
+        RAW
+    }
+
+    fn encode(&mut self, r: &Rect, pixels: &[u32], _params: &Params,
+        u: &mut Update)
+    {
+        u.rect(r, RAW);
+        serialise(u.data(), pixels);
+    }
+}
@@ -0,0 +1,404 @@
+use std::collections::HashMap;
+
+use jpeg_encoder::ColorType;
+
+use super::png;
+use super::tune::Limit;
+use super::{sub_pixels, wire_coord, Deflater, Encoder, Params, Rect, Update};
+use super::{TIGHT, TIGHT_PNG};
+
+/*
+ * Rectangles wider than this may not be sent with Tight, and we split larger
+ * areas so that no single rectangle needs an unreasonable amount of buffering
+ * on the client.
+ */
+const MAX_WIDTH: usize = 2048;
+const MAX_AREA: usize = 65536;
+
+/*
+ * Rectangles with at most this many colours are sent with the palette filter.
+ * When JPEG is available we prefer it for anything more colourful than a
+ * small palette.
+ */
+const MAX_PALETTE: usize = 256;
+const MAX_PALETTE_WITH_JPEG: usize = 24;
+const MIN_PALETTE: usize = 2;
+
+/*
+ * Below this size the JPEG headers cost more than they save.
+ */
+const MIN_JPEG_AREA: usize = 1024;
+
+/*
+ * Data shorter than this is sent without compression, as per the protocol.
+ */
+const MIN_COMPRESS: usize = 12;
+
+/*
+ * Map the QualityLevel pseudo-encoding levels onto libjpeg style quality
+ * values, using the same table as TigerVNC.
+ */
+const JPEG_QUALITY: [u8; 10] = [15, 29, 41, 42, 62, 77, 79, 86, 92, 100];
+
+const CTL_FILL: u8 = 0x80;
+const CTL_JPEG: u8 = 0x90;
+const CTL_PNG: u8 = 0xa0;
+const CTL_FILTER: u8 = 0x40;
+const FILTER_PALETTE: u8 = 1;
+
+/*
+ * We use a separate zlib stream for each kind of data, which tends to improve
+ * the compression ratio:
+ */
+const STREAM_FULL: u8 = 0;
+const STREAM_MONO: u8 = 1;
+const STREAM_INDEXED: u8 = 2;
+
+/*
+ * Tight PNG, used by browser clients, is Tight without the zlib streams: what
+ * would be compressed with them is sent as a PNG image instead, which the
+ * browser can decode for itself.
+ */
+pub struct Tight {
+    png: bool,
+    streams: Vec<Deflater>,
+    reset: u8,
+    /*
+     * The compression level for the update in progress:
+     */
+    level: Option<u8>,
+    /*
+     * The most colours sent with the palette filter, without JPEG and with
+     * it.  These start from the limits above, and are tuned to the content
+     * unless the output may be shared with other connections.
+     */
+    palette: Limit,
+    palette_jpeg: Limit,
+}
+
+impl Tight {
+    pub fn new() -> Tight {
+        Tight {
+            png: false,
+            streams: (0..4)
+                .map(|_| Deflater::new())
+                .collect(),
+            /*
+             * The client may have zlib state left over from an earlier Tight
+             * encoder on this connection, so ask for a reset of all streams in
+             * the first rectangle.
+             */
+            reset: 0x0f,
+            level: None,
+            palette: Limit::new(MAX_PALETTE, MIN_PALETTE, MAX_PALETTE),
+            palette_jpeg: Limit::new(MAX_PALETTE_WITH_JPEG, MIN_PALETTE,
+                MAX_PALETTE),
+        }
+    }
+
+    pub fn png() -> Tight {
+        Tight {
+            png: true,
+            streams: Vec::new(),
+            reset: 0,
+            level: None,
+            palette: Limit::new(MAX_PALETTE, MIN_PALETTE, MAX_PALETTE),
+            palette_jpeg: Limit::new(MAX_PALETTE_WITH_JPEG, MIN_PALETTE,
+                MAX_PALETTE),
+        }
+    }
+
+    fn control(&mut self, ctl: u8) -> u8 {
+        let ctl = ctl | self.reset;
+        self.reset = 0;
+        ctl
+    }
+
+    fn fill(&mut self, pixel: u32, u: &mut Update) {
+        let ctl = self.control(CTL_FILL);
+        let d = u.data();
+        d.push(ctl);
+        tpixel(d, pixel);
+    }
+
+    fn jpeg(&mut self, r: &Rect, pixels: &[u32], quality: u8,
+        u: &mut Update)
+    {
+        let mut rgb = Vec::with_capacity(pixels.len() * 3);
+        for p in pixels {
+            tpixel(&mut rgb, *p);
+        }
+
+        let mut jpeg = Vec::new();
+        let q = JPEG_QUALITY[quality.min(9) as usize];
+        jpeg_encoder::Encoder::new(&mut jpeg, q)
+            .encode(&rgb, wire_coord(r.width), wire_coord(r.height),
+                ColorType::Rgb)
+            .expect("jpeg encoding failure");
+
+        let ctl = self.control(CTL_JPEG);
+        let d = u.data();
+        d.push(ctl);
+        compact_len(d, jpeg.len());
+        d.extend_from_slice(&jpeg);
+    }
+
+    fn palette(&mut self, r: &Rect, pixels: &[u32], palette: &[u32],
+        index: &HashMap<u32, u8>, u: &mut Update)
+    {
+        let mut data = Vec::new();
+        let stream = if palette.len() == 2 {
+            /*
+             * Two colour rectangles are sent as a bitmap, with each row
+             * padded out to a whole byte:
+             */
+            for row in pixels.chunks(r.width) {
+                for bits in row.chunks(8) {
+                    let mut b = 0u8;
+                    for (i, p) in bits.iter().enumerate() {
+                        b |= index[p] << (7 - i);
+                    }
+                    data.push(b);
+                }
+            }
+            STREAM_MONO
+        } else {
+            data.extend(pixels.iter().map(|p| index[p]));
+            STREAM_INDEXED
+        };
+
+        if self.png {
+            let depth = if stream == STREAM_MONO { 1 } else { 8 };
+            let image = png::indexed(r.width, r.height, palette, depth, &data,
+                self.level);
+            self.png_rect(&image, u);
+            return;
+        }
+
+        let ctl = self.control(stream << 4 | CTL_FILTER);
+        let d = u.data();
+        d.push(ctl);
+        d.push(FILTER_PALETTE);
+        d.push((palette.len() - 1) as u8);
+        for p in palette {
+            tpixel(d, *p);
+        }
+        self.compressed(stream, &data, u);
+    }
+
+    fn full(&mut self, r: &Rect, pixels: &[u32], u: &mut Update) {
+        if self.png {
+            let image = png::rgb(r.width, r.height, pixels, self.level);
+            self.png_rect(&image, u);
+            return;
+        }
+
+        let mut data = Vec::with_capacity(pixels.len() * 3);
+        for p in pixels {
+            tpixel(&mut data, *p);
+        }
+
+        /*
+         * Without the explicit filter bit, the copy filter is implied:
+         */
+        let ctl = self.control(STREAM_FULL << 4);
+        u.data().push(ctl);
+        self.compressed(STREAM_FULL, &data, u);
+    }
+
+    fn png_rect(&mut self, image: &[u8], u: &mut Update) {
+        let ctl = self.control(CTL_PNG);
+        let d = u.data();
+        d.push(ctl);
+        compact_len(d, image.len());
+        d.extend_from_slice(image);
+    }
+
+    fn compressed(&mut self, stream: u8, data: &[u8], u: &mut Update) {
+        let d = u.data();
+        if data.len() < MIN_COMPRESS {
+            d.extend_from_slice(data);
+            return;
+        }
+
+        let mut z = Vec::new();
+        self.streams[stream as usize].deflate(data, &mut z, self.level);
+        compact_len(d, z.len());
+        d.extend_from_slice(&z);
+    }
+
+    fn encode_one(&mut self, r: &Rect, pixels: &[u32], params: &Params,
+        limit: usize, u: &mut Update)
+    {
+        u.rect(r, self.encoding());
+
+        /*
+         * Count the distinct colours in the rectangle, giving up once there
+         * are too many for a palette:
+         */
+        let mut palette = Vec::new();
+        let mut index = HashMap::new();
+        for p in pixels {
+            if !index.contains_key(p) {
+                if palette.len() == limit {
+                    palette.clear();
+                    break;
+                }
+                index.insert(*p, palette.len() as u8);
+                palette.push(*p);
+            }
+        }
+
+        match (palette.len(), params.quality) {
+            (1, _) => self.fill(palette[0], u),
+            (n, _) if n > 1 => self.palette(r, pixels, &palette, &index, u),
+            (_, Some(q)) if r.area() >= MIN_JPEG_AREA => {
+                self.jpeg(r, pixels, q, u)
+            }
+            _ => self.full(r, pixels, u),
+        }
+    }
+
+    /*
+     * Encode "r" in rectangles small enough for the client, using a palette
+     * for those with at most "limit" colours.
+     */
+    fn encode_all(&mut self, r: &Rect, pixels: &[u32], params: &Params,
+        limit: usize, u: &mut Update)
+    {
+        self.level = params.compression;
+
+        let w = r.width.min(MAX_WIDTH);
+        let h = (MAX_AREA / w.max(1)).max(1);
+
+        let mut y = 0;
+        while y < r.height {
+            let sh = h.min(r.height - y);
+            let mut x = 0;
+            while x < r.width {
+                let sw = w.min(r.width - x);
+                let sub = Rect::new(r.x + x, r.y + y, sw, sh);
+                let px = sub_pixels(r, pixels, &sub);
+
+                self.encode_one(&sub, &px, params, limit, u);
+                x += sw;
+            }
+            y += sh;
+        }
+    }
+}
+
+impl Encoder for Tight {
+    fn encoding(&self) -> i32 {
+        if self.png { TIGHT_PNG } else { TIGHT }
+    }
+
+    /*
+     * Without the zlib streams, the output depends only on the pixels.
+     */
+    fn cacheable(&self) -> bool {
+        self.png
+    }
+
+    fn encode(&mut self, r: &Rect, pixels: &[u32], params: &Params,
+        u: &mut Update)
+    {
+        let tuned = if self.png {
+            None
+        } else if params.quality.is_some() {
+            Some(&mut self.palette_jpeg)
+        } else {
+            Some(&mut self.palette)
+        };
+        let limit = match tuned {
+            Some(l) => {
+                if let Some(trial) = l.trial(r.area()) {
+                    let tried = trial_len(r, pixels, params, trial);
+                    let current = trial_len(r, pixels, params, l.value());
+                    l.outcome(trial, tried, current);
+                }
+                l.value()
+            }
+            None if params.quality.is_some() => MAX_PALETTE_WITH_JPEG,
+            None => MAX_PALETTE,
+        };
+
+        self.encode_all(r, pixels, params, limit, u);
+    }
+}
+
+/*
+ * The size of "r" sent with palettes of at most "limit" colours, using
+ * streams of its own so as not to disturb those the client is reading.
+ */
+fn trial_len(r: &Rect, pixels: &[u32], params: &Params, limit: usize)
+    -> usize
+{
+    let mut u = Update::new();
+    Tight::new().encode_all(r, pixels, params, limit, &mut u);
+    u.len()
+}
+
+/*
+ * For our 24-bit depth pixel format, Tight sends each pixel as three bytes of
+ * red, green and blue.
+ */
+fn tpixel(d: &mut Vec<u8>, p: u32) {
+    d.push((p >> 16) as u8);
+    d.push((p >> 8) as u8);
+    d.push(p as u8);
+}
+
+/*
+ * Lengths are sent in one to three bytes, seven bits at a time with the high
+ * bit set when another byte follows.
+ */
+fn compact_len(d: &mut Vec<u8>, len: usize) {
+    assert!(len < 1 << 22);
+
+    if len < 0x80 {
+        d.push(len as u8);
+    } else if len < 0x4000 {
+        d.push((len & 0x7f) as u8 | 0x80);
+        d.push((len >> 7) as u8);
+    } else {
+        d.push((len & 0x7f) as u8 | 0x80);
+        d.push(((len >> 7) & 0x7f) as u8 | 0x80);
+        d.push((len >> 14) as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tuned() {
+        /*
+         * Noise in a few dozen colours is much smaller with a palette than
+         * as JPEG, so a connection sending it learns to use one.
+         */
+        let mut seed = 1u32;
+        let pixels: Vec<u32> = (0..(64 * 64))
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) % 40 * 0x030507
+            })
+            .collect();
+        let r = Rect::new(0, 0, 64, 64);
+        let params = Params { quality: Some(5), compression: None };
+
+        let mut t = Tight::new();
+        let mut first = None;
+        for _ in 0..64 {
+            let mut u = Update::new();
+            t.encode(&r, &pixels, &params, &mut u);
+            first.get_or_insert(u.len());
+        }
+        assert_eq!(t.palette_jpeg.value(), 2 * MAX_PALETTE_WITH_JPEG);
+
+        let mut u = Update::new();
+        t.encode(&r, &pixels, &params, &mut u);
+        assert!(u.len() < first.unwrap());
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+
+use super::{sub_pixels, Rect};
+
+/*
+ * The tile format shared by TRLE and ZRLE.  Each tile is sent with whichever
+ * of the following subencodings is the most compact:
+ */
+const SUB_RAW: u8 = 0;
+const SUB_SOLID: u8 = 1;
+const SUB_PLAIN_RLE: u8 = 128;
+
+/*
+ * Packed palettes carry up to 16 colours, and palette RLE up to 127.
+ */
+const MAX_PACKED: usize = 16;
+pub const MAX_PALETTE_RLE: usize = 127;
+
+/*
+ * Our pixel format is 32 bits per pixel with a depth of 24, so each pixel is
+ * sent as a compressed CPIXEL: the three least significant bytes, in the
+ * little endian byte order we advertise.
+ */
+fn cpixel(d: &mut Vec<u8>, p: u32) {
+    d.extend_from_slice(&p.to_le_bytes()[0..3]);
+}
+
+/*
+ * Run lengths are sent as a series of bytes, each 255 except the last, which
+ * sum to one less than the length.
+ */
+fn run_bytes(len: usize) -> usize {
+    (len - 1) / 255 + 1
+}
+
+fn run_length(d: &mut Vec<u8>, len: usize) {
+    let mut rem = len - 1;
+    while rem >= 255 {
+        d.push(255);
+        rem -= 255;
+    }
+    d.push(rem as u8);
+}
+
+fn bits_per_index(ncolours: usize) -> usize {
+    match ncolours {
+        2 => 1,
+        3..=4 => 2,
+        _ => 4,
+    }
+}
+
+/*
+ * Encode "r" as a sequence of tiles, each at most "size" pixels square, using
+ * a palette only for tiles with at most "max_palette" colours.
+ */
+pub fn encode(r: &Rect, pixels: &[u32], size: usize, max_palette: usize,
+    d: &mut Vec<u8>)
+{
+    assert!(max_palette <= MAX_PALETTE_RLE);
+
+    let mut y = r.y;
+    while y < r.y + r.height {
+        let th = size.min(r.y + r.height - y);
+        let mut x = r.x;
+        while x < r.x + r.width {
+            let tw = size.min(r.x + r.width - x);
+            let t = Rect::new(x, y, tw, th);
+            tile(&t, &sub_pixels(r, pixels, &t), max_palette, d);
+            x += tw;
+        }
+        y += th;
+    }
+}
+
+fn tile(t: &Rect, pixels: &[u32], max_palette: usize,
+    d: &mut Vec<u8>)
+{
+    /*
+     * Gather the palette and the runs of identical pixels, which are all we
+     * need to determine the size of each possible subencoding:
+     */
+    let mut palette = Vec::new();
+    let mut index = HashMap::new();
+    let mut runs: Vec<(u32, usize)> = Vec::new();
+    for p in pixels {
+        if palette.len() <= max_palette && !index.contains_key(p) {
+            index.insert(*p, palette.len() as u8);
+            palette.push(*p);
+        }
+        match runs.last_mut() {
+            Some((rp, n)) if rp == p => *n += 1,
+            _ => runs.push((*p, 1)),
+        }
+    }
+
+    if palette.len() == 1 {
+        d.push(SUB_SOLID);
+        cpixel(d, palette[0]);
+        return;
+    }
+
+    let n = palette.len();
+    let raw = pixels.len() * 3;
+    let plain_rle: usize = runs.iter().map(|(_, l)| 3 + run_bytes(*l)).sum();
+    let packed = if n <= MAX_PACKED.min(max_palette) {
+        let row = (t.width * bits_per_index(n)).div_ceil(8);
+        Some(n * 3 + row * t.height)
+    } else {
+        None
+    };
+    let palette_rle = if n <= max_palette {
+        Some(n * 3 + runs.iter()
+            .map(|(_, l)| if *l == 1 { 1 } else { 1 + run_bytes(*l) })
+            .sum::<usize>())
+    } else {
+        None
+    };
+
+    let best = [Some(raw), Some(plain_rle), packed, palette_rle].iter()
+        .flatten()
+        .copied()
+        .min()
+        .unwrap();
+
+    if Some(best) == packed {
+        d.push(n as u8);
+        for p in &palette {
+            cpixel(d, *p);
+        }
+
+        let bits = bits_per_index(n);
+        for row in pixels.chunks(t.width) {
+            let mut b = 0u8;
+            let mut nbits = 0;
+            for p in row {
+                b = b << bits | index[p];
+                nbits += bits;
+                if nbits == 8 {
+                    d.push(b);
+                    b = 0;
+                    nbits = 0;
+                }
+            }
+            if nbits > 0 {
+                d.push(b << (8 - nbits));
+            }
+        }
+    } else if Some(best) == palette_rle {
+        d.push(128 + n as u8);
+        for p in &palette {
+            cpixel(d, *p);
+        }
+
+        for (p, l) in runs {
+            if l == 1 {
+                d.push(index[&p]);
+            } else {
+                d.push(index[&p] | 0x80);
+                run_length(d, l);
+            }
+        }
+    } else if best == plain_rle {
+        d.push(SUB_PLAIN_RLE);
+        for (p, l) in runs {
+            cpixel(d, p);
+            run_length(d, l);
+        }
+    } else {
+        d.push(SUB_RAW);
+        for p in pixels {
+            cpixel(d, *p);
+        }
+    }
+}
@@ -0,0 +1,26 @@
+use super::{tile, Encoder, Params, Rect, Update, TRLE};
+
+/*
+ * TRLE is the ZRLE tile format without the zlib stream, using smaller tiles.
+ */
+const TILE_SIZE: usize = 16;
+
+pub struct Trle;
+
+impl Encoder for Trle {
+    fn encoding(&self) -> i32 {
+        TRLE
+    }
+
+    fn cacheable(&self) -> bool {
+        true
+    }
+
+    fn encode(&mut self, r: &Rect, pixels: &[u32], _params: &Params,
+        u: &mut Update)
+    {
+        u.rect(r, TRLE);
+        tile::encode(r, pixels, TILE_SIZE, tile::MAX_PALETTE_RLE,
+            u.data());
+    }
+}
@@ -0,0 +1,132 @@
+/*
+ * A limit that an encoder tunes for itself, such as the most colours it will
+ * send with a palette.  Where a limit is best depends on what is on the
+ * screen: a palette that pays for itself on text and diagrams may cost more
+ * than it saves on a photograph.  So, every so often, the encoder tries an
+ * update both at the current limit and at one either side of it, and if the
+ * other does clearly better, adopts it.  Each connection tunes its own
+ * encoder, following whatever that client happens to be watching.
+ */
+pub struct Limit {
+    value: usize,
+    min: usize,
+    max: usize,
+    /*
+     * Updates large enough to try since the last trial, and whether the next
+     * trial is above the current limit rather than below:
+     */
+    since: usize,
+    up: bool,
+}
+
+/*
+ * A trial costs an extra encoding or two, so we run one only for every so
+ * many updates, and only on those large enough to tell us something.
+ */
+const TRIAL_EVERY: usize = 32;
+const MIN_TRIAL_AREA: usize = 64 * 64;
+
+/*
+ * The other limit is adopted only if it saves at least one part in this many
+ * of the bytes, so that noise does not move it back and forth:
+ */
+const MARGIN: usize = 20;
+
+impl Limit {
+    pub fn new(value: usize, min: usize, max: usize) -> Limit {
+        assert!(min <= value && value <= max);
+        Limit {
+            value,
+            min,
+            max,
+            since: 0,
+            up: false,
+        }
+    }
+
+    pub fn value(&self) -> usize {
+        self.value
+    }
+
+    /*
+     * For an update of "area" pixels, the limit to try, if it is time for a
+     * trial.  Trials alternate between twice and half the current limit.
+     */
+    pub fn trial(&mut self, area: usize) -> Option<usize> {
+        if area < MIN_TRIAL_AREA {
+            return None;
+        }
+        self.since += 1;
+        if self.since < TRIAL_EVERY {
+            return None;
+        }
+        self.since = 0;
+
+        self.up = !self.up;
+        let t = if self.up {
+            (self.value * 2).min(self.max)
+        } else {
+            (self.value / 2).max(self.min)
+        };
+        (t != self.value).then_some(t)
+    }
+
+    /*
+     * The update came to "tried" bytes at the trial limit, and "current" at
+     * the current one.
+     */
+    pub fn outcome(&mut self, trial: usize, tried: usize, current: usize) {
+        if tried + tried / MARGIN < current {
+            self.value = trial;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_savings() {
+        let mut l = Limit::new(24, 2, 256);
+        let area = MIN_TRIAL_AREA;
+
+        /*
+         * Small updates never start a trial, and others only now and then.
+         */
+        for _ in 0..(TRIAL_EVERY * 2) {
+            assert_eq!(l.trial(1), None);
+        }
+        for _ in 1..TRIAL_EVERY {
+            assert_eq!(l.trial(area), None);
+        }
+        assert_eq!(l.trial(area), Some(48));
+
+        /*
+         * A saving within the margin changes nothing, but a clear one moves
+         * the limit, and the next trial goes the other way.
+         */
+        l.outcome(48, 990, 1000);
+        assert_eq!(l.value(), 24);
+        l.outcome(48, 900, 1000);
+        assert_eq!(l.value(), 48);
+        for _ in 1..TRIAL_EVERY {
+            l.trial(area);
+        }
+        assert_eq!(l.trial(area), Some(24));
+
+        /*
+         * At the edge of the range, there is nothing to try in that
+         * direction.
+         */
+        let mut l = Limit::new(256, 2, 256);
+        for _ in 1..TRIAL_EVERY {
+            l.trial(area);
+        }
+        assert_eq!(l.trial(area), None);
+        for _ in 1..TRIAL_EVERY {
+            l.trial(area);
+        }
+        assert_eq!(l.trial(area), Some(128));
+    }
+}
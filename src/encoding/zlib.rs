@@ -0,0 +1,38 @@
+use super::{raw, Deflater, Encoder, Params, Rect, Update, ZLIB};
+
+/*
+ * The Zlib encoding is Raw pixel data, compressed with a zlib stream that
+ * persists for the life of the connection.
+ */
+pub struct Zlib {
+    stream: Deflater,
+}
+
+impl Zlib {
+    pub fn new() -> Zlib {
+        Zlib {
+            stream: Deflater::new(),
+        }
+    }
+}
+
+impl Encoder for Zlib {
+    fn encoding(&self) -> i32 {
+        ZLIB
+    }
+
+    fn encode(&mut self, r: &Rect, pixels: &[u32], params: &Params,
+        u: &mut Update)
+    {
+        let mut data = Vec::new();
+        raw::serialise(&mut data, pixels);
+
+        let mut z = Vec::new();
+        self.stream.deflate(&data, &mut z, params.compression);
+
+        u.rect(r, ZLIB);
+        let d = u.data();
+        d.extend_from_slice(&(z.len() as u32).to_be_bytes());
+        d.extend_from_slice(&z);
+    }
+}
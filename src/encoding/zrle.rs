@@ -0,0 +1,64 @@
+use super::tune::Limit;
+use super::{tile, Deflater, Encoder, Params, Rect, Update, ZRLE};
+
+const TILE_SIZE: usize = 64;
+
+pub struct Zrle {
+    stream: Deflater,
+    /*
+     * The most colours sent with a palette.  Palettes make tiles smaller
+     * before compression, but not always after it:
+     */
+    palette: Limit,
+}
+
+impl Zrle {
+    pub fn new() -> Zrle {
+        Zrle {
+            stream: Deflater::new(),
+            palette: Limit::new(tile::MAX_PALETTE_RLE, 2,
+                tile::MAX_PALETTE_RLE),
+        }
+    }
+}
+
+/*
+ * The compressed size of "r" with palettes of at most "max_palette" colours,
+ * in a stream of its own so as not to disturb the one the client is reading.
+ */
+fn trial_len(r: &Rect, pixels: &[u32], max_palette: usize, params: &Params)
+    -> usize
+{
+    let mut tiles = Vec::new();
+    tile::encode(r, pixels, TILE_SIZE, max_palette, &mut tiles);
+    let mut z = Vec::new();
+    Deflater::new().deflate(&tiles, &mut z, params.compression);
+    z.len()
+}
+
+impl Encoder for Zrle {
+    fn encoding(&self) -> i32 {
+        ZRLE
+    }
+
+    fn encode(&mut self, r: &Rect, pixels: &[u32], params: &Params,
+        u: &mut Update)
+    {
+        if let Some(trial) = self.palette.trial(r.area()) {
+            let tried = trial_len(r, pixels, trial, params);
+            let current = trial_len(r, pixels, self.palette.value(), params);
+            self.palette.outcome(trial, tried, current);
+        }
+
+        let mut tiles = Vec::new();
+        tile::encode(r, pixels, TILE_SIZE, self.palette.value(), &mut tiles);
+
+        let mut z = Vec::new();
+        self.stream.deflate(&tiles, &mut z, params.compression);
+
+        u.rect(r, ZRLE);
+        let d = u.data();
+        d.extend_from_slice(&(z.len() as u32).to_be_bytes());
+        d.extend_from_slice(&z);
+    }
+}
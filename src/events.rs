@@ -0,0 +1,146 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+
+use crate::handshake::Outcome;
+use crate::listen::Addr;
+
+/*
+ * Things that happen to the server that an operator may want to know about
+ * after the fact.  The most recent are kept in memory with the time they
+ * happened, so that somebody who attaches to the admin socket after an
+ * incident can see what led up to it, whether or not anybody was keeping the
+ * log.
+ */
+#[derive(Debug, Clone)]
+pub enum Event {
+    Connected {
+        session: u64,
+        addr: Addr,
+    },
+    /*
+     * The client did not get as far as ClientInit, including because it
+     * failed to authenticate:
+     */
+    HandshakeFailed {
+        session: u64,
+        outcome: Outcome,
+        error: String,
+    },
+    /*
+     * The connection has finished, with the error that ended it, if any:
+     */
+    Disconnected {
+        session: u64,
+        error: Option<String>,
+    },
+    Resized {
+        width: usize,
+        height: usize,
+        session: Option<u64>,
+    },
+    SourceReplaced {
+        width: usize,
+        height: usize,
+    },
+}
+
+impl Event {
+    pub fn describe(&self) -> serde_json::Value {
+        match self {
+            Event::Connected { session, addr } => json!({
+                "event": "connected",
+                "session": session,
+                "addr": addr.to_string(),
+            }),
+            Event::HandshakeFailed { session, outcome, error } => json!({
+                "event": "handshake_failed",
+                "session": session,
+                "outcome": outcome.name(),
+                "error": error,
+            }),
+            Event::Disconnected { session, error } => json!({
+                "event": "disconnected",
+                "session": session,
+                "error": error,
+            }),
+            Event::Resized { width, height, session } => json!({
+                "event": "resized",
+                "width": width,
+                "height": height,
+                "session": session,
+            }),
+            Event::SourceReplaced { width, height } => json!({
+                "event": "source_replaced",
+                "width": width,
+                "height": height,
+            }),
+        }
+    }
+}
+
+/*
+ * How many events are kept:
+ */
+const KEEP: usize = 256;
+
+pub struct Events {
+    recent: Mutex<VecDeque<(SystemTime, Event)>>,
+}
+
+impl Events {
+    pub fn new() -> Events {
+        Events {
+            recent: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn record(&self, event: Event) {
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == KEEP {
+            recent.pop_front();
+        }
+        recent.push_back((SystemTime::now(), event));
+    }
+
+    pub fn recent(&self) -> Vec<(SystemTime, Event)> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+
+    /*
+     * The events kept, oldest first, each with the time it happened in
+     * seconds since the epoch.
+     */
+    pub fn describe(&self) -> serde_json::Value {
+        self.recent().into_iter()
+            .map(|(t, e)| {
+                let mut v = e.describe();
+                let t = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+                v["time"] = json!(t.as_secs_f64());
+                v
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oldest_forgotten() {
+        let events = Events::new();
+        for session in 0..(KEEP as u64 + 10) {
+            events.record(Event::Disconnected { session, error: None });
+        }
+
+        let d = events.describe();
+        let d = d.as_array().unwrap();
+        assert_eq!(d.len(), KEEP);
+        assert_eq!(d[0]["event"], "disconnected");
+        assert_eq!(d[0]["session"], 10);
+        assert!(d[0]["time"].as_f64().unwrap() > 0.0);
+    }
+}
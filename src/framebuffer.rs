@@ -1,35 +1,313 @@
-use std::alloc::{Layout, alloc_zeroed, dealloc};
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::Range;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
+use crate::lease::{Lease, Leases};
+use crate::source::{Rect, Source};
+
+/*
+ * RFB carries every coordinate and size as an unsigned 16-bit quantity, so no
+ * framebuffer may be larger than this in either dimension.
+ */
+pub const MAX_DIMENSION: usize = u16::MAX as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeometryError {
+    /*
+     * A framebuffer must be at least one pixel in each dimension.
+     */
+    Empty,
+    /*
+     * The value does not fit in the 16 bits the protocol allows.
+     */
+    TooLarge(usize),
+    OutOfMemory,
+}
+
+impl fmt::Display for GeometryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GeometryError::Empty => write!(f, "framebuffer has no pixels"),
+            GeometryError::TooLarge(v) => {
+                write!(f, "{} exceeds the RFB limit of {}", v, MAX_DIMENSION)
+            }
+            GeometryError::OutOfMemory => {
+                write!(f, "could not allocate framebuffer")
+            }
+        }
+    }
+}
+
+impl std::error::Error for GeometryError {}
+
+/*
+ * Convert a coordinate or size to the form in which it goes on the wire.
+ */
+pub fn coord(v: usize) -> Result<u16, GeometryError> {
+    u16::try_from(v).map_err(|_| GeometryError::TooLarge(v))
+}
+
+/*
+ * The size of the square tiles in which changes to the framebuffer are
+ * tracked.
+ */
+pub const TILE: usize = 64;
+
+/*
+ * The colours of a poisoned framebuffer:
+ */
+const POISON: u32 = 0xff00ff;
+const POISON_ALT: u32 = 0x00ff00;
+
+/*
+ * Pixels are stored one to a word, as 0x00RRGGBB.  Each word is atomic so
+ * that the application may draw while the server reads from other threads,
+ * without a lock; a reader may see a frame part way through being drawn, but
+ * never a torn pixel.
+ *
+ * An application that must not show partial frames can ask for a second
+ * buffer.  It then draws into the back buffer and calls present() once a
+ * frame is complete, and clients are only ever sent the front buffer.
+ *
+ * Drawing a pixel marks its tile as drawn, and each present() records which
+ * tiles have changed, so that the server need only send those.  Without a
+ * back buffer, present() is optional: changes are recorded whenever somebody
+ * asks for them.  A pixel drawn at that very moment may then not be reported
+ * until its tile is drawn again.
+ */
 pub struct Framebuffer {
-    layout: Layout,
-    region: *mut u8,
-    pixelsize: usize,
+    /*
+     * The front buffer, from which clients are sent pixels, followed by the
+     * back buffer, if there is one.
+     */
+    pixels: Vec<AtomicU32>,
+    /*
+     * Where the buffer the application draws into starts: zero with a single
+     * buffer, and otherwise the start of the back buffer.  This does not
+     * change, so that drawing a pixel need not load it atomically.
+     */
+    back: usize,
+    /*
+     * Which tiles have been drawn in since the last present(), a row of
+     * tiles at a time, with "across" in each row.
+     */
+    drawn: Vec<AtomicBool>,
+    across: usize,
+    /*
+     * Held for reading while pixels are copied out of the front buffer, and
+     * for writing while a frame is presented, so that no read spans two
+     * frames.  The lock is kept out of line: with it inside the struct, the
+     * compiler cannot assume that drawing a pixel leaves the other fields
+     * alone, and must load them again for every pixel.
+     */
+    shown: Box<RwLock<Shown>>,
+    /*
+     * Areas leased to producers, kept out of line for the same reason:
+     */
+    leases: Box<Leases>,
     height: usize,
     width: usize,
 }
 
-unsafe impl Send for Framebuffer {}
-unsafe impl Sync for Framebuffer {}
+/*
+ * Changes to the front buffer.  Each present() that changes anything starts
+ * a new generation, and each tile records the last generation in which it
+ * changed.
+ */
+struct Shown {
+    generation: u64,
+    changed: Vec<u64>,
+    /*
+     * The generation as of the last take_damage():
+     */
+    taken: u64,
+}
 
 impl Framebuffer {
-    pub fn new(width: usize, height: usize) -> Self {
-        let pixelsize = 4;
-        let ncells = width.checked_mul(height).unwrap();
-        let size = ncells.checked_mul(pixelsize).unwrap();
-
-        let layout = Layout::from_size_align(size, pixelsize).unwrap();
-        let region = unsafe { alloc_zeroed(layout) };
-        println!("framebuffer memory @ {:?}", region);
-
-        Framebuffer {
-            layout,
-            pixelsize,
-            region,
+    /*
+     * Allocate a blank framebuffer.  Both dimensions must be representable
+     * in the protocol, so that every rectangle within the framebuffer is
+     * too.
+     */
+    pub fn new(width: usize, height: usize)
+        -> Result<Framebuffer, GeometryError>
+    {
+        Framebuffer::new_buffered(width, height, false)
+    }
+
+    /*
+     * Allocate a blank framebuffer, with a back buffer if "double" is set.
+     */
+    pub fn new_buffered(width: usize, height: usize, double: bool)
+        -> Result<Framebuffer, GeometryError>
+    {
+        if width == 0 || height == 0 {
+            return Err(GeometryError::Empty);
+        }
+        coord(width)?;
+        coord(height)?;
+
+        let ncells = width.checked_mul(height)
+            .ok_or(GeometryError::OutOfMemory)?;
+        let total = ncells.checked_mul(if double { 2 } else { 1 })
+            .ok_or(GeometryError::OutOfMemory)?;
+        let mut pixels = Vec::new();
+        pixels.try_reserve_exact(total)
+            .map_err(|_| GeometryError::OutOfMemory)?;
+        pixels.resize_with(total, || AtomicU32::new(0));
+
+        let across = width.div_ceil(TILE);
+        let ntiles = across * height.div_ceil(TILE);
+        let mut drawn = Vec::with_capacity(ntiles);
+        drawn.resize_with(ntiles, || AtomicBool::new(false));
+
+        Ok(Framebuffer {
+            pixels,
+            back: total - ncells,
+            drawn,
+            across,
+            shown: Box::new(RwLock::new(Shown {
+                generation: 0,
+                changed: vec![0; ntiles],
+                taken: 0,
+            })),
+            leases: Box::new(Leases::new()),
             height,
             width,
+        })
+    }
+
+    pub fn is_double(&self) -> bool {
+        self.back > 0
+    }
+
+    /*
+     * Show clients what has been drawn into the back buffer, by copying the
+     * tiles drawn in to the front.  The back buffer keeps its contents, so
+     * the application need only draw what changes in the next frame.
+     */
+    pub fn present(&self) {
+        let mut shown = self.shown.write().unwrap();
+        self.flush(&mut shown);
+    }
+
+    /*
+     * Record the tiles drawn in since the last flush as changed, copying them
+     * to the front buffer if there is a back buffer.  The caller holds the
+     * lock for writing.
+     */
+    fn flush(&self, shown: &mut Shown) {
+        let generation = shown.generation + 1;
+        for (t, drawn) in self.drawn.iter().enumerate() {
+            if !drawn.load(Ordering::Relaxed)
+                || !drawn.swap(false, Ordering::Relaxed)
+            {
+                continue;
+            }
+            shown.changed[t] = generation;
+            shown.generation = generation;
+
+            if self.is_double() {
+                let r = self.tile(t);
+                for y in r.y..(r.y + r.height) {
+                    for i in self.span(r.x, y, r.width) {
+                        self.pixels[i].store(self.pixels[self.back + i]
+                            .load(Ordering::Relaxed), Ordering::Relaxed);
+                    }
+                }
+            }
         }
     }
 
+    /*
+     * Where the "width" pixels from (x, y) are in the front buffer.  Every
+     * bulk access goes through here, so that in debug builds, a span that
+     * would run off the end of its row, into the next or into the back
+     * buffer, is caught where it is computed rather than seen by a client.
+     */
+    #[inline]
+    fn span(&self, x: usize, y: usize, width: usize) -> Range<usize> {
+        debug_assert!(x + width <= self.width && y < self.height,
+            "span of {} at ({}, {}) outside {}x{} framebuffer", width, x, y,
+            self.width, self.height);
+        let start = y * self.width + x;
+        start..start + width
+    }
+
+    /*
+     * The area covered by tile "t", which is smaller than the others at the
+     * right and bottom edges.
+     */
+    fn tile(&self, t: usize) -> Rect {
+        let x = (t % self.across) * TILE;
+        let y = (t / self.across) * TILE;
+        Rect::new(x, y, TILE.min(self.width - x), TILE.min(self.height - y))
+    }
+
+    /*
+     * The areas that have changed since generation "since", as rectangles
+     * made of whole tiles, along with the current generation.  Each client
+     * can keep its own place this way; one that has not yet been sent
+     * anything needs the whole framebuffer, whatever this says.
+     */
+    pub fn damage_since(&self, since: u64) -> (u64, Vec<Rect>) {
+        let mut shown = self.shown.write().unwrap();
+        if !self.is_double() {
+            self.flush(&mut shown);
+        }
+        (shown.generation, self.damaged(&shown.changed, since))
+    }
+
+    /*
+     * The areas that have changed since the last call, for an application
+     * with only one consumer of damage.
+     */
+    pub fn take_damage(&self) -> Vec<Rect> {
+        let mut shown = self.shown.write().unwrap();
+        if !self.is_double() {
+            self.flush(&mut shown);
+        }
+        let rects = self.damaged(&shown.changed, shown.taken);
+        shown.taken = shown.generation;
+        rects
+    }
+
+    /*
+     * Merge the tiles changed since "since" into rectangles: first runs of
+     * tiles along each row, and then runs of the same width down the
+     * columns.
+     */
+    fn damaged(&self, changed: &[u64], since: u64) -> Vec<Rect> {
+        let mut rects: Vec<Rect> = Vec::new();
+        for (ty, row) in changed.chunks(self.across).enumerate() {
+            let mut tx = 0;
+            while tx < row.len() {
+                if row[tx] <= since {
+                    tx += 1;
+                    continue;
+                }
+                let start = tx;
+                while tx < row.len() && row[tx] > since {
+                    tx += 1;
+                }
+
+                let x = start * TILE;
+                let y = ty * TILE;
+                let r = Rect::new(x, y, (tx * TILE).min(self.width) - x,
+                    TILE.min(self.height - y));
+                match rects.iter_mut().find(|p| {
+                    p.x == r.x && p.width == r.width && p.y + p.height == r.y
+                }) {
+                    Some(p) => p.height += r.height,
+                    None => rects.push(r),
+                }
+            }
+        }
+        rects
+    }
+
     pub fn width(&self) -> usize {
         self.width
     }
@@ -38,45 +316,373 @@ impl Framebuffer {
         self.height
     }
 
-    pub fn put(&self, x: usize, y: usize, red: u8, green: u8, blue: u8) {
+    /*
+     * Lease "r", which must lie within the framebuffer, so that no other
+     * producer that takes a lease draws in it until the lease is dropped.
+     * This waits for any overlapping leases to be released.
+     */
+    pub fn lock_rect(&self, r: Rect) -> Lease<'_> {
+        self.check_rect(&r);
+        let id = self.leases.acquire(r, true).unwrap();
+        Lease::new(self, id, r)
+    }
+
+    /*
+     * Lease "r" if no part of it is already leased.
+     */
+    pub fn try_lock_rect(&self, r: Rect) -> Option<Lease<'_>> {
+        self.check_rect(&r);
+        let id = self.leases.acquire(r, false)?;
+        Some(Lease::new(self, id, r))
+    }
+
+    pub fn leases(&self) -> &Leases {
+        &self.leases
+    }
+
+    fn check_rect(&self, r: &Rect) {
+        assert!(r.x + r.width <= self.width
+            && r.y + r.height <= self.height, "rect outside framebuffer");
+    }
+
+    /*
+     * Where the pixel at (x, y) is in the back buffer.  This and the drawing
+     * functions are called once per pixel, usually from another crate, so
+     * they must be inlined to be cheap.
+     */
+    #[inline]
+    fn index(&self, x: usize, y: usize) -> Option<usize> {
         if x >= self.width || y >= self.height {
-            return;
+            None
+        } else {
+            Some(self.back + y * self.width + x)
         }
+    }
 
-        let mut pix = 0u32;
-        pix |= (red as u32) << 16;
-        pix |= (green as u32) << 8;
-        pix |= blue as u32;
+    /*
+     * Set the pixel at (x, y), which must lie within the framebuffer.  Use
+     * try_put() to draw something that may be clipped at the edges.
+     */
+    #[inline(always)]
+    pub fn put(&self, x: usize, y: usize, red: u8, green: u8, blue: u8) {
+        if !self.try_put(x, y, red, green, blue) {
+            self.out_of_bounds(x, y);
+        }
+    }
+
+    /*
+     * Set the pixel at (x, y), if it lies within the framebuffer.  Returns
+     * whether it did.
+     */
+    #[inline(always)]
+    pub fn try_put(&self, x: usize, y: usize, red: u8, green: u8, blue: u8)
+        -> bool
+    {
+        let Some(i) = self.index(x, y) else {
+            return false;
+        };
 
-        let pixregion = self.region as *mut u32;
-        let target = (y * self.width + x) as isize;
+        /*
+         * Drawing a pixel in the colour it already has changes nothing, and
+         * is not damage.
+         */
+        let pix = (red as u32) << 16 | (green as u32) << 8 | blue as u32;
+        if self.pixels[i].load(Ordering::Relaxed) == pix {
+            return true;
+        }
+        self.pixels[i].store(pix, Ordering::Relaxed);
 
-        unsafe { pixregion.offset(target).write_volatile(pix) };
+        let tile = &self.drawn[(y / TILE) * self.across + x / TILE];
+        if !tile.load(Ordering::Relaxed) {
+            tile.store(true, Ordering::Relaxed);
+        }
+        true
     }
 
+    /*
+     * Read back a pixel the application has drawn, which with a back buffer
+     * may not yet have been presented.
+     */
+    #[inline]
     pub fn get(&self, x: usize, y: usize) -> (u8, u8, u8) {
-        if x >= self.width || y >= self.height {
-            panic!("out of bounds");
+        let Some(i) = self.index(x, y) else {
+            self.out_of_bounds(x, y);
+        };
+
+        let pix = self.pixels[i].load(Ordering::Relaxed);
+        ((pix >> 16) as u8, (pix >> 8) as u8, pix as u8)
+    }
+
+    #[cold]
+    fn out_of_bounds(&self, x: usize, y: usize) -> ! {
+        panic!("pixel ({}, {}) outside {}x{} framebuffer", x, y,
+            self.width, self.height);
+    }
+
+    /*
+     * Copy the pixels of "r" as last presented, which must lie within the
+     * framebuffer, into "out", which must be exactly large enough, a row at
+     * a time as 0x00RRGGBB.  Reading many pixels this way is several times
+     * cheaper than with get(), as the bounds are checked once rather than
+     * for each pixel.  Unlike read_rect(), the caller may fill a buffer of
+     * its own, such as part of a larger image, without growing a Vec.
+     */
+    pub fn read_rows(&self, r: &Rect, out: &mut [u32]) {
+        self.check_rect(r);
+        assert_eq!(out.len(), r.area(), "buffer does not fit rect");
+        if r.width == 0 {
+            return;
         }
 
-        let pixregion = self.region as *mut u32;
-        let target = (y * self.width + x) as isize;
+        let _shown = self.shown.read().unwrap();
+        for (y, dst) in (r.y..).zip(out.chunks_exact_mut(r.width)) {
+            let src = &self.pixels[self.span(r.x, y, r.width)];
+            for (d, s) in dst.iter_mut().zip(src) {
+                *d = s.load(Ordering::Relaxed);
+            }
+        }
+    }
 
-        let pix = unsafe { pixregion.offset(target).read_volatile() };
-        ((pix >> 16) as u8, (pix >> 8) as u8, pix as u8)
+    /*
+     * Fill both buffers with a pattern that nobody would draw on purpose:
+     * magenta and green squares.  A new framebuffer is otherwise black, and
+     * an application that leaves part of it undrawn, perhaps because it has
+     * the stride of its own buffer wrong, is then easier to spot.  Nothing is
+     * recorded as damage, as this is how the framebuffer starts out.
+     */
+    pub fn poison(&self) {
+        let _shown = self.shown.write().unwrap();
+        for (i, p) in self.pixels.iter().enumerate() {
+            let (x, y) = (i % self.width, i / self.width % self.height);
+            let pix = if (x / 8 + y / 8) % 2 == 0 {
+                POISON
+            } else {
+                POISON_ALT
+            };
+            p.store(pix, Ordering::Relaxed);
+        }
+    }
+
+    /*
+     * Check that what is drawn reads back as drawn, in the right place, by
+     * every path a client's pixels take: each pixel is drawn with a value
+     * derived from its position, and the whole framebuffer is then presented
+     * and read back, along with the damage reported.  This is for a new
+     * framebuffer, before anything else draws in it, and leaves it blank.
+     */
+    pub fn self_test(&self) -> Result<(), String> {
+        /*
+         * None of the values is black, so that every pixel drawn is damage.
+         */
+        let value = |x: usize, y: usize| {
+            ((x as u32).wrapping_mul(0x9e3779b1)
+                ^ (y as u32).wrapping_mul(0x85ebca77)) & 0xffffff | 1
+        };
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let v = value(x, y);
+                self.put(x, y, (v >> 16) as u8, (v >> 8) as u8, v as u8);
+            }
+        }
+        self.present();
+
+        let all = Rect::new(0, 0, self.width, self.height);
+        let mut out = Vec::new();
+        self.read_rect(&all, &mut out);
+        let mut rows = vec![0; all.area()];
+        self.read_rows(&all, &mut rows);
+        for (i, (a, b)) in out.iter().zip(&rows).enumerate() {
+            let (x, y) = (i % self.width, i / self.width);
+            let v = value(x, y);
+            if *a != v || *b != v {
+                return Err(format!("pixel ({}, {}) drawn as {:06x} read \
+                    back as {:06x} and {:06x}", x, y, v, a, b));
+            }
+            let (red, green, blue) = self.get(x, y);
+            let got = u32::from_be_bytes([0, red, green, blue]);
+            if got != v {
+                return Err(format!("pixel ({}, {}) drawn as {:06x} got \
+                    back as {:06x}", x, y, v, got));
+            }
+        }
+
+        let damaged: usize = self.take_damage().iter().map(Rect::area).sum();
+        if damaged != all.area() {
+            return Err(format!("{} pixels drawn but {} reported damaged",
+                all.area(), damaged));
+        }
+
+        /*
+         * Put things back as they were, without recording damage:
+         */
+        let _shown = self.shown.write().unwrap();
+        for p in &self.pixels {
+            p.store(0, Ordering::Relaxed);
+        }
+        Ok(())
     }
 
+    /*
+     * The bytes of the front buffer, in memory order.
+     */
     #[allow(dead_code)]
     pub fn copy_all(&self) -> Vec<u8> {
-        let ncells = self.width.checked_mul(self.height).unwrap();
-        let size = ncells.checked_mul(self.pixelsize).unwrap();
-        let slice = unsafe { std::slice::from_raw_parts(self.region, size) };
-        slice.to_vec()
+        let _shown = self.shown.read().unwrap();
+        self.pixels[..self.width * self.height].iter()
+            .flat_map(|p| p.load(Ordering::Relaxed).to_ne_bytes())
+            .collect()
+    }
+}
+
+impl Source for Framebuffer {
+    fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    fn read_rect(&self, r: &Rect, out: &mut Vec<u32>) {
+        self.check_rect(r);
+
+        let _shown = self.shown.read().unwrap();
+        out.reserve(r.area());
+        for y in r.y..(r.y + r.height) {
+            out.extend(self.pixels[self.span(r.x, y, r.width)].iter()
+                .map(|p| p.load(Ordering::Relaxed)));
+        }
+    }
+
+    fn changed_since(&self, since: u64) -> Option<(u64, Vec<Rect>)> {
+        Some(self.damage_since(since))
+    }
+
+    fn framebuffer(self: Arc<Self>) -> Option<Arc<Framebuffer>> {
+        Some(self)
     }
 }
 
-impl Drop for Framebuffer {
-    fn drop(&mut self) {
-        unsafe { dealloc(self.region, self.layout) };
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn put_get() {
+        let fb = Framebuffer::new(4, 3).unwrap();
+        fb.put(3, 2, 0x12, 0x34, 0x56);
+        assert_eq!(fb.get(3, 2), (0x12, 0x34, 0x56));
+        assert_eq!(fb.get(0, 0), (0, 0, 0));
+
+        let mut out = Vec::new();
+        fb.read_rect(&Rect::new(2, 1, 2, 2), &mut out);
+        assert_eq!(out, vec![0, 0, 0, 0x123456]);
+
+        let mut rows = [7; 6];
+        fb.read_rows(&Rect::new(2, 1, 2, 2), &mut rows[1..5]);
+        assert_eq!(rows, [7, 0, 0, 0, 0x123456, 7]);
+    }
+
+    #[test]
+    fn try_put_clips() {
+        let fb = Framebuffer::new(4, 3).unwrap();
+        assert!(fb.try_put(0, 0, 1, 2, 3));
+        assert!(!fb.try_put(4, 0, 1, 2, 3));
+        assert!(!fb.try_put(0, 3, 1, 2, 3));
+        assert!(fb.copy_all().iter().skip(4).all(|&b| b == 0));
+    }
+
+    #[test]
+    fn present() {
+        let fb = Framebuffer::new_buffered(2, 2, true).unwrap();
+        let full = Rect::new(0, 0, 2, 2);
+        fb.put(1, 0, 0, 0, 0xff);
+        assert_eq!(fb.get(1, 0), (0, 0, 0xff));
+
+        let mut out = Vec::new();
+        fb.read_rect(&full, &mut out);
+        assert_eq!(out, vec![0; 4]);
+
+        fb.present();
+        out.clear();
+        fb.read_rect(&full, &mut out);
+        assert_eq!(out, vec![0, 0xff, 0, 0]);
+
+        /*
+         * The next frame starts from the one presented, and is not seen
+         * until it too is presented.
+         */
+        assert_eq!(fb.get(1, 0), (0, 0, 0xff));
+        fb.put(0, 1, 0xff, 0, 0);
+        out.clear();
+        fb.read_rect(&full, &mut out);
+        assert_eq!(out, vec![0, 0xff, 0, 0]);
+        fb.present();
+        out.clear();
+        fb.read_rect(&full, &mut out);
+        assert_eq!(out, vec![0, 0xff, 0xff0000, 0]);
+    }
+
+    #[test]
+    fn damage() {
+        let fb = Framebuffer::new(200, 130).unwrap();
+        assert_eq!(fb.take_damage(), vec![]);
+
+        /*
+         * Adjacent tiles are merged along rows, then down columns, and tiles
+         * at the edges are clipped:
+         */
+        fb.put(10, 10, 1, 1, 1);
+        fb.put(70, 10, 1, 1, 1);
+        fb.put(10, 70, 1, 1, 1);
+        fb.put(70, 70, 1, 1, 1);
+        fb.put(199, 129, 1, 1, 1);
+        assert_eq!(fb.take_damage(), vec![
+            Rect::new(0, 0, 128, 128),
+            Rect::new(192, 128, 8, 2),
+        ]);
+        assert_eq!(fb.take_damage(), vec![]);
+
+        /*
+         * Drawing what is already there is not damage.
+         */
+        fb.put(10, 10, 1, 1, 1);
+        assert_eq!(fb.take_damage(), vec![]);
+
+        /*
+         * Each consumer of damage may keep its own place.
+         */
+        let (first, _) = fb.damage_since(0);
+        fb.put(130, 0, 2, 2, 2);
+        let (second, rects) = fb.damage_since(first);
+        assert_eq!(rects, vec![Rect::new(128, 0, 64, 64)]);
+        assert_eq!(fb.damage_since(second), (second, vec![]));
+        assert_eq!(fb.take_damage(), vec![Rect::new(128, 0, 64, 64)]);
+    }
+
+    #[test]
+    fn damage_presented() {
+        let fb = Framebuffer::new_buffered(100, 100, true).unwrap();
+        fb.put(80, 80, 1, 1, 1);
+        assert_eq!(fb.take_damage(), vec![]);
+        fb.present();
+        assert_eq!(fb.take_damage(), vec![Rect::new(64, 64, 36, 36)]);
+    }
+
+    #[test]
+    fn self_test() {
+        for double in [false, true] {
+            let fb = Framebuffer::new_buffered(130, 70, double).unwrap();
+            fb.self_test().unwrap();
+            assert!(fb.copy_all().iter().all(|&b| b == 0));
+            assert_eq!(fb.take_damage(), vec![]);
+
+            fb.poison();
+            assert_eq!(fb.get(0, 0), (0xff, 0, 0xff));
+            assert_eq!(fb.get(8, 0), (0, 0xff, 0));
+            assert_eq!(fb.take_damage(), vec![]);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "outside 4x3 framebuffer")]
+    fn put_out_of_bounds() {
+        Framebuffer::new(4, 3).unwrap().put(4, 0, 1, 2, 3);
     }
 }
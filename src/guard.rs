@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/*
+ * Protection against password guessing.  After each failed authentication,
+ * the address it came from must wait before trying again, for twice as long
+ * each time.  After enough failures in a row, the address is banned for a
+ * while.  A success clears the record.
+ *
+ * Only one attempt from an address may be in progress at a time, so that
+ * opening many connections at once does not buy a guess on each of them
+ * before the first failure is recorded.
+ */
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /*
+     * The wait after the first failure; zero disables the protection.
+     */
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /*
+     * The number of failures after which an address is banned, if any, and
+     * for how long.
+     */
+    pub ban_after: Option<u32>,
+    pub ban_time: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            ban_after: Some(10),
+            ban_time: Duration::from_secs(600),
+        }
+    }
+}
+
+struct Record {
+    failures: u32,
+    until: Instant,
+    banned: bool,
+    pending: bool,
+}
+
+/*
+ * Records are kept for this long after the wait they impose has passed, so
+ * that failures spread out over a short time still add up.
+ */
+const MEMORY: Duration = Duration::from_secs(3600);
+
+pub struct Guard {
+    policy: Policy,
+    records: Mutex<HashMap<IpAddr, Record>>,
+}
+
+/*
+ * Why an address may not try to authenticate yet, and for how much longer.
+ */
+#[derive(Debug)]
+pub struct Refusal {
+    pub banned: bool,
+    pub pending: bool,
+    pub wait: Duration,
+}
+
+impl Refusal {
+    pub fn reason(&self) -> String {
+        let secs = self.wait.as_secs().max(1);
+        if self.pending {
+            "another authentication attempt from this address is in \
+                progress".to_string()
+        } else if self.banned {
+            format!("too many authentication failures; try again in {} \
+                seconds", secs)
+        } else {
+            format!("authentication failed recently; try again in {} \
+                seconds", secs)
+        }
+    }
+}
+
+impl Guard {
+    pub fn new(policy: Policy) -> Guard {
+        Guard { policy, records: Mutex::new(HashMap::new()) }
+    }
+
+    fn enabled(&self) -> bool {
+        !self.policy.backoff.is_zero()
+    }
+
+    /*
+     * Decide whether a connection from "addr" may attempt to authenticate.
+     * If it may, the attempt is pending until its outcome is reported
+     * through the returned Attempt.
+     */
+    pub fn check(&self, addr: IpAddr) -> Result<Attempt<'_>, Refusal> {
+        if !self.enabled() {
+            return Ok(Attempt { guard: self, addr });
+        }
+        let now = Instant::now();
+        let mut records = self.records.lock().unwrap();
+        records.retain(|_, r| r.pending || r.until + MEMORY > now);
+
+        let r = records.entry(addr).or_insert(Record {
+            failures: 0,
+            until: now,
+            banned: false,
+            pending: false,
+        });
+        if r.pending {
+            return Err(Refusal {
+                banned: false,
+                pending: true,
+                wait: Duration::ZERO,
+            });
+        }
+        if r.until > now {
+            return Err(Refusal {
+                banned: r.banned,
+                pending: false,
+                wait: r.until - now,
+            });
+        }
+        r.pending = true;
+        Ok(Attempt { guard: self, addr })
+    }
+
+    fn failed(&self, addr: IpAddr) {
+        if !self.enabled() {
+            return;
+        }
+        let now = Instant::now();
+        let mut records = self.records.lock().unwrap();
+        let r = records.entry(addr).or_insert(Record {
+            failures: 0,
+            until: now,
+            banned: false,
+            pending: false,
+        });
+        r.failures = r.failures.saturating_add(1);
+        r.banned = self.policy.ban_after.is_some_and(|n| r.failures >= n);
+        let wait = if r.banned {
+            self.policy.ban_time
+        } else {
+            self.policy.backoff
+                .saturating_mul(1 << (r.failures - 1).min(16))
+                .min(self.policy.max_backoff)
+        };
+        r.until = now + wait;
+
+        if r.banned {
+            log!("banning {} for {:?} after {} authentication failures",
+                addr, wait, r.failures);
+        }
+    }
+
+    fn succeeded(&self, addr: IpAddr) {
+        self.records.lock().unwrap().remove(&addr);
+    }
+
+    /*
+     * The attempt from "addr" is over, whatever its outcome.  An address
+     * that has not failed needs no record once nothing is pending.
+     */
+    fn finished(&self, addr: IpAddr) {
+        let mut records = self.records.lock().unwrap();
+        if let Some(r) = records.get_mut(&addr) {
+            r.pending = false;
+            if r.failures == 0 {
+                records.remove(&addr);
+            }
+        }
+    }
+
+    /*
+     * Lift any restriction on "addr", returning whether there was one.
+     */
+    pub fn forgive(&self, addr: IpAddr) -> bool {
+        self.records.lock().unwrap().remove(&addr).is_some()
+    }
+
+    /*
+     * Addresses with recent failures, for the admin interface.
+     */
+    pub fn describe(&self) -> serde_json::Value {
+        let now = Instant::now();
+        let records = self.records.lock().unwrap();
+        records.iter()
+            .map(|(addr, r)| serde_json::json!({
+                "addr": addr.to_string(),
+                "failures": r.failures,
+                "banned": r.banned,
+                "pending": r.pending,
+                "wait_secs": r.until.saturating_duration_since(now).as_secs(),
+            }))
+            .collect()
+    }
+}
+
+/*
+ * An authentication attempt that Guard::check() has allowed.  Until it is
+ * resolved, other connections from the same address are refused.  Dropping
+ * it without reporting an outcome, as when the connection is lost part way
+ * through the handshake, ends the attempt without counting it as a failure.
+ */
+pub struct Attempt<'a> {
+    guard: &'a Guard,
+    addr: IpAddr,
+}
+
+impl Attempt<'_> {
+    pub fn failed(self) {
+        self.guard.failed(self.addr);
+    }
+
+    pub fn succeeded(self) {
+        self.guard.succeeded(self.addr);
+    }
+}
+
+impl std::fmt::Debug for Attempt<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Attempt").field("addr", &self.addr).finish()
+    }
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        self.guard.finished(self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    fn guard() -> Guard {
+        Guard::new(Policy {
+            backoff: Duration::from_secs(10),
+            max_backoff: Duration::from_secs(40),
+            ban_after: Some(5),
+            ban_time: Duration::from_secs(1000),
+        })
+    }
+
+    /*
+     * Fail one attempt, returning the refusal that follows it, and then let
+     * the wait pass as if the client had sat it out.
+     */
+    fn fail(g: &Guard) -> Refusal {
+        g.check(ADDR).unwrap().failed();
+        let r = g.check(ADDR).unwrap_err();
+        g.records.lock().unwrap().get_mut(&ADDR).unwrap().until =
+            Instant::now();
+        r
+    }
+
+    fn secs(r: &Refusal) -> u64 {
+        /*
+         * The wait has begun to elapse by the time we see it.
+         */
+        r.wait.as_secs_f64().round() as u64
+    }
+
+    #[test]
+    fn backoff() {
+        let g = guard();
+        let waits = (0..4).map(|_| fail(&g)).collect::<Vec<_>>();
+        assert!(waits.iter().all(|r| !r.banned && !r.pending));
+        assert_eq!(waits.iter().map(secs).collect::<Vec<_>>(),
+            [10, 20, 40, 40]);
+
+        g.check(ADDR).unwrap().succeeded();
+        assert_eq!(secs(&fail(&g)), 10);
+    }
+
+    #[test]
+    fn ban() {
+        let g = guard();
+        for _ in 0..4 {
+            assert!(!fail(&g).banned);
+        }
+        let r = fail(&g);
+        assert!(r.banned);
+        assert_eq!(secs(&r), 1000);
+
+        assert!(g.forgive(ADDR));
+        assert!(g.check(ADDR).is_ok());
+    }
+
+    #[test]
+    fn concurrent() {
+        let g = guard();
+        let other = IpAddr::V6(std::net::Ipv6Addr::LOCALHOST);
+
+        let a = g.check(ADDR).unwrap();
+        let r = g.check(ADDR).unwrap_err();
+        assert!(r.pending);
+        let b = g.check(other).unwrap();
+
+        /*
+         * Once the pending attempt fails, the next must wait out the
+         * backoff instead:
+         */
+        a.failed();
+        let r = g.check(ADDR).unwrap_err();
+        assert!(!r.pending);
+        assert_eq!(secs(&r), 10);
+
+        /*
+         * An attempt abandoned without an outcome neither counts nor blocks
+         * the next:
+         */
+        drop(b);
+        drop(g.check(other).unwrap());
+        assert!(g.check(other).is_ok());
+        assert!(!g.records.lock().unwrap().contains_key(&other));
+    }
+
+    #[test]
+    fn disabled() {
+        let g = Guard::new(Policy {
+            backoff: Duration::ZERO,
+            ..Policy::default()
+        });
+        let a = g.check(ADDR).unwrap();
+        g.check(ADDR).unwrap().failed();
+        a.failed();
+        assert!(g.check(ADDR).is_ok());
+    }
+}
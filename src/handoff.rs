@@ -0,0 +1,280 @@
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::Path;
+use std::ptr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Interest};
+use tokio::net::UnixStream;
+
+use crate::listen::{Listener, Transport};
+use crate::server::Server;
+
+/*
+ * A new jvnc process can take over the listening sockets of a running one,
+ * so that it can be upgraded without refusing connections in the meantime.
+ * The new process connects to the admin socket of the old one and asks for a
+ * handoff; the old process replies with its listening sockets, passed as
+ * SCM_RIGHTS, along with a description of itself in JSON.  Once the new
+ * process has started and is about to accept, it says so with a line of its
+ * own.  Only then does the old process stop accepting, carrying on with the
+ * connections it already has, and exit once they have all finished.
+ *
+ * Until then both processes hold the listening sockets, and the old one
+ * carries on accepting, so that nobody is refused if the new process fails
+ * to start.  If it does not say it is accepting within ACK_WAIT, we assume
+ * that it has failed, and keep the sockets to ourselves.
+ */
+
+/*
+ * The most listening sockets we expect to receive:
+ */
+const MAX_FDS: usize = 64;
+
+const ACK_WAIT: Duration = Duration::from_secs(30);
+const ACK: &str = "accepting";
+
+/*
+ * What a new process learns from the old one:
+ */
+pub struct Takeover {
+    pub listeners: Vec<Listener>,
+    /*
+     * The highest session number the old process has used, so that ours
+     * follow on without reuse:
+     */
+    pub last_session: u64,
+    /*
+     * The sessions the old process is still serving, as reported by the
+     * admin interface:
+     */
+    pub sessions: Vec<serde_json::Value>,
+    sock: StdUnixStream,
+}
+
+impl Takeover {
+    /*
+     * Tell the old process that we are ready to accept, so that it may stop.
+     * Should it have gone already, there is nobody left to tell, and no harm
+     * done.
+     */
+    pub fn accepting(mut self) {
+        if let Err(e) = self.sock.write_all(format!("{}\n", ACK).as_bytes()) {
+            log!("warning: could not tell the old process we are accepting: \
+                {}", e);
+        }
+    }
+}
+
+/*
+ * Give our listening sockets to the process on the other end of "sock",
+ * and stop accepting once it has started to.
+ */
+pub async fn give(server: &Server, sock: &mut UnixStream) -> Result<()> {
+    let fds = server.listener_fds()?;
+    if fds.is_empty() {
+        bail!("no listening sockets to hand off");
+    }
+    if fds.len() > MAX_FDS {
+        bail!("{} listening sockets are too many to hand off; the most is {}",
+            fds.len(), MAX_FDS);
+    }
+    let raw: Vec<RawFd> = fds.iter().map(|(fd, _)| fd.as_raw_fd()).collect();
+    let data = serde_json::to_vec(&json!({
+        "transports": fds.iter()
+            .map(|(_, t)| t.name())
+            .collect::<Vec<_>>(),
+        "last_session": server.last_session(),
+        "sessions": server.sessions().iter()
+            .map(|(_, s)| s.describe())
+            .collect::<Vec<_>>(),
+    }))?;
+
+    let sent = loop {
+        sock.writable().await?;
+        match sock.try_io(Interest::WRITABLE,
+            || send_fds(sock.as_raw_fd(), &raw, &data))
+        {
+            Ok(n) => break n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e.into()),
+        }
+    };
+    sock.write_all(&data[sent..]).await?;
+    sock.shutdown().await?;
+    log!("handed off {} listening sockets", fds.len());
+
+    /*
+     * Anything that goes wrong from here on is the new process's failure to
+     * start, not ours, and there is no way left to report it to it.
+     */
+    let mut line = String::new();
+    let mut r = BufReader::new(sock);
+    let ack = r.read_line(&mut line);
+    match tokio::time::timeout(ACK_WAIT, ack).await {
+        Ok(Ok(_)) if line.trim() == ACK => server.stop_accepting(),
+        Ok(Ok(_)) => {
+            log!("the new process did not start; still accepting");
+        }
+        Ok(Err(e)) => {
+            log!("the new process did not start ({}); still accepting", e);
+        }
+        Err(_) => {
+            log!("the new process has not started after {:?}; still \
+                accepting", ACK_WAIT);
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Ask the process with the admin socket at "path" for its listening sockets.
+ */
+pub fn take(path: &Path) -> Result<Takeover> {
+    let mut sock = StdUnixStream::connect(path)
+        .with_context(|| format!("connect to {:?}", path))?;
+    sock.write_all(b"handoff\n")?;
+
+    let mut buf = vec![0u8; 4096];
+    let (n, fds) = recv_fds(sock.as_raw_fd(), &mut buf)?;
+    buf.truncate(n);
+    if fds.is_empty() {
+        /*
+         * The old process has refused, and the reply explains why.
+         */
+        sock.read_to_end(&mut buf)?;
+        bail!("handoff refused: {}", String::from_utf8_lossy(&buf).trim());
+    }
+    sock.read_to_end(&mut buf)?;
+
+    let v: serde_json::Value = serde_json::from_slice(&buf)
+        .context("handoff description")?;
+    /*
+     * A process from before listeners had transports sends none, and all of
+     * its listeners speak plain RFB.
+     */
+    let transports = v["transports"].as_array().cloned().unwrap_or_default();
+    let listeners = fds.into_iter()
+        .enumerate()
+        .map(|(i, fd)| {
+            let t = transports.get(i)
+                .and_then(|t| t.as_str())
+                .map_or(Some(Transport::Rfb), Transport::from_name)
+                .ok_or_else(|| anyhow!("unknown transport {}", transports[i]))?;
+            Ok(Listener::from_fd(fd, t)?)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Takeover {
+        listeners,
+        last_session: v["last_session"].as_u64().unwrap_or(0),
+        sessions: v["sessions"].as_array().cloned().unwrap_or_default(),
+        sock,
+    })
+}
+
+fn send_fds(sock: RawFd, fds: &[RawFd], data: &[u8]) -> io::Result<usize> {
+    if data.is_empty() || fds.len() > MAX_FDS {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "descriptors must be sent with data, and no more than MAX_FDS"));
+    }
+
+    let len = mem::size_of_val(fds);
+    let space = unsafe { libc::CMSG_SPACE(len as u32) } as usize;
+    /*
+     * The control buffer must be aligned for a cmsghdr.
+     */
+    let mut control = vec![0u64; space.div_ceil(8)];
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+
+    let n = unsafe {
+        let c = libc::CMSG_FIRSTHDR(&msg);
+        (*c).cmsg_level = libc::SOL_SOCKET;
+        (*c).cmsg_type = libc::SCM_RIGHTS;
+        (*c).cmsg_len = libc::CMSG_LEN(len as u32) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr() as *const u8,
+            libc::CMSG_DATA(c), len);
+        libc::sendmsg(sock, &msg, 0)
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+fn recv_fds(sock: RawFd, buf: &mut [u8]) -> io::Result<(usize, Vec<OwnedFd>)> {
+    let space = unsafe {
+        libc::CMSG_SPACE((MAX_FDS * mem::size_of::<RawFd>()) as u32)
+    } as usize;
+    let mut control = vec![0u64; space.div_ceil(8)];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+
+    let n = unsafe { libc::recvmsg(sock, &mut msg, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut fds = Vec::new();
+    unsafe {
+        let mut c = libc::CMSG_FIRSTHDR(&msg);
+        while !c.is_null() {
+            if (*c).cmsg_level == libc::SOL_SOCKET
+                && (*c).cmsg_type == libc::SCM_RIGHTS
+            {
+                let data = libc::CMSG_DATA(c) as *const RawFd;
+                let count = ((*c).cmsg_len as usize
+                    - libc::CMSG_LEN(0) as usize) / mem::size_of::<RawFd>();
+                for i in 0..count {
+                    let fd = ptr::read_unaligned(data.add(i));
+                    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                    fds.push(OwnedFd::from_raw_fd(fd));
+                }
+            }
+            c = libc::CMSG_NXTHDR(&msg, c);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::other("too many descriptors in handoff"));
+    }
+    Ok((n as usize, fds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fds() {
+        let (a, b) = StdUnixStream::pair().unwrap();
+
+        let many = vec![a.as_raw_fd(); MAX_FDS + 1];
+        let e = send_fds(a.as_raw_fd(), &many, b"x").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+        let n = send_fds(a.as_raw_fd(), &many[..2], b"xy").unwrap();
+        assert_eq!(n, 2);
+        let mut buf = [0u8; 8];
+        let (n, fds) = recv_fds(b.as_raw_fd(), &mut buf).unwrap();
+        assert_eq!((&buf[..n], fds.len()), (&b"xy"[..], 2));
+    }
+}
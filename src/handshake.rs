@@ -0,0 +1,127 @@
+use std::io::ErrorKind;
+
+/*
+ * How a connection's handshake, from the ProtocolVersion exchange through to
+ * ClientInit, turned out.  Counting these lets an operator tell scanners and
+ * port probes, which mostly send a bad version or nothing at all, from real
+ * clients that cannot agree with us on security or fail to authenticate.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Outcome {
+    Completed,
+    /*
+     * The client went away without finishing:
+     */
+    Closed,
+    /*
+     * The client did not send a ProtocolVersion we understand:
+     */
+    BadVersion,
+    /*
+     * We could not agree on a security type or VeNCrypt subtype:
+     */
+    Unsupported,
+    AuthFailed,
+    /*
+     * The client was refused: its address after too many failures, a
+     * WebSocket from a page we do not trust, or by the access policy:
+     */
+    Refused,
+    Timeout,
+    ProtocolError,
+}
+
+impl Outcome {
+    pub const ALL: [Outcome; 8] = [
+        Outcome::Completed,
+        Outcome::Closed,
+        Outcome::BadVersion,
+        Outcome::Unsupported,
+        Outcome::AuthFailed,
+        Outcome::Refused,
+        Outcome::Timeout,
+        Outcome::ProtocolError,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Outcome::Completed => "completed",
+            Outcome::Closed => "closed",
+            Outcome::BadVersion => "bad_version",
+            Outcome::Unsupported => "unsupported",
+            Outcome::AuthFailed => "auth_failed",
+            Outcome::Refused => "refused",
+            Outcome::Timeout => "timeout",
+            Outcome::ProtocolError => "protocol_error",
+        }
+    }
+
+    /*
+     * Work out why a handshake failed from the error it failed with.  Errors
+     * we raise ourselves say so; otherwise, errors from the connection mean
+     * the client went away, and anything else is a protocol error.
+     */
+    pub fn of(e: &anyhow::Error) -> Outcome {
+        if let Some(Failed(o, _)) = e.downcast_ref() {
+            return *o;
+        }
+        if e.is::<crate::security::AuthFailed>() {
+            return Outcome::AuthFailed;
+        }
+        if e.is::<tokio::time::error::Elapsed>() {
+            return Outcome::Timeout;
+        }
+        match e.downcast_ref::<std::io::Error>().map(|e| e.kind()) {
+            Some(ErrorKind::UnexpectedEof)
+            | Some(ErrorKind::ConnectionReset)
+            | Some(ErrorKind::ConnectionAborted)
+            | Some(ErrorKind::BrokenPipe) => Outcome::Closed,
+            _ => Outcome::ProtocolError,
+        }
+    }
+}
+
+/*
+ * A handshake failure whose outcome is known where it is raised.
+ */
+#[derive(Debug)]
+pub struct Failed(pub Outcome, pub String);
+
+impl std::fmt::Display for Failed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.1)
+    }
+}
+
+impl std::error::Error for Failed {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn classify() {
+        let e = anyhow!(Failed(Outcome::BadVersion, "GET /".to_string()));
+        assert_eq!(Outcome::of(&e), Outcome::BadVersion);
+        assert_eq!(e.to_string(), "GET /");
+
+        let e = anyhow!(crate::security::AuthFailed("no".to_string()));
+        assert_eq!(Outcome::of(&e), Outcome::AuthFailed);
+
+        let e = anyhow::Error::from(
+            std::io::Error::from(ErrorKind::UnexpectedEof));
+        assert_eq!(Outcome::of(&e), Outcome::Closed);
+
+        let e = anyhow!("unexpected frame");
+        assert_eq!(Outcome::of(&e), Outcome::ProtocolError);
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let e: anyhow::Error = tokio::time::timeout(
+            std::time::Duration::from_millis(1),
+            std::future::pending::<()>()).await.unwrap_err().into();
+        assert_eq!(Outcome::of(&e), Outcome::Timeout);
+    }
+}
@@ -0,0 +1,122 @@
+use anyhow::{bail, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/*
+ * Just enough HTTP/1.1 to answer the one request a browser makes before a
+ * WebSocket takes over the connection.  There is no keep-alive: each
+ * connection carries one request, and is then upgraded or closed.
+ */
+
+/*
+ * The most we will read of a request before giving up on it, which is far
+ * more than any browser sends for an upgrade:
+ */
+const MAX_REQUEST: usize = 8 * 1024;
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /*
+     * Whether the comma-separated header "name" includes "token", which is
+     * compared without regard to case.
+     */
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.header(name).is_some_and(|v| {
+            v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token))
+        })
+    }
+}
+
+/*
+ * Read a request up to the blank line that ends its headers.  Whatever the
+ * client sent after that is returned with it.
+ */
+pub async fn read_request<S>(sock: &mut S) -> Result<(Request, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = Vec::new();
+    let end = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+        if buf.len() > MAX_REQUEST {
+            bail!("HTTP request too long");
+        }
+        let mut chunk = [0u8; 1024];
+        let n = sock.read(&mut chunk).await?;
+        if n == 0 {
+            bail!(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+    let rest = buf.split_off(end + 4);
+
+    let Ok(head) = std::str::from_utf8(&buf[..end]) else {
+        bail!("HTTP request is not UTF-8");
+    };
+    let mut lines = head.split("\r\n");
+    let line = lines.next().unwrap_or_default();
+    let (method, path) = match line.split(' ').collect::<Vec<_>>()[..] {
+        [method, path, version] if version.starts_with("HTTP/1.") => {
+            (method.to_string(), path.to_string())
+        }
+        _ => bail!("invalid HTTP request line {:?}", line),
+    };
+    let headers = lines
+        .map(|l| match l.split_once(':') {
+            Some((n, v)) => Ok((n.trim().to_string(), v.trim().to_string())),
+            None => bail!("invalid HTTP header {:?}", l),
+        })
+        .collect::<Result<_>>()?;
+
+    Ok((Request { method, path, headers }, rest))
+}
+
+/*
+ * Send a response with a short plain text body, after which the connection
+ * should be closed.  Each of "headers" is a complete header line.
+ */
+pub async fn respond<S>(sock: &mut S, status: &str, headers: &[&str],
+    body: &str) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    send(sock, status, headers, "text/plain; charset=utf-8",
+        format!("{}\n", body).as_bytes(), false).await
+}
+
+/*
+ * Send a response with "body" as content of the given type, or only the
+ * headers for it if "head" is set, as in reply to a HEAD request.
+ */
+pub async fn send<S>(sock: &mut S, status: &str, headers: &[&str],
+    content_type: &str, body: &[u8], head: bool) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut r = format!("HTTP/1.1 {}\r\nConnection: close\r\n\
+        Content-Type: {}\r\nContent-Length: {}\r\n",
+        status, content_type, body.len());
+    for h in headers {
+        r.push_str(h);
+        r.push_str("\r\n");
+    }
+    r.push_str("\r\n");
+    sock.write_all(r.as_bytes()).await?;
+    if !head {
+        sock.write_all(body).await?;
+    }
+    sock.flush().await?;
+    Ok(())
+}
@@ -0,0 +1,57 @@
+use crate::touch::TouchEvent;
+
+/*
+ * Keyboard, pointer and touch input from a client, for the application's
+ * input handler.  Positions are in the coordinates of the source, whatever
+ * scale the client is viewing it at, and of the whole of it when only part
+ * is shared.  Nothing from view-only clients is
+ * delivered, nor keys that the server itself consumes, as for pointer
+ * emulation.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    /*
+     * A key pressed or released, by X keysym.  Clients that send QEMU
+     * extended key events also give the XT scancode of the physical key,
+     * where they know it, in which case the keysym may be zero.
+     */
+    Key {
+        down: bool,
+        keysym: u32,
+        scancode: Option<u32>,
+    },
+    /*
+     * The pointer position and the buttons held, with the first button in
+     * the lowest bit.  Scroll wheels appear as buttons four and five.
+     */
+    Pointer {
+        buttons: u8,
+        x: u16,
+        y: u16,
+    },
+    Touch(TouchEvent),
+}
+
+impl InputEvent {
+    /*
+     * The same event, with any position moved right by "dx" and down by "dy".
+     */
+    pub fn offset(self, dx: usize, dy: usize) -> InputEvent {
+        let add = |v: u16, d: usize| {
+            (v as usize + d).min(u16::MAX as usize) as u16
+        };
+        match self {
+            InputEvent::Key { .. } => self,
+            InputEvent::Pointer { buttons, x, y } => InputEvent::Pointer {
+                buttons,
+                x: add(x, dx),
+                y: add(y, dy),
+            },
+            InputEvent::Touch(t) => InputEvent::Touch(TouchEvent {
+                x: add(t.x, dx),
+                y: add(t.y, dy),
+                ..t
+            }),
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use serde_json::json;
+
+/*
+ * Bucket upper bounds, in milliseconds.  Anything slower than the last bound
+ * goes in a final overflow bucket, reported with no bound.
+ */
+const BOUNDS: [u64; 12] = [1, 2, 4, 8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/*
+ * A histogram of delays, with buckets that double in width so that both
+ * snappy and sluggish connections are described usefully.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    buckets: [u64; BOUNDS.len() + 1],
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl Histogram {
+    pub fn record(&mut self, d: Duration) {
+        let ms = d.as_millis();
+        let i = BOUNDS.iter()
+            .position(|b| ms < *b as u128)
+            .unwrap_or(BOUNDS.len());
+        self.buckets[i] += 1;
+        self.count += 1;
+        self.total += d;
+        self.max = self.max.max(d);
+    }
+
+    /*
+     * Add the samples from another histogram to this one.
+     */
+    pub fn merge(&mut self, other: &Histogram) {
+        for (a, b) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *a += b;
+        }
+        self.count += other.count;
+        self.total += other.total;
+        self.max = self.max.max(other.max);
+    }
+
+    /*
+     * The upper bound of the bucket that contains the given fraction of all
+     * samples, or None for the overflow bucket.
+     */
+    fn percentile(&self, p: f64) -> Option<u64> {
+        let want = (self.count as f64 * p).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= want {
+                return BOUNDS.get(i).copied();
+            }
+        }
+        None
+    }
+
+    pub fn describe(&self) -> serde_json::Value {
+        if self.count == 0 {
+            return json!({ "count": 0 });
+        }
+
+        let mean = self.total / self.count as u32;
+        json!({
+            "count": self.count,
+            "mean_ms": mean.as_secs_f64() * 1000.0,
+            "max_ms": self.max.as_secs_f64() * 1000.0,
+            "p50_ms": self.percentile(0.5),
+            "p90_ms": self.percentile(0.9),
+            "p99_ms": self.percentile(0.99),
+            "buckets": self.buckets.iter()
+                .enumerate()
+                .filter(|(_, n)| **n > 0)
+                .map(|(i, n)| json!({ "lt_ms": BOUNDS.get(i), "count": n }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
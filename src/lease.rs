@@ -0,0 +1,183 @@
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+
+use serde_json::json;
+
+use crate::framebuffer::Framebuffer;
+use crate::source::Rect;
+
+/*
+ * Producers that share a framebuffer, such as a capture backend and something
+ * drawing annotations over it, can each take a lease on the area they are
+ * about to draw.  Leases on overlapping areas are granted one at a time, so
+ * that one producer never draws over another part way through; leases on
+ * disjoint areas are held at once.  Leases are advisory: drawing without one
+ * works as it always has.
+ *
+ * A thread that already holds a lease must not wait for another that
+ * overlaps it, or it will wait forever.
+ */
+pub struct Leases {
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    next: u64,
+    held: Vec<Held>,
+    /*
+     * Leases granted, and how many of those had to wait for another to be
+     * released first:
+     */
+    granted: u64,
+    contended: u64,
+    waiting: usize,
+}
+
+struct Held {
+    id: u64,
+    rect: Rect,
+    holder: Option<String>,
+    since: Instant,
+}
+
+impl Leases {
+    pub(crate) fn new() -> Leases {
+        Leases {
+            state: Mutex::new(State::default()),
+            released: Condvar::new(),
+        }
+    }
+
+    /*
+     * Take a lease on "r", waiting for any that overlap it to be released if
+     * "wait" is set, and otherwise giving up.
+     */
+    pub(crate) fn acquire(&self, r: Rect, wait: bool) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        let busy = |state: &State| {
+            state.held.iter().any(|h| h.rect.intersect(&r).is_some())
+        };
+
+        if busy(&state) {
+            if !wait {
+                return None;
+            }
+            state.contended += 1;
+            state.waiting += 1;
+            while busy(&state) {
+                state = self.released.wait(state).unwrap();
+            }
+            state.waiting -= 1;
+        }
+
+        state.next += 1;
+        state.granted += 1;
+        let id = state.next;
+        state.held.push(Held {
+            id,
+            rect: r,
+            holder: std::thread::current().name().map(str::to_string),
+            since: Instant::now(),
+        });
+        Some(id)
+    }
+
+    fn release(&self, id: u64) {
+        self.state.lock().unwrap().held.retain(|h| h.id != id);
+        self.released.notify_all();
+    }
+
+    /*
+     * The leases now held, and by which threads, for debugging.
+     */
+    pub fn describe(&self) -> serde_json::Value {
+        let state = self.state.lock().unwrap();
+        json!({
+            "held": state.held.iter().map(|h| json!({
+                "id": h.id,
+                "rect": [h.rect.x, h.rect.y, h.rect.width, h.rect.height],
+                "holder": h.holder,
+                "age_ms": h.since.elapsed().as_secs_f64() * 1000.0,
+            })).collect::<Vec<_>>(),
+            "waiting": state.waiting,
+            "granted": state.granted,
+            "contended": state.contended,
+        })
+    }
+}
+
+/*
+ * The right to draw in an area of the framebuffer, until dropped.
+ */
+pub struct Lease<'a> {
+    fb: &'a Framebuffer,
+    id: u64,
+    rect: Rect,
+}
+
+impl<'a> Lease<'a> {
+    pub(crate) fn new(fb: &'a Framebuffer, id: u64, rect: Rect) -> Lease<'a> {
+        Lease { fb, id, rect }
+    }
+
+    pub fn rect(&self) -> Rect {
+        self.rect
+    }
+
+    /*
+     * Set the pixel at (x, y), in framebuffer coordinates, which must lie
+     * within the leased area.
+     */
+    #[inline]
+    pub fn put(&self, x: usize, y: usize, red: u8, green: u8, blue: u8) {
+        let r = &self.rect;
+        assert!(x >= r.x && x < r.x + r.width && y >= r.y
+            && y < r.y + r.height, "({}, {}) outside lease {:?}", x, y, r);
+        self.fb.put(x, y, red, green, blue);
+    }
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        self.fb.leases().release(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn overlap() {
+        let fb = Arc::new(Framebuffer::new(100, 100).unwrap());
+        let left = fb.lock_rect(Rect::new(0, 0, 50, 100));
+        let right = fb.try_lock_rect(Rect::new(50, 0, 50, 100));
+        assert!(right.is_some());
+        assert!(fb.try_lock_rect(Rect::new(40, 40, 20, 20)).is_none());
+
+        /*
+         * A producer that wants the middle waits for both sides.
+         */
+        let waiter = {
+            let fb = Arc::clone(&fb);
+            std::thread::spawn(move || {
+                let l = fb.lock_rect(Rect::new(40, 40, 20, 20));
+                l.put(45, 45, 1, 2, 3);
+            })
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        left.put(0, 0, 4, 5, 6);
+        drop(left);
+        drop(right);
+        waiter.join().unwrap();
+
+        assert_eq!(fb.get(45, 45), (1, 2, 3));
+        let d = fb.leases().describe();
+        assert_eq!(d["held"], json!([]));
+        assert_eq!(d["granted"], 3);
+    }
+}
@@ -0,0 +1,171 @@
+/*
+ * jvnc: a VNC server that an application can embed as its remote display.
+ *
+ * The application builds a Server with ServerBuilder, draws into the
+ * framebuffer it holds, installs whatever handlers it needs, and runs
+ * serve() to accept connections:
+ *
+ *     let server = jvnc::ServerBuilder::new()
+ *         .geometry(1024, 768)
+ *         .name("my application")
+ *         .listen(["127.0.0.1:5900"])
+ *         .build()?;
+ *     jvnc::serve(&server).await?;
+ */
+
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use tokio::signal::unix::{signal, SignalKind};
+
+#[macro_use]
+pub mod log;
+#[macro_use]
+mod wire;
+
+pub mod admin;
+mod ard;
+mod builder;
+mod client;
+pub mod config;
+mod console;
+mod compress;
+mod clipboard;
+pub mod cursor;
+mod debounce;
+mod diag;
+mod encoding;
+mod events;
+pub mod framebuffer;
+pub mod guard;
+mod handoff;
+mod handshake;
+mod http;
+mod input;
+mod latency;
+pub mod lease;
+mod listen;
+mod mousekeys;
+pub mod notify;
+mod outbox;
+mod pacing;
+mod pool;
+mod prefs;
+mod reverse;
+mod rfb;
+pub mod security;
+pub mod server;
+pub mod soak;
+pub mod source;
+mod ticker;
+pub mod tier;
+mod tls;
+mod trace;
+mod touch;
+mod upstream;
+pub mod vncauth;
+mod web;
+mod websocket;
+
+pub use builder::ServerBuilder;
+pub use config::Config;
+pub use encoding::pseudo::Leds;
+pub use framebuffer::Framebuffer;
+pub use input::InputEvent;
+pub use server::{Server, Session};
+pub use ticker::Ticker;
+pub use source::Source;
+pub use touch::{TouchEvent, TouchPhase};
+
+/*
+ * Accept connections for "server" on the addresses in its configuration, or
+ * on those taken over from another process, until every listening socket
+ * has failed or been handed off, or until the process receives SIGINT or
+ * SIGTERM.  The admin socket and console are started too, if configured.
+ */
+pub async fn serve(server: &Arc<Server>) -> Result<()> {
+    let config = &server.config;
+    let mut takeover = None;
+    let (listeners, failed) = match &config.takeover {
+        Some(path) => {
+            let mut t = handoff::take(path)?;
+            log!("took over {} listening sockets from {:?}; {} sessions \
+                remain there", t.listeners.len(), path, t.sessions.len());
+            server.resume_sessions(t.last_session);
+            let listeners = std::mem::take(&mut t.listeners);
+            takeover = Some(t);
+            (listeners, Vec::new())
+        }
+        None if config.listen.is_empty() && !config.connect.is_empty() => {
+            (Vec::new(), Vec::new())
+        }
+        None => listen::bind(&config.listen, config.listen_partial)?,
+    };
+    /*
+     * Pre-shared keys are demanded of every client, but a client that has
+     * already begun TLS with our certificate could not then use them.
+     */
+    if listeners.iter().any(|l| l.transport() == listen::Transport::Tls) {
+        if server.tls.is_none() {
+            bail!("rfbs:// listeners need a certificate");
+        }
+        if server.psk.is_some() {
+            bail!("rfbs:// listeners cannot be used with pre-shared keys");
+        }
+    }
+    let bound = listeners.iter()
+        .map(|l| l.describe())
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    /*
+     * Watch for the desktop becoming idle.
+     */
+    let watched = Arc::clone(server);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(watched.check_idle()).await;
+        }
+    });
+    if let Some(path) = &config.admin {
+        let replace = config.takeover.as_ref() == Some(path);
+        admin::listen(server, path, replace)?;
+    }
+    if config.console {
+        console::spawn(server);
+    }
+    if let Some(target) = &config.upstream {
+        upstream::spawn(server, target);
+    }
+    for target in &config.connect {
+        reverse::spawn(server, target)?;
+    }
+
+    log!("{}", server.capabilities(&bound, &failed));
+
+    /*
+     * Stop when the process is asked to, or somebody asks the server, giving
+     * clients a little while to receive what they have been sent.
+     */
+    let mut int = signal(SignalKind::interrupt())?;
+    let mut term = signal(SignalKind::terminate())?;
+    let quit = Arc::clone(server);
+    let shutdown = async move {
+        tokio::select! {
+            _ = int.recv() => log!("interrupted; shutting down"),
+            _ = term.recv() => log!("terminated; shutting down"),
+            _ = quit.quit_requested() => (),
+        }
+    };
+
+    /*
+     * Everything that might have kept us from starting has been done, so the
+     * process we took over from may stop accepting.
+     */
+    if let Some(t) = takeover {
+        t.accepting();
+    }
+
+    server.serve(listeners, |server, socket, session, notes| async move {
+        client::serve_client(&server, socket, &session, notes).await
+    }, shutdown, config.shutdown_grace).await
+}
@@ -0,0 +1,524 @@
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use anyhow::{anyhow, bail, Result};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+use crate::websocket::WebSocket;
+
+/*
+ * Listen addresses with this prefix are the paths of Unix sockets, such as
+ * "unix:/run/jvnc/display.sock", for clients on this host and proxies in
+ * front of us.
+ */
+const UNIX_PREFIX: &str = "unix:";
+
+/*
+ * Where a connection came from, or where a listener is.  Unix sockets that
+ * connect to us are almost never bound to a path of their own, so a
+ * connection accepted on one is known by the path of the listener instead.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Addr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Addr {
+    /*
+     * The address to hold a client to account by, as when counting failed
+     * logins.  Everything arriving on a Unix socket is from this host.
+     */
+    pub fn ip(&self) -> IpAddr {
+        match self {
+            Addr::Tcp(sa) => sa.ip(),
+            Addr::Unix(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        }
+    }
+
+    pub fn tcp(&self) -> Option<SocketAddr> {
+        match self {
+            Addr::Tcp(sa) => Some(*sa),
+            Addr::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Addr::Tcp(sa) => write!(f, "{}", sa),
+            Addr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/*
+ * What a listener speaks, named by a prefix to its address.  Without one,
+ * connections speak RFB from the first byte.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Rfb,
+    /*
+     * RFB in the binary messages of a WebSocket, as used by noVNC and other
+     * viewers that run in a browser:
+     */
+    WebSocket,
+    /*
+     * A WebSocket as well, but requests that are not for one are answered
+     * with the pages of the viewer itself:
+     */
+    Http,
+    /*
+     * RFB inside TLS, begun as soon as the connection is accepted, with the
+     * certificate in the configuration:
+     */
+    Tls,
+    /*
+     * Plain RFB on a connection we made to a listening viewer, rather than
+     * one we accepted:
+     */
+    Reverse,
+    /*
+     * The same, but through a repeater, which holds the connection until a
+     * viewer asks it for us by our ID:
+     */
+    Repeater,
+}
+
+impl Transport {
+    const ALL: [Transport; 6] = [
+        Transport::Rfb,
+        Transport::WebSocket,
+        Transport::Http,
+        Transport::Tls,
+        Transport::Reverse,
+        Transport::Repeater,
+    ];
+
+    /*
+     * The prefix for a listener with this transport, if there can be one:
+     */
+    pub fn prefix(&self) -> Option<&'static str> {
+        match self {
+            Transport::Rfb => Some("rfb://"),
+            Transport::WebSocket => Some("ws://"),
+            Transport::Http => Some("http://"),
+            Transport::Tls => Some("rfbs://"),
+            Transport::Reverse | Transport::Repeater => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Transport::Rfb => "rfb",
+            Transport::WebSocket => "websocket",
+            Transport::Http => "http",
+            Transport::Tls => "tls",
+            Transport::Reverse => "reverse",
+            Transport::Repeater => "repeater",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Transport> {
+        Transport::ALL.iter().copied().find(|t| t.name() == name)
+    }
+
+    /*
+     * Split the prefix, if any, from a listen address.
+     */
+    fn split(addr: &str) -> (Transport, &str) {
+        Transport::ALL.iter().copied()
+            .find_map(|t| Some((t, addr.strip_prefix(t.prefix()?)?)))
+            .unwrap_or((Transport::Rfb, addr))
+    }
+}
+
+/*
+ * A socket on which we accept connections:
+ */
+pub struct Listener {
+    sock: Sock,
+    transport: Transport,
+}
+
+enum Sock {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub fn local_addr(&self) -> io::Result<Addr> {
+        Ok(match &self.sock {
+            Sock::Tcp(l) => Addr::Tcp(l.local_addr()?),
+            Sock::Unix(_, path) => Addr::Unix(path.clone()),
+        })
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /*
+     * The listener as it would be written in the configuration.
+     */
+    pub fn describe(&self) -> io::Result<String> {
+        let addr = self.local_addr()?;
+        Ok(match self.transport.prefix() {
+            Some(prefix) if self.transport != Transport::Rfb => {
+                format!("{}{}", prefix, addr)
+            }
+            _ => addr.to_string(),
+        })
+    }
+
+    async fn accept(&self) -> io::Result<(Stream, Addr)> {
+        Ok(match &self.sock {
+            Sock::Tcp(l) => {
+                let (s, sa) = l.accept().await?;
+                (Stream::Tcp(s), Addr::Tcp(sa))
+            }
+            Sock::Unix(l, path) => {
+                let (s, _) = l.accept().await?;
+                (Stream::Unix(s), Addr::Unix(path.clone()))
+            }
+        })
+    }
+
+    /*
+     * Rebuild a listener from a socket handed to us by another process.
+     */
+    pub fn from_fd(fd: OwnedFd, transport: Transport)
+        -> io::Result<Listener>
+    {
+        let local = SockRef::from(&fd).local_addr()?;
+        let sock = if local.domain() == Domain::UNIX {
+            let path = local.as_pathname()
+                .map(Path::to_path_buf)
+                .unwrap_or_default();
+            let l = std::os::unix::net::UnixListener::from(fd);
+            l.set_nonblocking(true)?;
+            Sock::Unix(UnixListener::from_std(l)?, path)
+        } else {
+            let l = std::net::TcpListener::from(fd);
+            l.set_nonblocking(true)?;
+            Sock::Tcp(TcpListener::from_std(l)?)
+        };
+        Ok(Listener { sock, transport })
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match &self.sock {
+            Sock::Tcp(l) => l.as_raw_fd(),
+            Sock::Unix(l, _) => l.as_raw_fd(),
+        }
+    }
+}
+
+/*
+ * A connection accepted on either kind of listener, or one of those once the
+ * client has upgraded it to a WebSocket:
+ */
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    WebSocket(Box<WebSocket<Stream>>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>>
+    {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::WebSocket(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::WebSocket(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>)
+        -> Poll<io::Result<()>>
+    {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            Stream::Unix(s) => Pin::new(s).poll_flush(cx),
+            Stream::WebSocket(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>)
+        -> Poll<io::Result<()>>
+    {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            Stream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+            Stream::WebSocket(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/*
+ * An address we were asked to listen on, but could not:
+ */
+#[derive(Debug)]
+pub struct BindFailure {
+    pub addr: String,
+    pub error: String,
+}
+
+/*
+ * An address from the configuration, once resolved:
+ */
+enum Target {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/*
+ * Bind every address in "addrs", each either a host and port or a Unix
+ * socket path with the "unix:" prefix, and either of those may follow the
+ * prefix of a transport, as in "ws://[::]:5800".  If "partial" is set,
+ * addresses that fail are reported and skipped, as long as at least one
+ * succeeds; otherwise the first failure is fatal.
+ */
+pub fn bind(addrs: &[String], partial: bool)
+    -> Result<(Vec<Listener>, Vec<BindFailure>)>
+{
+    let resolved = addrs.iter()
+        .map(|a| {
+            let (transport, rest) = Transport::split(a);
+            Ok((a, transport, resolve(rest)?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    /*
+     * A wildcard IPv6 socket normally accepts IPv4 connections as well, and
+     * would then collide with an explicit IPv4 listener on the same port.
+     * When both are requested, the IPv6 socket is made IPv6-only.
+     */
+    let v4_ports: Vec<u16> = resolved.iter()
+        .filter_map(|(_, _, t)| match t {
+            Target::Tcp(sa) if sa.is_ipv4() => Some(sa.port()),
+            _ => None,
+        })
+        .collect();
+
+    let mut listeners = Vec::new();
+    let mut failures = Vec::new();
+    for (name, transport, t) in resolved {
+        let res = match &t {
+            Target::Tcp(sa) => {
+                let only_v6 = sa.is_ipv6() && v4_ports.contains(&sa.port());
+                listen(*sa, only_v6).map(Sock::Tcp)
+            }
+            Target::Unix(path) => listen_unix(path)
+                .map(|l| Sock::Unix(l, path.clone())),
+        };
+        match res {
+            Ok(sock) => listeners.push(Listener { sock, transport }),
+            Err(e) => {
+                let error = match &t {
+                    Target::Tcp(sa) => explain(sa, &e),
+                    Target::Unix(_) => e.to_string(),
+                };
+                if !partial {
+                    bail!("listen on {}: {}", name, error);
+                }
+                log!("warning: not listening on {}: {}", name, error);
+                failures.push(BindFailure { addr: name.clone(), error });
+            }
+        }
+    }
+
+    if listeners.is_empty() {
+        bail!("could not listen on any address");
+    }
+    Ok((listeners, failures))
+}
+
+fn resolve(addr: &str) -> Result<Target> {
+    if let Some(path) = addr.strip_prefix(UNIX_PREFIX) {
+        if path.is_empty() {
+            bail!("listen address {:?} has no path", addr);
+        }
+        return Ok(Target::Unix(PathBuf::from(path)));
+    }
+    addr.to_socket_addrs()
+        .map_err(|e| anyhow!("listen address {:?}: {}", addr, e))?
+        .next()
+        .map(Target::Tcp)
+        .ok_or_else(|| anyhow!("listen address {:?} did not resolve", addr))
+}
+
+fn listen(sa: SocketAddr, only_v6: bool) -> std::io::Result<TcpListener> {
+    let s = Socket::new(Domain::for_address(sa), Type::STREAM,
+        Some(Protocol::TCP))?;
+    s.set_reuse_address(true)?;
+    if sa.is_ipv6() {
+        s.set_only_v6(only_v6)?;
+    }
+    s.bind(&sa.into())?;
+    s.listen(1024)?;
+    s.set_nonblocking(true)?;
+    TcpListener::from_std(s.into())
+}
+
+fn listen_unix(path: &Path) -> io::Result<UnixListener> {
+    clear(path)?;
+    UnixListener::bind(path)
+}
+
+/*
+ * Make way for a socket at "path".  A socket left behind by an earlier
+ * instance is removed, but not one that a live process is still listening
+ * on, nor anything else that might be at the path.
+ */
+pub(crate) fn clear(path: &Path) -> io::Result<()> {
+    let Ok(md) = std::fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !md.file_type().is_socket() {
+        return Err(io::Error::new(ErrorKind::AlreadyExists,
+            "the path exists and is not a socket"));
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(ErrorKind::AddrInUse,
+            "another process is listening on the socket"));
+    }
+    std::fs::remove_file(path)
+}
+
+/*
+ * Turn a bind error into something the operator can act on.
+ */
+fn explain(sa: &SocketAddr, e: &std::io::Error) -> String {
+    match e.kind() {
+        ErrorKind::AddrInUse if sa.ip().is_unspecified() => format!(
+            "port {} is already in use; another server may be running, or \
+            an IPv4 and IPv6 wildcard listener on this port may overlap",
+            sa.port()),
+        ErrorKind::AddrInUse => {
+            format!("{} is already in use by another process", sa)
+        }
+        ErrorKind::AddrNotAvailable => {
+            format!("{} is not an address of this host", sa.ip())
+        }
+        ErrorKind::PermissionDenied if sa.port() < 1024 => format!(
+            "port {} is privileged; use a port of 1024 or above", sa.port()),
+        _ => e.to_string(),
+    }
+}
+
+/*
+ * Accept connections from all of the listeners, and send them to "tx" in the
+ * order they arrive, with the transport each is to speak.  The listeners are
+ * closed when "tasks" is dropped.
+ */
+pub fn accept_all(listeners: Vec<Listener>, tasks: &mut JoinSet<()>,
+    tx: &mpsc::Sender<(Stream, Addr, Transport)>)
+{
+    for l in listeners {
+        let tx = tx.clone();
+        tasks.spawn(async move {
+            loop {
+                match l.accept().await {
+                    Ok((s, addr)) => {
+                        if tx.send((s, addr, l.transport)).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        /*
+                         * Errors here are usually transient, such as running
+                         * out of file descriptors.
+                         */
+                        log!("accept on {:?}: {}", l.local_addr(), e);
+                        tokio::time::sleep(
+                            std::time::Duration::from_millis(100)).await;
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn unix_and_tcp() {
+        let path = std::env::temp_dir()
+            .join(format!("jvnc-listen-{}.sock", std::process::id()));
+        let unix = format!("unix:{}", path.display());
+
+        /*
+         * A stale socket is replaced, and both kinds of listener feed the
+         * same stream of connections.
+         */
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let (listeners, _) = bind(&[unix.clone(),
+            "ws://127.0.0.1:0".to_string()], false).unwrap();
+        assert_eq!(listeners[0].describe().unwrap(), unix);
+        assert_eq!(listeners[0].transport(), Transport::Rfb);
+        assert!(listeners[1].describe().unwrap().starts_with("ws://127."));
+        let tcp = listeners[1].local_addr().unwrap().tcp().unwrap();
+
+        let mut tasks = JoinSet::new();
+        let (tx, mut conns) = mpsc::channel(16);
+        accept_all(listeners, &mut tasks, &tx);
+        let mut u = UnixStream::connect(&path).await.unwrap();
+        let (mut s, addr, _) = conns.recv().await.unwrap();
+        assert_eq!(addr, Addr::Unix(path.clone()));
+        assert_eq!(addr.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+        u.write_all(b"hi").await.unwrap();
+        let mut buf = [0; 2];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hi");
+
+        let _t = TcpStream::connect(tcp).await.unwrap();
+        let (_, addr, transport) = conns.recv().await.unwrap();
+        assert!(matches!(addr, Addr::Tcp(sa) if sa.ip().is_loopback()));
+        assert_eq!(transport, Transport::WebSocket);
+
+        /*
+         * A socket that something is listening on is left alone, and so is
+         * anything other than a socket.
+         */
+        let e = bind(std::slice::from_ref(&unix), false).err().unwrap();
+        assert!(e.to_string().contains("another process"), "{}", e);
+        assert!(UnixStream::connect(&path).await.is_ok());
+
+        drop(tasks);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, b"").unwrap();
+        assert!(bind(&[unix], false).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
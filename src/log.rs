@@ -0,0 +1,41 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/*
+ * Log lines go to the "log" facade, with the target "jvnc", for whichever
+ * logger the application has installed; those that begin "warning:" are
+ * warnings, and the rest are informational.  The most recent are also kept
+ * in memory so that they can be included in a diagnostics bundle.
+ */
+const HISTORY: usize = 1000;
+const TARGET: &str = "jvnc";
+
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::log::record(format!($($arg)*))
+    };
+}
+
+pub(crate) fn record(line: String) {
+    let level = if line.trim_start().starts_with("warning:") {
+        ::log::Level::Warn
+    } else {
+        ::log::Level::Info
+    };
+    ::log::log!(target: TARGET, level, "{}", line);
+
+    let t = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut recent = RECENT.lock().unwrap();
+    if recent.len() == HISTORY {
+        recent.pop_front();
+    }
+    recent.push_back(format!("{}.{:03} {}", t.as_secs(), t.subsec_millis(),
+        line));
+}
+
+pub fn recent() -> Vec<String> {
+    RECENT.lock().unwrap().iter().cloned().collect()
+}
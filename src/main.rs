@@ -1,315 +1,346 @@
+/*
+ * The jvnc demo: a tartan that breathes, served with the jvnc library, and
+ * the tools for managing a running server.
+ */
+
 use anyhow::{bail, Result};
-use tokio::net::{TcpListener, TcpStream};
-use futures::StreamExt;
+use clap::Parser;
 use std::sync::Arc;
-use tokio::io::AsyncWriteExt;
-use std::time::Duration;
-use tokio::time::{Instant, sleep_until};
 use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
 
-mod framebuffer;
-mod rfb;
-use rfb::{Frame, Security, UpdateRequest};
+use jvnc::cursor::Cursor;
+use jvnc::server::IdleEvent;
+use jvnc::{admin, soak, vncauth};
+use jvnc::{Framebuffer, InputEvent, Server, ServerBuilder};
 
-fn sleep_ms(ms: u64) {
-    std::thread::sleep(std::time::Duration::from_millis(ms));
-}
+mod cli;
+mod pattern;
+use cli::Command;
+use pattern::{Breathe, Standby};
 
-fn spawn_draw(
-    cc: &Arc<AtomicU32>,
-    fb: &Arc<framebuffer::Framebuffer>
-) -> Result<()> {
-    let fb = Arc::clone(fb);
-    let cc = Arc::clone(cc);
-    std::thread::Builder::new()
-        .name("draw".to_string())
-        .spawn(move || {
-            let mut colour = 0u8;
-            let mut colourup = true;
+/*
+ * The lock keys, which toggle the keyboard lights:
+ */
+const XK_CAPS_LOCK: u32 = 0xffe5;
+const XK_NUM_LOCK: u32 = 0xff7f;
+const XK_SCROLL_LOCK: u32 = 0xff14;
 
-            /*
-             * Make a tartan of alternating colours with squares of this size:
-             */
-            let pitch = 16;
+/*
+ * The demo writes the log, its own and the library's, to standard output.
+ */
+struct Stdout;
 
-            loop {
-                /*
-                 * Put breathing blue everywhere:
-                 */
-                for y in 0..fb.height() {
-                    let mut c = (y % pitch < pitch / 2) as usize * (pitch / 2);
-                    for x in 0..fb.width() {
-                        if c % pitch < (pitch / 2) {
-                            fb.put(x, y, 0, 0, 0);
-                        } else {
-                            match cc.load(Ordering::Relaxed) {
-                                0 => fb.put(x, y, 0, 0, 0),
-                                1 => fb.put(x, y, colour, colour, colour),
-                                2 => fb.put(x, y, colour, 0, 0),
-                                3 => fb.put(x, y, 0, colour, 0),
-                                4 => fb.put(x, y, 0, 0, colour),
-                                _ => (),
-                            }
-                        }
-                        c += 1;
-                    }
-                }
+impl log::Log for Stdout {
+    fn enabled(&self, md: &log::Metadata) -> bool {
+        md.level() <= log::Level::Info
+    }
 
-                for _ in 0..8 {
-                    if colourup {
-                        colour += 1;
-                        if colour > 240 {
-                            colourup = false;
-                        }
-                    } else {
-                        colour -= 1;
-                        if colour < 10 {
-                            colourup = true;
-                        }
-                    }
-                }
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            println!("{}", record.args());
+        }
+    }
 
-                sleep_ms(50);
-            }
-        })?;
-    Ok(())
+    fn flush(&self) {}
 }
 
-async fn process_socket(
-    fb: &Arc<framebuffer::Framebuffer>,
-    mut sock: TcpStream,
-    cc: &Arc<AtomicU32>,
-) -> Result<()> {
-    let (r, mut w) = sock.split();
-    let rfb = rfb::read_stream(r);
-    tokio::pin!(rfb);
+static STDOUT: Stdout = Stdout;
 
-    /*
-     * Send the RFB ProtocolVersion Handshake.
-     */
-    let hs = b"RFB 003.008\n";
-    w.write_all(hs).await?;
+/*
+ * Draw the pattern twenty times a second, for as long as anybody is watching.
+ * Each frame is drawn on a blocking thread, so as not to hold up connections
+ * being served meanwhile.
+ */
+fn spawn_draw(server: &Arc<Server>, cc: &Arc<AtomicU32>) {
+    let server = Arc::clone(server);
+    let cc = Arc::clone(cc);
+    let mut ticker = server.ticker(Duration::from_millis(50));
+    tokio::spawn(async move {
+        let mut breathe = Breathe::new();
 
-    /*
-     * Wait for the client to return a handshake:
-     */
-    match rfb.next().await.transpose()? {
-        Some(Frame::ProtocolVersion(ver)) => {
-            if &ver != "RFB 003.008" {
-                bail!("invalid handshake: {:?}", ver);
+        loop {
+            ticker.tick().await;
+
+            /*
+             * Put breathing blue everywhere, unless somebody has given the
+             * server pixels from elsewhere:
+             */
+            let Some(fb) = server.framebuffer() else {
+                continue;
+            };
+            let colour = cc.load(Ordering::Relaxed);
+            let level = breathe.level();
+            let draw = tokio::task::spawn_blocking(move || {
+                pattern::tartan(&fb, colour, level);
+                fb.present();
+            });
+            if draw.await.is_err() {
+                return;
             }
+            breathe.step();
         }
-        Some(f) => {
-            bail!("unexpected frame: {:?}", f);
+    });
+}
+
+/*
+ * Keys pressed in the demo change the colour of the pattern, or toggle the
+ * keyboard lights; "q" disconnects the client that pressed it.
+ */
+fn key(server: &Server, id: u64, keysym: u32, cc: &AtomicU32) {
+    let colour = match keysym {
+        0x71 => {
+            log::info!("q is for quit!");
+            if let Ok(session) = server.session(id) {
+                session.close.notify_one();
+            }
+            return;
         }
-        None => {
-            println!("stream done early?");
-            return Ok(());
+        XK_CAPS_LOCK | XK_NUM_LOCK | XK_SCROLL_LOCK => {
+            let mut l = server.led_state().unwrap_or_default();
+            match keysym {
+                XK_CAPS_LOCK => l.caps = !l.caps,
+                XK_NUM_LOCK => l.num = !l.num,
+                _ => l.scroll = !l.scroll,
+            }
+            log::info!("  lock lights now {:?}", l);
+            server.set_led_state(l.caps, l.num, l.scroll);
+            return;
         }
-    }
+        0x7a => "black",
+        0x77 => "white",
+        0x72 => "red",
+        0x67 => "green",
+        0x62 => "blue",
+        _ => return,
+    };
+    log::info!("{} is for {}!", char::from_u32(keysym).unwrap(), colour);
+    cc.store(colour_number(colour).unwrap(), Ordering::Relaxed);
+}
 
-    /*
-     * Security Handshake:
-     */
-    w.write_u8(1).await?; /* 1 type */
-    w.write_u8(1).await?; /* type None */
+fn colour_number(colour: &str) -> Option<u32> {
+    ["black", "white", "red", "green", "blue"].iter()
+        .position(|&c| c == colour)
+        .map(|n| n as u32)
+}
 
-    /*
-     * Wait for client to choose:
-     */
-    match rfb.next().await.transpose()? {
-        Some(Frame::SecuritySelection(Security::None)) => {
-            println!("  security: none");
-        }
-        Some(f) => {
-            bail!("unexpected frame: {:?}", f);
-        }
-        None => {
-            println!("stream done early?");
-            return Ok(());
-        }
-    }
+/*
+ * Prompt for a new password, and optionally a view-only password, on the
+ * terminal.  If standard input is not a terminal, as when run from a script,
+ * the first line is the password and the second, if any, is the view-only
+ * password.
+ */
+fn read_new_passwords() -> Result<(String, Option<String>)> {
+    use std::io::{BufRead, IsTerminal};
 
-    /*
-     * SecurityResult Handshake:
-     */
-    w.write_u32(0).await?; /* ok */
+    if !std::io::stdin().is_terminal() {
+        let mut lines = std::io::stdin().lock().lines();
+        let password = lines.next().transpose()?.unwrap_or_default();
+        let view = lines.next().transpose()?.filter(|l| !l.is_empty());
+        return Ok((password, view));
+    }
 
-    /*
-     * Wait for client init:
-     */
-    let _acc = match rfb.next().await.transpose()? {
-        Some(Frame::ClientInit(acc)) => {
-            println!("  access: {:?}", acc);
-            acc
+    let prompt = |what: &str| -> Result<String> {
+        let pw = rpassword::prompt_password(format!("{}: ", what))?;
+        if pw.len() > 8 {
+            println!("warning: only the first 8 characters are significant");
         }
-        Some(f) => {
-            bail!("unexpected frame: {:?}", f);
-        }
-        None => {
-            println!("stream done early?");
-            return Ok(());
+        if !pw.is_empty()
+            && rpassword::prompt_password("Verify: ")? != pw
+        {
+            bail!("passwords do not match");
         }
+        Ok(pw)
     };
 
-    /*
-     * ServerInit:
-     */
-    w.write_u16(fb.width() as u16).await?; /* width, pixels */
-    w.write_u16(fb.height() as u16).await?; /* height, pixels */
-
-    /* PIXEL_FORMAT */
-    w.write_u8(32).await?; /* bpp */
-    w.write_u8(24).await?; /* depth */
-    w.write_u8(0).await?; /* big endian */
-    w.write_u8(1).await?; /* true colour */
-    w.write_u16(255).await?; /* red max */
-    w.write_u16(255).await?; /* green max */
-    w.write_u16(255).await?; /* blue max */
-    w.write_u8(16).await?; /* red shift */
-    w.write_u8(8).await?; /* green shift */
-    w.write_u8(0).await?; /* blue shift */
-    w.write_u8(0).await?; /* padding ... */
-    w.write_u8(0).await?;
-    w.write_u8(0).await?; /* ... padding */
-
-    w.write_u32(4).await?; /* name length */
-    let buf = b"jvnc";
-    w.write_all(buf).await?;
-
-    let mut draw: Option<UpdateRequest> = None;
-    let mut drawtime = Instant::now();
-    let fps = 12;
-
-    loop {
-        tokio::select! {
-            _ = sleep_until(drawtime), if draw.is_some() => {
-                let ur = draw.take().unwrap();
-
-                /*
-                 * Fashion some pixel data for the client...
-                 */
-                w.write_u8(0).await?; /* type: FramebufferUpdate */
-                w.write_u8(0).await?; /* padding */
+    let password = prompt("Password")?;
+    let view = prompt("View-only password (empty for none)")?;
+    Ok((password, Some(view).filter(|v| !v.is_empty())))
+}
 
-                w.write_u16(1).await?; /* nrects */
+#[tokio::main]
+async fn main() -> Result<()> {
+    log::set_logger(&STDOUT)?;
+    log::set_max_level(log::LevelFilter::Info);
+    let args = cli::Args::parse();
+    let config = args.options.config()?;
 
-                w.write_u16(ur.xpos as u16).await?; /* xpos */
-                w.write_u16(ur.ypos as u16).await?; /* ypos */
-                w.write_u16(ur.width as u16).await?; /* width */
-                w.write_u16(ur.height as u16).await?; /* height */
-                w.write_i32(0).await?; /* encoding: Raw */
+    match args.command {
+        None => (),
+        /*
+         * "jvnc diag [file]" asks a running server for a diagnostics bundle:
+         */
+        Some(Command::Diag { file }) => {
+            let path = match &config.admin {
+                Some(path) => path,
+                None => bail!("no admin socket is configured; give one with \
+                    --admin"),
+            };
+            let out = file.unwrap_or_else(|| {
+                format!("jvnc-diag-{}.tar", std::process::id()).into()
+            });
 
-                let mut v = Vec::new();
-                for y in ur.ypos..(ur.ypos + ur.height) {
-                    for x in ur.xpos..(ur.xpos + ur.width) {
-                        let (r, g, b) = fb.get(x, y);
-                        v.push(b);
-                        v.push(g);
-                        v.push(r);
-                        v.push(0);
+            let tar = admin::request(path, "diag").await?;
+            std::fs::write(&out, tar)?;
+            println!("diagnostics written to {}", out.display());
+            return Ok(());
+        }
+        /*
+         * "jvnc passwd [file]" writes a password file in the traditional
+         * format, by default where vncpasswd would put it:
+         */
+        Some(Command::Passwd { file }) => {
+            let path = match file {
+                Some(p) => p,
+                None => match std::env::var_os("HOME") {
+                    Some(home) => {
+                        std::path::Path::new(&home).join(".vnc/passwd")
                     }
-                }
-                w.write_all(&v).await?;
-
-                /*
-                 * Schedule the next draw cycle at the expected time
-                 * based on the target maximum frame rate:
-                 */
-                drawtime = Instant::now()
-                    .checked_add(Duration::from_millis(1000 / fps))
-                    .unwrap();
+                    None => bail!("HOME is not set; specify a file"),
+                },
+            };
+            let (password, view) = read_new_passwords()?;
+            vncauth::write_file(&path, &password, view.as_deref())?;
+            println!("password written to {}", path.display());
+            return Ok(());
+        }
+        /*
+         * "jvnc soak [seconds] [clients] [file]" runs the demo under
+         * synthetic load, with nothing else able to connect, and writes a
+         * report:
+         */
+        Some(Command::Soak { seconds, clients, file }) => {
+            let mut opts = soak::Options::default();
+            if let Some(secs) = seconds {
+                opts.duration = Duration::from_secs(secs);
+            }
+            if let Some(clients) = clients {
+                opts.clients = clients;
             }
-            f = rfb.next() => {
-                let f = match f {
-                    Some(f) => f?,
-                    None => return Ok(()),
-                };
+            let out = file.unwrap_or_else(|| {
+                format!("jvnc-soak-{}.json", std::process::id()).into()
+            });
 
-                match f {
-                    Frame::FramebufferUpdateRequest(mut ur) => {
-                        /*
-                         * Make sure the update request is not out of bounds for
-                         * the actual framebuffer we have:
-                         */
-                        if ur.xpos >= fb.width() {
-                            ur.xpos = fb.width() - 1;
-                        }
-                        if ur.ypos >= fb.height() {
-                            ur.ypos = fb.height() - 1;
-                        }
-                        if ur.width > fb.width() {
-                            ur.width = fb.width();
-                        }
-                        if ur.height > fb.height() {
-                            ur.height = fb.height();
-                        }
+            let server = ServerBuilder::from_config(config)
+                .configure(|c| {
+                    c.admin = None;
+                    c.console = false;
+                    c.security = None;
+                    c.password = None;
+                    c.view_password = None;
+                    c.password_file = None;
+                    c.psk = None;
+                    c.stream_zstd = None;
+                })
+                .build()?;
+            spawn_draw(&server, &Arc::new(AtomicU32::new(4)));
 
-                        /*
-                         * Schedule a redraw at the next appropriate moment:
-                         */
-                        draw = Some(ur);
-                    }
-                    Frame::KeyEvent(down, key) if down == 1 && key == 113 => {
-                        println!("q is for quit!");
-                        return Ok(());
-                    }
-                    Frame::KeyEvent(down, key) if down == 1 && key == 122 => {
-                        println!("z is for black!");
-                        cc.store(0, Ordering::Relaxed);
-                    }
-                    Frame::KeyEvent(down, key) if down == 1 && key == 119 => {
-                        println!("w is for white!");
-                        cc.store(1, Ordering::Relaxed);
-                    }
-                    Frame::KeyEvent(down, key) if down == 1 && key == 114 => {
-                        println!("r is for red!");
-                        cc.store(2, Ordering::Relaxed);
-                    }
-                    Frame::KeyEvent(down, key) if down == 1 && key == 103 => {
-                        println!("g is for green!");
-                        cc.store(3, Ordering::Relaxed);
-                    }
-                    Frame::KeyEvent(down, key) if down == 1 && key == 98 => {
-                        println!("b is for blue!");
-                        cc.store(4, Ordering::Relaxed);
-                    }
-                    f => {
-                        println!("f: {:?}", f);
-                    }
-                }
-            }
+            let report = soak::run(&server, &opts).await?;
+            let mut json = serde_json::to_vec_pretty(&report)?;
+            json.push(b'\n');
+            std::fs::write(&out, json)?;
+            println!("soak report written to {}", out.display());
+            return Ok(());
         }
     }
-}
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let listener = TcpListener::bind("0.0.0.0:5915").await?;
+    let server = ServerBuilder::from_config(config).build()?;
+    if server.config.upstream.is_some() {
+        /*
+         * As a proxy, the pixels and the input are the upstream server's,
+         * and there is nothing for the demo to do.
+         */
+        return jvnc::serve(&server).await;
+    }
 
     /*
-     * Colour coordination:
+     * The colour of the pattern, which starts out blue:
      */
     let cc = Arc::new(AtomicU32::new(4));
-
+    spawn_draw(&server, &cc);
+    server.set_cursor(Cursor::arrow());
+    server.set_power_handler(|id, action| {
+        log::info!("[{}] power {:?} requested; nothing to do in the demo", id,
+            action);
+        Ok(())
+    });
+    let weak = Arc::downgrade(&server);
+    let colour = Arc::clone(&cc);
+    server.set_input_handler(move |id, ev| match ev {
+        InputEvent::Key { down: true, keysym, .. } => {
+            if let Some(server) = weak.upgrade() {
+                key(&server, id, *keysym, &colour);
+            }
+        }
+        InputEvent::Touch(t) => {
+            log::info!("[{}] touch {} {:?} at {}, {}", id, t.contact, t.phase,
+                t.x, t.y);
+        }
+        _ => (),
+    });
     /*
-     * Spawn the simulated framebuffer:
+     * "pattern COLOUR" on the admin socket or console changes the colour.
      */
-    let fb = Arc::new(framebuffer::Framebuffer::new(512, 384));
-    spawn_draw(&cc, &fb)?;
-
-    let mut c = 0;
-    loop {
-        let (socket, addr) = listener.accept().await?;
-        c += 1;
-        println!("[{}] accept: {:?}", c, addr);
-
-        let fb = Arc::clone(&fb);
-        let cc = Arc::clone(&cc);
-        tokio::spawn(async move {
-            let res = process_socket(&fb, socket, &cc).await;
-            println!("[{}] connection done: {:?}", c, res);
-            println!();
+    let colour = Arc::clone(&cc);
+    server.set_command_handler(move |cmd| {
+        let arg = cmd.strip_prefix("pattern ")?.trim();
+        Some(match colour_number(arg) {
+            Some(n) => {
+                colour.store(n, Ordering::Relaxed);
+                Ok(())
+            }
+            None => Err(anyhow::anyhow!("unknown colour {:?}", arg)),
+        })
+    });
+    /*
+     * The demo shares clipboard text from one client with all of them.
+     */
+    let weak = Arc::downgrade(&server);
+    server.set_clipboard_handler(move |_, text| {
+        if let Some(server) = weak.upgrade() {
+            server.set_clipboard(text);
+        }
+    });
+    /*
+     * The demo can switch to a standby screen, at VGA resolution, and back
+     * to its pattern.
+     */
+    let weak = Arc::downgrade(&server);
+    let size = (server.config.width, server.config.height);
+    let double = server.config.double_buffer;
+    server.set_source_handler(move |name| {
+        let server = match weak.upgrade() {
+            Some(server) => server,
+            None => bail!("server has gone away"),
+        };
+        match name {
+            "pattern" => server.set_framebuffer(Arc::new(
+                Framebuffer::new_buffered(size.0, size.1, double)?)),
+            "standby" => server.set_source(Arc::new(Standby {
+                width: 640,
+                height: 480,
+            })),
+            n => bail!("unknown source {:?}", n),
+        }
+        Ok(())
+    });
+    if let Some(timeout) = server.config.idle_timeout {
+        /*
+         * The demo blanks its pattern while nobody is using it.
+         */
+        let saved = AtomicU32::new(0);
+        server.set_idle_handler(timeout, move |ev| match ev {
+            IdleEvent::Idle => {
+                saved.store(cc.swap(0, Ordering::Relaxed), Ordering::Relaxed);
+            }
+            IdleEvent::Wake => {
+                cc.store(saved.load(Ordering::Relaxed), Ordering::Relaxed);
+            }
         });
     }
+
+    /*
+     * The demo runs until it is killed.
+     */
+    jvnc::serve(&server).await
 }
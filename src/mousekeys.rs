@@ -0,0 +1,210 @@
+/*
+ * Keyboard pointer emulation, modelled on the X11 MouseKeys feature, for
+ * servers configured to offer it.  The mode is toggled by pressing Num_Lock
+ * while holding Shift.  When active, the
+ * numeric keypad and arrow keys move the pointer, and the following keypad
+ * keys operate the buttons:
+ *
+ *     5           click the selected button
+ *     +           double click the selected button
+ *     0 (Ins)     press and hold the selected button
+ *     . (Del)     release the selected button
+ *     /  *  -     select the left, middle or right button
+ */
+
+use crate::encoding::wire_coord;
+
+const XK_SHIFT_L: u32 = 0xffe1;
+const XK_SHIFT_R: u32 = 0xffe2;
+const XK_NUM_LOCK: u32 = 0xff7f;
+
+/*
+ * Movement steps start small for precise positioning and grow while the key
+ * is held down and auto-repeating.
+ */
+const STEP_MIN: i32 = 2;
+const STEP_MAX: i32 = 32;
+
+const BUTTON_LEFT: u8 = 1 << 0;
+const BUTTON_MIDDLE: u8 = 1 << 1;
+const BUTTON_RIGHT: u8 = 1 << 2;
+
+enum Action {
+    Move(i32, i32),
+    Click(usize),
+    Press,
+    Release,
+    Select(u8),
+}
+
+fn action(key: u32) -> Option<Action> {
+    Some(match key {
+        0xff51 | 0xff96 | 0xffb4 => Action::Move(-1, 0), /* Left, 4 */
+        0xff52 | 0xff97 | 0xffb8 => Action::Move(0, -1), /* Up, 8 */
+        0xff53 | 0xff98 | 0xffb6 => Action::Move(1, 0), /* Right, 6 */
+        0xff54 | 0xff99 | 0xffb2 => Action::Move(0, 1), /* Down, 2 */
+        0xff95 | 0xffb7 => Action::Move(-1, -1), /* Home, 7 */
+        0xff9a | 0xffb9 => Action::Move(1, -1), /* Prior, 9 */
+        0xff9c | 0xffb1 => Action::Move(-1, 1), /* End, 1 */
+        0xff9b | 0xffb3 => Action::Move(1, 1), /* Next, 3 */
+        0xff9d | 0xffb5 => Action::Click(1), /* Begin, 5 */
+        0xffab => Action::Click(2), /* Add */
+        0xff9e | 0xffb0 => Action::Press, /* Insert, 0 */
+        0xff9f | 0xffae => Action::Release, /* Delete, Decimal */
+        0xffaf => Action::Select(BUTTON_LEFT), /* Divide */
+        0xffaa => Action::Select(BUTTON_MIDDLE), /* Multiply */
+        0xffad => Action::Select(BUTTON_RIGHT), /* Subtract */
+        _ => return None,
+    })
+}
+
+pub struct MouseKeys {
+    enabled: bool,
+    shift: bool,
+    /*
+     * Whether we swallowed the press of Num_Lock that is still down, whose
+     * release must then be swallowed too, even if Shift has since been let
+     * go.
+     */
+    num_lock: bool,
+    width: u16,
+    height: u16,
+    x: u16,
+    y: u16,
+    button: u8,
+    held: u8,
+    repeat: i32,
+}
+
+impl MouseKeys {
+    pub fn new(width: usize, height: usize) -> MouseKeys {
+        MouseKeys {
+            enabled: false,
+            shift: false,
+            num_lock: false,
+            width: wire_coord(width),
+            height: wire_coord(height),
+            x: 0,
+            y: 0,
+            button: BUTTON_LEFT,
+            held: 0,
+            repeat: 0,
+        }
+    }
+
+    /*
+     * The framebuffer geometry has changed; keep the pointer on the screen.
+     */
+    pub fn resize(&mut self, width: usize, height: usize) {
+        self.width = wire_coord(width);
+        self.height = wire_coord(height);
+        self.x = self.x.min(self.width.saturating_sub(1));
+        self.y = self.y.min(self.height.saturating_sub(1));
+    }
+
+    /*
+     * Track the position of the real pointer, so that emulated movement
+     * begins wherever the client last put it.
+     */
+    pub fn pointer(&mut self, x: u16, y: u16) {
+        self.x = x;
+        self.y = y;
+    }
+
+    /*
+     * Examine a key event from the client.  If the key was consumed by the
+     * pointer emulation, the resulting pointer events (button mask and
+     * position) are returned in place of the key.
+     */
+    pub fn key(&mut self, down: bool, key: u32)
+        -> Option<Vec<(u8, u16, u16)>>
+    {
+        if key == XK_SHIFT_L || key == XK_SHIFT_R {
+            self.shift = down;
+            return None;
+        }
+
+        if key == XK_NUM_LOCK {
+            if down && self.shift {
+                self.num_lock = true;
+                self.enabled = !self.enabled;
+                log!("  pointer emulation {}",
+                    if self.enabled { "on" } else { "off" });
+                return Some(Vec::new());
+            }
+            if !down && self.num_lock {
+                self.num_lock = false;
+                return Some(Vec::new());
+            }
+            return None;
+        }
+
+        if !self.enabled {
+            return None;
+        }
+
+        let a = action(key)?;
+        if !down {
+            self.repeat = 0;
+            return Some(Vec::new());
+        }
+
+        let mut ev = Vec::new();
+        match a {
+            Action::Move(dx, dy) => {
+                let step = (STEP_MIN << self.repeat.min(4)).min(STEP_MAX);
+                self.repeat += 1;
+
+                let max_x = self.width.saturating_sub(1) as i32;
+                let max_y = self.height.saturating_sub(1) as i32;
+                self.x = (self.x as i32 + dx * step).clamp(0, max_x) as u16;
+                self.y = (self.y as i32 + dy * step).clamp(0, max_y) as u16;
+                ev.push((self.held, self.x, self.y));
+            }
+            Action::Click(n) => {
+                for _ in 0..n {
+                    ev.push((self.held | self.button, self.x, self.y));
+                    ev.push((self.held & !self.button, self.x, self.y));
+                }
+            }
+            Action::Press => {
+                self.held |= self.button;
+                ev.push((self.held, self.x, self.y));
+            }
+            Action::Release => {
+                self.held &= !self.button;
+                ev.push((self.held, self.x, self.y));
+            }
+            Action::Select(b) => self.button = b,
+        }
+        Some(ev)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn num_lock() {
+        let mut mk = MouseKeys::new(100, 100);
+
+        /*
+         * Shift is let go before Num_Lock, and the release of Num_Lock must
+         * not reach the application without its press:
+         */
+        assert_eq!(mk.key(true, XK_SHIFT_L), None);
+        assert_eq!(mk.key(true, XK_NUM_LOCK), Some(Vec::new()));
+        assert_eq!(mk.key(false, XK_SHIFT_L), None);
+        assert_eq!(mk.key(false, XK_NUM_LOCK), Some(Vec::new()));
+        assert!(mk.enabled);
+
+        /*
+         * Without Shift, Num_Lock is the application's, in both directions:
+         */
+        assert_eq!(mk.key(true, XK_NUM_LOCK), None);
+        assert_eq!(mk.key(false, XK_NUM_LOCK), None);
+
+        assert_eq!(mk.key(true, 0xffb6), Some(vec![(0, 2, 0)]));
+    }
+}
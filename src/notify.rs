@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+/*
+ * A message for the person using a client.  RFB has no dedicated way to show
+ * one, so each connection picks the best of what the client supports:
+ *
+ *  - clients that accept the DesktopName pseudo-encoding show the message in
+ *    the window title, in full UTF-8, for a while;
+ *  - otherwise, if the message can be represented in Latin-1, it is sent as
+ *    ServerCutText, which most viewers make visible in some form;
+ *  - urgent messages also ring the bell, which every client supports.
+ */
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub text: String,
+    pub urgent: bool,
+}
+
+/*
+ * How long a message stays in the window title:
+ */
+pub const TITLE_TIME: Duration = Duration::from_secs(10);
+
+impl Notification {
+    pub fn new(text: &str) -> Notification {
+        Notification {
+            text: text.to_string(),
+            urgent: false,
+        }
+    }
+
+    pub fn urgent(text: &str) -> Notification {
+        Notification {
+            text: text.to_string(),
+            urgent: true,
+        }
+    }
+
+    /*
+     * The message as Latin-1, the only character set that ServerCutText is
+     * defined to carry, if it can be represented that way.
+     */
+    pub fn latin1(&self) -> Option<Vec<u8>> {
+        self.text.chars()
+            .map(|c| (u32::from(c) <= 0xff).then_some(c as u8))
+            .collect()
+    }
+}
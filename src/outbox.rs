@@ -0,0 +1,279 @@
+use std::io::Result;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+/*
+ * Messages for the client travel in one of two lanes, and are written by a
+ * separate task so that the connection can keep reading input while a large
+ * update drains.
+ *
+ * The bulk lane carries framebuffer updates, along with anything whose
+ * position relative to them matters: desktop size changes and cursor shapes
+ * are themselves pseudo-rectangles in an update, and a fence we send must
+ * follow the update it measures.  The control lane carries the small,
+ * self-contained messages (bell, cut text, xvp, fence replies), which jump
+ * ahead of any bulk messages still queued.  RFB messages cannot be split, so
+ * a control message waits at most for the bulk message already being
+ * written.  Within each lane, messages go out in the order they were queued.
+ *
+ * A client that reads slowly must not hold the server up, nor have it keep
+ * everything the client has yet to read.  The connection queues another
+ * update only once the last has been written, and then covers everything
+ * that changed in the meantime, so a slow client simply gets fewer frames.
+ * Clipboard text, which may be large, is replaced if it has not been written
+ * by the time the clipboard changes again.  The rest of the control lane is
+ * limited in size, and a client that lets it fill up is disconnected.
+ */
+pub struct Outbox<T> {
+    control: UnboundedSender<Control>,
+    bulk: UnboundedSender<(Vec<u8>, T)>,
+    sent: UnboundedReceiver<Result<T>>,
+    backlog: Arc<Mutex<Backlog>>,
+    buffers: Buffers,
+    writer: JoinHandle<()>,
+}
+
+enum Control {
+    Message(Vec<u8>),
+    /*
+     * The latest clipboard message, if it has not already been written:
+     */
+    Clipboard,
+}
+
+/*
+ * What is queued in the control lane and not yet being written:
+ */
+#[derive(Default)]
+struct Backlog {
+    bytes: usize,
+    clipboard: Option<Vec<u8>>,
+}
+
+/*
+ * How much may wait in the control lane, not counting clipboard text.  A
+ * single message larger than this is allowed if nothing else is waiting.
+ */
+const CONTROL_LIMIT: usize = 1024 * 1024;
+
+/*
+ * Bulk messages are large, and a connection sends one after another, so once
+ * each has been written its buffer is kept for building the next, rather
+ * than allocating a new one for every update.  Only a few are kept: one being
+ * written, and one being built.
+ */
+#[derive(Clone, Default)]
+pub struct Buffers(Arc<Mutex<Vec<Vec<u8>>>>);
+
+const KEEP: usize = 2;
+
+impl Buffers {
+    /*
+     * An empty buffer, with room from an earlier message if there is one.
+     */
+    pub fn take(&self) -> Vec<u8> {
+        self.0.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    pub fn put(&self, mut buf: Vec<u8>) {
+        buf.clear();
+        let mut bufs = self.0.lock().unwrap();
+        if bufs.len() < KEEP {
+            bufs.push(buf);
+        }
+    }
+}
+
+impl<T: Send + 'static> Outbox<T> {
+    pub fn new<W>(w: W) -> Outbox<T>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (control, crx) = mpsc::unbounded_channel();
+        let (bulk, brx) = mpsc::unbounded_channel();
+        let (stx, sent) = mpsc::unbounded_channel();
+        let backlog = Arc::new(Mutex::new(Backlog::default()));
+        let buffers = Buffers::default();
+        let writer = tokio::spawn(writer(w, crx, brx, stx,
+            Arc::clone(&backlog), buffers.clone()));
+        Outbox { control, bulk, sent, backlog, buffers, writer }
+    }
+
+    /*
+     * Where the buffers of bulk messages go once they have been written.
+     */
+    pub fn buffers(&self) -> Buffers {
+        self.buffers.clone()
+    }
+
+    /*
+     * Queue a message in the control lane.  If the writer has failed, the
+     * message is discarded; the error is reported by sent().  If the client
+     * has left too much of the lane unread, this fails instead.
+     */
+    pub fn control(&self, buf: Vec<u8>) -> Result<()> {
+        let len = buf.len();
+        let mut backlog = self.backlog.lock().unwrap();
+        if backlog.bytes > 0 && backlog.bytes + len > CONTROL_LIMIT {
+            return Err(std::io::Error::other(format!(
+                "client has left {} bytes of messages unread",
+                backlog.bytes)));
+        }
+        if self.control.send(Control::Message(buf)).is_ok() {
+            backlog.bytes += len;
+        }
+        Ok(())
+    }
+
+    /*
+     * Queue clipboard text for the client in the control lane, in place of
+     * any that has not yet been written, which is now out of date.
+     */
+    pub fn clipboard(&self, buf: Vec<u8>) {
+        let stale = self.backlog.lock().unwrap().clipboard.replace(buf);
+        if stale.is_none() {
+            self.control.send(Control::Clipboard).ok();
+        }
+    }
+
+    /*
+     * Queue a message in the bulk lane.  Once it has been written, "tag" is
+     * returned by sent().
+     */
+    pub fn bulk(&self, buf: Vec<u8>, tag: T) {
+        self.bulk.send((buf, tag)).ok();
+    }
+
+    /*
+     * Wait for the next bulk message to be written, returning its tag, or
+     * for the writer to fail.
+     */
+    pub async fn sent(&mut self) -> Result<T> {
+        match self.sent.recv().await {
+            Some(res) => res,
+            None => Err(std::io::Error::other("writer has stopped")),
+        }
+    }
+
+    /*
+     * Queue nothing more, and wait for everything already queued to be
+     * written, or for the writer to fail.
+     */
+    pub async fn finish(self) {
+        let Outbox { control, bulk, writer, .. } = self;
+        drop(control);
+        drop(bulk);
+        writer.await.ok();
+    }
+}
+
+async fn writer<W, T>(
+    mut w: W,
+    mut control: UnboundedReceiver<Control>,
+    mut bulk: UnboundedReceiver<(Vec<u8>, T)>,
+    sent: UnboundedSender<Result<T>>,
+    backlog: Arc<Mutex<Backlog>>,
+    buffers: Buffers,
+) where
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let (buf, tag) = tokio::select! {
+            biased;
+            Some(c) = control.recv() => {
+                let mut backlog = backlog.lock().unwrap();
+                let buf = match c {
+                    Control::Message(buf) => {
+                        backlog.bytes -= buf.len();
+                        buf
+                    }
+                    Control::Clipboard => match backlog.clipboard.take() {
+                        Some(buf) => buf,
+                        None => continue,
+                    },
+                };
+                (buf, None)
+            }
+            Some((buf, tag)) = bulk.recv() => (buf, Some(tag)),
+            else => break,
+        };
+
+        let res = async {
+            w.write_all(&buf).await?;
+            w.flush().await
+        }.await;
+
+        match (res, tag) {
+            (Err(e), _) => {
+                sent.send(Err(e)).ok();
+                return;
+            }
+            (Ok(()), Some(tag)) => {
+                buffers.put(buf);
+                sent.send(Ok(tag)).ok();
+            }
+            (Ok(()), None) => (),
+        }
+    }
+
+    /*
+     * The connection has finished with us, and everything it queued has
+     * been written.
+     */
+    w.shutdown().await.ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn slow_client() {
+        let (w, mut r) = tokio::io::duplex(64);
+        let out: Outbox<()> = Outbox::new(w);
+
+        /*
+         * While the first clipboard text is being written, the client
+         * reads nothing, and the next is replaced by the one after.
+         */
+        out.clipboard(vec![1; 100]);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        out.clipboard(vec![2; 10]);
+        out.clipboard(vec![3; 10]);
+
+        out.control(vec![4; CONTROL_LIMIT]).unwrap();
+        assert!(out.control(vec![5]).is_err());
+
+        let mut got = vec![0; 120];
+        r.read_exact(&mut got).await.unwrap();
+        assert!(got[..100].iter().all(|&b| b == 1));
+        assert!(got[100..110].iter().all(|&b| b == 3));
+        assert!(got[110..].iter().all(|&b| b == 4));
+    }
+
+    #[tokio::test]
+    async fn finished() {
+        let (w, mut r) = tokio::io::duplex(64);
+        let out: Outbox<()> = Outbox::new(w);
+
+        /*
+         * Finishing waits for what was queued to be read, however slowly,
+         * and the client then sees the end of the stream.
+         */
+        out.bulk(vec![1; 1000], ());
+        out.control(vec![2; 10]).unwrap();
+        let reader = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let mut got = Vec::new();
+            r.read_to_end(&mut got).await.unwrap();
+            got
+        });
+        out.finish().await;
+        assert_eq!(reader.await.unwrap().len(), 1010);
+    }
+}
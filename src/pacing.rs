@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+/*
+ * Paces the updates sent to one client.  Updates are sent no more often than
+ * the frame rate allows, but a client whose connection cannot carry them that
+ * fast is sent them less often still: the wait between one update being
+ * written and the next being drawn is at least as long as writing recent
+ * updates has taken.  The connection is then busy with updates no more than
+ * about half the time, so that the reply to the client's next input is not
+ * stuck behind a frame it did not ask for.
+ */
+pub struct Pacer {
+    /*
+     * How long recent updates took to write, from being queued to the last
+     * byte being accepted by the socket, smoothed:
+     */
+    write: Option<Duration>,
+}
+
+impl Pacer {
+    pub fn new() -> Pacer {
+        Pacer { write: None }
+    }
+
+    /*
+     * An update took "t" to write.
+     */
+    pub fn written(&mut self, t: Duration) {
+        self.write = Some(match self.write {
+            Some(w) => (w * 3 + t) / 4,
+            None => t,
+        });
+    }
+
+    pub fn write_time(&self) -> Option<Duration> {
+        self.write
+    }
+
+    /*
+     * The time to wait after an update before drawing the next, at "fps"
+     * frames per second.
+     */
+    pub fn interval(&self, fps: u64) -> Duration {
+        let base = Duration::from_millis(1000 / fps.max(1));
+        self.write.map_or(base, |w| base.max(w))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off() {
+        let mut p = Pacer::new();
+        assert_eq!(p.interval(10), Duration::from_millis(100));
+
+        /*
+         * Updates that write quickly change nothing; slow ones stretch the
+         * interval, and it recovers as writes speed up again.
+         */
+        p.written(Duration::from_millis(5));
+        assert_eq!(p.interval(10), Duration::from_millis(100));
+        for _ in 0..10 {
+            p.written(Duration::from_millis(400));
+        }
+        assert!(p.interval(10) > Duration::from_millis(350));
+        for _ in 0..20 {
+            p.written(Duration::from_millis(5));
+        }
+        assert_eq!(p.interval(10), Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,171 @@
+use jvnc::framebuffer::Framebuffer;
+use jvnc::source::{Rect, Source};
+
+/*
+ * The demo draws a tartan of alternating colours with squares of this size:
+ */
+const PITCH: usize = 16;
+
+/*
+ * Paint the tartan into "fb".  Half of the squares are black, and the rest
+ * take the colour selected by "cc" (black, white, red, green or blue) at the
+ * given brightness.  Any other selection leaves those squares untouched.
+ */
+pub fn tartan(fb: &Framebuffer, cc: u32, level: u8) {
+    for y in 0..fb.height() {
+        let c = (y % PITCH < PITCH / 2) as usize * (PITCH / 2);
+        for x in 0..fb.width() {
+            if (c + x) % PITCH < (PITCH / 2) {
+                fb.put(x, y, 0, 0, 0);
+            } else {
+                match cc {
+                    0 => fb.put(x, y, 0, 0, 0),
+                    1 => fb.put(x, y, level, level, level),
+                    2 => fb.put(x, y, level, 0, 0),
+                    3 => fb.put(x, y, 0, level, 0),
+                    4 => fb.put(x, y, 0, 0, level),
+                    _ => (),
+                }
+            }
+        }
+    }
+}
+
+/*
+ * The plain grey screen the demo shows while on standby.  It is made up as it
+ * is read, rather than drawn into a framebuffer.
+ */
+pub struct Standby {
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Source for Standby {
+    fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    fn read_rect(&self, r: &Rect, out: &mut Vec<u32>) {
+        out.extend(std::iter::repeat_n(0x404040, r.area()));
+    }
+}
+
+/*
+ * The brightness of the pattern breathes slowly up and down.
+ */
+pub struct Breathe {
+    level: u8,
+    up: bool,
+}
+
+impl Breathe {
+    pub fn new() -> Breathe {
+        Breathe { level: 0, up: true }
+    }
+
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    /*
+     * Advance by one frame.
+     */
+    pub fn step(&mut self) {
+        for _ in 0..8 {
+            if self.up {
+                self.level += 1;
+                if self.level > 240 {
+                    self.up = false;
+                }
+            } else {
+                self.level -= 1;
+                if self.level < 10 {
+                    self.up = true;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::hash::{hash, MessageDigest};
+
+    fn digest(buf: &[u8]) -> String {
+        hash(MessageDigest::sha256(), buf).unwrap()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /*
+     * If a change to the pattern is intended, run the tests and replace the
+     * hash with the one reported.
+     */
+    const GOLDEN: &[(u32, u8, &str)] = &[
+        (0, 128,
+            "151ff79f29e96d211576b9a2e3e78f518b26109916616945d50cdee82dd2ba8b"),
+        (1, 0,
+            "151ff79f29e96d211576b9a2e3e78f518b26109916616945d50cdee82dd2ba8b"),
+        (1, 128,
+            "f6859f40e9297cdda46aca330b7e1d6a81ad96b1f67b45bce46f6014fa74ba48"),
+        (1, 241,
+            "2d64e7d310349c3f7d677c3c151aa7ce61c3ac19d9973f1646f7bea6c4fef0af"),
+        (2, 128,
+            "99258ece99b036c64324ac24e8b80648fa5921475bef8bfa9767a2f5cba4becb"),
+        (3, 128,
+            "e2fb6c677ba55ae68788ea0911ed8b59273e162b20b35813489a5a034c026aeb"),
+        (4, 10,
+            "29eece44e5917f31216d1462f8a8e933dd00beb5d58c633cdf1a68ddd5e631eb"),
+        (4, 128,
+            "1a9172d6d6c0b9f7dddf0097b488efd0573bc13c28511a0a1cad1c759844786d"),
+        (4, 241,
+            "bad80b10cc1e91d326a61db24df70561ab733c7a105c61c6460cb78f1390a8db"),
+        (5, 128,
+            "151ff79f29e96d211576b9a2e3e78f518b26109916616945d50cdee82dd2ba8b"),
+    ];
+
+    #[test]
+    fn tartan_golden() {
+        let mut failed = Vec::new();
+        for &(cc, level, want) in GOLDEN {
+            /*
+             * The geometry is deliberately not a multiple of the pitch, so
+             * that the partial squares at the edges are covered too.
+             */
+            let fb = Framebuffer::new(100, 60).unwrap();
+            tartan(&fb, cc, level);
+            let got = digest(&fb.copy_all());
+            if got != want {
+                failed.push(format!("    ({}, {}, \"{}\"),", cc, level, got));
+            }
+        }
+        assert!(failed.is_empty(), "pattern changed:\n{}", failed.join("\n"));
+    }
+
+    /*
+     * With no colour selected, the coloured squares keep whatever was there
+     * before.
+     */
+    #[test]
+    fn tartan_unknown_colour() {
+        let fb = Framebuffer::new(32, 32).unwrap();
+        tartan(&fb, 2, 200);
+        let before = fb.copy_all();
+        tartan(&fb, 99, 10);
+        assert!(before == fb.copy_all());
+    }
+
+    #[test]
+    fn breathe_golden() {
+        let mut b = Breathe::new();
+        let mut levels = Vec::new();
+        for _ in 0..200 {
+            levels.push(b.level());
+            b.step();
+        }
+        assert_eq!(digest(&levels),
+            "f8f4f9be5f209941addd1af4075fe5742fa535897da07ddbf28e294eb215a216");
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::VecDeque;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+
+use anyhow::{anyhow, Result};
+use tokio::sync::{oneshot, Semaphore};
+
+/*
+ * Encoding is CPU intensive, so it happens on a dedicated set of threads
+ * rather than on the tokio workers that service network I/O.  Jobs for
+ * connections where somebody is actively typing or moving the pointer are
+ * run ahead of those for connections that are just watching.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    Interactive,
+    Bulk,
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct Queues {
+    interactive: VecDeque<Job>,
+    bulk: VecDeque<Job>,
+}
+
+struct Inner {
+    queues: Mutex<Queues>,
+    cv: Condvar,
+}
+
+pub struct Pool {
+    inner: Arc<Inner>,
+    /*
+     * Limits the number of jobs waiting for a thread.  Once the queue is full,
+     * submitters wait for space rather than piling up more work.
+     */
+    slots: Arc<Semaphore>,
+}
+
+impl Pool {
+    pub fn new(threads: usize, depth: usize) -> Result<Pool> {
+        let inner = Arc::new(Inner {
+            queues: Mutex::new(Queues::default()),
+            cv: Condvar::new(),
+        });
+
+        for i in 0..threads.max(1) {
+            let inner = Arc::clone(&inner);
+            std::thread::Builder::new()
+                .name(format!("encode-{}", i))
+                .spawn(move || worker(&inner))?;
+        }
+
+        Ok(Pool {
+            inner,
+            slots: Arc::new(Semaphore::new(depth.max(1))),
+        })
+    }
+
+    /*
+     * Run "f" on one of the pool threads and return the result.
+     */
+    pub async fn run<T, F>(&self, pri: Priority, f: F) -> Result<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let slot = Arc::clone(&self.slots).acquire_owned().await?;
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            /*
+             * The job has left the queue, so make room for another.
+             */
+            drop(slot);
+            tx.send(f()).ok();
+        });
+
+        {
+            let mut q = self.inner.queues.lock().unwrap();
+            match pri {
+                Priority::Interactive => q.interactive.push_back(job),
+                Priority::Bulk => q.bulk.push_back(job),
+            }
+        }
+        self.inner.cv.notify_one();
+
+        rx.await.map_err(|_| anyhow!("encode job failed"))
+    }
+
+    /*
+     * The number of interactive and bulk jobs waiting for a thread.
+     */
+    pub fn queued(&self) -> (usize, usize) {
+        let q = self.inner.queues.lock().unwrap();
+        (q.interactive.len(), q.bulk.len())
+    }
+}
+
+fn worker(inner: &Inner) {
+    loop {
+        let job = {
+            let mut q = inner.queues.lock().unwrap();
+            loop {
+                if let Some(job) = q.interactive.pop_front() {
+                    break job;
+                }
+                if let Some(job) = q.bulk.pop_front() {
+                    break job;
+                }
+                q = inner.cv.wait(q).unwrap();
+            }
+        };
+
+        /*
+         * A panic in one job should fail only the connection that submitted
+         * it, which will see its result channel close.
+         */
+        if catch_unwind(AssertUnwindSafe(job)).is_err() {
+            log!("encode job panicked");
+        }
+    }
+}
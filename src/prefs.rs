@@ -0,0 +1,186 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{bail, Result};
+use serde_json::{json, Map, Value};
+
+use crate::tier::{Tier, MAX_SCALE};
+
+/*
+ * Settings remembered for a particular user, which override the server
+ * defaults whenever that user connects.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Prefs {
+    /*
+     * Keep the connection in this quality tier, rather than choosing one
+     * based on the measured round trip time.
+     */
+    pub tier: Option<Tier>,
+    /*
+     * Ignore keyboard and pointer input from the client.
+     */
+    pub view_only: Option<bool>,
+    /*
+     * Reduce the framebuffer by this factor, regardless of the tier.
+     */
+    pub scale: Option<usize>,
+    /*
+     * Merge repeated keystrokes that arrive within this many milliseconds,
+     * for viewers that send them twice.
+     */
+    pub debounce: Option<u64>,
+}
+
+impl Prefs {
+    fn is_empty(&self) -> bool {
+        *self == Prefs::default()
+    }
+
+    fn to_json(&self) -> Value {
+        let mut m = Map::new();
+        if let Some(tier) = self.tier {
+            m.insert("tier".into(), json!(tier.name()));
+        }
+        if let Some(view_only) = self.view_only {
+            m.insert("view_only".into(), json!(view_only));
+        }
+        if let Some(scale) = self.scale {
+            m.insert("scale".into(), json!(scale));
+        }
+        if let Some(debounce) = self.debounce {
+            m.insert("debounce".into(), json!(debounce));
+        }
+        Value::Object(m)
+    }
+
+    fn from_json(v: &Value) -> Result<Prefs> {
+        let mut p = Prefs::default();
+        let Some(m) = v.as_object() else {
+            bail!("preferences must be an object");
+        };
+        for (k, v) in m {
+            let v = match v.as_str() {
+                Some(s) => s.to_string(),
+                None => v.to_string(),
+            };
+            p.set(k, &v)?;
+        }
+        Ok(p)
+    }
+
+    /*
+     * Set one preference from its string form, or clear it if the value is
+     * "-".
+     */
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let clear = value == "-";
+        match key {
+            "tier" if clear => self.tier = None,
+            "tier" => match Tier::parse(value) {
+                Some(t) => self.tier = Some(t),
+                None => bail!("tier must be lan, wan, or poor"),
+            },
+            "view_only" if clear => self.view_only = None,
+            "view_only" => self.view_only = Some(value.parse()?),
+            "scale" if clear => self.scale = None,
+            "scale" => match value.parse() {
+                Ok(n @ 1..=MAX_SCALE) => self.scale = Some(n),
+                _ => bail!("scale must be between 1 and {}", MAX_SCALE),
+            },
+            "debounce" if clear => self.debounce = None,
+            "debounce" => match value.parse() {
+                Ok(n @ 0..=MAX_DEBOUNCE) => self.debounce = Some(n),
+                _ => bail!("debounce must be between 0 and {} ms",
+                    MAX_DEBOUNCE),
+            },
+            _ => bail!("unknown preference {:?}", key),
+        }
+        Ok(())
+    }
+}
+
+const MAX_DEBOUNCE: u64 = 500;
+
+/*
+ * Preferences for all users, keyed by identity and kept in a JSON file.
+ */
+pub struct Store {
+    path: PathBuf,
+    prefs: Mutex<BTreeMap<String, Prefs>>,
+}
+
+impl Store {
+    /*
+     * Load the preferences file, which need not exist yet.
+     */
+    pub fn load(path: &Path) -> Result<Store> {
+        let mut prefs = BTreeMap::new();
+        match std::fs::read(path) {
+            Ok(buf) => {
+                let v: Value = serde_json::from_slice(&buf)?;
+                let Some(m) = v.as_object() else {
+                    bail!("{:?} does not contain an object", path);
+                };
+                for (id, p) in m {
+                    prefs.insert(id.clone(), Prefs::from_json(p)?);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(Store {
+            path: path.to_path_buf(),
+            prefs: Mutex::new(prefs),
+        })
+    }
+
+    pub fn get(&self, id: &str) -> Prefs {
+        self.prefs.lock().unwrap().get(id).cloned().unwrap_or_default()
+    }
+
+    /*
+     * Change one preference for a user, and save the result.
+     */
+    pub fn set(&self, id: &str, key: &str, value: &str) -> Result<()> {
+        let mut prefs = self.prefs.lock().unwrap();
+        let mut p = prefs.get(id).cloned().unwrap_or_default();
+        p.set(key, value)?;
+        if p.is_empty() {
+            prefs.remove(id);
+        } else {
+            prefs.insert(id.to_string(), p);
+        }
+        self.save(&prefs)
+    }
+
+    /*
+     * The preferences of one user, or all users, as JSON.
+     */
+    pub fn describe(&self, id: Option<&str>) -> Value {
+        let prefs = self.prefs.lock().unwrap();
+        match id {
+            Some(id) => prefs.get(id).cloned().unwrap_or_default().to_json(),
+            None => Value::Object(prefs.iter()
+                .map(|(id, p)| (id.clone(), p.to_json()))
+                .collect()),
+        }
+    }
+
+    /*
+     * Write to a temporary file and rename it into place, so that the file is
+     * never left half written.
+     */
+    fn save(&self, prefs: &BTreeMap<String, Prefs>) -> Result<()> {
+        let v: Map<String, Value> = prefs.iter()
+            .map(|(id, p)| (id.clone(), p.to_json()))
+            .collect();
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&v)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
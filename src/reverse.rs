@@ -0,0 +1,177 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+use crate::listen::{Addr, Stream, Transport};
+use crate::server::Server;
+
+/*
+ * Reverse connections, in which we connect to a viewer that is listening for
+ * us, rather than waiting for one to connect to us.  This lets a machine
+ * behind NAT or a firewall show its desktop to somebody outside, as for
+ * remote assistance.  Once connected, the viewer is served just as if it had
+ * connected to one of our listeners.
+ *
+ * We may instead connect to an UltraVNC repeater, giving it an ID, and the
+ * repeater joins us to the first viewer that asks it for that ID.  Until
+ * then, the repeater holds on to our connection.
+ *
+ * The viewer or repeater may not be listening yet, or may be out of reach for
+ * a while, so we keep trying, with longer and longer pauses between attempts,
+ * until it answers.
+ */
+
+/*
+ * Where viewers and repeaters conventionally listen, if the address names no
+ * port:
+ */
+const VIEWER_PORT: u16 = 5500;
+
+/*
+ * The repeater reads our ID as a string of exactly this many bytes, padded
+ * with zeros:
+ */
+const REPEATER_ID_LEN: usize = 250;
+
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(60);
+
+/*
+ * Connect to the viewer or repeater at "target", in the background, and serve
+ * the viewer.
+ */
+pub fn spawn(server: &Arc<Server>, target: &str) -> Result<()> {
+    let (id, host) = parse(target)?;
+    let server = Arc::clone(server);
+    tokio::spawn(async move {
+        let sock = dial(&host, id.as_deref()).await;
+        let addr = match sock.peer_addr() {
+            Ok(sa) => Addr::Tcp(sa),
+            Err(e) => {
+                log!("reverse connection to {}: {}", host, e);
+                return;
+            }
+        };
+        let transport = match &id {
+            Some(id) => {
+                log!("repeater connection to {} ({}) as {}", host, addr, id);
+                Transport::Repeater
+            }
+            None => {
+                log!("reverse connection to {} ({})", host, addr);
+                Transport::Reverse
+            }
+        };
+        if let Err(e) = server.adopt(Stream::Tcp(sock), addr, transport).await
+        {
+            log!("reverse connection to {}: {}", host, e);
+        }
+    });
+    Ok(())
+}
+
+/*
+ * Split a target into the ID to give a repeater, if it names one, and the
+ * address to connect to.
+ */
+fn parse(target: &str) -> Result<(Option<String>, String)> {
+    let Some((id, host)) = target.split_once('@') else {
+        return Ok((None, with_port(target, VIEWER_PORT)));
+    };
+    let Some(n) = id.strip_prefix("ID:") else {
+        bail!("expected a repeater ID as \"ID:NNNN\", not {:?}", id);
+    };
+    if n.is_empty() || !n.bytes().all(|b| b.is_ascii_digit()) {
+        bail!("a repeater ID must be a number, not {:?}", n);
+    }
+    if id.len() >= REPEATER_ID_LEN {
+        bail!("repeater ID is too long");
+    }
+    if host.is_empty() {
+        bail!("no address for the repeater");
+    }
+    Ok((Some(id.to_string()), with_port(host, VIEWER_PORT)))
+}
+
+/*
+ * "host" and "host:port" both name a peer, as do "[v6addr]" and
+ * "[v6addr]:port"; without a port, the peer is at "port".
+ */
+pub fn with_port(target: &str, port: u16) -> String {
+    let has_port = match target.rsplit_once(':') {
+        Some((host, _)) if target.starts_with('[') => host.ends_with(']'),
+        Some(_) => true,
+        None => false,
+    };
+    if has_port {
+        target.to_string()
+    } else {
+        format!("{}:{}", target, port)
+    }
+}
+
+/*
+ * What we send a repeater before anything else, to say which viewer we are
+ * waiting for:
+ */
+fn preamble(id: &str) -> [u8; REPEATER_ID_LEN] {
+    let mut buf = [0u8; REPEATER_ID_LEN];
+    buf[..id.len()].copy_from_slice(id.as_bytes());
+    buf
+}
+
+async fn connect(target: &str, id: Option<&str>) -> Result<TcpStream> {
+    let mut sock = TcpStream::connect(target).await?;
+    if let Some(id) = id {
+        sock.write_all(&preamble(id)).await?;
+    }
+    Ok(sock)
+}
+
+async fn dial(target: &str, id: Option<&str>) -> TcpStream {
+    let mut wait = FIRST_RETRY;
+    loop {
+        match connect(target, id).await {
+            Ok(sock) => return sock,
+            Err(e) => {
+                log!("reverse connection to {}: {}; trying again in {:?}",
+                    target, e, wait);
+            }
+        }
+        tokio::time::sleep(wait).await;
+        wait = (wait * 2).min(MAX_RETRY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ports() {
+        assert_eq!(with_port("viewer", 5500), "viewer:5500");
+        assert_eq!(with_port("viewer:5501", 5500), "viewer:5501");
+        assert_eq!(with_port("[::1]", 5500), "[::1]:5500");
+        assert_eq!(with_port("[::1]:5501", 5500), "[::1]:5501");
+    }
+
+    #[test]
+    fn repeaters() {
+        assert_eq!(parse("viewer").unwrap(), (None, "viewer:5500".into()));
+        assert_eq!(parse("ID:1234@repeater").unwrap(),
+            (Some("ID:1234".into()), "repeater:5500".into()));
+        assert_eq!(parse("ID:1234@[::1]:5901").unwrap(),
+            (Some("ID:1234".into()), "[::1]:5901".into()));
+        assert!(parse("1234@repeater").is_err());
+        assert!(parse("ID:12a4@repeater").is_err());
+        assert!(parse("ID:@repeater").is_err());
+        assert!(parse("ID:1234@").is_err());
+
+        let p = preamble("ID:1234");
+        assert_eq!(&p[..7], b"ID:1234");
+        assert!(p[7..].iter().all(|&b| b == 0));
+    }
+}
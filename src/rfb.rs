@@ -1,77 +1,284 @@
-use std::io::{Result, Error, ErrorKind};
+use std::convert::{TryFrom, TryInto};
+use std::io::{Result, Error};
 
 use async_stream::try_stream;
 use bytes::{BytesMut, Buf};
 use futures_core::stream::Stream;
-use tokio::io::AsyncReadExt;
-use tokio::net::tcp::ReadHalf;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::encoding::wire_coord;
+use crate::server::Access;
+use crate::touch;
+use crate::wire::{self, Counted, Reader, Wire};
+
+/*
+ * Pseudo-encodings that a client may include in SetEncodings to signal support
+ * for protocol extensions:
+ */
+pub const PSEUDO_QUALITY: std::ops::RangeInclusive<i32> = -32..=-23;
+pub const PSEUDO_COMPRESSION: std::ops::RangeInclusive<i32> = -256..=-247;
+pub const PSEUDO_FENCE: i32 = -312;
+pub const PSEUDO_XVP: i32 = -309;
+pub const PSEUDO_QEMU_EXTENDED_KEY: i32 = -258;
+pub const PSEUDO_EXTENDED_CLIPBOARD: i32 = 0xc0a1e5ce_u32 as i32;
+pub const PSEUDO_GII: i32 = -305;
+
+/*
+ * Flags for the Fence message:
+ */
+pub const FENCE_BLOCK_BEFORE: u32 = 1 << 0;
+pub const FENCE_REQUEST: u32 = 1 << 31;
+
+/*
+ * Version and message codes for the xvp extension, which lets a client ask
+ * for the machine behind the framebuffer to be shut down or restarted:
+ */
+pub const XVP_VERSION: u8 = 1;
+pub const XVP_FAIL: u8 = 0;
+pub const XVP_INIT: u8 = 1;
+pub const XVP_SHUTDOWN: u8 = 2;
+pub const XVP_REBOOT: u8 = 3;
+pub const XVP_RESET: u8 = 4;
 
-trait SighFactoryExt {
-    fn peek_u16(&self, offset: usize) -> Option<u16>;
-    fn peek_u32(&self, offset: usize) -> Option<u32>;
+#[derive(Debug)]
+pub struct UpdateRequest {
+    pub incremental: bool,
+    pub xpos: usize,
+    pub ypos: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/*
+ * One of the screens that make up the framebuffer, as described in the
+ * ExtendedDesktopSize pseudo-encoding and the SetDesktopSize message.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Screen {
+    pub id: u32,
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    pub flags: u32,
 }
 
-impl SighFactoryExt for BytesMut {
-    fn peek_u16(&self, offset: usize) -> Option<u16> {
-        if self.len() < offset + 2 {
-            None
-        } else {
-            let b0 = self[offset] as u16;
-            let b1 = self[offset + 1] as u16;
-            Some(b0 << 8 | b1)
+impl Wire for Screen {
+    const SIZE: usize = 16;
+
+    fn read(r: &mut Reader) -> wire::Result<Screen> {
+        Ok(Screen {
+            id: u32::read(r)?,
+            x: u16::read(r)? as usize,
+            y: u16::read(r)? as usize,
+            width: u16::read(r)? as usize,
+            height: u16::read(r)? as usize,
+            flags: u32::read(r)?,
+        })
+    }
+
+    fn write(&self, w: &mut Vec<u8>) {
+        self.id.write(w);
+        for v in [self.x, self.y, self.width, self.height] {
+            wire_coord(v).write(w);
         }
+        self.flags.write(w);
     }
+}
+
+/*
+ * How the client would like pixels to be represented.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelFormat {
+    pub bpp: u8,
+    pub depth: u8,
+    pub big_endian: bool,
+    /*
+     * If not set, pixel values are indices into a colour map that the server
+     * provides with SetColourMapEntries, and the maximum and shift values
+     * have no meaning.
+     */
+    pub true_colour: bool,
+    pub red_max: u16,
+    pub green_max: u16,
+    pub blue_max: u16,
+    pub red_shift: u8,
+    pub green_shift: u8,
+    pub blue_shift: u8,
+}
+
+impl PixelFormat {
+    /*
+     * The format we advertise in ServerInit: little endian 32 bits per pixel,
+     * which is the in-memory layout of our pixel word.
+     */
+    pub const NATIVE: PixelFormat = PixelFormat {
+        bpp: 32,
+        depth: 24,
+        big_endian: false,
+        true_colour: true,
+        red_max: 255,
+        green_max: 255,
+        blue_max: 255,
+        red_shift: 16,
+        green_shift: 8,
+        blue_shift: 0,
+    };
+}
 
-    fn peek_u32(&self, offset: usize) -> Option<u32> {
-        if self.len() < offset + 2 {
-            None
-        } else {
-            let b0 = self[offset] as u32;
-            let b1 = self[offset + 1] as u32;
-            let b2 = self[offset + 2] as u32;
-            let b3 = self[offset + 3] as u32;
-            Some(b0 << 24 | b1 << 16 | b2 << 8 | b3)
+impl Wire for PixelFormat {
+    const SIZE: usize = 16;
+
+    fn read(r: &mut Reader) -> wire::Result<PixelFormat> {
+        let pf = PixelFormat {
+            bpp: u8::read(r)?,
+            depth: u8::read(r)?,
+            big_endian: u8::read(r)? != 0,
+            true_colour: u8::read(r)? != 0,
+            red_max: u16::read(r)?,
+            green_max: u16::read(r)?,
+            blue_max: u16::read(r)?,
+            red_shift: u8::read(r)?,
+            green_shift: u8::read(r)?,
+            blue_shift: u8::read(r)?,
+        };
+        r.take(3)?;
+        Ok(pf)
+    }
+
+    fn write(&self, w: &mut Vec<u8>) {
+        for v in [self.bpp, self.depth, self.big_endian as u8,
+            self.true_colour as u8]
+        {
+            v.write(w);
+        }
+        for v in [self.red_max, self.green_max, self.blue_max] {
+            v.write(w);
+        }
+        for v in [self.red_shift, self.green_shift, self.blue_shift, 0, 0, 0] {
+            v.write(w);
         }
     }
 }
 
-#[derive(Debug)]
-pub enum Security {
-    None,
+/*
+ * An entry in the colour map, with 16 bits for each channel.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Colour {
+    pub red: u16,
+    pub green: u16,
+    pub blue: u16,
 }
 
-#[derive(Debug)]
-pub enum Access {
-    Exclusive,
-    Shared,
+impl Wire for Colour {
+    const SIZE: usize = 6;
+
+    fn read(r: &mut Reader) -> wire::Result<Colour> {
+        Ok(Colour {
+            red: u16::read(r)?,
+            green: u16::read(r)?,
+            blue: u16::read(r)?,
+        })
+    }
+
+    fn write(&self, w: &mut Vec<u8>) {
+        for v in [self.red, self.green, self.blue] {
+            v.write(w);
+        }
+    }
 }
 
-#[derive(Debug)]
-pub struct UpdateRequest {
-    pub incremental: bool,
-    pub xpos: usize,
-    pub ypos: usize,
-    pub width: usize,
-    pub height: usize,
+messages! {
+    /*
+     * Messages sent by the client once the handshake is complete:
+     */
+    pub enum ClientMessage {
+        0 => SetPixelFormat { [3] format: PixelFormat },
+        2 => SetEncodings { [1] encodings: Counted<u16, i32> },
+        3 => FramebufferUpdateRequest {
+            incremental: u8,
+            x: u16,
+            y: u16,
+            width: u16,
+            height: u16,
+        },
+        4 => KeyEvent { down: u8, [2] key: u32 },
+        5 => PointerEvent { buttons: u8, x: u16, y: u16 },
+        6 => ClientCutText { [3] text: Counted<u32, u8> },
+        248 => Fence { [3] flags: u32, payload: Counted<u8, u8> },
+        250 => Xvp { [1] version: u8, code: u8 },
+        251 => SetDesktopSize {
+            [1] width: u16,
+            height: u16,
+            screens: Counted<u8, Screen, 1>,
+        },
+        255/0 => QemuKeyEvent { down: u16, keysym: u32, keycode: u32 },
+    }
+}
+
+messages! {
+    /*
+     * Messages sent by the server, other than FramebufferUpdate, which is
+     * assembled by the encoding pipeline.
+     */
+    pub enum ServerMessage {
+        1 => SetColourMapEntries {
+            [1] first: u16,
+            colours: Counted<u16, Colour>,
+        },
+        2 => Bell {},
+        3 => ServerCutText { [3] text: Counted<u32, u8> },
+        248 => ServerFence { [3] flags: u32, payload: Counted<u8, u8> },
+        250 => ServerXvp { [1] version: u8, code: u8 },
+    }
 }
 
 #[derive(Debug)]
 pub enum Frame {
-    ProtocolVersion(String),
-    SecuritySelection(Security),
     ClientInit(Access),
-    SetPixelFormat,
+    SetPixelFormat(PixelFormat),
     SetEncodings(Vec<i32>),
     KeyEvent(u8, u32),
+    /*
+     * A key event from the QEMU extension, with both the keysym and the XT
+     * scancode of the physical key.  Either may be zero if the client does
+     * not know it.
+     */
+    ExtendedKeyEvent(u8, u32, u32),
     PointerEvent(u8, u16, u16),
-    ClientCutText,
+    /*
+     * Clipboard text from the client, converted from Latin-1:
+     */
+    ClientCutText(String),
+    /*
+     * Clipboard text longer than we are prepared to accept, which has been
+     * discarded; the length it claimed is included.
+     */
+    ClientCutTextTooLong(usize),
+    /*
+     * An extended clipboard message, with its flags and payload:
+     */
+    ExtendedClipboard(u32, Vec<u8>),
+    /*
+     * A message from the General Input Interface extension, with its subtype
+     * and payload:
+     */
+    Gii(u8, Vec<u8>),
+    /*
+     * A message from an extension we do not implement, with its type and
+     * subtype, which has been skipped:
+     */
+    Skipped(u8, Option<u8>),
     FramebufferUpdateRequest(UpdateRequest),
-    EOF,
+    Fence(u32, Vec<u8>),
+    SetDesktopSize(usize, usize, Vec<Screen>),
+    Xvp(u8, u8),
+    Eof,
 }
 
 enum State {
-    Version,
-    SecuritySelection,
     ClientInit,
     Message,
 }
@@ -81,19 +288,33 @@ struct Rfb {
     eof: bool,
     failed: bool,
     state: State,
+    max_cut_text: usize,
+    /*
+     * Whether to skip messages we do not understand, where we know how long
+     * they are, rather than failing:
+     */
+    skip_unknown: bool,
+    /*
+     * Bytes still to be thrown away from an oversized ClientCutText or a
+     * skipped message:
+     */
+    discard: usize,
 }
 
 fn fail_<T>(msg: &str) -> Result<T> {
-    Err(Error::new(ErrorKind::Other, msg.to_string()))
+    Err(Error::other(msg.to_string()))
 }
 
 impl Rfb {
-    fn new() -> Self {
+    fn new(max_cut_text: usize, skip_unknown: bool) -> Self {
         Rfb {
             buf: BytesMut::with_capacity(4096),
             eof: false,
             failed: false,
-            state: State::Version,
+            state: State::ClientInit,
+            max_cut_text,
+            skip_unknown,
+            discard: 0,
         }
     }
 
@@ -102,10 +323,22 @@ impl Rfb {
             return fail_("earlier failure");
         }
         self.failed = true;
-        return fail_(msg);
+        fail_(msg)
     }
 
+    /*
+     * The next frame, or None if we must wait for more of it to arrive.  Once
+     * the client has closed its end, nothing more will, and a partial message
+     * is an error.
+     */
     fn parse(&mut self) -> Result<Option<Frame>> {
+        match self.parse_buffered()? {
+            None if self.eof => self.fail("truncated message"),
+            f => Ok(f),
+        }
+    }
+
+    fn parse_buffered(&mut self) -> Result<Option<Frame>> {
         if self.failed {
             return self.fail("");
         }
@@ -115,53 +348,22 @@ impl Rfb {
          * byte (typically the message ID) in the front of the buffer for all
          * states:
          */
-        if self.buf.is_empty() {
+        if self.discard > 0 {
+            let n = self.discard.min(self.buf.len());
+            self.buf.advance(n);
+            self.discard -= n;
+            if self.discard > 0 && self.eof {
+                return self.fail("truncated message");
+            }
+        }
+        if self.buf.is_empty() || self.discard > 0 {
             if self.eof {
-                return Ok(Some(Frame::EOF));
+                return Ok(Some(Frame::Eof));
             }
             return Ok(None);
         }
 
         match self.state {
-            State::Version => {
-                /*
-                 * Wait for a complete version handshake.
-                 */
-                if !self.buf.contains(&('\n' as u8)) {
-                    if self.buf.len() > 100 {
-                        /*
-                         * This handshake is too long.
-                         */
-                        return self.fail("handshake too long");
-                    }
-
-                    return Ok(None);
-                }
-
-                let mut s = String::new();
-                loop {
-                    let c = self.buf.get_u8();
-                    if c >= 128 {
-                        return self.fail("invalid handshake byte");
-                    }
-                    if c == '\n' as u8 {
-                        break;
-                    }
-                    s.push(c as char);
-                }
-
-                self.state = State::SecuritySelection;
-                return Ok(Some(Frame::ProtocolVersion(s)));
-            }
-            State::SecuritySelection => {
-                let sec = self.buf.get_u8();
-                if sec != 1 {
-                    return self.fail(&format!("invalid security {}", sec));
-                }
-
-                self.state = State::ClientInit;
-                return Ok(Some(Frame::SecuritySelection(Security::None)));
-            }
             State::ClientInit => {
                 let acc = if self.buf.get_u8() == 0 {
                     Access::Exclusive
@@ -170,108 +372,136 @@ impl Rfb {
                 };
 
                 self.state = State::Message;
-                return Ok(Some(Frame::ClientInit(acc)));
+                Ok(Some(Frame::ClientInit(acc)))
             }
             State::Message => {
-                match self.buf[0] {
-                    0 => {
-                        if self.buf.len() < 1 + 3 + 16 {
-                            return Ok(None);
-                        }
-
-                        /*
-                         * XXX
-                         */
-                        self.buf.advance(1 + 3 + 16);
-                        return Ok(Some(Frame::SetPixelFormat));
+                /*
+                 * Check the length of clipboard text before we wait for all
+                 * of it to arrive in the buffer.  A negative length marks an
+                 * extended clipboard message, which our message table
+                 * cannot describe.
+                 */
+                if self.buf[0] == 6 && self.buf.len() >= 8 {
+                    let len = i32::from_be_bytes(
+                        self.buf[4..8].try_into().unwrap());
+                    let extended = len < 0;
+                    let len = len.unsigned_abs() as usize;
+                    if len > self.max_cut_text {
+                        self.buf.advance(8);
+                        self.discard = len;
+                        return Ok(Some(Frame::ClientCutTextTooLong(len)));
                     }
-                    2 => {
-                        let nenc = if let Some(nenc) = self.buf.peek_u16(2) {
-                            let nenc = nenc as usize;
-                            if self.buf.len() < 4 + nenc * 4 {
-                                return Ok(None);
-                            } else {
-                                nenc
-                            }
-                        } else {
+                    if extended {
+                        if len < 4 {
+                            return self.fail("invalid extended clipboard");
+                        }
+                        if self.buf.len() < 8 + len {
                             return Ok(None);
-                        };
-
-                        self.buf.advance(4);
-                        let mut encs = Vec::new();
-                        for _ in 0..nenc {
-                            encs.push(self.buf.get_i32());
                         }
+                        self.buf.advance(8);
+                        let flags = self.buf.get_u32();
+                        let payload = self.buf.split_to(len - 4).to_vec();
+                        return Ok(Some(Frame::ExtendedClipboard(flags,
+                            payload)));
+                    }
+                }
 
-                        return Ok(Some(Frame::SetEncodings(encs)));
+                /*
+                 * GII messages may be in either byte order, which our
+                 * message table cannot describe either.
+                 */
+                if self.buf[0] == touch::MESSAGE_TYPE {
+                    if self.buf.len() < 4 {
+                        return Ok(None);
                     }
-                    3 => {
-                        if self.buf.len() < 10 {
-                            return Ok(None);
-                        }
+                    let sub = self.buf[1];
+                    let len = touch::length(sub,
+                        self.buf[2..4].try_into().unwrap());
+                    if self.buf.len() < 4 + len {
+                        return Ok(None);
+                    }
+                    self.buf.advance(4);
+                    let payload = self.buf.split_to(len).to_vec();
+                    return Ok(Some(Frame::Gii(sub, payload)));
+                }
 
-                        self.buf.advance(1);
-                        let ur = UpdateRequest {
-                            incremental: self.buf.get_u8() != 0,
-                            xpos: self.buf.get_u16() as usize,
-                            ypos: self.buf.get_u16() as usize,
-                            width: self.buf.get_u16() as usize,
-                            height: self.buf.get_u16() as usize,
+                let mut r = Reader::new(&self.buf);
+                let m = match ClientMessage::read(&mut r) {
+                    Ok(m) => m,
+                    Err(wire::Error::Short) => return Ok(None),
+                    Err(wire::Error::Unknown(n, sub)) if self.skip_unknown => {
+                        let len = match unknown_length(&self.buf) {
+                            Ok(Some(len)) => len,
+                            Ok(None) => {
+                                return self.fail(&format!("invalid message \
+                                    {} of unknown length", n));
+                            }
+                            Err(_) => return Ok(None),
                         };
-
-                        return Ok(Some(Frame::FramebufferUpdateRequest(ur)));
+                        let have = len.min(self.buf.len());
+                        self.buf.advance(have);
+                        self.discard = len - have;
+                        return Ok(Some(Frame::Skipped(n, sub)));
                     }
-                    4 => {
-                        if self.buf.len() < 1 + 1 + 2 + 4 {
-                            return Ok(None);
-                        }
-
-                        self.buf.advance(1);
-                        let downflag = self.buf.get_u8();
-                        self.buf.advance(2);
-                        let key = self.buf.get_u32();
+                    Err(wire::Error::Unknown(n, None)) => {
+                        return self.fail(&format!("invalid message {}", n));
+                    }
+                    Err(wire::Error::Unknown(n, Some(sub))) => {
+                        return self.fail(&format!("invalid message {}/{}",
+                            n, sub));
+                    }
+                };
+                let len = r.pos();
+                self.buf.advance(len);
 
-                        return Ok(Some(Frame::KeyEvent(downflag, key)));
+                Ok(Some(match m {
+                    ClientMessage::SetPixelFormat(m) => {
+                        Frame::SetPixelFormat(m.format)
                     }
-                    5 => {
-                        if self.buf.len() < 1 + 1 + 2 + 2 {
-                            return Ok(None);
+                    ClientMessage::SetEncodings(m) => {
+                        Frame::SetEncodings(m.encodings.items)
+                    }
+                    ClientMessage::FramebufferUpdateRequest(m) => {
+                        Frame::FramebufferUpdateRequest(UpdateRequest {
+                            incremental: m.incremental != 0,
+                            xpos: m.x as usize,
+                            ypos: m.y as usize,
+                            width: m.width as usize,
+                            height: m.height as usize,
+                        })
+                    }
+                    ClientMessage::KeyEvent(m) => Frame::KeyEvent(m.down, m.key),
+                    ClientMessage::PointerEvent(m) => {
+                        Frame::PointerEvent(m.buttons, m.x, m.y)
+                    }
+                    ClientMessage::ClientCutText(m) => {
+                        Frame::ClientCutText(m.text.items.iter()
+                            .map(|&b| char::from(b))
+                            .collect())
+                    }
+                    ClientMessage::Fence(m) => {
+                        if m.payload.items.len() > 64 {
+                            return self.fail("fence payload too long");
                         }
-
-                        self.buf.advance(1);
-                        let button_mask = self.buf.get_u8();
-                        let xpos = self.buf.get_u16();
-                        let ypos = self.buf.get_u16();
-
-                        return Ok(Some(Frame::PointerEvent(button_mask,
-                            xpos, ypos)));
+                        Frame::Fence(m.flags, m.payload.items)
                     }
-                    6 => {
-                        let nchar = if let Some(v) = self.buf.peek_u32(1 + 3) {
-                            let nchar = v as usize;
-                            if self.buf.len() < 1 + 3 + 4 + nchar {
-                                return Ok(None);
-                            } else {
-                                nchar
-                            }
-                        } else {
-                            return Ok(None);
-                        };
-
-                        self.buf.advance(1 + 3 + 4);
-                        self.buf.advance(nchar); /* XXX */
-
-                        return Ok(Some(Frame::ClientCutText));
+                    ClientMessage::Xvp(m) => Frame::Xvp(m.version, m.code),
+                    ClientMessage::SetDesktopSize(m) => {
+                        Frame::SetDesktopSize(m.width as usize,
+                            m.height as usize, m.screens.items)
                     }
-                    n => {
-                        return self.fail(&format!("invalid message {}", n));
+                    ClientMessage::QemuKeyEvent(m) => {
+                        Frame::ExtendedKeyEvent((m.down != 0) as u8, m.keysym,
+                            m.keycode)
                     }
-                }
+                }))
             }
         }
     }
 
-    async fn ingest(&mut self, r: &mut ReadHalf<'_>) -> Result<()> {
+    async fn ingest<R: AsyncRead + Unpin>(&mut self, r: &mut R)
+        -> Result<()>
+    {
         if self.eof {
             /*
              * XXX
@@ -287,19 +517,170 @@ impl Rfb {
     }
 }
 
-pub fn read_stream<'a>(r: ReadHalf<'a>)
-    -> impl Stream<Item = Result<Frame>> + 'a
+/*
+ * The length of a client message from an extension we do not implement, if
+ * we know how to tell, so that it can be skipped.  The header must have
+ * arrived, but not the rest of the message.
+ */
+fn unknown_length(buf: &[u8]) -> wire::Result<Option<usize>> {
+    let mut r = Reader::new(buf);
+    Ok(Some(match u8::read(&mut r)? {
+        /*
+         * UltraVNC file transfer, with a 12 byte header and a payload:
+         */
+        7 => {
+            r.take(7)?;
+            12 + u32::read(&mut r)? as usize
+        }
+        /*
+         * UltraVNC SetScale, SetServerInput and SetScaleFactor:
+         */
+        8 | 9 | 15 => 4,
+        /*
+         * UltraVNC SetSW, which selects a single window to share:
+         */
+        10 => 6,
+        /*
+         * UltraVNC text chat.  The lengths at the very top of the range are
+         * commands, such as opening the chat window, and carry no text.
+         */
+        11 => {
+            r.take(3)?;
+            8 + match u32::read(&mut r)? {
+                n if n >= 0xffff_fff0 => 0,
+                n => n as usize,
+            }
+        }
+        /*
+         * UltraVNC KeyFrameRequest:
+         */
+        12 => 1,
+        /*
+         * EnableContinuousUpdates:
+         */
+        150 => 10,
+        /*
+         * QEMU audio, for which only the "set format" operation has
+         * arguments:
+         */
+        255 if r.peek(0)? == 1 => {
+            r.take(1)?;
+            if u16::read(&mut r)? == 2 { 10 } else { 4 }
+        }
+        _ => return Ok(None),
+    }))
+}
+
+/*
+ * The protocol versions we speak.  The differences are all in the security
+ * handshake.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Version {
+    V3_3,
+    V3_7,
+    V3_8,
+}
+
+impl Version {
+    /*
+     * Interpret a ProtocolVersion string.  We are lenient about what we
+     * accept: anything after the version number is taken to be vendor
+     * information and ignored, and minor versions we do not know are mapped
+     * to the nearest one below that we do.  Apple's Screen Sharing, for
+     * example, claims 3.889 but otherwise behaves as 3.8, and some older
+     * viewers claim 3.4 or 3.6 and speak 3.3.
+     */
+    pub fn parse(s: &str) -> Option<Version> {
+        fn number(s: &str) -> Option<u32> {
+            if s.len() != 3 || !s.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            s.parse().ok()
+        }
+
+        let v = s.strip_prefix("RFB ")?;
+        let major = number(v.get(0..3)?)?;
+        if v.get(3..4)? != "." {
+            return None;
+        }
+        let minor = number(v.get(4..7)?)?;
+
+        match (major, minor) {
+            (3, 3..=6) => Some(Version::V3_3),
+            (3, 7) => Some(Version::V3_7),
+            (3, 8..) | (4.., _) => Some(Version::V3_8),
+            _ => None,
+        }
+    }
+
+    /*
+     * The ProtocolVersion string for this version, without the newline.
+     */
+    pub fn wire(&self) -> &'static str {
+        match self {
+            Version::V3_3 => "RFB 003.003",
+            Version::V3_7 => "RFB 003.007",
+            Version::V3_8 => "RFB 003.008",
+        }
+    }
+}
+
+/*
+ * Convert text for ServerCutText, which carries Latin-1 with lines ended by
+ * a bare newline.  Characters outside Latin-1 become question marks.
+ */
+pub fn cut_text(text: &str) -> Vec<u8> {
+    text.replace("\r\n", "\n")
+        .chars()
+        .map(|c| u8::try_from(u32::from(c)).unwrap_or(b'?'))
+        .collect()
+}
+
+/*
+ * Read the client's ProtocolVersion handshake.  This happens before any
+ * security type has been negotiated, which may replace the transport, so we
+ * read only as far as the end of the line.
+ */
+pub async fn read_version<R>(r: &mut R) -> Result<String>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut s = String::new();
+    loop {
+        let c = r.read_u8().await?;
+        if c >= 128 {
+            return fail_("invalid handshake byte");
+        }
+        if c == b'\n' {
+            return Ok(s);
+        }
+        if s.len() >= 100 {
+            return fail_("handshake too long");
+        }
+        s.push(c as char);
+    }
+}
+
+/*
+ * Parse client messages from "r".  The stream owns the reader, so it may
+ * outlive the function that created it, independently of the writer.
+ * Clipboard text longer than "max_cut_text" bytes is discarded.
+ */
+pub fn read_stream<R>(mut r: R, max_cut_text: usize, skip_unknown: bool)
+    -> impl Stream<Item = Result<Frame>>
+where
+    R: AsyncRead + Unpin,
 {
     try_stream! {
-        tokio::pin!(r);
-        let mut rfb = Rfb::new();
+        let mut rfb = Rfb::new(max_cut_text, skip_unknown);
 
         'outer: loop {
             rfb.ingest(&mut r).await?;
 
             'parse: loop {
                 match rfb.parse()? {
-                    Some(Frame::EOF) => break 'outer,
+                    Some(Frame::Eof) => break 'outer,
                     Some(f) => yield f,
                     None => break 'parse,
                 }
@@ -307,3 +688,147 @@ pub fn read_stream<'a>(r: ReadHalf<'a>)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_CUT_TEXT: usize = 1024;
+
+    fn parse(bytes: &[u8], eof: bool) -> Result<Option<Frame>> {
+        let mut rfb = Rfb::new(MAX_CUT_TEXT, false);
+        rfb.state = State::Message;
+        rfb.buf.extend_from_slice(bytes);
+        rfb.eof = eof;
+        rfb.parse()
+    }
+
+    fn message(m: ClientMessage) -> Vec<u8> {
+        let mut v = Vec::new();
+        m.write(&mut v);
+        v
+    }
+
+    /*
+     * One of each message a client may send:
+     */
+    fn messages() -> Vec<Vec<u8>> {
+        let screen = Screen {
+            id: 1,
+            x: 0,
+            y: 0,
+            width: 640,
+            height: 480,
+            flags: 0,
+        };
+        let mut extended = vec![6, 0, 0, 0];
+        extended.extend((-8i32).to_be_bytes());
+        extended.extend([0, 0, 0, 1, b'a', b'b', b'c', 0]);
+
+        vec![
+            message(SetPixelFormat { format: PixelFormat::NATIVE }.into()),
+            message(SetEncodings {
+                encodings: vec![0, 16, PSEUDO_FENCE].into(),
+            }.into()),
+            message(FramebufferUpdateRequest {
+                incremental: 1,
+                x: 0,
+                y: 0,
+                width: 640,
+                height: 480,
+            }.into()),
+            message(KeyEvent { down: 1, key: 0x61 }.into()),
+            message(PointerEvent { buttons: 1, x: 10, y: 20 }.into()),
+            message(ClientCutText { text: b"hello".to_vec().into() }.into()),
+            extended,
+            message(Fence {
+                flags: FENCE_REQUEST,
+                payload: vec![1, 2, 3].into(),
+            }.into()),
+            message(Xvp { version: XVP_VERSION, code: XVP_REBOOT }.into()),
+            message(SetDesktopSize {
+                width: 640,
+                height: 480,
+                screens: vec![screen].into(),
+            }.into()),
+            message(QemuKeyEvent { down: 1, keysym: 0x61, keycode: 30 }
+                .into()),
+            vec![touch::MESSAGE_TYPE, 0x80 | 1, 0, 4, 1, 2, 3, 4],
+            vec![touch::MESSAGE_TYPE, 1, 4, 0, 1, 2, 3, 4],
+        ]
+    }
+
+    #[test]
+    fn truncated() {
+        for m in messages() {
+            match parse(&m, false) {
+                Ok(Some(_)) => (),
+                r => panic!("{:?}: {:?}", m, r),
+            }
+
+            /*
+             * Every prefix of the message leaves us waiting for the rest,
+             * or, if the client has gone, is an error.
+             */
+            for n in 1..m.len() {
+                match parse(&m[..n], false) {
+                    Ok(None) => (),
+                    r => panic!("{:?} cut at {}: {:?}", m, n, r),
+                }
+                assert!(parse(&m[..n], true).is_err(),
+                    "{:?} cut at {} and closed", m, n);
+            }
+        }
+    }
+
+    #[test]
+    fn oversized() {
+        let mut encodings = vec![2, 0, 0xff, 0xff];
+        encodings.extend([0; 40]);
+
+        let mut screens = vec![251, 0, 2, 128, 1, 224, 0xff, 0];
+        screens.extend([0; 16]);
+
+        let mut gii = vec![touch::MESSAGE_TYPE, 0x80 | 1, 0xff, 0xff];
+        gii.extend([0; 16]);
+
+        /*
+         * Counts far beyond what has arrived leave us waiting, without first
+         * allocating room for that many items:
+         */
+        for m in [encodings, screens, gii] {
+            match parse(&m, false) {
+                Ok(None) => (),
+                r => panic!("{:?}: {:?}", &m[..4], r),
+            }
+        }
+
+        /*
+         * Clipboard text is refused from its header if it would be too long,
+         * whether plain or extended:
+         */
+        for len in [i32::MAX, -i32::MAX, MAX_CUT_TEXT as i32 + 1] {
+            let mut m = vec![6, 0, 0, 0];
+            m.extend(len.to_be_bytes());
+            m.extend(b"abc");
+            match parse(&m, false) {
+                Ok(Some(Frame::ClientCutTextTooLong(n))) => {
+                    assert_eq!(n, len.unsigned_abs() as usize);
+                }
+                r => panic!("cut text of {}: {:?}", len, r),
+            }
+        }
+
+        /*
+         * An extended clipboard message too short for its flags, and a fence
+         * with more payload than the extension allows, are errors.
+         */
+        let mut m = vec![6, 0, 0, 0];
+        m.extend((-2i32).to_be_bytes());
+        assert!(parse(&m, false).is_err());
+
+        let mut m = vec![248, 0, 0, 0, 0, 0, 0, 0, 255];
+        m.extend([0; 255]);
+        assert!(parse(&m, false).is_err());
+    }
+}
@@ -0,0 +1,467 @@
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::ard;
+use crate::config::Config;
+use crate::handshake::{Failed, Outcome};
+use crate::rfb::Version;
+use crate::server::Server;
+use crate::vncauth;
+
+/*
+ * Security types:
+ */
+pub const NONE: u8 = 1;
+pub const VNC_AUTH: u8 = 2;
+pub const VENCRYPT: u8 = 19;
+pub const ARD: u8 = 30;
+
+/*
+ * VeNCrypt subtypes.  Each pairs a kind of TLS with a means of
+ * authentication once the session is encrypted.
+ */
+pub const TLS_NONE: u32 = 257;
+pub const TLS_VNC: u32 = 258;
+pub const TLS_PLAIN: u32 = 259;
+pub const X509_NONE: u32 = 260;
+pub const X509_VNC: u32 = 261;
+pub const X509_PLAIN: u32 = 262;
+
+/*
+ * Plain credentials longer than this are refused before we read them.
+ */
+const MAX_CREDENTIAL: usize = 1024;
+
+/*
+ * After telling a client why it has been turned away, we wait this long for
+ * it to close the connection.
+ */
+const LINGER: Duration = Duration::from_secs(2);
+
+/*
+ * A connection over which the RFB session may run, once security has been
+ * negotiated.  It may or may not be the one that was accepted.
+ */
+pub trait Conn: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Conn for T {}
+
+/*
+ * The client gave the wrong password, as distinct from any other failure of
+ * the handshake.
+ */
+#[derive(Debug)]
+pub struct AuthFailed(pub String);
+
+impl std::fmt::Display for AuthFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for AuthFailed {}
+
+/*
+ * The outcome of a successful security handshake.
+ */
+pub struct Authenticated {
+    /*
+     * The connection over which the session continues:
+     */
+    pub conn: Box<dyn Conn>,
+    /*
+     * The name the client gave, if any, which nothing has verified:
+     */
+    pub identity: Option<String>,
+    /*
+     * Whether the client gave the view-only password, and so may watch but
+     * not interact.
+     */
+    pub view_only: bool,
+}
+
+/*
+ * Work out which security types to offer.  Unless the configuration lists
+ * them, we use VNC Authentication when there is a password and None
+ * otherwise.
+ */
+pub fn resolve(config: &Config) -> Result<Vec<u8>> {
+    let password = config.password.is_some();
+    if config.view_password.is_some() && !password {
+        bail!("a view-only password requires a password for full access");
+    }
+    let Some(names) = &config.security else {
+        return Ok(vec![if password { VNC_AUTH } else { NONE }]);
+    };
+
+    let mut types = Vec::new();
+    for n in names {
+        let Some(sec) = from_name(n) else {
+            bail!("unknown security type {:?}", n);
+        };
+        if (sec == VNC_AUTH || sec == ARD) && !password {
+            bail!("security type {:?} requires a password", n);
+        }
+        if sec == NONE && password {
+            log!("warning: clients may choose security type \"none\" and \
+                skip the password");
+        }
+        if !types.contains(&sec) {
+            types.push(sec);
+        }
+    }
+    if types.is_empty() {
+        bail!("no security types configured");
+    }
+    Ok(types)
+}
+
+/*
+ * The VeNCrypt subtypes we offer, in order of preference.  The ones with a
+ * certificate require it to be configured, and there is no way to skip
+ * authentication once a password is set.
+ */
+pub fn subtypes(server: &Server) -> Vec<u32> {
+    let x509 = server.vencrypt.as_ref().is_some_and(|v| v.has_x509());
+    let all: &[u32] = if server.config.password.is_some() {
+        &[X509_VNC, X509_PLAIN, TLS_VNC, TLS_PLAIN]
+    } else {
+        &[X509_NONE, TLS_NONE]
+    };
+    all.iter()
+        .copied()
+        .filter(|s| x509 || !is_x509(*s))
+        .collect()
+}
+
+fn is_x509(subtype: u32) -> bool {
+    (X509_NONE..=X509_PLAIN).contains(&subtype)
+}
+
+const NAMES: [(u8, &str); 4] = [
+    (NONE, "none"),
+    (VNC_AUTH, "vnc-auth"),
+    (VENCRYPT, "vencrypt"),
+    (ARD, "ard"),
+];
+
+pub fn name(sec: u8) -> &'static str {
+    NAMES.iter()
+        .find(|(s, _)| *s == sec)
+        .map_or("unknown", |(_, n)| n)
+}
+
+pub fn from_name(name: &str) -> Option<u8> {
+    NAMES.iter().find(|(_, n)| *n == name).map(|(s, _)| *s)
+}
+
+pub fn subtype_name(subtype: u32) -> &'static str {
+    match subtype {
+        TLS_NONE => "tls-none",
+        TLS_VNC => "tls-vnc",
+        TLS_PLAIN => "tls-plain",
+        X509_NONE => "x509-none",
+        X509_VNC => "x509-vnc",
+        X509_PLAIN => "x509-plain",
+        _ => "unknown",
+    }
+}
+
+/*
+ * Conduct the security handshake, through to a successful SecurityResult.
+ */
+pub async fn negotiate<S>(server: &Server, mut sock: S, version: Version)
+    -> Result<Authenticated>
+where
+    S: Conn + 'static,
+{
+    let offer = &server.security;
+    let sec = if version == Version::V3_3 {
+        /*
+         * In RFB 3.3 the server decides, and only None and VNC
+         * Authentication exist.  A zero type means the connection has
+         * failed, and is followed by the reason.
+         */
+        let Some(sec) = offer.iter()
+            .copied()
+            .find(|s| *s == NONE || *s == VNC_AUTH)
+        else {
+            let reason = "no security type available for RFB 3.3";
+            refuse(&mut sock, version, reason).await?;
+            bail!(Failed(Outcome::Unsupported, reason.to_string()));
+        };
+        sock.write_u32(sec as u32).await?;
+        sock.flush().await?;
+        sec
+    } else {
+        let mut buf = vec![offer.len() as u8];
+        buf.extend_from_slice(offer);
+        sock.write_all(&buf).await?;
+        sock.flush().await?;
+
+        let sec = sock.read_u8().await?;
+        if !offer.contains(&sec) {
+            failure(&mut sock, version, "security type not offered").await?;
+            let reason = format!(
+                "client chose security type {}, which we did not offer", sec);
+            bail!(Failed(Outcome::Unsupported, reason));
+        }
+        sec
+    };
+    log!("  security: {}", name(sec));
+
+    let config = &server.config;
+    let mut identity = None;
+    let mut view_only = false;
+    match sec {
+        VNC_AUTH => view_only = vnc_auth(&mut sock, version, config).await?,
+        VENCRYPT => return vencrypt(server, sock, version).await,
+        ARD => {
+            let (user, v) = ard_auth(&mut sock, version, config).await?;
+            identity = Some(user);
+            view_only = v;
+        }
+        _ => (),
+    }
+
+    /*
+     * Before RFB 3.8, there is no SecurityResult for None.
+     */
+    if sec != NONE || version >= Version::V3_8 {
+        sock.write_u32(0).await?; /* SecurityResult ok */
+        sock.flush().await?;
+    }
+    Ok(Authenticated { conn: Box::new(sock), identity, view_only })
+}
+
+fn reason_string(buf: &mut Vec<u8>, reason: &str) {
+    buf.extend_from_slice(&(reason.len() as u32).to_be_bytes());
+    buf.extend_from_slice(reason.as_bytes());
+}
+
+/*
+ * Turn the client away before security negotiation, in place of the list of
+ * security types.  Every version allows a reason here, though in RFB 3.3 it
+ * follows a zero security type rather than an empty list.
+ */
+pub async fn refuse<S: Conn>(sock: &mut S, version: Version, reason: &str)
+    -> Result<()>
+{
+    let mut buf = Vec::new();
+    if version == Version::V3_3 {
+        buf.extend_from_slice(&0u32.to_be_bytes());
+    } else {
+        buf.push(0);
+    }
+    reason_string(&mut buf, reason);
+    sock.write_all(&buf).await?;
+    linger(sock).await
+}
+
+/*
+ * A failed SecurityResult, which from RFB 3.8 carries a reason for the
+ * client to display.  Earlier clients only learn that authentication failed.
+ */
+async fn failure<S: Conn>(sock: &mut S, version: Version, reason: &str)
+    -> Result<()>
+{
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&1u32.to_be_bytes()); /* failed */
+    if version >= Version::V3_8 {
+        reason_string(&mut buf, reason);
+    }
+    sock.write_all(&buf).await?;
+    linger(sock).await
+}
+
+/*
+ * Close our side of the connection, then give the client a moment to read
+ * the reason and close its own.  If we closed outright while the client was
+ * still sending, the reset could discard the reason before it was read, and
+ * the user would see only that the connection was lost.
+ */
+async fn linger<S: Conn>(sock: &mut S) -> Result<()> {
+    sock.flush().await?;
+    sock.shutdown().await?;
+    let drain = async {
+        let mut buf = [0u8; 1024];
+        while sock.read(&mut buf).await? > 0 {}
+        Ok::<_, std::io::Error>(())
+    };
+    tokio::time::timeout(LINGER, drain).await.ok();
+    Ok(())
+}
+
+/*
+ * Check a password against those configured, returning whether it grants
+ * only view-only access, or None if it matches neither.
+ */
+fn check(config: &Config, matches: impl Fn(&str) -> bool) -> Option<bool> {
+    if config.password.as_deref().is_some_and(&matches) {
+        Some(false)
+    } else if config.view_password.as_deref().is_some_and(&matches) {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+async fn vnc_auth<S: Conn>(sock: &mut S, version: Version, config: &Config)
+    -> Result<bool>
+{
+    let challenge = vncauth::challenge();
+    sock.write_all(&challenge).await?;
+    sock.flush().await?;
+
+    let mut resp = [0; vncauth::CHALLENGE_SIZE];
+    sock.read_exact(&mut resp).await?;
+
+    match check(config, |pw| vncauth::verify(pw, &challenge, &resp)) {
+        Some(view_only) => Ok(view_only),
+        None => {
+            failure(sock, version, "authentication failed").await?;
+            Err(AuthFailed("vnc authentication failed".into()).into())
+        }
+    }
+}
+
+fn password_matches(given: &str, password: &str) -> bool {
+    given.len() == password.len()
+        && openssl::memcmp::eq(given.as_bytes(), password.as_bytes())
+}
+
+/*
+ * Apple Remote Desktop authentication, in which the client sends a username
+ * and password encrypted with a key from a Diffie-Hellman exchange.  Only
+ * the password is checked; the username becomes the client's identity,
+ * unverified.
+ */
+async fn ard_auth<S: Conn>(sock: &mut S, version: Version, config: &Config)
+    -> Result<(String, bool)>
+{
+    let ex = ard::Exchange::new()?;
+    sock.write_all(&ex.params()?).await?;
+    sock.flush().await?;
+
+    let mut creds = [0; ard::CREDENTIALS_SIZE];
+    sock.read_exact(&mut creds).await?;
+    let mut public = vec![0; ex.key_len()];
+    sock.read_exact(&mut public).await?;
+
+    let (user, pass) = match ex.credentials(&public, &creds) {
+        Ok(c) => c,
+        Err(e) => {
+            failure(sock, version, "invalid credentials").await?;
+            return Err(e);
+        }
+    };
+    match check(config, |pw| password_matches(&pass, pw)) {
+        Some(view_only) => Ok((user, view_only)),
+        None => {
+            failure(sock, version, "authentication failed").await?;
+            Err(AuthFailed(format!("ard authentication failed for {:?}",
+                user)).into())
+        }
+    }
+}
+
+/*
+ * VeNCrypt begins with a version exchange, then the client picks one of our
+ * subtypes and we switch to TLS before authenticating it.
+ */
+async fn vencrypt<S>(server: &Server, mut sock: S, version: Version)
+    -> Result<Authenticated>
+where
+    S: Conn + 'static,
+{
+    let tls = server.vencrypt.as_ref().unwrap();
+
+    sock.write_all(&[0, 2]).await?; /* version 0.2 */
+    sock.flush().await?;
+    let mut ver = [0; 2];
+    sock.read_exact(&mut ver).await?;
+    if ver != [0, 2] {
+        sock.write_u8(255).await?; /* unsupported */
+        sock.flush().await?;
+        bail!(Failed(Outcome::Unsupported, format!(
+            "unsupported VeNCrypt version {}.{}", ver[0], ver[1])));
+    }
+    sock.write_u8(0).await?; /* ok */
+
+    let subtypes = subtypes(server);
+    let mut buf = vec![subtypes.len() as u8];
+    for s in &subtypes {
+        buf.extend_from_slice(&s.to_be_bytes());
+    }
+    sock.write_all(&buf).await?;
+    sock.flush().await?;
+
+    let sub = sock.read_u32().await?;
+    log!("  vencrypt: {}", subtype_name(sub));
+    if !subtypes.contains(&sub) {
+        sock.write_u8(0).await?; /* rejected */
+        sock.flush().await?;
+        let reason = format!(
+            "client chose VeNCrypt subtype {}, which we did not offer", sub);
+        bail!(Failed(Outcome::Unsupported, reason));
+    }
+    sock.write_u8(1).await?; /* accepted */
+    sock.flush().await?;
+
+    let mut sock = tls.accept(sock, is_x509(sub)).await?;
+
+    let config = &server.config;
+    let mut identity = None;
+    let mut view_only = false;
+    match sub {
+        TLS_VNC | X509_VNC => {
+            view_only = vnc_auth(&mut sock, version, config).await?;
+        }
+        TLS_PLAIN | X509_PLAIN => {
+            let (user, pass) = plain(&mut sock, version).await?;
+            match check(config, |pw| password_matches(&pass, pw)) {
+                Some(v) => view_only = v,
+                None => {
+                    failure(&mut sock, version, "authentication failed")
+                        .await?;
+                    return Err(AuthFailed(format!(
+                        "plain authentication failed for {:?}", user))
+                        .into());
+                }
+            }
+            identity = Some(user);
+        }
+        _ => (),
+    }
+
+    sock.write_u32(0).await?; /* SecurityResult ok */
+    sock.flush().await?;
+    Ok(Authenticated { conn: Box::new(sock), identity, view_only })
+}
+
+/*
+ * The Plain subtypes send a username and password in the clear, which is
+ * acceptable only because the session is already encrypted.  We have no
+ * accounts, so only the password is checked, against the shared one; the
+ * username is whatever the client says it is.
+ */
+async fn plain<S: Conn>(sock: &mut S, version: Version)
+    -> Result<(String, String)>
+{
+    let ulen = sock.read_u32().await? as usize;
+    let plen = sock.read_u32().await? as usize;
+    if ulen > MAX_CREDENTIAL || plen > MAX_CREDENTIAL {
+        failure(sock, version, "credentials too long").await?;
+        bail!("plain credentials too long ({}, {})", ulen, plen);
+    }
+
+    let mut user = vec![0; ulen];
+    sock.read_exact(&mut user).await?;
+    let mut pass = vec![0; plen];
+    sock.read_exact(&mut pass).await?;
+
+    Ok((String::from_utf8_lossy(&user).into_owned(),
+        String::from_utf8_lossy(&pass).into_owned()))
+}
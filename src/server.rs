@@ -0,0 +1,1457 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::json;
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::task::JoinSet;
+
+use crate::config::Config;
+use crate::cursor::Cursor;
+use crate::encoding::{self, cache::Cache, frame::Frames, overlay::Layer};
+use crate::encoding::pseudo::{self, Leds};
+use crate::framebuffer::{Framebuffer, GeometryError};
+use crate::events::{Event, Events};
+use crate::guard::Guard;
+use crate::handshake::Outcome;
+use crate::input::InputEvent;
+use crate::latency::Histogram;
+use crate::listen::{self, Addr, BindFailure, Listener, Stream, Transport};
+use crate::notify::Notification;
+use crate::pool::{Pool, Priority};
+use crate::prefs::Store;
+use crate::tls::{self, CertAcceptor, PskAcceptor, Vencrypt};
+use crate::trace::{self, Trace, Verdict};
+use crate::rfb::{self, Version};
+use crate::security;
+use crate::source::{Rect, Region, Source};
+use crate::ticker::Ticker;
+use crate::tier::Tier;
+use crate::vncauth;
+use crate::web;
+
+/*
+ * The protocol allows framebuffers up to framebuffer::RESIZE_LIMIT on a
+ * side, but we use a more modest limit for resize requests to avoid
+ * outlandish allocations.
+ */
+const RESIZE_LIMIT: usize = 8192;
+
+/*
+ * What we know about a client connection, for reporting purposes.
+ */
+pub struct Session {
+    pub id: u64,
+    pub addr: Addr,
+    pub transport: Transport,
+    pub started: SystemTime,
+    pub stats: Mutex<SessionStats>,
+    notify: mpsc::UnboundedSender<Notification>,
+    /*
+     * An operator may override the frame rate limit for the connection, which
+     * is woken to pick up the change straight away.
+     */
+    pace: Mutex<Option<u64>>,
+    pub retune: Notify,
+    /*
+     * Whether every message to and from the connection is being logged, and
+     * until when:
+     */
+    trace: Mutex<Option<Trace>>,
+    /*
+     * Asks the connection to send everything it would send to a new client:
+     * the cursor, the desktop name, and an update without delay.
+     */
+    pub refresh: Notify,
+    /*
+     * Asks the connection to ring the client's bell.  Rings that arrive
+     * before the connection gets to the first are merged with it.
+     */
+    pub bell: Notify,
+    /*
+     * Asks the connection to finish, because the server is shutting down or
+     * another client has taken exclusive access.
+     */
+    pub close: Notify,
+}
+
+#[derive(Debug, Default)]
+pub struct SessionStats {
+    pub identity: Option<String>,
+    /*
+     * The ProtocolVersion string the client sent, and the version we settled
+     * on:
+     */
+    pub client_version: Option<String>,
+    pub version: Option<Version>,
+    /*
+     * How the handshake turned out, once it has:
+     */
+    pub handshake: Option<Outcome>,
+    pub encoding: i32,
+    pub tier: Option<Tier>,
+    pub access: Option<Access>,
+    /*
+     * The smoothed round trip time, and whether it is being measured with
+     * fences, which also provide flow control.
+     */
+    pub rtt: Option<Duration>,
+    pub fence: bool,
+    pub updates: u64,
+    pub bytes: u64,
+    /*
+     * Delays between input from the client and the update that reflects it:
+     */
+    pub input_latency: Histogram,
+    /*
+     * Repeated keystrokes merged into one:
+     */
+    pub keys_merged: u64,
+    /*
+     * Messages from extensions we do not implement that were skipped:
+     */
+    pub skipped: u64,
+    pub sched: Sched,
+}
+
+/*
+ * The state of the update scheduler for a connection.
+ */
+#[derive(Debug, Default)]
+pub struct Sched {
+    /*
+     * The area of the update the client has asked for and not yet received,
+     * and whether it is being held until the client replies to a fence.
+     */
+    pub pending: Option<usize>,
+    pub fence_wait: bool,
+    /*
+     * The minimum time between updates, how long recent updates took to
+     * write, and the priority of encode jobs.
+     */
+    pub interval: Duration,
+    pub write_time: Option<Duration>,
+    pub priority: Option<Priority>,
+}
+
+impl Session {
+    pub fn describe(&self) -> serde_json::Value {
+        let stats = self.stats.lock().unwrap();
+        json!({
+            "id": self.id,
+            "addr": self.addr.to_string(),
+            "transport": self.transport.name(),
+            "age": self.started.elapsed().unwrap_or_default().as_secs(),
+            "identity": stats.identity,
+            "client_version": stats.client_version,
+            "version": stats.version.map(|v| v.wire()),
+            "encoding": stats.encoding,
+            "tier": stats.tier.map(|t| t.name()),
+            "access": stats.access.map(|a| a.name()),
+            "rtt_ms": stats.rtt.map(|d| d.as_secs_f64() * 1000.0),
+            "fence": stats.fence,
+            "updates": stats.updates,
+            "bytes": stats.bytes,
+            "input_latency": stats.input_latency.describe(),
+            "keys_merged": stats.keys_merged,
+            "skipped": stats.skipped,
+            "trace_secs": self.trace.lock().unwrap().as_ref()
+                .map(|t| t.remaining().as_secs()),
+        })
+    }
+
+    /*
+     * The scheduler state, for those trying to work out why a client is not
+     * seeing the updates they expect.
+     */
+    pub fn describe_sched(&self) -> serde_json::Value {
+        let stats = self.stats.lock().unwrap();
+        let sched = &stats.sched;
+        json!({
+            "id": self.id,
+            "encoding": stats.encoding,
+            "pending_area": sched.pending,
+            "fence_wait": sched.fence_wait,
+            "interval_ms": sched.interval.as_millis() as u64,
+            "write_ms": sched.write_time.map(|d| d.as_secs_f64() * 1000.0),
+            "priority": sched.priority.map(|p| match p {
+                Priority::Interactive => "interactive",
+                Priority::Bulk => "bulk",
+            }),
+            "pace": self.pace(),
+        })
+    }
+
+    pub fn pace(&self) -> Option<u64> {
+        *self.pace.lock().unwrap()
+    }
+
+    /*
+     * Override the frame rate limit for the connection, or with None, go back
+     * to the limit for its quality tier.
+     */
+    pub fn set_pace(&self, fps: Option<u64>) {
+        *self.pace.lock().unwrap() = fps;
+        self.retune.notify_one();
+    }
+
+    /*
+     * Log every message to and from the connection for "time", or with None,
+     * stop.
+     */
+    pub fn set_trace(&self, time: Option<Duration>) {
+        let mut trace = self.trace.lock().unwrap();
+        match time {
+            Some(time) => {
+                let t = Trace::new(time);
+                log!("[{}] tracing for {}s", self.id, t.remaining().as_secs());
+                *trace = Some(t);
+            }
+            None => {
+                if trace.take().is_some() {
+                    log!("[{}] tracing stopped", self.id);
+                }
+            }
+        }
+    }
+
+    /*
+     * Log the line that "f" produces, if the connection is being traced and
+     * has not used up its share of the log.
+     */
+    pub fn trace<F: FnOnce() -> String>(&self, f: F) {
+        let mut trace = self.trace.lock().unwrap();
+        let Some(t) = trace.as_mut() else {
+            return;
+        };
+        let (dropped, line) = match t.offer(Instant::now()) {
+            Verdict::Log(dropped) => (dropped, Some(trace::clip(f()))),
+            Verdict::Drop => return,
+            Verdict::Expired(dropped) => {
+                *trace = None;
+                (dropped, None)
+            }
+        };
+        if dropped > 0 {
+            log!("[{}] ({} trace lines dropped)", self.id, dropped);
+        }
+        match line {
+            Some(line) => log!("[{}] {}", self.id, line),
+            None => log!("[{}] tracing expired", self.id),
+        }
+    }
+}
+
+/*
+ * Why a client request to resize the framebuffer was refused, with values as
+ * reported in the ExtendedDesktopSize pseudo-encoding.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeError {
+    Prohibited = 1,
+    OutOfResources = 2,
+    InvalidLayout = 3,
+}
+
+/*
+ * Decides whether a client may resize the framebuffer to the given width and
+ * height.
+ */
+pub type ResizePolicy = dyn Fn(usize, usize) -> Result<(), ResizeError>
+    + Send + Sync;
+
+/*
+ * The access a client has to the desktop.  In ClientInit, a client asks
+ * either to share the desktop with any others or to have it to itself; the
+ * access policy may give it that or something else.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Shared,
+    /*
+     * Every other client is disconnected:
+     */
+    Exclusive,
+    /*
+     * Shared, but any input from the client is ignored:
+     */
+    ViewOnly,
+}
+
+impl Access {
+    pub fn name(self) -> &'static str {
+        match self {
+            Access::Shared => "shared",
+            Access::Exclusive => "exclusive",
+            Access::ViewOnly => "view_only",
+        }
+    }
+}
+
+/*
+ * What an access policy is told of a client.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct ClientInfo<'a> {
+    /*
+     * The name the client authenticated as, or its address if it gave none.
+     */
+    pub identity: &'a str,
+    /*
+     * Whether the client proved that the name is its own, as it does with a
+     * TLS pre-shared key.  The usernames in VeNCrypt Plain and Apple Remote
+     * Desktop authentication are merely asserted: only the shared password
+     * is checked, so anybody who knows it may claim any name.
+     */
+    pub verified: bool,
+    /*
+     * Whether the client may only watch, because it gave the view-only
+     * password or its preferences say so.  Such a client is never given
+     * exclusive access, whatever the policy decides.
+     */
+    pub view_only: bool,
+}
+
+/*
+ * Decides the access of a client, which has asked for the given access in
+ * ClientInit, or refuses the client if it returns None.  Without a policy,
+ * every client shares the desktop, whatever it asked for.
+ */
+pub type AccessPolicy = dyn Fn(&ClientInfo, Access) -> Option<Access>
+    + Send + Sync;
+
+/*
+ * Power operations that a client may request through the xvp extension.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerAction {
+    Shutdown,
+    Reboot,
+    Reset,
+}
+
+impl PowerAction {
+    pub fn from_xvp(code: u8) -> Option<PowerAction> {
+        match code {
+            rfb::XVP_SHUTDOWN => Some(PowerAction::Shutdown),
+            rfb::XVP_REBOOT => Some(PowerAction::Reboot),
+            rfb::XVP_RESET => Some(PowerAction::Reset),
+            _ => None,
+        }
+    }
+}
+
+/*
+ * Carries out a power operation requested by the client with the given session
+ * ID.  The handler is called from the connection task, so anything slow should
+ * be handed off elsewhere; an error is reported to the client as a failure.
+ */
+pub type PowerHandler = dyn Fn(u64, PowerAction) -> Result<()> + Send + Sync;
+
+/*
+ * Switches the server to the named source of pixels, at the request of an
+ * administrator.  What the names mean is up to the embedder, which installs
+ * the new framebuffer with Server::set_framebuffer().
+ */
+pub type SourceHandler = dyn Fn(&str) -> Result<()> + Send + Sync;
+
+/*
+ * Receives clipboard text sent by the client with the given session ID.  As
+ * with the power handler, it is called from the connection task.
+ */
+pub type ClipboardHandler = dyn Fn(u64, &str) + Send + Sync;
+
+/*
+ * Receives input from the client with the given session ID.  As with the
+ * power handler, it is called from the connection task.  Clients are only
+ * offered touch input once a handler is installed.
+ */
+pub type InputHandler = dyn Fn(u64, &InputEvent) + Send + Sync;
+
+/*
+ * Carries out an admin or console command that the server does not know
+ * itself, returning None if the embedder does not know it either.
+ */
+pub type CommandHandler = dyn Fn(&str) -> Option<Result<()>> + Send + Sync;
+
+/*
+ * Changes in whether anybody is using the desktop:
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleEvent {
+    /*
+     * No client has sent input for the configured time.
+     */
+    Idle,
+    /*
+     * Input has arrived after the desktop went idle.
+     */
+    Wake,
+}
+
+/*
+ * Called when the desktop becomes idle or wakes again, so that an embedder
+ * may blank the display or lock the session behind it.  The handler is
+ * called with no locks held, but from whichever task noticed the change.
+ */
+pub type IdleHandler = dyn Fn(IdleEvent) + Send + Sync;
+
+/*
+ * The power state of the display behind the framebuffer, in the terms of
+ * VESA DPMS.  In any state but On, the screen is dark.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayPower {
+    On,
+    Standby,
+    Suspend,
+    Off,
+}
+
+impl std::str::FromStr for DisplayPower {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<DisplayPower> {
+        Ok(match s {
+            "on" => DisplayPower::On,
+            "standby" => DisplayPower::Standby,
+            "suspend" => DisplayPower::Suspend,
+            "off" => DisplayPower::Off,
+            _ => bail!("unknown display power state {:?}", s),
+        })
+    }
+}
+
+struct Activity {
+    last_input: Instant,
+    idle: bool,
+    timeout: Option<Duration>,
+    handler: Option<Arc<IdleHandler>>,
+}
+
+/*
+ * Totals for all rectangles produced by a particular encoder.
+ */
+#[derive(Debug, Default, Clone)]
+pub struct EncoderStats {
+    pub updates: u64,
+    pub bytes: u64,
+    pub time: Duration,
+}
+
+/*
+ * State shared by all connections:
+ */
+pub struct Server {
+    pub config: Config,
+    /*
+     * Where the pixels come from, and the session that resized it, if any:
+     */
+    source: Mutex<(Arc<dyn Source>, Option<u64>)>,
+    /*
+     * If only part of the source is shared, the region that now stands in
+     * for it:
+     */
+    region: Mutex<Option<Arc<Region>>>,
+    resize_policy: Mutex<Box<ResizePolicy>>,
+    access_policy: Mutex<Box<AccessPolicy>>,
+    /*
+     * Power control is only offered to clients once a handler is installed.
+     */
+    power: Mutex<Option<Box<PowerHandler>>>,
+    clipboard_handler: Mutex<Option<Box<ClipboardHandler>>>,
+    source_handler: Mutex<Option<Box<SourceHandler>>>,
+    input_handler: Mutex<Option<Box<InputHandler>>>,
+    command_handler: Mutex<Option<Box<CommandHandler>>>,
+    /*
+     * When a client last sent keyboard or pointer input, and whether we have
+     * since declared the desktop idle:
+     */
+    activity: Mutex<Activity>,
+    pub pool: Pool,
+    pub encode_cache: Option<Arc<Cache>>,
+    /*
+     * Pixels captured from the source, shared by connections that are sent
+     * the same generation of it:
+     */
+    pub frames: Frames,
+    /*
+     * Connections, failures and other things that have happened lately, for
+     * an operator to look back on:
+     */
+    pub events: Events,
+    pub prefs: Option<Store>,
+    pub psk: Option<PskAcceptor>,
+    /*
+     * The security types we offer, in order of preference:
+     */
+    pub security: Vec<u8>,
+    /*
+     * Failed authentication attempts, by address:
+     */
+    pub guard: Guard,
+    pub vencrypt: Option<Vencrypt>,
+    /*
+     * TLS with our certificate, if we have one, for "rfbs://" listeners:
+     */
+    pub tls: Option<CertAcceptor>,
+    name: Mutex<Arc<String>>,
+    cursor: Mutex<Option<Arc<Cursor>>>,
+    overlays: Mutex<Vec<(String, Arc<Layer>)>>,
+    clipboard: watch::Sender<Option<Arc<str>>>,
+    leds: Mutex<Option<Leds>>,
+    display: Mutex<DisplayPower>,
+    sessions: Mutex<BTreeMap<u64, Arc<Session>>>,
+    /*
+     * How many sessions there are, for those waiting for the first:
+     */
+    session_count: watch::Sender<usize>,
+    /*
+     * The number of the last session started:
+     */
+    last_session: AtomicU64,
+    /*
+     * Copies of the sockets we accept on, for a handoff to another process,
+     * with what each speaks, and a way to stop accepting once it has
+     * happened:
+     */
+    listeners: Mutex<Vec<(OwnedFd, Transport)>>,
+    handed_off: Notify,
+    /*
+     * New connections, whether accepted or made by us, on their way to
+     * serve(), which takes the receiving end:
+     */
+    connections: mpsc::Sender<(Stream, Addr, Transport)>,
+    incoming: Mutex<Option<mpsc::Receiver<(Stream, Addr, Transport)>>>,
+    /*
+     * Asks serve() to shut down, as a signal to the process would:
+     */
+    quit: Notify,
+    encoders: Mutex<BTreeMap<i32, EncoderStats>>,
+    handshakes: Mutex<BTreeMap<Outcome, u64>>,
+}
+
+impl Server {
+    pub fn new(mut config: Config, source: Arc<dyn Source>)
+        -> Result<Server>
+    {
+        if config.password.is_none() {
+            if let Some(path) = &config.password_file {
+                let (password, view) = vncauth::read_file(path)?;
+                config.password = Some(password);
+                config.view_password = config.view_password.take().or(view);
+            }
+        }
+        let pool = Pool::new(config.encode_threads, config.encode_queue)?;
+        let prefs = config.prefs.as_deref().map(Store::load).transpose()?;
+        let psk = match &config.psk {
+            Some(path) => Some(PskAcceptor::new(tls::load_keys(path)?)?),
+            None => None,
+        };
+        let security = security::resolve(&config)?;
+
+        /*
+         * Without a password there is nothing to guess, and no reason to
+         * hold back a connection while another from the same address
+         * completes the security handshake.
+         */
+        let mut guard = config.guard.clone();
+        if config.password.is_none() {
+            guard.backoff = Duration::ZERO;
+        }
+
+        let key = config.key.as_ref().or(config.cert.as_ref());
+        let cert = config.cert.as_deref().zip(key.map(|k| k.as_path()));
+        let vencrypt = if security.contains(&security::VENCRYPT) {
+            Some(Vencrypt::new(cert)?)
+        } else {
+            None
+        };
+        let tls = cert.map(|(cert, key)| CertAcceptor::new(cert, key))
+            .transpose()?;
+        let (connections, incoming) = mpsc::channel(16);
+
+        Ok(Server {
+            source: Mutex::new((source, None)),
+            region: Mutex::new(None),
+            resize_policy: Mutex::new(Box::new(|_, _| Ok(()))),
+            access_policy: Mutex::new(Box::new(|_, _| Some(Access::Shared))),
+            power: Mutex::new(None),
+            clipboard_handler: Mutex::new(None),
+            source_handler: Mutex::new(None),
+            input_handler: Mutex::new(None),
+            command_handler: Mutex::new(None),
+            activity: Mutex::new(Activity {
+                last_input: Instant::now(),
+                idle: false,
+                timeout: None,
+                handler: None,
+            }),
+            pool,
+            encode_cache: (config.encode_cache > 0)
+                .then(|| Arc::new(Cache::new(config.encode_cache))),
+            frames: Frames::new(),
+            events: Events::new(),
+            prefs,
+            psk,
+            security,
+            guard: Guard::new(guard),
+            vencrypt,
+            tls,
+            name: Mutex::new(Arc::new(config.name.clone())),
+            cursor: Mutex::new(None),
+            overlays: Mutex::new(Vec::new()),
+            clipboard: watch::channel(None).0,
+            leds: Mutex::new(None),
+            display: Mutex::new(DisplayPower::On),
+            sessions: Mutex::new(BTreeMap::new()),
+            session_count: watch::channel(0).0,
+            last_session: AtomicU64::new(0),
+            listeners: Mutex::new(Vec::new()),
+            handed_off: Notify::new(),
+            connections,
+            incoming: Mutex::new(Some(incoming)),
+            quit: Notify::new(),
+            encoders: Mutex::new(BTreeMap::new()),
+            handshakes: Mutex::new(BTreeMap::new()),
+            config,
+        })
+    }
+
+    pub fn source(&self) -> Arc<dyn Source> {
+        Arc::clone(&self.source.lock().unwrap().0)
+    }
+
+    /*
+     * The current framebuffer, if the pixels come from one.  This changes
+     * when a client resizes the desktop, so those drawing into it should
+     * look again each time they draw.
+     */
+    pub fn framebuffer(&self) -> Option<Arc<Framebuffer>> {
+        self.source().framebuffer()
+    }
+
+    /*
+     * The current source, and the session that resized it, if any.
+     */
+    pub fn source_origin(&self) -> (Arc<dyn Source>, Option<u64>) {
+        self.source.lock().unwrap().clone()
+    }
+
+    /*
+     * Replace the framebuffer with a new, blank one of a different size.
+     * Clients notice the change when they next receive an update.
+     */
+    pub fn resize(&self, width: usize, height: usize) -> Result<()> {
+        if !(1..=RESIZE_LIMIT).contains(&width)
+            || !(1..=RESIZE_LIMIT).contains(&height)
+        {
+            bail!("framebuffer size {}x{} is not between 1x1 and {}x{}",
+                width, height, RESIZE_LIMIT, RESIZE_LIMIT);
+        }
+
+        self.replace_fb(width, height, None)?;
+        Ok(())
+    }
+
+    /*
+     * Switch to a different framebuffer, perhaps from a different source
+     * and of a different size.  Clients that support it are told of any new
+     * geometry, and all of them are sent the new contents in full.
+     */
+    pub fn set_framebuffer(&self, fb: Arc<Framebuffer>) {
+        self.set_source(fb);
+    }
+
+    /*
+     * Take pixels from a different source, as for set_framebuffer().
+     */
+    pub fn set_source(&self, source: Arc<dyn Source>) {
+        let (width, height) = source.dimensions();
+        log!("source replaced; now {}x{}", width, height);
+        self.events.record(Event::SourceReplaced { width, height });
+        *self.source.lock().unwrap() = (source, None);
+        *self.region.lock().unwrap() = None;
+        self.refresh_all();
+    }
+
+    /*
+     * Share only "r" of the source, such as the window of one application,
+     * or all of it again if None.  Clients that can be told of a new desktop
+     * size see the region from its top left corner, and their input is moved
+     * back to where it belongs in the source.  Replacing or resizing the
+     * source shares all of it again.
+     */
+    pub fn share_region(&self, r: Option<Rect>) -> Result<()> {
+        let mut source = self.source.lock().unwrap();
+        let mut region = self.region.lock().unwrap();
+        let whole = match &*region {
+            Some(reg) => Arc::clone(reg.whole()),
+            None => Arc::clone(&source.0),
+        };
+
+        let (width, height) = whole.dimensions();
+        match r {
+            Some(r) => {
+                if r.width == 0 || r.height == 0
+                    || r.x + r.width > width || r.y + r.height > height
+                {
+                    bail!("region {}x{} at {}, {} is not within {}x{}",
+                        r.width, r.height, r.x, r.y, width, height);
+                }
+                let reg = Arc::new(Region::new(whole, r));
+                *source = (Arc::clone(&reg) as Arc<dyn Source>, None);
+                *region = Some(reg);
+                log!("sharing {}x{} at {}, {}", r.width, r.height, r.x, r.y);
+            }
+            None => {
+                *source = (whole, None);
+                *region = None;
+                log!("sharing all {}x{}", width, height);
+            }
+        }
+        drop(region);
+        drop(source);
+
+        self.refresh_all();
+        Ok(())
+    }
+
+    /*
+     * The part of the source that is shared, if not all of it.
+     */
+    pub fn shared_region(&self) -> Option<Rect> {
+        self.region.lock().unwrap().as_ref().map(|reg| reg.rect())
+    }
+
+    pub fn set_source_handler<F>(&self, f: F)
+    where
+        F: Fn(&str) -> Result<()> + Send + Sync + 'static,
+    {
+        *self.source_handler.lock().unwrap() = Some(Box::new(f));
+    }
+
+    /*
+     * An administrator has asked for the named source.
+     */
+    pub fn switch_source(&self, name: &str) -> Result<()> {
+        match &*self.source_handler.lock().unwrap() {
+            Some(f) => f(name),
+            None => bail!("no other sources are available"),
+        }
+    }
+
+    pub fn set_command_handler<F>(&self, f: F)
+    where
+        F: Fn(&str) -> Option<Result<()>> + Send + Sync + 'static,
+    {
+        *self.command_handler.lock().unwrap() = Some(Box::new(f));
+    }
+
+    /*
+     * An administrator has sent a command that is not one of ours.
+     */
+    pub fn command(&self, cmd: &str) -> Option<Result<()>> {
+        self.command_handler.lock().unwrap().as_ref().and_then(|f| f(cmd))
+    }
+
+    /*
+     * A client has asked for the framebuffer to be resized.
+     */
+    pub fn client_resize(&self, id: u64, width: usize, height: usize)
+        -> Result<(), ResizeError>
+    {
+        (self.resize_policy.lock().unwrap())(width, height)?;
+        if !(1..=RESIZE_LIMIT).contains(&width)
+            || !(1..=RESIZE_LIMIT).contains(&height)
+        {
+            return Err(ResizeError::OutOfResources);
+        }
+
+        self.replace_fb(width, height, Some(id))
+            .map_err(|_| ResizeError::OutOfResources)
+    }
+
+    pub fn set_resize_policy<F>(&self, f: F)
+    where
+        F: Fn(usize, usize) -> Result<(), ResizeError> + Send + Sync + 'static,
+    {
+        *self.resize_policy.lock().unwrap() = Box::new(f);
+    }
+
+    /*
+     * A client has finished its handshake and asked for "access".
+     */
+    pub fn client_access(&self, client: &ClientInfo, access: Access)
+        -> Option<Access>
+    {
+        match (self.access_policy.lock().unwrap())(client, access) {
+            Some(Access::Exclusive) if client.view_only => {
+                Some(Access::ViewOnly)
+            }
+            given => given,
+        }
+    }
+
+    pub fn set_access_policy<F>(&self, f: F)
+    where
+        F: Fn(&ClientInfo, Access) -> Option<Access> + Send + Sync + 'static,
+    {
+        *self.access_policy.lock().unwrap() = Box::new(f);
+    }
+
+    pub fn set_power_handler<F>(&self, f: F)
+    where
+        F: Fn(u64, PowerAction) -> Result<()> + Send + Sync + 'static,
+    {
+        *self.power.lock().unwrap() = Some(Box::new(f));
+    }
+
+    pub fn has_power_handler(&self) -> bool {
+        self.power.lock().unwrap().is_some()
+    }
+
+    /*
+     * A client has asked for a power operation.
+     */
+    pub fn power(&self, id: u64, action: PowerAction) -> Result<()> {
+        match &*self.power.lock().unwrap() {
+            Some(f) => f(id, action),
+            None => bail!("power control is not available"),
+        }
+    }
+
+    pub fn set_clipboard_handler<F>(&self, f: F)
+    where
+        F: Fn(u64, &str) + Send + Sync + 'static,
+    {
+        *self.clipboard_handler.lock().unwrap() = Some(Box::new(f));
+    }
+
+    /*
+     * A client has sent clipboard text.  Without a handler, it is ignored.
+     */
+    pub fn client_clipboard(&self, id: u64, text: &str) {
+        if let Some(f) = &*self.clipboard_handler.lock().unwrap() {
+            f(id, text);
+        }
+    }
+
+    pub fn set_input_handler<F>(&self, f: F)
+    where
+        F: Fn(u64, &InputEvent) + Send + Sync + 'static,
+    {
+        *self.input_handler.lock().unwrap() = Some(Box::new(f));
+    }
+
+    pub fn has_input_handler(&self) -> bool {
+        self.input_handler.lock().unwrap().is_some()
+    }
+
+    /*
+     * A client has sent input.  Without a handler, it is ignored.
+     */
+    pub fn input_event(&self, id: u64, ev: &InputEvent) {
+        let ev = match self.shared_region() {
+            Some(r) => ev.offset(r.x, r.y),
+            None => *ev,
+        };
+        if let Some(f) = &*self.input_handler.lock().unwrap() {
+            f(id, &ev);
+        }
+    }
+
+    /*
+     * Arrange for "f" to be called once no client has sent input for
+     * "timeout", and again when input resumes.
+     */
+    pub fn set_idle_handler<F>(&self, timeout: Duration, f: F)
+    where
+        F: Fn(IdleEvent) + Send + Sync + 'static,
+    {
+        let mut a = self.activity.lock().unwrap();
+        a.timeout = Some(timeout);
+        a.handler = Some(Arc::new(f));
+    }
+
+    /*
+     * The last time any client sent keyboard or pointer input, or when the
+     * server started if none has.
+     */
+    pub fn last_input(&self) -> Instant {
+        self.activity.lock().unwrap().last_input
+    }
+
+    /*
+     * A client has sent input.  If the desktop was idle, it wakes, and every
+     * client is sent a fresh copy of the screen.
+     */
+    pub fn input(&self) {
+        let wake = {
+            let mut a = self.activity.lock().unwrap();
+            a.last_input = Instant::now();
+            if a.idle {
+                a.idle = false;
+                a.handler.clone()
+            } else {
+                None
+            }
+        };
+
+        if let Some(f) = wake {
+            log!("input after idle; waking");
+            f(IdleEvent::Wake);
+            self.refresh_all();
+        }
+    }
+
+    /*
+     * Declare the desktop idle if it has been long enough since the last
+     * input.  Returns how long to wait before checking again.
+     */
+    pub fn check_idle(&self) -> Duration {
+        let idle = {
+            let mut a = self.activity.lock().unwrap();
+            let Some(timeout) = a.timeout else {
+                return Duration::from_secs(1);
+            };
+            let elapsed = a.last_input.elapsed();
+            if a.idle {
+                return Duration::from_secs(1);
+            }
+            if elapsed < timeout {
+                return timeout - elapsed;
+            }
+            a.idle = true;
+            a.handler.clone()
+        };
+
+        if let Some(f) = idle {
+            log!("no input for a while; idle");
+            f(IdleEvent::Idle);
+        }
+        Duration::from_secs(1)
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.activity.lock().unwrap().idle
+    }
+
+    fn replace_fb(&self, width: usize, height: usize, origin: Option<u64>)
+        -> Result<(), GeometryError>
+    {
+        /*
+         * A framebuffer the application has presented whole frames in keeps
+         * its back buffer.
+         */
+        let double = self.config.double_buffer
+            || self.framebuffer().is_some_and(|fb| fb.is_double());
+        let fb = Arc::new(Framebuffer::new_buffered(width, height, double)?);
+        if self.config.debug_framebuffer {
+            fb.poison();
+        }
+        *self.source.lock().unwrap() = (fb, origin);
+        *self.region.lock().unwrap() = None;
+        match origin {
+            Some(id) => {
+                log!("[{}] framebuffer resized to {}x{}", id, width, height);
+            }
+            None => log!("framebuffer resized to {}x{}", width, height),
+        }
+        self.events.record(Event::Resized {
+            width,
+            height,
+            session: origin,
+        });
+        Ok(())
+    }
+
+    /*
+     * Rename the desktop.  Clients that support the DesktopName
+     * pseudo-encoding see the new name with their next update; others keep
+     * the name they were given when they connected.
+     */
+    pub fn set_name(&self, name: &str) {
+        *self.name.lock().unwrap() =
+            Arc::new(pseudo::truncate_name(name).to_string());
+        log!("desktop renamed to {:?}", name);
+    }
+
+    pub fn name(&self) -> Arc<String> {
+        Arc::clone(&self.name.lock().unwrap())
+    }
+
+    /*
+     * Change the shape of the pointer.  Clients that draw the cursor locally
+     * receive the new shape with their next update.
+     */
+    pub fn set_cursor(&self, cursor: Cursor) {
+        *self.cursor.lock().unwrap() = Some(Arc::new(cursor));
+    }
+
+    pub fn cursor(&self) -> Option<Arc<Cursor>> {
+        self.cursor.lock().unwrap().clone()
+    }
+
+    /*
+     * Add, replace or remove the overlay layer with the given name.  New
+     * layers go on top of the others.
+     */
+    pub fn set_overlay(&self, name: &str, layer: Option<Layer>) {
+        let mut overlays = self.overlays.lock().unwrap();
+        match (overlays.iter_mut().find(|(n, _)| n == name), layer) {
+            (Some((_, l)), Some(layer)) => *l = Arc::new(layer),
+            (None, Some(layer)) => {
+                overlays.push((name.to_string(), Arc::new(layer)));
+            }
+            (_, None) => overlays.retain(|(n, _)| n != name),
+        }
+    }
+
+    pub fn overlays(&self) -> Vec<Arc<Layer>> {
+        self.overlays.lock().unwrap().iter()
+            .map(|(_, l)| Arc::clone(l))
+            .collect()
+    }
+
+    /*
+     * Put text on the clipboard of every client, including those that
+     * connect later.
+     */
+    pub fn set_clipboard(&self, text: &str) {
+        self.clipboard.send_replace(Some(Arc::from(text)));
+    }
+
+    /*
+     * Follow changes to the clipboard.  The current contents, if any, are
+     * already marked as seen; a new connection should send them itself.
+     */
+    pub fn watch_clipboard(&self) -> watch::Receiver<Option<Arc<str>>> {
+        self.clipboard.subscribe()
+    }
+
+    /*
+     * Change the state of the keyboard lock lights.  Clients that can show
+     * them receive the new state with their next update.
+     */
+    pub fn set_led_state(&self, caps: bool, num: bool, scroll: bool) {
+        *self.leds.lock().unwrap() = Some(Leds { caps, num, scroll });
+    }
+
+    pub fn led_state(&self) -> Option<Leds> {
+        *self.leds.lock().unwrap()
+    }
+
+    /*
+     * Declare that the display has gone to sleep, or woken.  While it is
+     * asleep, clients are shown the configured placeholder instead, and
+     * updated only rarely.
+     */
+    pub fn set_display_power(&self, power: DisplayPower) {
+        let was = std::mem::replace(&mut *self.display.lock().unwrap(), power);
+        if was != power {
+            log!("display power now {:?}", power);
+            self.refresh_all();
+        }
+    }
+
+    pub fn display_power(&self) -> DisplayPower {
+        *self.display.lock().unwrap()
+    }
+
+    /*
+     * Register a new connection.  Notifications for the connection arrive on
+     * the returned channel.
+     */
+    pub fn add_session(&self, id: u64, addr: Addr, transport: Transport)
+        -> (Arc<Session>, mpsc::UnboundedReceiver<Notification>)
+    {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let s = Arc::new(Session {
+            id,
+            addr,
+            transport,
+            started: SystemTime::now(),
+            stats: Mutex::new(SessionStats::default()),
+            notify: tx,
+            pace: Mutex::new(None),
+            retune: Notify::new(),
+            trace: Mutex::new(None),
+            refresh: Notify::new(),
+            bell: Notify::new(),
+            close: Notify::new(),
+        });
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.insert(id, Arc::clone(&s));
+        self.session_count.send_replace(sessions.len());
+        drop(sessions);
+        (s, rx)
+    }
+
+    pub fn session(&self, id: u64) -> Result<Arc<Session>> {
+        self.sessions.lock().unwrap().get(&id)
+            .cloned()
+            .ok_or_else(|| anyhow!("no session {}", id))
+    }
+
+    pub fn remove_session(&self, id: u64) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.remove(&id);
+        self.session_count.send_replace(sessions.len());
+    }
+
+    /*
+     * Pace an application that renders its own frames, at one every
+     * "period" while any client is connected.
+     */
+    pub fn ticker(&self, period: Duration) -> Ticker {
+        Ticker::new(period, self.session_count.subscribe())
+    }
+
+    pub fn sessions(&self) -> Vec<(u64, Arc<Session>)> {
+        self.sessions.lock().unwrap().iter()
+            .map(|(id, s)| (*id, Arc::clone(s)))
+            .collect()
+    }
+
+    /*
+     * Ask every connection but that of session "id" to finish, as for a
+     * client given exclusive access.
+     */
+    pub fn close_others(&self, id: u64) {
+        for (other, s) in self.sessions() {
+            if other != id {
+                log!("closing session {} for exclusive access by {}",
+                    other, id);
+                s.close.notify_one();
+            }
+        }
+    }
+
+    /*
+     * Show a message to the person using a particular client, by whatever
+     * means that client supports.
+     */
+    pub fn notify(&self, id: u64, n: Notification) -> Result<()> {
+        let sessions = self.sessions.lock().unwrap();
+        let s = sessions.get(&id)
+            .ok_or_else(|| anyhow!("no session {}", id))?;
+        s.notify.send(n).map_err(|_| anyhow!("session {} is closing", id))
+    }
+
+    pub fn last_session(&self) -> u64 {
+        self.last_session.load(Ordering::Relaxed)
+    }
+
+    /*
+     * Number new sessions from "last" onwards, as when taking over from
+     * another process which has used the numbers before it.
+     */
+    pub fn resume_sessions(&self, last: u64) {
+        self.last_session.fetch_max(last, Ordering::Relaxed);
+    }
+
+    /*
+     * Duplicates of the sockets serve() is accepting on.
+     */
+    pub fn listener_fds(&self) -> Result<Vec<(OwnedFd, Transport)>> {
+        Ok(self.listeners.lock().unwrap().iter()
+            .map(|(fd, t)| Ok((fd.try_clone()?, *t)))
+            .collect::<std::io::Result<_>>()?)
+    }
+
+    /*
+     * Another process has taken over our listening sockets.  We stop
+     * accepting, but keep serving the connections we have.
+     */
+    pub fn stop_accepting(&self) {
+        self.listeners.lock().unwrap().clear();
+        self.handed_off.notify_one();
+    }
+
+    /*
+     * Shut down as if the process had been interrupted: stop accepting, and
+     * close every connection once it has written what it has queued.
+     */
+    pub fn shut_down(&self) {
+        self.quit.notify_one();
+    }
+
+    pub async fn quit_requested(&self) {
+        self.quit.notified().await
+    }
+
+    /*
+     * Serve a connection we made ourselves, to the viewer or repeater at
+     * "addr", as if it had arrived on a listener.  If serve() has not yet
+     * begun, the connection waits for it.
+     */
+    pub async fn adopt(&self, sock: Stream, addr: Addr, transport: Transport)
+        -> Result<()>
+    {
+        self.connections.send((sock, addr, transport)).await
+            .map_err(|_| anyhow!("the server has stopped"))
+    }
+
+    /*
+     * Accept connections on "listeners", and run "handle" for each in a task
+     * of its own, until "shutdown" completes.  We then stop accepting, ask
+     * every session to close, and wait up to "grace" for them to finish
+     * before aborting the rest.  The connection tasks belong to this future:
+     * if it is dropped instead, they are all aborted with it.
+     *
+     * If the listening sockets are handed off to another process, we stop
+     * accepting straight away, and finish once the connections we have
+     * finish by themselves.
+     */
+    pub async fn serve<H, F>(
+        self: &Arc<Self>,
+        listeners: Vec<Listener>,
+        handle: H,
+        shutdown: impl Future<Output = ()>,
+        grace: Duration,
+    ) -> Result<()>
+    where
+        H: Fn(Arc<Server>, Stream, Arc<Session>,
+            mpsc::UnboundedReceiver<Notification>) -> F,
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        *self.listeners.lock().unwrap() = listeners.iter()
+            .map(|l| {
+                /*
+                 * The listener is open for as long as we borrow it here.
+                 */
+                let fd = unsafe { BorrowedFd::borrow_raw(l.as_raw_fd()) };
+                Ok((fd.try_clone_to_owned()?, l.transport()))
+            })
+            .collect::<std::io::Result<_>>()?;
+        let mut accepting = JoinSet::new();
+        let mut conns = self.incoming.lock().unwrap().take()
+            .ok_or_else(|| anyhow!("the server is already serving"))?;
+        listen::accept_all(listeners, &mut accepting, &self.connections);
+        /*
+         * Connections that begin with an HTTP request, until they have
+         * become WebSockets, and then sessions:
+         */
+        let mut requests = JoinSet::new();
+        let mut tasks = JoinSet::new();
+        let mut handed_off = false;
+
+        tokio::pin!(shutdown);
+        loop {
+            if handed_off && tasks.is_empty() {
+                log!("all connections finished after handoff");
+                return Ok(());
+            }
+
+            tokio::select! {
+                _ = &mut shutdown => break,
+                _ = self.handed_off.notified(), if !handed_off => {
+                    handed_off = true;
+                    accepting.shutdown().await;
+                    requests.shutdown().await;
+                    log!("no longer accepting; waiting for {} connections",
+                        tasks.len());
+                }
+                accepted = conns.recv(), if !handed_off => {
+                    let Some((socket, addr, transport)) = accepted else {
+                        break;
+                    };
+                    if matches!(transport, Transport::WebSocket
+                        | Transport::Http)
+                        && !matches!(socket, Stream::WebSocket(_))
+                    {
+                        let server = Arc::clone(self);
+                        let tx = self.connections.clone();
+                        requests.spawn(async move {
+                            match web::accept(&server, socket, &addr,
+                                transport).await
+                            {
+                                Ok(Some(ws)) => {
+                                    tx.send((ws, addr, transport)).await.ok();
+                                }
+                                Ok(None) => (),
+                                Err(e) => log!("{}: {:#}", addr, e),
+                            }
+                        });
+                        continue;
+                    }
+
+                    let c = self.last_session.fetch_add(1, Ordering::Relaxed)
+                        + 1;
+                    log!("[{}] accept: {} ({})", c, addr, transport.name());
+                    self.events.record(Event::Connected {
+                        session: c,
+                        addr: addr.clone(),
+                    });
+
+                    let (session, notes) =
+                        self.add_session(c, addr, transport);
+                    let fut = handle(Arc::clone(self), socket, session, notes);
+                    let server = Arc::clone(self);
+                    tasks.spawn(async move {
+                        let res = fut.await;
+                        server.remove_session(c);
+                        log!("[{}] connection done: {:?}", c, res);
+                        server.events.record(Event::Disconnected {
+                            session: c,
+                            error: res.err().map(|e| format!("{:#}", e)),
+                        });
+                    });
+                }
+                /*
+                 * Reap finished connections as we go, so that the set does
+                 * not grow without bound.
+                 */
+                Some(res) = tasks.join_next() => {
+                    if let Err(e) = res {
+                        log!("connection task failed: {}", e);
+                    }
+                }
+                Some(_) = requests.join_next() => (),
+            }
+        }
+
+        drop(accepting);
+        drop(requests);
+        log!("no longer accepting; closing {} connections", tasks.len());
+        for (_, s) in self.sessions() {
+            s.close.notify_one();
+        }
+        let drain = async {
+            while tasks.join_next().await.is_some() {}
+        };
+        if tokio::time::timeout(grace, drain).await.is_err() {
+            log!("aborting {} connections after {:?}", tasks.len(), grace);
+            tasks.shutdown().await;
+        } else {
+            log!("all connections closed");
+        }
+        Ok(())
+    }
+
+    pub fn refresh_all(&self) {
+        for s in self.sessions.lock().unwrap().values() {
+            s.refresh.notify_one();
+        }
+    }
+
+    /*
+     * Ring the bell on every client.
+     */
+    pub fn bell(&self) {
+        for s in self.sessions.lock().unwrap().values() {
+            s.bell.notify_one();
+        }
+    }
+
+    pub fn notify_all(&self, n: Notification) {
+        for s in self.sessions.lock().unwrap().values() {
+            s.notify.send(n.clone()).ok();
+        }
+    }
+
+    pub fn record_encode(&self, encoding: i32, bytes: usize, time: Duration) {
+        let mut encoders = self.encoders.lock().unwrap();
+        let e = encoders.entry(encoding).or_default();
+        e.updates += 1;
+        e.bytes += bytes as u64;
+        e.time += time;
+    }
+
+    pub fn encoder_stats(&self) -> BTreeMap<i32, EncoderStats> {
+        self.encoders.lock().unwrap().clone()
+    }
+
+    /*
+     * Record how the handshake for a session turned out.  Only the first
+     * outcome for each session counts.
+     */
+    pub fn record_handshake(&self, session: &Session, outcome: Outcome) {
+        let mut stats = session.stats.lock().unwrap();
+        if stats.handshake.is_none() {
+            stats.handshake = Some(outcome);
+            *self.handshakes.lock().unwrap().entry(outcome).or_default() += 1;
+        }
+    }
+
+    /*
+     * The number of handshakes with each outcome, including those that have
+     * not yet happened, so that the set of keys is always the same.
+     */
+    pub fn handshake_stats(&self) -> BTreeMap<&'static str, u64> {
+        let handshakes = self.handshakes.lock().unwrap();
+        Outcome::ALL.iter()
+            .map(|o| (o.name(), handshakes.get(o).copied().unwrap_or(0)))
+            .collect()
+    }
+
+    /*
+     * A description of this instance, logged as a single line of JSON at
+     * startup so that orchestration tools can check that we came up as
+     * intended.
+     */
+    pub fn capabilities(&self, listeners: &[String],
+        failed: &[BindFailure]) -> serde_json::Value
+    {
+        let c = &self.config;
+        json!({
+            "event": "startup",
+            "version": env!("CARGO_PKG_VERSION"),
+            "listeners": listeners,
+            "listeners_failed": failed.iter()
+                .map(|f| json!({ "addr": f.addr, "error": f.error }))
+                .collect::<Vec<_>>(),
+            "admin": c.admin,
+            "framebuffer": {
+                "width": c.width,
+                "height": c.height,
+            },
+            "name": self.name().as_str(),
+            "encodings": encoding::SUPPORTED.iter()
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>(),
+            "pseudo_encodings": {
+                "desktop_size": pseudo::DESKTOP_SIZE,
+                "extended_desktop_size": pseudo::EXTENDED_DESKTOP_SIZE,
+                "cursor": pseudo::CURSOR,
+                "cursor_alpha": pseudo::CURSOR_ALPHA,
+                "led_state": pseudo::LED_STATE,
+                "vmware_led_state": pseudo::VMWARE_LED_STATE,
+                "desktop_name": pseudo::DESKTOP_NAME,
+                "fence": rfb::PSEUDO_FENCE,
+                "xvp": rfb::PSEUDO_XVP,
+                "qemu_extended_key": rfb::PSEUDO_QEMU_EXTENDED_KEY,
+                "gii": rfb::PSEUDO_GII,
+                "quality_level": [
+                    rfb::PSEUDO_QUALITY.start(),
+                    rfb::PSEUDO_QUALITY.end(),
+                ],
+                "compress_level": [
+                    rfb::PSEUDO_COMPRESSION.start(),
+                    rfb::PSEUDO_COMPRESSION.end(),
+                ],
+            },
+            "features": (["quality-tiers", "diag", "resize"].iter().copied()
+                .chain(c.mousekeys.then_some("mousekeys"))
+                .collect::<Vec<_>>()),
+            "power_control": self.has_power_handler(),
+            "input": self.has_input_handler(),
+            "encode_threads": c.encode_threads,
+            "security": self.security.iter()
+                .map(|s| security::name(*s))
+                .collect::<Vec<_>>(),
+            "vencrypt": security::subtypes(self).into_iter()
+                .filter(|_| self.vencrypt.is_some())
+                .map(security::subtype_name)
+                .collect::<Vec<_>>(),
+            "tls": if c.psk.is_some() { Some("psk") } else { None },
+            "tls_certificate": self.tls.is_some(),
+            "stream_compression": c.stream_zstd.map(|l| format!("zstd:{}", l)),
+        })
+    }
+}
@@ -0,0 +1,362 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::task::JoinSet;
+use tokio::time::{Instant, sleep_until};
+
+use crate::client;
+use crate::latency::Histogram;
+use crate::listen;
+use crate::rfb::{ClientMessage, FramebufferUpdateRequest, SetEncodings};
+use crate::rfb::Version;
+use crate::server::Server;
+
+/*
+ * A soak test runs the server for a while under load from synthetic clients
+ * on the loopback interface, then reports how well it kept pace with them and
+ * what it consumed in doing so.  The load follows a fixed schedule, so that
+ * reports from different builds can be compared.
+ */
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub duration: Duration,
+    pub clients: usize,
+    /*
+     * The rate at which each client asks for a full update.  This should be
+     * below the rate at which the server paces updates, or every frame will
+     * be late.
+     */
+    pub fps: u32,
+    /*
+     * How often to record memory and descriptor use.
+     */
+    pub sample_interval: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            duration: Duration::from_secs(60),
+            clients: 4,
+            fps: 10,
+            sample_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/*
+ * How closely one client's updates kept to its schedule.  A frame misses its
+ * deadline if it has not arrived by the time the next one is due.
+ */
+#[derive(Debug, Default)]
+struct Pacing {
+    frames: u64,
+    missed: u64,
+    jitter: Histogram,
+    latency: Histogram,
+}
+
+impl Pacing {
+    fn merge(&mut self, other: &Pacing) {
+        self.frames += other.frames;
+        self.missed += other.missed;
+        self.jitter.merge(&other.jitter);
+        self.latency.merge(&other.latency);
+    }
+}
+
+/*
+ * The most memory the process has had resident, in bytes, if the system will
+ * tell us.
+ */
+fn peak_rss() -> Option<u64> {
+    let mut ru: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut ru) } != 0 {
+        return None;
+    }
+    let unit = if cfg!(target_os = "macos") { 1 } else { 1024 };
+    Some(ru.ru_maxrss as u64 * unit).filter(|n| *n > 0)
+}
+
+/*
+ * The number of open file descriptors, not counting the one used to find
+ * out.
+ */
+fn open_fds() -> Option<usize> {
+    std::fs::read_dir("/dev/fd")
+        .ok()
+        .map(|d| d.count().saturating_sub(1))
+}
+
+/*
+ * Run the soak test against "server", which must offer security type None,
+ * and return the report.  The server accepts only the synthetic clients,
+ * on a loopback address of its own, regardless of its configuration.
+ */
+pub async fn run(server: &Arc<Server>, opts: &Options)
+    -> Result<serde_json::Value>
+{
+    if opts.clients == 0 || opts.fps == 0 {
+        bail!("a soak test needs at least one client and frame rate");
+    }
+
+    let fds_before = open_fds();
+    let (listeners, _) = listen::bind(&["127.0.0.1:0".to_string()], false)?;
+    let addr = listeners[0].local_addr()?.tcp().unwrap();
+    log!("soak: {} clients at {} fps for {:?} against {}", opts.clients,
+        opts.fps, opts.duration, addr);
+
+    let (stop, stopped) = oneshot::channel::<()>();
+    let serving = server.serve(listeners,
+        |server, socket, session, notes| async move {
+            client::serve_client(&server, socket, &session, notes).await
+        },
+        async {
+            stopped.await.ok();
+        },
+        Duration::from_secs(5));
+
+    let load = async {
+        let start = Instant::now();
+        let end = start + opts.duration;
+        let period = Duration::from_secs(1) / opts.fps;
+
+        /*
+         * The clients start at even intervals across the first period, so
+         * that their requests do not all arrive together.
+         */
+        let mut clients = JoinSet::new();
+        for i in 0..opts.clients {
+            let first = start + period * i as u32 / opts.clients as u32;
+            clients.spawn(load_client(addr, first, period, end));
+        }
+
+        let mut samples = Vec::new();
+        let mut fds_max = fds_before;
+        let mut next = start;
+        while next < end {
+            sleep_until(next).await;
+            let fds = open_fds();
+            fds_max = fds_max.max(fds);
+            samples.push(json!({
+                "secs": (next - start).as_secs_f64(),
+                "peak_rss_bytes": peak_rss(),
+                "fds": fds,
+                "sessions": server.sessions().len(),
+            }));
+            next += opts.sample_interval;
+        }
+
+        let mut pacing = Pacing::default();
+        let mut errors = Vec::new();
+        while let Some(res) = clients.join_next().await {
+            match res? {
+                Ok(p) => pacing.merge(&p),
+                Err(e) => errors.push(format!("{:#}", e)),
+            }
+        }
+        stop.send(()).ok();
+
+        Ok::<_, anyhow::Error>((pacing, errors, samples, fds_max))
+    };
+
+    let (served, loaded) = tokio::join!(serving, load);
+    served?;
+    let (pacing, errors, samples, fds_max) = loaded?;
+    let fds_after = open_fds();
+
+    let expected = opts.duration.as_secs_f64() * opts.fps as f64
+        * opts.clients as f64;
+    Ok(json!({
+        "duration_secs": opts.duration.as_secs_f64(),
+        "clients": opts.clients,
+        "fps": opts.fps,
+        "frames": pacing.frames,
+        "expected_frames": expected.floor() as u64,
+        "missed_deadlines": pacing.missed,
+        "jitter": pacing.jitter.describe(),
+        "latency": pacing.latency.describe(),
+        "errors": errors,
+        "handshakes": server.handshake_stats(),
+        "capture": server.frames.describe(),
+        "peak_rss_bytes": peak_rss(),
+        "fds": {
+            "before": fds_before,
+            "max": fds_max,
+            "after": fds_after,
+        },
+        "samples": samples,
+    }))
+}
+
+fn request<M: Into<ClientMessage>>(m: M) -> Vec<u8> {
+    let mut buf = Vec::new();
+    m.into().write(&mut buf);
+    buf
+}
+
+/*
+ * Read and throw away "n" bytes.
+ */
+async fn discard<R>(r: &mut R, n: u64) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let got = tokio::io::copy(&mut (&mut *r).take(n),
+        &mut tokio::io::sink()).await?;
+    if got != n {
+        bail!("connection closed in the middle of a message");
+    }
+    Ok(())
+}
+
+/*
+ * Read server messages up to the end of the next FramebufferUpdate.  We ask
+ * only for Raw encoding in the native pixel format, so every rectangle is
+ * four bytes a pixel.
+ */
+async fn read_update<R>(r: &mut R) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        match r.read_u8().await? {
+            0 => {
+                r.read_u8().await?;
+                let nrects = r.read_u16().await?;
+                for _ in 0..nrects {
+                    r.read_u32().await?; /* x, y */
+                    let width = r.read_u16().await? as u64;
+                    let height = r.read_u16().await? as u64;
+                    let enc = r.read_i32().await?;
+                    if enc != 0 {
+                        bail!("unexpected encoding {} in update", enc);
+                    }
+                    discard(r, width * height * 4).await?;
+                }
+                return Ok(());
+            }
+            1 => {
+                discard(r, 3).await?;
+                let n = r.read_u16().await? as u64;
+                discard(r, n * 6).await?;
+            }
+            2 => (),
+            3 => {
+                discard(r, 3).await?;
+                let n = r.read_u32().await? as u64;
+                discard(r, n).await?;
+            }
+            t => bail!("unexpected message type {}", t),
+        }
+    }
+}
+
+/*
+ * Connect to the server as an ordinary viewer, and ask for a full update
+ * every "period" from "first" until "end".
+ */
+async fn load_client(
+    addr: SocketAddr,
+    first: Instant,
+    period: Duration,
+    end: Instant,
+) -> Result<Pacing> {
+    let sock = TcpStream::connect(addr).await?;
+    sock.set_nodelay(true)?;
+    let mut sock = BufReader::new(sock);
+
+    let ver = crate::rfb::read_version(&mut sock).await?;
+    if Version::parse(&ver).is_none_or(|v| v < Version::V3_8) {
+        bail!("server offered {:?}; we need RFB 3.8", ver);
+    }
+    sock.write_all(format!("{}\n", Version::V3_8.wire()).as_bytes()).await?;
+
+    let n = sock.read_u8().await? as usize;
+    let mut types = vec![0; n];
+    sock.read_exact(&mut types).await?;
+    if !types.contains(&1) {
+        bail!("server does not offer security type None");
+    }
+    sock.write_u8(1).await?;
+    if sock.read_u32().await? != 0 {
+        bail!("security handshake failed");
+    }
+
+    sock.write_u8(1).await?; /* ClientInit, shared */
+    let width = sock.read_u16().await?;
+    let height = sock.read_u16().await?;
+    discard(&mut sock, 16).await?; /* pixel format */
+    let namelen = sock.read_u32().await? as u64;
+    discard(&mut sock, namelen).await?;
+
+    sock.write_all(&request(SetEncodings {
+        encodings: vec![0].into(),
+    })).await?;
+    let ask = request(FramebufferUpdateRequest {
+        incremental: 0,
+        x: 0,
+        y: 0,
+        width,
+        height,
+    });
+
+    let mut pacing = Pacing::default();
+    let mut last: Option<Instant> = None;
+    let mut due = first;
+    while due < end {
+        sleep_until(due).await;
+        let asked = Instant::now();
+        sock.write_all(&ask).await?;
+        sock.flush().await?;
+        read_update(&mut sock).await?;
+        let done = Instant::now();
+
+        pacing.frames += 1;
+        pacing.latency.record(done - asked);
+        if let Some(last) = last {
+            pacing.jitter.record((done - last).abs_diff(period));
+        }
+        last = Some(done);
+
+        /*
+         * The schedule does not slip: if this frame ran past the times at
+         * which later ones were due, those are counted as missed.
+         */
+        due += period;
+        while due < done {
+            pacing.missed += 1;
+            due += period;
+        }
+    }
+
+    Ok(pacing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ServerBuilder;
+
+    #[tokio::test]
+    async fn short_soak() {
+        let server = ServerBuilder::new().geometry(64, 48).build().unwrap();
+        let opts = Options {
+            duration: Duration::from_millis(500),
+            clients: 2,
+            ..Options::default()
+        };
+
+        let report = run(&server, &opts).await.unwrap();
+        assert_eq!(report["errors"], json!([]));
+        assert_eq!(report["handshakes"]["completed"], json!(2));
+        assert!(report["frames"].as_u64().unwrap() > 0);
+        assert!(report["samples"].as_array().is_some_and(|s| !s.is_empty()));
+    }
+}
@@ -0,0 +1,167 @@
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+
+pub use crate::encoding::Rect;
+use crate::framebuffer::Framebuffer;
+
+/*
+ * Where the server gets the pixels it sends to clients.  A Framebuffer, which
+ * an application draws into, is the usual source, but an application with a
+ * render target of its own can implement this instead, and save a copy.
+ *
+ * The server reads from the source whenever a client is due an update, from
+ * whichever thread is encoding for that client, so reads must be cheap and
+ * must not block for long.
+ */
+pub trait Source: Send + Sync {
+    /*
+     * The width and height, in pixels.  These must not change; to change
+     * size, give the server a new source with Server::set_source().
+     */
+    fn dimensions(&self) -> (usize, usize);
+
+    /*
+     * Append the pixels of "r", which lies within the source, to "out", a
+     * row at a time, as 0x00RRGGBB.
+     */
+    fn read_rect(&self, r: &Rect, out: &mut Vec<u32>);
+
+    /*
+     * A channel on which the source reports areas that have changed, if it
+     * can tell.  Sources that cannot are assumed to change at any moment.
+     */
+    fn subscribe_damage(&self) -> Option<mpsc::UnboundedReceiver<Rect>> {
+        None
+    }
+
+    /*
+     * The areas that have changed since generation "since", along with the
+     * current generation, if the source keeps track.  Nothing has been drawn
+     * before generation zero.  Sources that do not keep track are assumed to
+     * have changed everywhere.
+     */
+    fn changed_since(&self, _since: u64) -> Option<(u64, Vec<Rect>)> {
+        None
+    }
+
+    /*
+     * The framebuffer behind the source, if it is one, for those that draw
+     * into it.
+     */
+    fn framebuffer(self: Arc<Self>) -> Option<Arc<Framebuffer>> {
+        None
+    }
+}
+
+/*
+ * Part of another source, such as the window of one application on a
+ * captured desktop, seen as a source in its own right with its top left
+ * corner at the origin.
+ */
+pub struct Region {
+    whole: Arc<dyn Source>,
+    rect: Rect,
+}
+
+impl Region {
+    /*
+     * The part of "whole" covered by "r", which must lie within it.
+     */
+    pub fn new(whole: Arc<dyn Source>, r: Rect) -> Region {
+        let (width, height) = whole.dimensions();
+        assert!(r.width > 0 && r.height > 0
+            && r.x + r.width <= width && r.y + r.height <= height,
+            "region {:?} outside {}x{} source", r, width, height);
+        Region { whole, rect: r }
+    }
+
+    pub fn whole(&self) -> &Arc<dyn Source> {
+        &self.whole
+    }
+
+    pub fn rect(&self) -> Rect {
+        self.rect
+    }
+}
+
+impl Source for Region {
+    fn dimensions(&self) -> (usize, usize) {
+        (self.rect.width, self.rect.height)
+    }
+
+    fn read_rect(&self, r: &Rect, out: &mut Vec<u32>) {
+        let r = Rect::new(r.x + self.rect.x, r.y + self.rect.y, r.width,
+            r.height);
+        self.whole.read_rect(&r, out);
+    }
+
+    /*
+     * Changes outside the region are of no interest, and those inside it
+     * are moved to where they are in the region.
+     */
+    fn changed_since(&self, since: u64) -> Option<(u64, Vec<Rect>)> {
+        let (gen, rects) = self.whole.changed_since(since)?;
+        let rects = rects.iter()
+            .filter_map(|d| d.intersect(&self.rect))
+            .map(|d| Rect::new(d.x - self.rect.x, d.y - self.rect.y,
+                d.width, d.height))
+            .collect();
+        Some((gen, rects))
+    }
+
+    /*
+     * Those who draw do so in the whole of the framebuffer.
+     */
+    fn framebuffer(self: Arc<Self>) -> Option<Arc<Framebuffer>> {
+        Arc::clone(&self.whole).framebuffer()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::InputEvent;
+    use crate::ServerBuilder;
+    use std::sync::Mutex;
+
+    #[test]
+    fn region() {
+        let server = ServerBuilder::new().geometry(100, 80).build().unwrap();
+        let fb = server.framebuffer().unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        server.set_input_handler({
+            let seen = Arc::clone(&seen);
+            move |_, ev| seen.lock().unwrap().push(*ev)
+        });
+
+        assert!(server.share_region(Some(Rect::new(60, 0, 50, 10))).is_err());
+        server.share_region(Some(Rect::new(20, 30, 50, 40))).unwrap();
+        let source = server.source();
+        assert_eq!(source.dimensions(), (50, 40));
+
+        /*
+         * Pixels and damage are moved to where they are in the region, and
+         * input back to where it is in the framebuffer, into which the
+         * application still draws.
+         */
+        let (gen, _) = source.changed_since(0).unwrap();
+        fb.put(25, 35, 1, 2, 3);
+        let mut out = Vec::new();
+        source.read_rect(&Rect::new(5, 5, 1, 1), &mut out);
+        assert_eq!(out, vec![0x010203]);
+        assert_eq!(source.changed_since(gen).unwrap().1,
+            vec![Rect::new(0, 0, 44, 34)]);
+
+        server.input_event(1, &InputEvent::Pointer { buttons: 0, x: 5, y: 5 });
+        assert!(Arc::ptr_eq(&server.framebuffer().unwrap(), &fb));
+
+        server.share_region(None).unwrap();
+        assert_eq!(server.source().dimensions(), (100, 80));
+        server.input_event(1, &InputEvent::Pointer { buttons: 0, x: 5, y: 5 });
+        assert_eq!(*seen.lock().unwrap(), vec![
+            InputEvent::Pointer { buttons: 0, x: 25, y: 35 },
+            InputEvent::Pointer { buttons: 0, x: 5, y: 5 },
+        ]);
+    }
+}
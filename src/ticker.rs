@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::time::{Interval, MissedTickBehavior};
+
+/*
+ * Paces an application that renders frames of its own into the framebuffer.
+ * Each call to tick() waits until the next frame is due, and while no client
+ * is connected, until one is, so that nothing is drawn that nobody will see.
+ * An application that would rather decide for itself when to draw need not
+ * use one at all: whatever it presents is sent to clients as it changes.
+ */
+pub struct Ticker {
+    interval: Interval,
+    sessions: watch::Receiver<usize>,
+}
+
+impl Ticker {
+    pub(crate) fn new(period: Duration, sessions: watch::Receiver<usize>)
+        -> Ticker
+    {
+        /*
+         * A frame that is late, perhaps because drawing the last one took a
+         * while, pushes back those after it rather than being made up for.
+         */
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Ticker { interval, sessions }
+    }
+
+    /*
+     * Wait until it is time to draw the next frame.  The first frame after a
+     * pause is due as soon as a client connects.
+     */
+    pub async fn tick(&mut self) {
+        let mut paused = false;
+        while *self.sessions.borrow_and_update() == 0 {
+            paused = true;
+            if self.sessions.changed().await.is_err() {
+                /*
+                 * The server is gone, and nobody will connect again.
+                 */
+                std::future::pending::<()>().await;
+            }
+        }
+
+        if paused {
+            self.interval.reset();
+        } else {
+            self.interval.tick().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*
+     * Whether the ticker ticks in good time:
+     */
+    async fn tick(ticker: &mut Ticker) -> bool {
+        tokio::time::timeout(Duration::from_millis(100), ticker.tick())
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn paused() {
+        let (tx, rx) = watch::channel(0);
+        let mut ticker = Ticker::new(Duration::from_millis(10), rx);
+
+        assert!(!tick(&mut ticker).await);
+        tx.send_replace(1);
+        assert!(tick(&mut ticker).await);
+        assert!(tick(&mut ticker).await);
+        tx.send_replace(0);
+        assert!(!tick(&mut ticker).await);
+    }
+}
@@ -0,0 +1,229 @@
+use std::time::Duration;
+
+/*
+ * Clients are sorted into one of these quality tiers based on how long they
+ * take to turn around a round trip through the protocol.  The round trip
+ * includes the time spent by the client decoding whatever we sent before the
+ * probe, so a slow viewer on a fast network will also land in a lower tier.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tier {
+    Lan,
+    Wan,
+    Poor,
+}
+
+impl Tier {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tier::Lan => "lan",
+            Tier::Wan => "wan",
+            Tier::Poor => "poor",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Tier> {
+        match s {
+            "lan" => Some(Tier::Lan),
+            "wan" => Some(Tier::Wan),
+            "poor" => Some(Tier::Poor),
+            _ => None,
+        }
+    }
+}
+
+/*
+ * Encoder parameters applied to a connection while it is in a particular tier.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Preset {
+    /*
+     * JPEG quality level, 0 (worst) through 9 (best), as per the QualityLevel
+     * pseudo-encodings.
+     */
+    pub quality: u8,
+    /*
+     * Maximum number of framebuffer updates per second.
+     */
+    pub fps: u64,
+    /*
+     * The client sees the framebuffer reduced by this integer factor in each
+     * dimension.  Scaling is fixed at ServerInit time.  It is one in every
+     * tier unless configured otherwise, as a client that has been shown a
+     * smaller desktop cannot be shown the full size again when its
+     * connection improves.
+     */
+    pub scale: usize,
+}
+
+pub const MAX_QUALITY: u8 = 9;
+pub const MAX_SCALE: usize = 8;
+
+/*
+ * Changes to a preset, as given in the configuration: any of "quality=Q",
+ * "fps=F" and "scale=S", separated by commas.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PresetChange {
+    pub quality: Option<u8>,
+    pub fps: Option<u64>,
+    pub scale: Option<usize>,
+}
+
+impl PresetChange {
+    pub fn parse(s: &str) -> Result<PresetChange, String> {
+        let mut c = PresetChange::default();
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let Some((key, value)) = part.split_once('=') else {
+                return Err(format!("expected KEY=VALUE, not {:?}", part));
+            };
+            match key.trim() {
+                "quality" => match value.trim().parse() {
+                    Ok(n @ 0..=MAX_QUALITY) => c.quality = Some(n),
+                    _ => return Err(format!("quality must be between 0 \
+                        and {}", MAX_QUALITY)),
+                },
+                "fps" => match value.trim().parse() {
+                    Ok(n @ 1..) => c.fps = Some(n),
+                    _ => return Err("fps must be at least 1".into()),
+                },
+                "scale" => match value.trim().parse() {
+                    Ok(n @ 1..=MAX_SCALE) => c.scale = Some(n),
+                    _ => return Err(format!("scale must be between 1 and \
+                        {}", MAX_SCALE)),
+                },
+                k => return Err(format!("unknown setting {:?}", k)),
+            }
+        }
+        Ok(c)
+    }
+
+    pub fn apply(&self, p: &mut Preset) {
+        if let Some(v) = self.quality {
+            p.quality = v;
+        }
+        if let Some(v) = self.fps {
+            p.fps = v;
+        }
+        if let Some(v) = self.scale {
+            p.scale = v;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Presets {
+    pub lan: Preset,
+    pub wan: Preset,
+    pub poor: Preset,
+    /*
+     * Round trip times below "lan_rtt" are considered LAN, those below
+     * "wan_rtt" are WAN, and anything slower is poor.
+     */
+    pub lan_rtt: Duration,
+    pub wan_rtt: Duration,
+}
+
+impl Default for Presets {
+    fn default() -> Self {
+        Presets {
+            lan: Preset { quality: 9, fps: 30, scale: 1 },
+            wan: Preset { quality: 6, fps: 12, scale: 1 },
+            poor: Preset { quality: 3, fps: 5, scale: 1 },
+            lan_rtt: Duration::from_millis(20),
+            wan_rtt: Duration::from_millis(150),
+        }
+    }
+}
+
+impl Presets {
+    pub fn preset(&self, tier: Tier) -> Preset {
+        match tier {
+            Tier::Lan => self.lan,
+            Tier::Wan => self.wan,
+            Tier::Poor => self.poor,
+        }
+    }
+
+    pub fn preset_mut(&mut self, tier: Tier) -> &mut Preset {
+        match tier {
+            Tier::Lan => &mut self.lan,
+            Tier::Wan => &mut self.wan,
+            Tier::Poor => &mut self.poor,
+        }
+    }
+
+    /*
+     * Set the round trip thresholds, in milliseconds.
+     */
+    pub fn set_rtt(&mut self, lan: u64, wan: u64) -> Result<(), String> {
+        if lan > wan {
+            return Err(format!("the LAN round trip time, {} ms, is more \
+                than the WAN one, {} ms", lan, wan));
+        }
+        self.lan_rtt = Duration::from_millis(lan);
+        self.wan_rtt = Duration::from_millis(wan);
+        Ok(())
+    }
+
+    fn classify(&self, rtt: Duration) -> Tier {
+        if rtt < self.lan_rtt {
+            Tier::Lan
+        } else if rtt < self.wan_rtt {
+            Tier::Wan
+        } else {
+            Tier::Poor
+        }
+    }
+}
+
+/*
+ * Tracks a smoothed round trip time for a connection and the tier it implies.
+ */
+pub struct Classifier {
+    srtt: Option<Duration>,
+    tier: Tier,
+}
+
+impl Classifier {
+    pub(crate) fn new() -> Self {
+        Classifier {
+            srtt: None,
+            tier: Tier::Lan,
+        }
+    }
+
+    pub fn tier(&self) -> Tier {
+        self.tier
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /*
+     * Feed in a new round trip sample.  If the connection has moved to a
+     * different tier as a result, the new tier is returned.
+     */
+    pub fn observe(&mut self, presets: &Presets, rtt: Duration)
+        -> Option<Tier>
+    {
+        /*
+         * Smooth the samples in the same manner as the TCP SRTT estimator,
+         * with each new sample contributing one eighth.
+         */
+        let srtt = match self.srtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        };
+        self.srtt = Some(srtt);
+
+        let tier = presets.classify(srtt);
+        if tier != self.tier {
+            self.tier = tier;
+            Some(tier)
+        } else {
+            None
+        }
+    }
+}
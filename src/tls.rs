@@ -0,0 +1,220 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::pin::Pin;
+
+use anyhow::{bail, Context, Result};
+use openssl::dh::Dh;
+use openssl::ex_data::Index;
+use openssl::ssl::{Ssl, SslContext, SslFiletype, SslMethod, SslVersion};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_openssl::SslStream;
+
+/*
+ * TLS with pre-shared keys, for closed systems where managing certificates is
+ * impractical.  The whole connection is wrapped in TLS before the RFB
+ * handshake begins, and each client proves it holds the secret for the
+ * identity it presents.
+ */
+pub struct PskAcceptor {
+    ctx: SslContext,
+    /*
+     * Where the PSK callback records the identity it accepted.  OpenSSL does
+     * not report the identity itself for TLS 1.3 connections.
+     */
+    identity: Index<Ssl, String>,
+}
+
+/*
+ * Suites with an ephemeral key exchange come first, so that a compromised key
+ * does not expose past sessions, when the client supports them.
+ */
+const CIPHERS: &str = "ECDHE-PSK-CHACHA20-POLY1305:ECDHE-PSK-AES256-CBC-SHA384:\
+    ECDHE-PSK-AES128-CBC-SHA256:PSK-CHACHA20-POLY1305:PSK-AES256-GCM-SHA384:\
+    PSK-AES128-GCM-SHA256";
+
+/*
+ * Read a key file, in which each line is an identity and a hexadecimal secret
+ * separated by a colon.  Blank lines and lines that start with "#" are
+ * ignored.
+ */
+pub fn load_keys(path: &Path) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut keys = BTreeMap::new();
+    for (n, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some((id, secret)) = line.split_once(':') else {
+            bail!("{:?} line {}: expected IDENTITY:SECRET", path, n + 1);
+        };
+        let Some(secret) = unhex(secret.trim()) else {
+            bail!("{:?} line {}: secret must be hexadecimal", path, n + 1);
+        };
+        if secret.is_empty() {
+            bail!("{:?} line {}: secret is empty", path, n + 1);
+        }
+        keys.insert(id.trim().to_string(), secret);
+    }
+
+    if keys.is_empty() {
+        bail!("{:?} contains no keys", path);
+    }
+    Ok(keys)
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl PskAcceptor {
+    pub fn new(keys: BTreeMap<String, Vec<u8>>) -> Result<PskAcceptor> {
+        let index = Ssl::new_ex_index()?;
+
+        let mut b = SslContext::builder(SslMethod::tls_server())?;
+        b.set_min_proto_version(Some(SslVersion::TLS1_2))?;
+        b.set_cipher_list(CIPHERS)?;
+        b.set_psk_server_callback(move |ssl, identity, psk| {
+            let Some((id, key)) = identity
+                .and_then(|id| std::str::from_utf8(id).ok())
+                .and_then(|id| keys.get_key_value(id))
+                .filter(|(_, key)| key.len() <= psk.len())
+            else {
+                /*
+                 * A zero length tells OpenSSL that the identity is unknown,
+                 * and the handshake fails.
+                 */
+                return Ok(0);
+            };
+
+            psk[..key.len()].copy_from_slice(key);
+            ssl.set_ex_data(index, id.clone());
+            Ok(key.len())
+        });
+
+        Ok(PskAcceptor {
+            ctx: b.build(),
+            identity: index,
+        })
+    }
+
+    /*
+     * Complete the TLS handshake, returning the stream and the identity the
+     * client authenticated as.
+     */
+    pub async fn accept<S>(&self, sock: S) -> Result<(SslStream<S>, String)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut s = SslStream::new(Ssl::new(&self.ctx)?, sock)?;
+        Pin::new(&mut s).accept().await?;
+
+        let id = match s.ssl().ex_data(self.identity) {
+            Some(id) => id.clone(),
+            None => bail!("client did not present a PSK identity"),
+        };
+        Ok((s, id))
+    }
+}
+
+/*
+ * The TLS contexts for the VeNCrypt security type, in which the RFB
+ * connection switches to TLS part way through the security handshake.  The
+ * "TLS" subtypes use anonymous key exchange, which protects against
+ * eavesdropping but not impersonation; the "X509" subtypes present a
+ * certificate, if one has been configured.
+ */
+pub struct Vencrypt {
+    anon: SslContext,
+    x509: Option<SslContext>,
+}
+
+impl Vencrypt {
+    pub fn new(cert: Option<(&Path, &Path)>) -> Result<Vencrypt> {
+        /*
+         * Anonymous suites are absent from TLS 1.3, and OpenSSL only allows
+         * them at the lowest security level.
+         */
+        let mut b = SslContext::builder(SslMethod::tls_server())?;
+        b.set_min_proto_version(Some(SslVersion::TLS1_2))?;
+        b.set_max_proto_version(Some(SslVersion::TLS1_2))?;
+        b.set_cipher_list("aNULL:!eNULL:@SECLEVEL=0")?;
+        b.set_tmp_dh(&*Dh::get_2048_256()?)?;
+        let anon = b.build();
+
+        let x509 = match cert {
+            Some((cert, key)) => Some(x509_context(cert, key)?),
+            None => None,
+        };
+
+        Ok(Vencrypt { anon, x509 })
+    }
+
+    pub fn has_x509(&self) -> bool {
+        self.x509.is_some()
+    }
+
+    /*
+     * Complete the TLS handshake, with the certificate if "x509" is set.
+     */
+    pub async fn accept<S>(&self, sock: S, x509: bool) -> Result<SslStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let ctx = match (x509, &self.x509) {
+            (false, _) => &self.anon,
+            (true, Some(ctx)) => ctx,
+            (true, None) => bail!("no certificate configured"),
+        };
+
+        let mut s = SslStream::new(Ssl::new(ctx)?, sock)?;
+        Pin::new(&mut s).accept().await?;
+        Ok(s)
+    }
+}
+
+/*
+ * A context that presents the certificate in "cert", whose private key is in
+ * "key", which may be the same file.
+ */
+fn x509_context(cert: &Path, key: &Path) -> Result<SslContext> {
+    let mut b = SslContext::builder(SslMethod::tls_server())?;
+    b.set_min_proto_version(Some(SslVersion::TLS1_2))?;
+    b.set_certificate_chain_file(cert)
+        .with_context(|| format!("certificate {:?}", cert))?;
+    b.set_private_key_file(key, SslFiletype::PEM)
+        .with_context(|| format!("private key {:?}", key))?;
+    b.check_private_key()?;
+    Ok(b.build())
+}
+
+/*
+ * TLS with a certificate around the whole connection, as for "rfbs://"
+ * listeners, so that the server can sit directly behind the same kind of
+ * name and certificate as any other TLS service.  Clients connect through a
+ * TLS tunnel, or a viewer that knows how, and then speak RFB as usual.
+ */
+pub struct CertAcceptor {
+    ctx: SslContext,
+}
+
+impl CertAcceptor {
+    pub fn new(cert: &Path, key: &Path) -> Result<CertAcceptor> {
+        Ok(CertAcceptor { ctx: x509_context(cert, key)? })
+    }
+
+    pub async fn accept<S>(&self, sock: S) -> Result<SslStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut s = SslStream::new(Ssl::new(&self.ctx)?, sock)?;
+        Pin::new(&mut s).accept().await?;
+        Ok(s)
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+use anyhow::{bail, Result};
+
+/*
+ * Touch input arrives through the General Input Interface (GII) extension,
+ * which lets a client describe its input devices and then send events from
+ * them.  A client that includes the pseudo-encoding in SetEncodings is sent
+ * our GII version; it replies with its own, and then creates each device it
+ * wants to use, giving its name and the valuators (axes) it has.  We answer
+ * each creation with an origin number for the device, or zero if we will not
+ * take events from it.
+ *
+ * Each GII message has a subtype, whose top bit says whether the sender's
+ * fields are big endian, and a 16-bit length.  The server may choose either
+ * order for its own messages; we use big endian, as for the rest of RFB.
+ *
+ * We only accept touch screens, which we recognise by a device name ending
+ * in "-MT", as used by the multitouch devices of some viewers.  Each event
+ * from a touch screen is a set of absolute valuators, in groups of three for
+ * each finger on the screen: a contact number, chosen by the client, and the
+ * position.  A contact that was in the last event but not in this one has
+ * been lifted.
+ */
+pub const MESSAGE_TYPE: u8 = 253;
+
+const BIG_ENDIAN: u8 = 0x80;
+const SUB_EVENTS: u8 = 0;
+const SUB_VERSION: u8 = 1;
+const SUB_CREATE: u8 = 2;
+const SUB_DESTROY: u8 = 3;
+
+const EVENT_VALUATOR_ABSOLUTE: u8 = 13;
+const DEVICE_NAME: usize = 32;
+const TOUCH_SUFFIX: &str = "-MT";
+
+/*
+ * The only GII version there is:
+ */
+const VERSION: u16 = 1;
+
+fn message(subtype: u8, payload: &[u8]) -> Vec<u8> {
+    let mut buf = vec![MESSAGE_TYPE, BIG_ENDIAN | subtype];
+    buf.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/*
+ * The range of GII versions we speak, from highest to lowest.
+ */
+pub fn version_message() -> Vec<u8> {
+    let mut payload = VERSION.to_be_bytes().to_vec();
+    payload.extend_from_slice(&VERSION.to_be_bytes());
+    message(SUB_VERSION, &payload)
+}
+
+fn created_message(origin: u32) -> Vec<u8> {
+    message(SUB_CREATE, &origin.to_be_bytes())
+}
+
+/*
+ * The length of the GII message with the given subtype, from the two bytes
+ * that follow it, which are in the sender's byte order.
+ */
+pub fn length(subtype: u8, len: [u8; 2]) -> usize {
+    if subtype & BIG_ENDIAN != 0 {
+        u16::from_be_bytes(len) as usize
+    } else {
+        u16::from_le_bytes(len) as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TouchPhase {
+    Down,
+    Move,
+    Up,
+}
+
+/*
+ * A change to one of the contacts on a client's touch screen.  The contact
+ * number stays the same from when the finger goes down until it is lifted,
+ * but may be reused after that.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TouchEvent {
+    pub contact: u32,
+    pub phase: TouchPhase,
+    pub x: u16,
+    pub y: u16,
+}
+
+/*
+ * Fields in the sender's byte order:
+ */
+struct Payload<'a> {
+    buf: &'a [u8],
+    big: bool,
+}
+
+impl<'a> Payload<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < n {
+            bail!("GII message is too short");
+        }
+        let (b, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(b)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let b = self.take(2)?.try_into().unwrap();
+        Ok(if self.big {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        })
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let b = self.take(4)?.try_into().unwrap();
+        Ok(if self.big {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        })
+    }
+}
+
+/*
+ * The GII state of one connection: the touch screens the client has
+ * created, and the contacts on each that are down.
+ */
+pub struct Touch {
+    devices: BTreeMap<u32, BTreeMap<u32, (u16, u16)>>,
+    next_origin: u32,
+}
+
+impl Touch {
+    pub fn new() -> Touch {
+        Touch { devices: BTreeMap::new(), next_origin: 1 }
+    }
+
+    /*
+     * Handle a GII message from the client, returning any reply to send and
+     * the touch events it carried.
+     */
+    pub fn message(&mut self, subtype: u8, payload: &[u8])
+        -> Result<(Option<Vec<u8>>, Vec<TouchEvent>)>
+    {
+        let mut p = Payload { buf: payload, big: subtype & BIG_ENDIAN != 0 };
+        match subtype & !BIG_ENDIAN {
+            SUB_VERSION => {
+                let v = p.u16()?;
+                if v != VERSION {
+                    bail!("unexpected GII version {}", v);
+                }
+                Ok((None, Vec::new()))
+            }
+            SUB_CREATE => {
+                let name = p.take(DEVICE_NAME)?;
+                let name = name.split(|&b| b == 0).next().unwrap_or(&[]);
+                let name = String::from_utf8_lossy(name);
+                let origin = if name.ends_with(TOUCH_SUFFIX) {
+                    let origin = self.next_origin;
+                    self.next_origin += 1;
+                    self.devices.insert(origin, BTreeMap::new());
+                    log!("  touch screen {:?} is device {}", name, origin);
+                    origin
+                } else {
+                    log!("  ignoring GII device {:?}", name);
+                    0
+                };
+                Ok((Some(created_message(origin)), Vec::new()))
+            }
+            SUB_DESTROY => {
+                let origin = p.u32()?;
+                let events = self.devices.remove(&origin)
+                    .map(lift)
+                    .unwrap_or_default();
+                Ok((None, events))
+            }
+            SUB_EVENTS => {
+                let mut events = Vec::new();
+                while !p.buf.is_empty() {
+                    let size = p.buf[0] as usize;
+                    if size < 2 {
+                        bail!("GII event of {} bytes", size);
+                    }
+                    let mut e = Payload { buf: p.take(size)?, big: p.big };
+                    let ty = e.take(2)?[1];
+                    if ty == EVENT_VALUATOR_ABSOLUTE {
+                        e.take(2)?;
+                        let origin = e.u32()?;
+                        let first = e.u32()?;
+                        let count = e.u32()? as usize;
+                        let values = (0..count.min(e.buf.len() / 4))
+                            .map(|_| e.u32())
+                            .collect::<Result<Vec<_>>>()?;
+                        if first == 0 {
+                            self.contacts(origin, &values, &mut events);
+                        }
+                    }
+                }
+                Ok((None, events))
+            }
+            s => bail!("unexpected GII subtype {}", s),
+        }
+    }
+
+    /*
+     * Compare the contacts in an event with those down before, in order to
+     * tell which have gone down, moved or been lifted.
+     */
+    fn contacts(&mut self, origin: u32, values: &[u32],
+        events: &mut Vec<TouchEvent>)
+    {
+        let down = match self.devices.get_mut(&origin) {
+            Some(down) => down,
+            None => return,
+        };
+
+        let now: BTreeMap<u32, (u16, u16)> = values.chunks_exact(3)
+            .map(|v| (v[0], (coord(v[1]), coord(v[2]))))
+            .collect();
+        for (&contact, &(x, y)) in &now {
+            let phase = match down.get(&contact) {
+                None => TouchPhase::Down,
+                Some(&was) if was != (x, y) => TouchPhase::Move,
+                Some(_) => continue,
+            };
+            events.push(TouchEvent { contact, phase, x, y });
+        }
+        let lifted = std::mem::replace(down, now).into_iter()
+            .filter(|(c, _)| !down.contains_key(c))
+            .collect();
+        events.extend(lift(lifted));
+    }
+}
+
+/*
+ * Lift every contact in "down".
+ */
+fn lift(down: BTreeMap<u32, (u16, u16)>) -> Vec<TouchEvent> {
+    down.into_iter()
+        .map(|(contact, (x, y))| TouchEvent {
+            contact,
+            phase: TouchPhase::Up,
+            x,
+            y,
+        })
+        .collect()
+}
+
+/*
+ * Valuators are signed, and may stray outside the framebuffer.
+ */
+fn coord(v: u32) -> u16 {
+    (v as i32).clamp(0, u16::MAX as i32) as u16
+}
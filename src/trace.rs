@@ -0,0 +1,124 @@
+use std::time::{Duration, Instant};
+
+/*
+ * Logging of every message exchanged with one connection, which an operator
+ * can turn on for a while to see what a misbehaving client is doing without
+ * doing the same for every other.  Tracing ends by itself once its time is
+ * up, in case the operator forgets, and a client that sends a flood of
+ * messages produces no more than a few lines a second; the rest are counted
+ * and the count logged instead.
+ */
+pub struct Trace {
+    until: Instant,
+    /*
+     * When the current second began, the lines logged in it, and those
+     * dropped since the last were logged:
+     */
+    second: Instant,
+    lines: usize,
+    dropped: u64,
+}
+
+/*
+ * The most lines logged for a connection in any one second, the longest a
+ * line may be, and the longest tracing may be turned on for at once:
+ */
+pub const LINES_PER_SEC: usize = 20;
+pub const LINE_MAX: usize = 160;
+pub const MAX_TIME: Duration = Duration::from_secs(30 * 60);
+
+/*
+ * What to do with a line offered to a trace:
+ */
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /*
+     * Log it, after noting how many lines were dropped before it, if any:
+     */
+    Log(u64),
+    Drop,
+    /*
+     * Tracing is over, having dropped this many lines at the end:
+     */
+    Expired(u64),
+}
+
+impl Trace {
+    pub fn new(time: Duration) -> Trace {
+        let now = Instant::now();
+        Trace {
+            until: now + time.min(MAX_TIME),
+            second: now,
+            lines: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.until.saturating_duration_since(Instant::now())
+    }
+
+    pub fn offer(&mut self, now: Instant) -> Verdict {
+        if now >= self.until {
+            return Verdict::Expired(self.dropped);
+        }
+
+        if now.duration_since(self.second) >= Duration::from_secs(1) {
+            self.second = now;
+            self.lines = 0;
+        }
+        if self.lines == LINES_PER_SEC {
+            self.dropped += 1;
+            return Verdict::Drop;
+        }
+        self.lines += 1;
+        Verdict::Log(std::mem::take(&mut self.dropped))
+    }
+}
+
+/*
+ * Shorten a line to at most LINE_MAX characters, so that a message with a
+ * large payload does not fill the log.
+ */
+pub fn clip(mut line: String) -> String {
+    if let Some((i, _)) = line.char_indices().nth(LINE_MAX) {
+        line.truncate(i);
+        line.push_str("...");
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_capped() {
+        let mut t = Trace::new(Duration::from_secs(5));
+        let start = Instant::now();
+        for _ in 0..LINES_PER_SEC {
+            assert_eq!(t.offer(start), Verdict::Log(0));
+        }
+        for _ in 0..7 {
+            assert_eq!(t.offer(start), Verdict::Drop);
+        }
+
+        /*
+         * The next second begins with a count of what was dropped, and once
+         * the time is up, the trace is over.
+         */
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(t.offer(later), Verdict::Log(7));
+        assert_eq!(t.offer(later), Verdict::Log(0));
+        assert_eq!(t.offer(start + Duration::from_secs(6)),
+            Verdict::Expired(0));
+    }
+
+    #[test]
+    fn clipped() {
+        assert_eq!(clip("short".to_string()), "short");
+        let long = clip("é".repeat(LINE_MAX * 2));
+        assert_eq!(long.chars().count(), LINE_MAX + 3);
+        assert!(long.ends_with("..."));
+    }
+}
@@ -0,0 +1,596 @@
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use flate2::{Decompress, FlushDecompress};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::BufReader;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::time::timeout;
+
+use crate::encoding::{pseudo, COPYRECT, RAW, ZLIB};
+use crate::framebuffer::Framebuffer;
+use crate::input::InputEvent;
+use crate::reverse::with_port;
+use crate::rfb::{self, ClientCutText, ClientMessage, FramebufferUpdateRequest,
+    KeyEvent, PixelFormat, PointerEvent, SetEncodings, SetPixelFormat,
+    Version};
+use crate::security::{NONE, VNC_AUTH};
+use crate::server::Server;
+use crate::source::Rect;
+use crate::vncauth;
+
+/*
+ * Proxy mode, in which the pixels we serve are those of another VNC server.
+ * We connect to it as a client, decode what it sends into a framebuffer of
+ * our own, and serve that to our clients with our own security and whatever
+ * encodings they prefer.  Input and clipboard text from our clients are sent
+ * on to the upstream server, as is its clipboard text back to them.
+ *
+ * Should the upstream server go away, clients see the last of its desktop
+ * while we try to connect again, with longer and longer pauses between
+ * attempts.
+ */
+
+const UPSTREAM_PORT: u16 = 5900;
+
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(60);
+
+/*
+ * Limits on what we will accept from the upstream server, which might
+ * otherwise have us allocate as much as it liked:
+ */
+const MAX_REASON: usize = 4096;
+const MAX_NAME: usize = 4096;
+const MAX_DATA: usize = 64 * 1024 * 1024;
+
+/*
+ * The encodings we ask for, in order of preference:
+ */
+const ENCODINGS: [i32; 5] = [
+    COPYRECT,
+    ZLIB,
+    RAW,
+    pseudo::DESKTOP_SIZE,
+    pseudo::DESKTOP_NAME,
+];
+
+/*
+ * Show the desktop of the VNC server at "target", and send it the input of
+ * our clients, for as long as the process runs.
+ */
+pub fn spawn(server: &Arc<Server>, target: &str) {
+    let target = with_port(target, UPSTREAM_PORT);
+    let (tx, mut input) = mpsc::unbounded_channel();
+
+    let keys = tx.clone();
+    server.set_input_handler(move |_, ev| {
+        if let Some(m) = input_message(ev) {
+            let _ = keys.send(m);
+        }
+    });
+    server.set_clipboard_handler(move |_, text| {
+        let _ = tx.send(message(ClientCutText {
+            text: rfb::cut_text(text).into(),
+        }));
+    });
+
+    let server = Arc::clone(server);
+    tokio::spawn(async move {
+        let mut wait = FIRST_RETRY;
+        loop {
+            /*
+             * Input meant for an earlier connection is not sent on to the
+             * next.
+             */
+            while input.try_recv().is_ok() {}
+
+            let start = Instant::now();
+            let res = match TcpStream::connect(&target).await {
+                Ok(sock) => {
+                    let _ = sock.set_nodelay(true);
+                    session(&server, sock, &target, &mut input).await
+                }
+                Err(e) => Err(e.into()),
+            };
+            match res {
+                Ok(()) => log!("upstream {}: closed", target),
+                Err(e) => log!("upstream {}: {:#}", target, e),
+            }
+
+            if start.elapsed() > MAX_RETRY {
+                wait = FIRST_RETRY;
+            }
+            log!("upstream {}: trying again in {:?}", target, wait);
+            tokio::time::sleep(wait).await;
+            wait = (wait * 2).min(MAX_RETRY);
+        }
+    });
+}
+
+fn message<M: Into<ClientMessage>>(m: M) -> Vec<u8> {
+    let mut buf = Vec::new();
+    m.into().write(&mut buf);
+    buf
+}
+
+/*
+ * What to send upstream for input from a client.  Keys given only by their
+ * scancode, and touches, cannot be sent in the basic protocol.
+ */
+fn input_message(ev: &InputEvent) -> Option<Vec<u8>> {
+    match *ev {
+        InputEvent::Key { down, keysym, .. } if keysym != 0 => {
+            Some(message(KeyEvent { down: down as u8, key: keysym }))
+        }
+        InputEvent::Pointer { buttons, x, y } => {
+            Some(message(PointerEvent { buttons, x, y }))
+        }
+        _ => None,
+    }
+}
+
+fn update_request(incremental: bool, width: usize, height: usize)
+    -> Vec<u8>
+{
+    message(FramebufferUpdateRequest {
+        incremental: incremental as u8,
+        x: 0,
+        y: 0,
+        width: width as u16,
+        height: height as u16,
+    })
+}
+
+/*
+ * Show the desktop of the upstream server on "sock" until the connection
+ * ends.
+ */
+async fn session<S>(server: &Server, sock: S, target: &str,
+    input: &mut UnboundedReceiver<Vec<u8>>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (rd, mut wr) = tokio::io::split(sock);
+    let mut rd = BufReader::new(rd);
+
+    let config = &server.config;
+    let password = config.upstream_password.as_deref();
+    let (width, height, name) = timeout(config.handshake_timeout,
+        handshake(&mut rd, &mut wr, password)).await
+        .map_err(|_| anyhow::anyhow!("timed out in the handshake"))??;
+    log!("upstream {}: {}x{}, {:?}", target, width, height, name);
+    server.set_name(&name);
+    let mut fb = Arc::new(Framebuffer::new_buffered(width, height, true)?);
+    server.set_framebuffer(Arc::clone(&fb));
+
+    let mut out = message(SetPixelFormat { format: PixelFormat::NATIVE });
+    out.extend(message(SetEncodings {
+        encodings: ENCODINGS.to_vec().into(),
+    }));
+    out.extend(update_request(false, width, height));
+    wr.write_all(&out).await?;
+
+    /*
+     * Once an update has been drawn, we ask for the next, with the size of
+     * the framebuffer at the time.
+     */
+    let (more, mut asked) = mpsc::unbounded_channel();
+    let reader = async {
+        let mut z = Decompress::new(true);
+        loop {
+            match rd.read_u8().await? {
+                0 => {
+                    update(server, &mut rd, &mut fb, &mut z).await?;
+                    let _ = more.send((fb.width(), fb.height()));
+                }
+                1 => {
+                    /*
+                     * SetColourMapEntries, which we should not see, as we
+                     * asked for true colour:
+                     */
+                    rd.read_u8().await?;
+                    rd.read_u16().await?;
+                    let n = rd.read_u16().await?;
+                    skip(&mut rd, n as usize * 6).await?;
+                }
+                2 => server.bell(),
+                3 => {
+                    skip(&mut rd, 3).await?;
+                    let len = rd.read_u32().await? as usize;
+                    if len > config.max_cut_text {
+                        skip(&mut rd, len).await?;
+                        continue;
+                    }
+                    let mut text = vec![0; len];
+                    rd.read_exact(&mut text).await?;
+                    let text: String = text.into_iter().map(char::from)
+                        .collect();
+                    server.set_clipboard(&text);
+                }
+                t => bail!("unexpected message type {}", t),
+            }
+        }
+    };
+    let writer = async {
+        loop {
+            let m = tokio::select! {
+                m = input.recv() => match m {
+                    Some(m) => m,
+                    None => return Ok(()),
+                },
+                Some((w, h)) = asked.recv() => update_request(true, w, h),
+            };
+            wr.write_all(&m).await?;
+        }
+    };
+
+    tokio::select! {
+        res = reader => res,
+        res = writer => res,
+    }
+}
+
+/*
+ * Conduct the handshake as a client, up to and including ServerInit, and
+ * return the size and name of the desktop.
+ */
+async fn handshake<R, W>(rd: &mut R, wr: &mut W, password: Option<&str>)
+    -> Result<(usize, usize, String)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let ver = rfb::read_version(rd).await?;
+    let Some(version) = Version::parse(&ver) else {
+        bail!("invalid version {:?}", ver);
+    };
+    let version = version.min(Version::V3_8);
+    wr.write_all(format!("{}\n", version.wire()).as_bytes()).await?;
+
+    let sec = if version == Version::V3_3 {
+        match rd.read_u32().await? {
+            0 => bail!("refused: {}", reason(rd).await?),
+            n => u8::try_from(n).unwrap_or(0),
+        }
+    } else {
+        let n = rd.read_u8().await?;
+        if n == 0 {
+            bail!("refused: {}", reason(rd).await?);
+        }
+        let mut offer = vec![0; n as usize];
+        rd.read_exact(&mut offer).await?;
+        let sec = if offer.contains(&VNC_AUTH) && password.is_some() {
+            VNC_AUTH
+        } else if offer.contains(&NONE) {
+            NONE
+        } else if offer.contains(&VNC_AUTH) {
+            VNC_AUTH
+        } else {
+            bail!("no security type we can use among {:?}", offer);
+        };
+        wr.write_u8(sec).await?;
+        sec
+    };
+
+    match sec {
+        NONE => (),
+        VNC_AUTH => {
+            let Some(password) = password else {
+                bail!("a password is needed, and none is configured");
+            };
+            let mut challenge = [0; vncauth::CHALLENGE_SIZE];
+            rd.read_exact(&mut challenge).await?;
+            wr.write_all(&vncauth::response(password, &challenge)).await?;
+        }
+        _ => bail!("unsupported security type {}", sec),
+    }
+    if (sec != NONE || version >= Version::V3_8) && rd.read_u32().await? != 0
+    {
+        if version >= Version::V3_8 {
+            bail!("security failed: {}", reason(rd).await?);
+        }
+        bail!("security failed");
+    }
+
+    /*
+     * ClientInit, asking to share the desktop with any other clients:
+     */
+    wr.write_u8(1).await?;
+
+    let width = rd.read_u16().await? as usize;
+    let height = rd.read_u16().await? as usize;
+    skip(rd, 16).await?;
+    let len = rd.read_u32().await? as usize;
+    if len > MAX_NAME {
+        bail!("desktop name too long");
+    }
+    let mut name = vec![0; len];
+    rd.read_exact(&mut name).await?;
+    Ok((width, height, String::from_utf8_lossy(&name).into_owned()))
+}
+
+async fn reason<R: AsyncRead + Unpin>(rd: &mut R) -> Result<String> {
+    let len = rd.read_u32().await? as usize;
+    if len > MAX_REASON {
+        bail!("reason too long");
+    }
+    let mut buf = vec![0; len];
+    rd.read_exact(&mut buf).await?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+async fn skip<R: AsyncRead + Unpin>(rd: &mut R, n: usize) -> Result<()> {
+    let copied = tokio::io::copy(&mut rd.take(n as u64), &mut tokio::io::sink())
+        .await?;
+    if copied < n as u64 {
+        bail!(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+    }
+    Ok(())
+}
+
+/*
+ * Draw a FramebufferUpdate, following its type, into "fb", and show it to
+ * our clients.  A change of size gives us a new framebuffer.
+ */
+async fn update<R>(server: &Server, rd: &mut R, fb: &mut Arc<Framebuffer>,
+    z: &mut Decompress) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    rd.read_u8().await?;
+    let n = rd.read_u16().await?;
+    for _ in 0..n {
+        let x = rd.read_u16().await? as usize;
+        let y = rd.read_u16().await? as usize;
+        let width = rd.read_u16().await? as usize;
+        let height = rd.read_u16().await? as usize;
+        let r = Rect::new(x, y, width, height);
+        let encoding = rd.read_i32().await?;
+
+        match encoding {
+            pseudo::DESKTOP_SIZE => {
+                fb.present();
+                *fb = Arc::new(Framebuffer::new_buffered(width, height,
+                    true)?);
+                server.set_framebuffer(Arc::clone(fb));
+                continue;
+            }
+            pseudo::DESKTOP_NAME => {
+                let len = rd.read_u32().await? as usize;
+                if len > MAX_NAME {
+                    bail!("desktop name too long");
+                }
+                let mut name = vec![0; len];
+                rd.read_exact(&mut name).await?;
+                server.set_name(&String::from_utf8_lossy(&name));
+                continue;
+            }
+            _ => (),
+        }
+
+        if x + width > fb.width() || y + height > fb.height() {
+            bail!("rectangle {:?} outside {}x{} framebuffer", r, fb.width(),
+                fb.height());
+        }
+        match encoding {
+            RAW => {
+                let mut pixels = vec![0; r.area() * 4];
+                rd.read_exact(&mut pixels).await?;
+                draw(fb, &r, &pixels);
+            }
+            COPYRECT => {
+                let sx = rd.read_u16().await? as usize;
+                let sy = rd.read_u16().await? as usize;
+                if sx + width > fb.width() || sy + height > fb.height() {
+                    bail!("copy from outside the framebuffer");
+                }
+                copy(fb, sx, sy, &r);
+            }
+            ZLIB => {
+                let len = rd.read_u32().await? as usize;
+                if len > MAX_DATA {
+                    bail!("zlib rectangle too long");
+                }
+                let mut data = vec![0; len];
+                rd.read_exact(&mut data).await?;
+                let pixels = inflate(z, &data, r.area() * 4)?;
+                draw(fb, &r, &pixels);
+            }
+            e => bail!("unexpected encoding {}", e),
+        }
+    }
+    fb.present();
+    Ok(())
+}
+
+/*
+ * Draw pixels in our native format, as we asked the upstream server to send
+ * them.
+ */
+fn draw(fb: &Framebuffer, r: &Rect, pixels: &[u8]) {
+    let rows = pixels.chunks_exact(r.width * 4);
+    for (y, row) in (r.y..).zip(rows) {
+        for (x, p) in (r.x..).zip(row.chunks_exact(4)) {
+            fb.put(x, y, p[2], p[1], p[0]);
+        }
+    }
+}
+
+/*
+ * Copy pixels within the framebuffer, from an area that may overlap the one
+ * they are copied to.
+ */
+fn copy(fb: &Framebuffer, sx: usize, sy: usize, r: &Rect) {
+    let mut pixels = Vec::with_capacity(r.area());
+    for y in sy..sy + r.height {
+        for x in sx..sx + r.width {
+            pixels.push(fb.get(x, y));
+        }
+    }
+    let mut src = pixels.into_iter();
+    for y in r.y..r.y + r.height {
+        for x in r.x..r.x + r.width {
+            let (red, green, blue) = src.next().unwrap();
+            fb.put(x, y, red, green, blue);
+        }
+    }
+}
+
+/*
+ * Inflate exactly "len" bytes from "data".  The stream carries on from one
+ * rectangle to the next, for the whole of the connection.
+ */
+fn inflate(z: &mut Decompress, data: &[u8], len: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut data = data;
+    while out.len() < len {
+        let before = (z.total_in(), out.len());
+        z.decompress_vec(data, &mut out, FlushDecompress::Sync)?;
+        let used = (z.total_in() - before.0) as usize;
+        data = &data[used..];
+        if used == 0 && out.len() == before.1 {
+            bail!("zlib rectangle is short");
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::{select, Params, Update};
+    use crate::ServerBuilder;
+
+    #[tokio::test]
+    async fn proxied() {
+        let server = ServerBuilder::new().geometry(64, 48).build().unwrap();
+        let (up, sock) = tokio::io::duplex(64 * 1024);
+        let (mut rd, mut wr) = tokio::io::split(up);
+        let (tx, mut input) = mpsc::unbounded_channel();
+        let s = Arc::clone(&server);
+        let proxy = tokio::spawn(async move {
+            session(&s, sock, "test", &mut input).await
+        });
+
+        /*
+         * Play the part of the upstream server, with VNC Authentication
+         * offered but None used, as we have no password:
+         */
+        wr.write_all(b"RFB 003.008\n\x02\x02\x01").await.unwrap();
+        let mut buf = [0; 13];
+        rd.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"RFB 003.008\n\x01");
+        let mut init = vec![0, 4, 0, 2];
+        init.extend([0; 16]);
+        init.extend(b"\0\0\0\x02up");
+        wr.write_all(&[0, 0, 0, 0]).await.unwrap();
+        wr.write_all(&init).await.unwrap();
+
+        let mut setup = vec![0; 1 + 20 + 4 + 4 * ENCODINGS.len() + 10];
+        rd.read_exact(&mut setup).await.unwrap();
+        assert_eq!(setup[0], 1);
+        assert_eq!(&setup[setup.len() - 10..], [3, 0, 0, 0, 0, 0, 0, 4, 0, 2]);
+
+        /*
+         * Two red pixels in raw, and then a copy of them below.
+         */
+        let mut upd = vec![0, 0, 0, 2];
+        upd.extend([0, 1, 0, 0, 0, 2, 0, 1, 0, 0, 0, 0]);
+        upd.extend([0, 0, 0xff, 0, 0, 0, 0xff, 0]);
+        upd.extend([0, 2, 0, 1, 0, 2, 0, 1, 0, 0, 0, 1, 0, 1, 0, 0]);
+        wr.write_all(&upd).await.unwrap();
+
+        let mut more = [0; 10];
+        rd.read_exact(&mut more).await.unwrap();
+        assert_eq!(more, [3, 1, 0, 0, 0, 0, 0, 4, 0, 2]);
+        let fb = server.framebuffer().unwrap();
+        assert_eq!((fb.width(), fb.height()), (4, 2));
+        assert_eq!(*server.name(), "up");
+        let mut px = vec![0; 8];
+        fb.read_rows(&Rect::new(0, 0, 4, 2), &mut px);
+        assert_eq!(px, [0, 0xff0000, 0xff0000, 0, 0, 0, 0xff0000, 0xff0000]);
+
+        /*
+         * Input from our clients goes upstream.
+         */
+        tx.send(input_message(&InputEvent::Pointer { buttons: 1, x: 3,
+            y: 1 }).unwrap()).unwrap();
+        let mut ptr = [0; 6];
+        rd.read_exact(&mut ptr).await.unwrap();
+        assert_eq!(ptr, [5, 1, 0, 3, 0, 1]);
+
+        drop((rd, wr));
+        assert!(proxy.await.unwrap().is_err());
+    }
+
+    /*
+     * Rectangles of awkward shapes, encoded by our own encoders and drawn by
+     * the decoders here, must come out as they went in.  The zlib stream
+     * carries on across rectangles and changes of compression level.
+     */
+    #[tokio::test]
+    async fn corpus() {
+        let server = ServerBuilder::new().geometry(8, 8).build().unwrap();
+        let mut fb = Arc::new(Framebuffer::new_buffered(37, 23, true)
+            .unwrap());
+        let mut want = vec![0u32; 37 * 23];
+        let mut z = Decompress::new(true);
+        let shapes = [(0, 0, 1, 1), (36, 22, 1, 1), (1, 2, 3, 1),
+            (5, 0, 1, 23), (7, 3, 17, 13), (0, 0, 37, 23), (20, 9, 15, 7)];
+
+        for (n, enc) in [RAW, ZLIB].iter().copied().enumerate() {
+            let mut e = select(&[enc]);
+            for (i, &(x, y, w, h)) in shapes.iter().enumerate() {
+                let r = Rect::new(x, y, w, h);
+                let pixels: Vec<u32> = (0..r.area())
+                    .map(|p| ((p * 7919 + i * 31 + n) as u32)
+                        .wrapping_mul(2654435761) & 0xffffff)
+                    .collect();
+                let params = Params {
+                    quality: None,
+                    compression: Some((i % 10) as u8),
+                };
+                let mut u = Update::new();
+                e.encode(&r, &pixels, &params, &mut u);
+                for (row, src) in (y..).zip(pixels.chunks_exact(w)) {
+                    want[row * 37 + x..][..w].copy_from_slice(src);
+                }
+
+                let msg = u.finish();
+                update(&server, &mut &msg[1..], &mut fb, &mut z).await
+                    .unwrap();
+                let mut got = vec![0; 37 * 23];
+                fb.read_rows(&Rect::new(0, 0, 37, 23), &mut got);
+                assert_eq!(got, want, "encoding {} rect {:?}", enc, r);
+            }
+        }
+
+        /*
+         * CopyRect, overlapping its source, and as a single pixel:
+         */
+        let copies = [(0, 0, 1, 1, 9, 5), (36, 22, 0, 0, 1, 1)];
+        for &(sx, sy, x, y, w, h) in &copies {
+            let mut msg = vec![0, 0, 1];
+            for v in [x, y, w, h] {
+                msg.extend((v as u16).to_be_bytes());
+            }
+            msg.extend(COPYRECT.to_be_bytes());
+            msg.extend((sx as u16).to_be_bytes());
+            msg.extend((sy as u16).to_be_bytes());
+            update(&server, &mut &msg[..], &mut fb, &mut z).await.unwrap();
+
+            let old = want.clone();
+            for row in 0..h {
+                want[(y + row) * 37 + x..][..w]
+                    .copy_from_slice(&old[(sy + row) * 37 + sx..][..w]);
+            }
+            let mut got = vec![0; 37 * 23];
+            fb.read_rows(&Rect::new(0, 0, 37, 23), &mut got);
+            assert_eq!(got, want);
+        }
+    }
+}
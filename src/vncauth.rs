@@ -0,0 +1,176 @@
+use std::convert::TryInto;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use des::cipher::generic_array::GenericArray;
+use des::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use des::Des;
+
+/*
+ * Classic VNC Authentication: the server sends a random challenge, which the
+ * client encrypts with DES using the password as the key.  Only the first
+ * eight bytes of the password are significant.
+ */
+pub const CHALLENGE_SIZE: usize = 16;
+
+pub type Challenge = [u8; CHALLENGE_SIZE];
+
+pub fn challenge() -> Challenge {
+    let mut c = [0; CHALLENGE_SIZE];
+    openssl::rand::rand_bytes(&mut c).expect("random challenge");
+    c
+}
+
+/*
+ * The response we expect from a client that knows the password.
+ */
+pub fn response(password: &str, challenge: &Challenge) -> Challenge {
+    let des = cipher(&pad(password));
+
+    let mut out = *challenge;
+    for block in out.chunks_exact_mut(8) {
+        des.encrypt_block(GenericArray::from_mut_slice(block));
+    }
+    out
+}
+
+/*
+ * The password is padded with zeroes, or truncated, to the size of a DES
+ * key.
+ */
+fn pad(password: &str) -> [u8; 8] {
+    let mut key = [0u8; 8];
+    for (k, p) in key.iter_mut().zip(password.bytes()) {
+        *k = p;
+    }
+    key
+}
+
+/*
+ * For historical reasons the bits of each key byte are reversed.
+ */
+fn cipher(key: &[u8; 8]) -> Des {
+    let key = key.map(u8::reverse_bits);
+    Des::new(GenericArray::from_slice(&key))
+}
+
+pub fn verify(password: &str, challenge: &Challenge, resp: &Challenge)
+    -> bool
+{
+    openssl::memcmp::eq(&response(password, challenge), resp)
+}
+
+/*
+ * The traditional password file, as written by vncpasswd, holds the padded
+ * password encrypted with a fixed key.  This is obfuscation rather than
+ * protection, so the file must only be readable by its owner.
+ */
+const FILE_KEY: [u8; 8] = [23, 82, 107, 6, 35, 78, 88, 7];
+
+pub fn read_file(path: &Path) -> Result<(String, Option<String>)> {
+    let buf = std::fs::read(path)
+        .with_context(|| format!("reading {:?}", path))?;
+    if buf.len() < 8 {
+        bail!("{:?} is not a VNC password file", path);
+    }
+
+    let password = |block: &[u8]| {
+        let mut block: [u8; 8] = block.try_into().unwrap();
+        cipher(&FILE_KEY)
+            .decrypt_block(GenericArray::from_mut_slice(&mut block));
+        let end = block.iter().position(|c| *c == 0).unwrap_or(block.len());
+        match std::str::from_utf8(&block[..end]) {
+            Ok(s) if !s.is_empty() => Ok(s.to_string()),
+            _ => bail!("{:?} does not contain a valid password", path),
+        }
+    };
+
+    /*
+     * The file may hold a second password, for view-only access.
+     */
+    let view = match buf.get(8..16) {
+        Some(block) => Some(password(block)?),
+        None => None,
+    };
+    Ok((password(&buf[..8])?, view))
+}
+
+pub fn write_file(path: &Path, password: &str, view: Option<&str>)
+    -> Result<()>
+{
+    let mut buf = Vec::new();
+    for pw in std::iter::once(password).chain(view) {
+        if pw.is_empty() {
+            bail!("the password must not be empty");
+        }
+        let mut block = pad(pw);
+        cipher(&FILE_KEY)
+            .encrypt_block(GenericArray::from_mut_slice(&mut block));
+        buf.extend_from_slice(&block);
+    }
+
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("creating {:?}", dir))?;
+    }
+    let mut f = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("writing {:?}", path))?;
+    f.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    f.write_all(&buf)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_vector() {
+        /*
+         * Worked out separately with "openssl enc -des-ecb", using the
+         * password with the bits of each byte reversed as the key.  Only the
+         * first eight bytes of the password count.
+         */
+        let challenge: Challenge = std::array::from_fn(|i| i as u8);
+        let expect = [
+            0xb8, 0x66, 0x92, 0x41, 0x25, 0xc8, 0xee, 0xbb,
+            0x9d, 0xeb, 0xc1, 0xdb, 0x61, 0xc5, 0x38, 0xe2,
+        ];
+        assert_eq!(response("password", &challenge), expect);
+        assert!(verify("password123", &challenge, &expect));
+        assert!(!verify("passwore", &challenge, &expect));
+    }
+
+    #[test]
+    fn file() {
+        let path = std::env::temp_dir()
+            .join(format!("jvnc-passwd-{}", std::process::id()));
+
+        /*
+         * This is what vncpasswd writes for "password".
+         */
+        write_file(&path, "password", None).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(),
+            [0xdb, 0xd8, 0x3c, 0xfd, 0x72, 0x7a, 0x14, 0x58]);
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode()
+            & 0o777, 0o600);
+        assert_eq!(read_file(&path).unwrap(), ("password".to_string(), None));
+
+        write_file(&path, "secret", Some("view")).unwrap();
+        assert_eq!(read_file(&path).unwrap(),
+            ("secret".to_string(), Some("view".to_string())));
+
+        assert!(write_file(&path, "", None).is_err());
+        std::fs::write(&path, b"short").unwrap();
+        assert!(read_file(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use tokio::time::timeout;
+
+use crate::http::{self, Request};
+use crate::listen::{Addr, Stream, Transport};
+use crate::server::Server;
+use crate::websocket;
+
+/*
+ * Connections to the listeners for browsers begin with an HTTP request.  One
+ * for a WebSocket is upgraded, and the connection then goes on to become a
+ * session like any other.  On an "http://" listener, any other request is
+ * for a page of the viewer, which is served and the connection closed, so
+ * that loading the viewer does not count as a session of its own.
+ */
+const INDEX: &str = include_str!("novnc.html");
+
+/*
+ * Read the request from a connection on a listener for "transport", and
+ * return the connection if it has become a WebSocket.
+ */
+pub async fn accept(server: &Server, mut sock: Stream, addr: &Addr,
+    transport: Transport) -> Result<Option<Stream>>
+{
+    let config = &server.config;
+    let (req, rest) = timeout(config.version_timeout,
+        http::read_request(&mut sock)).await
+        .map_err(|_| anyhow!("timed out waiting for a request"))??;
+
+    if transport == Transport::Http && !websocket::is_upgrade(&req) {
+        serve(&mut sock, addr, &req, config.novnc.as_deref()).await?;
+        return Ok(None);
+    }
+
+    let ws = websocket::upgrade(sock, &req, rest, &config.origins).await?;
+    log!("{}: websocket {} (origin {:?})", addr, req.path,
+        req.header("Origin"));
+    Ok(Some(Stream::WebSocket(Box::new(ws))))
+}
+
+/*
+ * Answer a request for a page: the root is our own page, and everything else
+ * is a file from noVNC in "dir", if we have it.
+ */
+async fn serve(sock: &mut Stream, addr: &Addr, req: &Request,
+    dir: Option<&Path>) -> Result<()>
+{
+    let head = req.method == "HEAD";
+    let path = req.path.split('?').next().unwrap_or_default();
+    let (status, page) = if req.method != "GET" && !head {
+        ("405 Method Not Allowed", None)
+    } else if dir.is_none() {
+        ("503 Service Unavailable", None)
+    } else if path == "/" {
+        ("200 OK", Some((INDEX.as_bytes().to_vec(), "text/html")))
+    } else {
+        match file(dir.unwrap(), path).await {
+            Some(page) => ("200 OK", Some(page)),
+            None => ("404 Not Found", None),
+        }
+    };
+    log!("{}: {} {}: {}", addr, req.method, req.path, status);
+
+    match page {
+        Some((body, content_type)) => {
+            http::send(sock, status, &["Cache-Control: no-cache"],
+                content_type, &body, head).await
+        }
+        None if dir.is_none() => {
+            http::respond(sock, status, &[], "The viewer is not available, \
+                as no directory for noVNC has been configured.").await
+        }
+        None => http::respond(sock, status, &[], status).await,
+    }
+}
+
+/*
+ * Read the file at "path" under "dir", if the path names one there and
+ * nowhere else, with its content type.
+ */
+async fn file(dir: &Path, path: &str) -> Option<(Vec<u8>, &'static str)> {
+    let rel = PathBuf::from(path.trim_start_matches('/'));
+    if rel.as_os_str().is_empty()
+        || !rel.components().all(|c| matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+
+    let body = match tokio::fs::read(dir.join(&rel)).await {
+        Ok(body) => body,
+        Err(e) if e.kind() == ErrorKind::NotFound => return None,
+        Err(e) => {
+            log!("reading {:?}: {}", rel, e);
+            return None;
+        }
+    };
+    let ext = rel.extension().and_then(|e| e.to_str()).unwrap_or_default();
+    Some((body, content_type(ext)))
+}
+
+/*
+ * Browsers will only run modules served as JavaScript, so the type has to
+ * be right at least for those.
+ */
+fn content_type(ext: &str) -> &'static str {
+    match ext {
+        "html" => "text/html",
+        "js" | "mjs" => "text/javascript",
+        "css" => "text/css",
+        "json" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "ico" => "image/x-icon",
+        "ogg" | "oga" => "audio/ogg",
+        "mp3" => "audio/mpeg",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn files() {
+        let dir = std::env::temp_dir()
+            .join(format!("jvnc-web-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("core")).unwrap();
+        std::fs::write(dir.join("core/rfb.js"), b"export default 1;")
+            .unwrap();
+
+        let (body, ct) = file(&dir, "/core/rfb.js").await.unwrap();
+        assert_eq!(body, b"export default 1;");
+        assert_eq!(ct, "text/javascript");
+
+        /*
+         * Nothing outside the directory may be reached, nor the directory
+         * itself read as a file.
+         */
+        assert!(file(&dir, "/core/missing.js").await.is_none());
+        assert!(file(&dir, "/core/../core/rfb.js").await.is_none());
+        assert!(file(&dir, "/../etc/passwd").await.is_none());
+        assert!(file(&dir, "/core").await.is_none());
+        assert!(file(&dir, "/").await.is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,557 @@
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind, Result};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use openssl::base64;
+use openssl::sha::sha1;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::handshake::{Failed, Outcome};
+use crate::http::{self, Request};
+
+/*
+ * RFB carried in a WebSocket (RFC 6455), so that viewers running in a
+ * browser, such as noVNC, can connect to us without a proxy like websockify
+ * in between.  Each flush of the RFB stream is sent as one binary message,
+ * and the messages from the client are read back as a single stream, without
+ * regard to where one ends and the next begins, just as RFB over TCP would
+ * be.
+ */
+pub struct WebSocket<S> {
+    inner: S,
+    /*
+     * Data read from the inner stream but not yet consumed, and what is left
+     * of the message payload being read, with the mask that applies to it
+     * and where in the mask the next byte falls:
+     */
+    input: Vec<u8>,
+    consumed: usize,
+    remaining: u64,
+    mask: [u8; 4],
+    offset: usize,
+    /*
+     * Whether the client has sent a close frame, after which it sends
+     * nothing more:
+     */
+    closed: bool,
+    /*
+     * Data written but not yet framed, and the frames being written to the
+     * inner stream, with how much of them has been sent:
+     */
+    pending: Vec<u8>,
+    out: Vec<u8>,
+    sent: usize,
+    close_sent: bool,
+}
+
+/*
+ * Appended to the key sent by the client to make the one we send back, to
+ * show that we understood the upgrade:
+ */
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/*
+ * Data written between flushes is sent as a single message, unless it grows
+ * beyond this size first.
+ */
+const MAX_MESSAGE: usize = 1024 * 1024;
+
+const READ_SIZE: usize = 16 * 1024;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/*
+ * Whether "req" asks for a WebSocket at all, whether or not it does so
+ * correctly.
+ */
+pub fn is_upgrade(req: &Request) -> bool {
+    req.has_token("Upgrade", "websocket")
+}
+
+/*
+ * Whether the page that sent "req", if it came from a browser, may talk to
+ * us: it must have been loaded from the host the request was sent to, or
+ * from one of "origins".  Clients other than browsers send no origin.
+ */
+fn trusted(req: &Request, origins: &[String]) -> bool {
+    let Some(origin) = req.header("Origin") else {
+        return true;
+    };
+    if origins.iter().any(|o| o.eq_ignore_ascii_case(origin)) {
+        return true;
+    }
+    match (origin.split_once("://"), req.header("Host")) {
+        (Some((_, host)), Some(ours)) => host.eq_ignore_ascii_case(ours),
+        _ => false,
+    }
+}
+
+/*
+ * Agree to the upgrade in "req", or refuse the request if it is not one, or
+ * if it comes from a page that is not in "origins" or served by us.
+ * Anything the client sent after the request is in "rest".
+ */
+pub async fn upgrade<S>(mut sock: S, req: &Request, rest: Vec<u8>,
+    origins: &[String]) -> anyhow::Result<WebSocket<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let key = match req.header("Sec-WebSocket-Key") {
+        Some(key) if req.method == "GET"
+            && is_upgrade(req)
+            && req.has_token("Connection", "upgrade") => key,
+        _ => {
+            http::respond(&mut sock, "400 Bad Request", &[],
+                "This is a VNC server, for WebSocket clients only.").await?;
+            anyhow::bail!(Failed(Outcome::BadVersion,
+                format!("not a WebSocket upgrade: {} {}", req.method,
+                req.path)));
+        }
+    };
+    if req.header("Sec-WebSocket-Version") != Some("13") {
+        http::respond(&mut sock, "426 Upgrade Required",
+            &["Sec-WebSocket-Version: 13"],
+            "Only version 13 of the WebSocket protocol is supported.").await?;
+        anyhow::bail!(Failed(Outcome::BadVersion,
+            format!("WebSocket version {:?}",
+            req.header("Sec-WebSocket-Version"))));
+    }
+    if !trusted(req, origins) {
+        http::respond(&mut sock, "403 Forbidden", &[],
+            "WebSocket connections are not accepted from this origin.")
+            .await?;
+        anyhow::bail!(Failed(Outcome::Refused,
+            format!("WebSocket from origin {:?}", req.header("Origin"))));
+    }
+
+    /*
+     * Older versions of noVNC ask for the "binary" subprotocol, and will not
+     * go on unless we agree to it.
+     */
+    let accept = base64::encode_block(&sha1(format!("{}{}", key,
+        GUID).as_bytes()));
+    let protocol = if req.has_token("Sec-WebSocket-Protocol", "binary") {
+        "Sec-WebSocket-Protocol: binary\r\n"
+    } else {
+        ""
+    };
+    sock.write_all(format!("HTTP/1.1 101 Switching Protocols\r\n\
+        Upgrade: websocket\r\nConnection: Upgrade\r\n\
+        Sec-WebSocket-Accept: {}\r\n{}\r\n", accept, protocol).as_bytes())
+        .await?;
+    Ok(WebSocket::new(sock, rest))
+}
+
+impl<S> WebSocket<S> {
+    fn new(inner: S, input: Vec<u8>) -> WebSocket<S> {
+        WebSocket {
+            inner,
+            input,
+            consumed: 0,
+            remaining: 0,
+            mask: [0; 4],
+            offset: 0,
+            closed: false,
+            pending: Vec::new(),
+            out: Vec::new(),
+            sent: 0,
+            close_sent: false,
+        }
+    }
+}
+
+/*
+ * The header of a frame from the client:
+ */
+struct Header {
+    opcode: u8,
+    len: usize,
+    payload: u64,
+    mask: [u8; 4],
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("WebSocket: {}", msg))
+}
+
+impl Header {
+    /*
+     * Parse the header at the start of "b", if it is all there.  The payload
+     * of a control frame, which is short and must be dealt with as a whole,
+     * has to be there too.
+     */
+    fn parse(b: &[u8]) -> Result<Option<Header>> {
+        if b.len() < 2 {
+            return Ok(None);
+        }
+        let fin = b[0] & 0x80 != 0;
+        let opcode = b[0] & 0x0f;
+        if b[0] & 0x70 != 0 {
+            return Err(invalid("reserved bits set"));
+        }
+        if b[1] & 0x80 == 0 {
+            return Err(invalid("unmasked frame from the client"));
+        }
+        if !matches!(opcode, CONTINUATION | TEXT | BINARY | CLOSE | PING
+            | PONG)
+        {
+            return Err(invalid("reserved opcode"));
+        }
+
+        let (ext, payload) = match b[1] & 0x7f {
+            126 if b.len() >= 4 => {
+                (2, u16::from_be_bytes([b[2], b[3]]) as u64)
+            }
+            127 if b.len() >= 10 => {
+                (8, u64::from_be_bytes(b[2..10].try_into().unwrap()))
+            }
+            126 | 127 => return Ok(None),
+            n => (0, n as u64),
+        };
+        let len = 2 + ext + 4;
+        if b.len() < len {
+            return Ok(None);
+        }
+        if opcode & 0x8 != 0 {
+            if !fin || payload > 125 {
+                return Err(invalid("control frame too long"));
+            }
+            if b.len() < len + payload as usize {
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(Header {
+            opcode,
+            len,
+            payload,
+            mask: b[2 + ext..len].try_into().unwrap(),
+        }))
+    }
+}
+
+/*
+ * Append a frame to "out".  Frames from the server are not masked.
+ */
+fn frame(out: &mut Vec<u8>, opcode: u8, payload: &[u8]) {
+    out.push(0x80 | opcode);
+    match payload.len() {
+        n if n < 126 => out.push(n as u8),
+        n if n <= u16::MAX as usize => {
+            out.push(126);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        n => {
+            out.push(127);
+            out.extend_from_slice(&(n as u64).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(payload);
+}
+
+impl<S: AsyncRead + Unpin> WebSocket<S> {
+    /*
+     * Read more from the inner stream, returning how much was read.
+     */
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.input.drain(..self.consumed);
+        self.consumed = 0;
+        let have = self.input.len();
+        self.input.resize(have + READ_SIZE, 0);
+        let mut rb = ReadBuf::new(&mut self.input[have..]);
+        let res = Pin::new(&mut self.inner).poll_read(cx, &mut rb);
+        let n = rb.filled().len();
+        self.input.truncate(have + n);
+        ready!(res)?;
+        Poll::Ready(Ok(n))
+    }
+
+    /*
+     * Deal with a control frame, whose payload follows "h" in the input.
+     * Pings are answered along with the next message we send, and a close
+     * frame is echoed, as the protocol requires.
+     */
+    fn control(&mut self, h: &Header) -> Result<()> {
+        let start = self.consumed + h.len;
+        let mut payload = self.input[start..start + h.payload as usize]
+            .to_vec();
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= h.mask[i % 4];
+        }
+        self.consumed = start + payload.len();
+
+        match h.opcode {
+            PING => frame(&mut self.out, PONG, &payload),
+            PONG => (),
+            CLOSE => {
+                self.closed = true;
+                if !self.close_sent {
+                    payload.truncate(2);
+                    frame(&mut self.out, CLOSE, &payload);
+                    self.close_sent = true;
+                }
+            }
+            _ => return Err(invalid("unknown control frame")),
+        }
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for WebSocket<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let s = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            if s.closed {
+                return Poll::Ready(Ok(()));
+            }
+
+            let avail = &s.input[s.consumed..];
+            if s.remaining > 0 {
+                if !avail.is_empty() {
+                    let n = avail.len()
+                        .min(buf.remaining())
+                        .min(s.remaining.min(usize::MAX as u64) as usize);
+                    let dst = buf.initialize_unfilled_to(n);
+                    for (i, (d, b)) in dst.iter_mut().zip(avail).enumerate() {
+                        *d = b ^ s.mask[(s.offset + i) % 4];
+                    }
+                    buf.advance(n);
+                    s.consumed += n;
+                    s.remaining -= n as u64;
+                    s.offset = (s.offset + n) % 4;
+                    return Poll::Ready(Ok(()));
+                }
+            } else if let Some(h) = Header::parse(avail)? {
+                match h.opcode {
+                    CONTINUATION | BINARY => {
+                        s.consumed += h.len;
+                        s.remaining = h.payload;
+                        s.mask = h.mask;
+                        s.offset = 0;
+                    }
+                    TEXT => return Poll::Ready(Err(invalid(
+                        "text messages are not supported"))),
+                    _ => s.control(&h)?,
+                }
+                continue;
+            }
+
+            if ready!(s.poll_fill(cx))? == 0 {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> WebSocket<S> {
+    /*
+     * Frame whatever is pending, and write out all of the frames we have.
+     */
+    fn poll_out(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        loop {
+            if self.sent == self.out.len() {
+                self.out.clear();
+                self.sent = 0;
+                if self.pending.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                frame(&mut self.out, BINARY, &self.pending);
+                self.pending.clear();
+            }
+
+            let n = ready!(Pin::new(&mut self.inner)
+                .poll_write(cx, &self.out[self.sent..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.sent += n;
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for WebSocket<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        let s = self.get_mut();
+        if s.close_sent {
+            return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+        }
+        if s.pending.len() >= MAX_MESSAGE {
+            ready!(s.poll_out(cx))?;
+        }
+        s.pending.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>)
+        -> Poll<Result<()>>
+    {
+        let s = self.get_mut();
+        ready!(s.poll_out(cx))?;
+        Pin::new(&mut s.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>)
+        -> Poll<Result<()>>
+    {
+        let s = self.get_mut();
+        ready!(s.poll_out(cx))?;
+        if !s.close_sent {
+            /*
+             * A normal closure, with status 1000:
+             */
+            frame(&mut s.out, CLOSE, &1000u16.to_be_bytes());
+            s.close_sent = true;
+            ready!(s.poll_out(cx))?;
+        }
+        Pin::new(&mut s.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    async fn accept<S>(mut sock: S) -> anyhow::Result<WebSocket<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (req, rest) = http::read_request(&mut sock).await?;
+        upgrade(sock, &req, rest, &["https://viewer.example".to_string()])
+            .await
+    }
+
+    fn masked(opcode: u8, fin: bool, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut f = vec![opcode | if fin { 0x80 } else { 0 },
+            0x80 | payload.len() as u8];
+        f.extend_from_slice(&mask);
+        f.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        f
+    }
+
+    #[tokio::test]
+    async fn upgraded() {
+        let (mut c, s) = tokio::io::duplex(64 * 1024);
+
+        /*
+         * The key and the answer to it are those of the example in the RFC.
+         * The first message follows hard on the request, and is split
+         * across two frames, with a ping between them.
+         */
+        let mut req = b"GET /websockify HTTP/1.1\r\nHost: x\r\n\
+            Upgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Protocol: binary\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n".to_vec();
+        req.extend(masked(BINARY, false, b"RFB 003"));
+        req.extend(masked(PING, true, b"hi"));
+        req.extend(masked(CONTINUATION, true, b".008\n"));
+        c.write_all(&req).await.unwrap();
+
+        let mut ws = accept(s).await.unwrap();
+        let mut version = [0u8; 12];
+        ws.read_exact(&mut version).await.unwrap();
+        assert_eq!(&version, b"RFB 003.008\n");
+        ws.write_all(b"RFB ").await.unwrap();
+        ws.write_all(b"003.008\n").await.unwrap();
+        ws.flush().await.unwrap();
+
+        let mut expect = b"HTTP/1.1 101 Switching Protocols\r\n\
+            Upgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\
+            Sec-WebSocket-Protocol: binary\r\n\r\n".to_vec();
+        expect.extend([0x8a, 2]);
+        expect.extend(b"hi");
+        expect.extend([0x82, 12]);
+        expect.extend(b"RFB 003.008\n");
+        let mut got = vec![0; expect.len()];
+        c.read_exact(&mut got).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&got),
+            String::from_utf8_lossy(&expect));
+
+        /*
+         * Once the client closes, the stream ends and the close is echoed.
+         */
+        c.write_all(&masked(CLOSE, true, &1000u16.to_be_bytes())).await
+            .unwrap();
+        assert_eq!(ws.read(&mut version).await.unwrap(), 0);
+        ws.shutdown().await.unwrap();
+        let mut close = [0; 4];
+        c.read_exact(&mut close).await.unwrap();
+        assert_eq!(close, [0x88, 2, 0x03, 0xe8]);
+    }
+
+    async fn request(headers: &str) -> (anyhow::Result<()>, String) {
+        let (mut c, s) = tokio::io::duplex(64 * 1024);
+        c.write_all(format!("GET / HTTP/1.1\r\nHost: x:5800\r\n{}\r\n",
+            headers).as_bytes()).await.unwrap();
+        let res = accept(s).await.map(|_| ());
+        let mut reply = String::new();
+        c.read_to_string(&mut reply).await.unwrap();
+        (res, reply)
+    }
+
+    #[tokio::test]
+    async fn refused() {
+        let (res, reply) = request("").await;
+        assert_eq!(Outcome::of(&res.err().unwrap()), Outcome::BadVersion);
+        assert!(reply.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        /*
+         * A page from anywhere but here, or the origins we were given, may
+         * not connect, whatever the name it gives for us:
+         */
+        let upgrade = "Upgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n";
+        for origin in ["https://evil.example", "http://x:5801",
+            "https://evil.example/x:5800", "null"]
+        {
+            let (res, reply) = request(&format!("{}Origin: {}\r\n",
+                upgrade, origin)).await;
+            assert_eq!(Outcome::of(&res.err().unwrap()), Outcome::Refused);
+            assert!(reply.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        }
+        for origin in ["http://x:5800", "https://viewer.example"] {
+            let (res, reply) = request(&format!("{}Origin: {}\r\n",
+                upgrade, origin)).await;
+            assert!(res.is_ok(), "{} refused", origin);
+            assert!(reply.starts_with("HTTP/1.1 101 "));
+        }
+    }
+
+    #[tokio::test]
+    async fn reserved() {
+        /*
+         * A frame with a reserved opcode is refused as soon as its header
+         * arrives, whatever the length of the payload it claims.
+         */
+        for opcode in [3, 7, 0xb, 0xf] {
+            let mut ws = WebSocket::new(tokio::io::empty(),
+                vec![0x80 | opcode, 0x80 | 100, 1, 2, 3, 4]);
+            let mut buf = [0; 16];
+            let e = ws.read(&mut buf).await.err().unwrap();
+            assert_eq!(e.kind(), ErrorKind::InvalidData);
+            assert!(e.to_string().contains("reserved opcode"));
+        }
+    }
+}
@@ -0,0 +1,354 @@
+use std::convert::{TryFrom, TryInto};
+use std::marker::PhantomData;
+
+/*
+ * Protocol messages are described declaratively with the messages! macro
+ * below, which generates a struct for each message along with the code to
+ * parse and serialise it.  Parsing works on whatever has arrived so far: if
+ * the buffer does not yet hold a whole message, nothing is consumed and the
+ * caller should try again once more data has arrived.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /*
+     * The buffer does not yet hold the whole message.
+     */
+    Short,
+    /*
+     * The message type is not one we know, along with the subtype if the
+     * message type has them.
+     */
+    Unknown(u8, Option<u8>),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf, pos: 0 }
+    }
+
+    /*
+     * The number of bytes consumed so far.
+     */
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    /*
+     * Look at a byte ahead of the current position without consuming it.
+     */
+    pub fn peek(&self, offset: usize) -> Result<u8> {
+        self.buf.get(self.pos + offset).copied().ok_or(Error::Short)
+    }
+
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.remaining() < n {
+            return Err(Error::Short);
+        }
+        let b = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(b)
+    }
+}
+
+/*
+ * Something that can appear in a message.  SIZE is the smallest number of
+ * bytes it can occupy, which allows us to tell that a message is incomplete
+ * before we go to the trouble of parsing it.
+ */
+pub trait Wire: Sized {
+    const SIZE: usize;
+
+    fn read(r: &mut Reader) -> Result<Self>;
+    fn write(&self, w: &mut Vec<u8>);
+}
+
+macro_rules! wire_int {
+    ($($t:ty),*) => {
+        $(
+            impl Wire for $t {
+                const SIZE: usize = std::mem::size_of::<$t>();
+
+                fn read(r: &mut Reader) -> Result<$t> {
+                    let b = r.take(Self::SIZE)?;
+                    Ok(<$t>::from_be_bytes(b.try_into().unwrap()))
+                }
+
+                fn write(&self, w: &mut Vec<u8>) {
+                    w.extend_from_slice(&self.to_be_bytes());
+                }
+            }
+        )*
+    };
+}
+
+wire_int!(u8, u16, u32, i32);
+
+impl<const N: usize> Wire for [u8; N] {
+    const SIZE: usize = N;
+
+    fn read(r: &mut Reader) -> Result<[u8; N]> {
+        let mut a = [0; N];
+        a.copy_from_slice(r.take(N)?);
+        Ok(a)
+    }
+
+    fn write(&self, w: &mut Vec<u8>) {
+        w.extend_from_slice(self);
+    }
+}
+
+/*
+ * The types that may be used for the item count of a list:
+ */
+pub trait Count: Wire {
+    fn to_usize(&self) -> usize;
+    fn from_usize(n: usize) -> Option<Self>;
+}
+
+macro_rules! count_int {
+    ($($t:ty),*) => {
+        $(
+            impl Count for $t {
+                fn to_usize(&self) -> usize {
+                    *self as usize
+                }
+
+                fn from_usize(n: usize) -> Option<$t> {
+                    <$t>::try_from(n).ok()
+                }
+            }
+        )*
+    };
+}
+
+count_int!(u8, u16, u32);
+
+/*
+ * A list of items, preceded by a count of type C, which may in turn be
+ * followed by PAD bytes of padding before the items themselves.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Counted<C, T, const PAD: usize = 0> {
+    pub items: Vec<T>,
+    count: PhantomData<C>,
+}
+
+impl<C, T, const PAD: usize> From<Vec<T>> for Counted<C, T, PAD> {
+    fn from(items: Vec<T>) -> Self {
+        Counted { items, count: PhantomData }
+    }
+}
+
+impl<C: Count, T: Wire, const PAD: usize> Wire for Counted<C, T, PAD> {
+    const SIZE: usize = C::SIZE + PAD;
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        let n = C::read(r)?.to_usize();
+        r.take(PAD)?;
+
+        /*
+         * Make sure the whole list has arrived before we allocate anything,
+         * as the count may be large.
+         */
+        if n.saturating_mul(T::SIZE) > r.remaining() {
+            return Err(Error::Short);
+        }
+
+        let mut items = Vec::with_capacity(n);
+        for _ in 0..n {
+            items.push(T::read(r)?);
+        }
+        Ok(items.into())
+    }
+
+    /*
+     * The caller must ensure that the number of items fits in the count.
+     */
+    fn write(&self, w: &mut Vec<u8>) {
+        C::from_usize(self.items.len())
+            .expect("too many items for count")
+            .write(w);
+        w.extend_from_slice(&[0; PAD]);
+        for item in &self.items {
+            item.write(w);
+        }
+    }
+}
+
+macro_rules! wire_opt {
+    () => {
+        None
+    };
+    ($e:expr) => {
+        Some($e)
+    };
+}
+
+/*
+ * Define a set of messages, and an enum that can hold any one of them:
+ *
+ *     messages! {
+ *         pub enum ClientMessage {
+ *             4 => KeyEvent { down: u8, [2] key: u32 },
+ *             255/0 => QemuKeyEvent { ... },
+ *         }
+ *     }
+ *
+ * Each message has a type byte, and optionally a subtype byte after it.  The
+ * fields follow in order, each optionally preceded by a number of padding
+ * bytes in square brackets.
+ */
+macro_rules! messages {
+    (
+        $(#[$emeta:meta])*
+        $vis:vis enum $enum:ident {
+            $(
+                $(#[$meta:meta])*
+                $id:literal $(/ $sub:literal)? => $name:ident {
+                    $( $([$pad:literal])? $field:ident : $ty:ty ),* $(,)?
+                }
+            ),* $(,)?
+        }
+    ) => {
+        $(
+            $(#[$meta])*
+            #[derive(Debug, Clone, PartialEq, Eq)]
+            pub struct $name {
+                $( pub $field: $ty, )*
+            }
+
+            impl $name {
+                pub const TYPE: u8 = $id;
+                pub const SUBTYPE: Option<u8> = wire_opt!($($sub)?);
+            }
+
+            impl $crate::wire::Wire for $name {
+                const SIZE: usize = 1 + $name::SUBTYPE.is_some() as usize
+                    $( $(+ $pad)? + <$ty as $crate::wire::Wire>::SIZE )*;
+
+                fn read(r: &mut $crate::wire::Reader)
+                    -> $crate::wire::Result<$name>
+                {
+                    if r.remaining() < Self::SIZE {
+                        return Err($crate::wire::Error::Short);
+                    }
+
+                    let ty = r.take(1)?[0];
+                    let sub = match Self::SUBTYPE {
+                        Some(_) => Some(r.take(1)?[0]),
+                        None => None,
+                    };
+                    if ty != Self::TYPE || sub != Self::SUBTYPE {
+                        return Err($crate::wire::Error::Unknown(ty, sub));
+                    }
+
+                    $(
+                        $( r.take($pad)?; )?
+                        let $field = <$ty as $crate::wire::Wire>::read(r)?;
+                    )*
+                    Ok($name { $( $field, )* })
+                }
+
+                fn write(&self, w: &mut Vec<u8>) {
+                    w.push(Self::TYPE);
+                    w.extend(Self::SUBTYPE);
+                    $(
+                        $( w.extend_from_slice(&[0; $pad]); )?
+                        $crate::wire::Wire::write(&self.$field, w);
+                    )*
+                }
+            }
+
+            impl From<$name> for $enum {
+                fn from(m: $name) -> $enum {
+                    $enum::$name(m)
+                }
+            }
+        )*
+
+        $(#[$emeta])*
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        $vis enum $enum {
+            $( $name($name), )*
+        }
+
+        /*
+         * We may only ever read, or only ever write, a particular set of
+         * messages.
+         */
+        #[allow(dead_code)]
+        impl $enum {
+            /*
+             * Parse the message at the front of the buffer.
+             */
+            pub fn read(r: &mut $crate::wire::Reader)
+                -> $crate::wire::Result<$enum>
+            {
+                let ty = r.peek(0)?;
+                let mut sub = None;
+                $(
+                    if ty == $name::TYPE {
+                        match $name::SUBTYPE {
+                            Some(s) if r.peek(1)? != s => {
+                                sub = Some(r.peek(1)?);
+                            }
+                            _ => {
+                                let m = <$name as $crate::wire::Wire>::read(r)?;
+                                return Ok($enum::$name(m));
+                            }
+                        }
+                    }
+                )*
+                Err($crate::wire::Error::Unknown(ty, sub))
+            }
+
+            pub fn write(&self, w: &mut Vec<u8>) {
+                match self {
+                    $(
+                        $enum::$name(m) => {
+                            $crate::wire::Wire::write(m, w);
+                        }
+                    )*
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counted() {
+        let mut w = Vec::new();
+        Counted::<u16, u32, 1>::from(vec![1, 2]).write(&mut w);
+        assert_eq!(w, [0, 2, 0, 0, 0, 0, 1, 0, 0, 0, 2]);
+
+        /*
+         * Every prefix is short, and so is a count that claims more than
+         * could ever arrive, without the list being allocated first.
+         */
+        for n in 0..w.len() {
+            let r = Counted::<u16, u32, 1>::read(&mut Reader::new(&w[..n]));
+            assert_eq!(r.err(), Some(Error::Short), "cut at {}", n);
+        }
+        let big = [0xff, 0xff, 0xff, 0xff, 1, 2, 3];
+        let r = Counted::<u32, u32>::read(&mut Reader::new(&big));
+        assert_eq!(r.err(), Some(Error::Short));
+
+        let c = Counted::<u16, u32, 1>::read(&mut Reader::new(&w)).unwrap();
+        assert_eq!(c.items, [1, 2]);
+    }
+}